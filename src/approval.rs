@@ -0,0 +1,377 @@
+//! Two person approval. `plan --out` writes the pending migrations with their checksums into a
+//! plan file signed with the configured `approval_key`, `approve` countersigns it as a second
+//! operator and `apply` runs exactly the planned migrations once both signatures check out.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct PlannedMigration {
+    pub(crate) version: i64,
+    /// Checksum of the up and down file, see `lock::checksum`
+    pub(crate) checksum: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SignedPlan {
+    pub(crate) app: String,
+    pub(crate) dbname: String,
+    pub(crate) environment: String,
+    /// Last applied version when the plan was made
+    pub(crate) from: i64,
+    pub(crate) migrations: Vec<PlannedMigration>,
+    pub(crate) planned_by: String,
+    pub(crate) planned_at: String,
+    pub(crate) signature: String,
+    #[serde(default)]
+    pub(crate) approved_by: Option<String>,
+    #[serde(default)]
+    pub(crate) approval: Option<String>,
+}
+
+/// HMAC-SHA256 of `message`, hex encoded.
+fn hmac(key: &[u8], message: &[u8]) -> String {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|v| v ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|v| v ^ 0x5c));
+    outer.update(inner.finalize());
+    outer
+        .finalize()
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+/// The operator's identity: `explicit`, else `ARCHITECT_OPERATOR`, else `USER`.
+pub(crate) fn operator(explicit: Option<String>) -> Result<String> {
+    explicit
+        .or_else(|| std::env::var("ARCHITECT_OPERATOR").ok())
+        .or_else(|| std::env::var("USER").ok())
+        .filter(|v| !v.trim().is_empty())
+        .ok_or_else(|| {
+            anyhow::anyhow!("unknown operator. pass --operator or set ARCHITECT_OPERATOR")
+        })
+}
+
+fn key(key: &str) -> Result<&[u8]> {
+    if key.is_empty() {
+        return Err(anyhow::anyhow!(
+            "approval_key is not configured. it's needed to sign and verify plans"
+        ));
+    }
+    Ok(key.as_bytes())
+}
+
+impl SignedPlan {
+    /// What the planner signs.
+    fn payload(&self) -> String {
+        let mut result = format!(
+            "{}\n{}\n{}\n{}\n",
+            self.app, self.dbname, self.environment, self.from
+        );
+        for m in self.migrations.iter() {
+            result.push_str(&format!("{} {}\n", m.version, m.checksum));
+        }
+        result.push_str(&format!("{}\n{}", self.planned_by, self.planned_at));
+        result
+    }
+
+    /// What the approver signs.
+    fn approval_payload(&self, approved_by: &str) -> String {
+        format!("approve\n{}\n{}", self.signature, approved_by)
+    }
+
+    pub(crate) fn sign(&mut self, approval_key: &str) -> Result<()> {
+        self.signature = hmac(key(approval_key)?, self.payload().as_bytes());
+        Ok(())
+    }
+
+    /// Countersigns the plan as `operator`, who has to be someone else than the planner.
+    pub(crate) fn approve(&mut self, approval_key: &str, operator: &str) -> Result<()> {
+        self.verify_signature(approval_key)?;
+        if let Some(v) = &self.approved_by {
+            return Err(anyhow::anyhow!("plan was approved by {} already", v));
+        }
+        if operator == self.planned_by {
+            return Err(anyhow::anyhow!(
+                "plan was made by {}. it has to be approved by another operator",
+                operator
+            ));
+        }
+        self.approval = Some(hmac(
+            key(approval_key)?,
+            self.approval_payload(operator).as_bytes(),
+        ));
+        self.approved_by = Some(operator.to_owned());
+        Ok(())
+    }
+
+    fn verify_signature(&self, approval_key: &str) -> Result<()> {
+        if hmac(key(approval_key)?, self.payload().as_bytes()) != self.signature {
+            return Err(anyhow::anyhow!(
+                "invalid plan signature. the plan was changed or signed with another key"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks both signatures, returning the approver.
+    pub(crate) fn verify(&self, approval_key: &str) -> Result<&str> {
+        self.verify_signature(approval_key)?;
+        let (approved_by, approval) = match (&self.approved_by, &self.approval) {
+            (Some(by), Some(approval)) => (by, approval),
+            _ => return Err(anyhow::anyhow!("plan is not approved")),
+        };
+        if approved_by == &self.planned_by {
+            return Err(anyhow::anyhow!(
+                "plan was approved by its planner {}",
+                approved_by
+            ));
+        }
+        if &hmac(
+            key(approval_key)?,
+            self.approval_payload(approved_by).as_bytes(),
+        ) != approval
+        {
+            return Err(anyhow::anyhow!("invalid approval signature"));
+        }
+        Ok(approved_by)
+    }
+
+    pub(crate) fn read(path: &std::path::Path) -> Result<SignedPlan> {
+        match serde_json::from_str(&std::fs::read_to_string(path)?) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(anyhow::anyhow!("invalid plan file {:?}: {}", path, e)),
+        }
+    }
+
+    pub(crate) fn write(&self, path: &std::path::Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
+
+/// Approves the plan in `path` as `operator`, writing the approval into the file.
+pub(crate) fn approve(path: &std::path::Path, approval_key: &str, operator: &str) -> Result<()> {
+    let mut plan = SignedPlan::read(path)?;
+    plan.approve(approval_key, operator)?;
+    plan.write(path)?;
+    crate::output::result(
+        &format!(
+            "Approved {} migrations for {} planned by {}",
+            plan.migrations.len(),
+            plan.dbname,
+            plan.planned_by
+        ),
+        serde_json::json!({
+            "approved_by": operator,
+            "migrations": plan.migrations.len(),
+            "planned_by": plan.planned_by
+        }),
+    );
+    Ok(())
+}
+
+impl Migrator {
+    /// Refuses applying migrations outside of an approved plan with `require_approval = true`.
+    /// With an `auto_apply` policy only the pending migrations it doesn't cover need a plan.
+    pub(crate) fn check_approval_mode(&self) -> Result<()> {
+        if let Some(manual) = self.manual_migrations()? {
+            if manual.is_empty() {
+                return Ok(());
+            }
+            let reasons: Vec<String> = manual
+                .iter()
+                .map(|(file, reason)| format!("{}: {}", file, reason))
+                .collect();
+            return Err(crate::error::ArchitectError::ApprovalRequired(format!(
+                "migrations need approval in {}: {}. create a plan with `plan --out`, have it \
+                approved and run it with `apply`",
+                self.config.environment,
+                reasons.join(", ")
+            ))
+            .into());
+        }
+        if self.config.require_approval {
+            return Err(crate::error::ArchitectError::ApprovalRequired(format!(
+                "{} requires approval. create a plan with `plan --out`, have it approved and \
+                run it with `apply`",
+                self.config.dbname
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The pending migrations as a plan signed by `operator`.
+    pub(crate) fn signed_plan(&self, operator: &str) -> Result<SignedPlan> {
+        let mut plan = SignedPlan {
+            app: self.config.app.clone(),
+            dbname: self.config.dbname.clone(),
+            environment: self.config.environment.clone(),
+            from: self.last_version,
+            migrations: self.planned_migrations()?,
+            planned_by: operator.to_owned(),
+            planned_at: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(),
+            approved_by: None,
+            approval: None,
+        };
+        plan.sign(&self.config.approval_key)?;
+        Ok(plan)
+    }
+
+    fn planned_migrations(&self) -> Result<Vec<PlannedMigration>> {
+        let mut result = Vec::<PlannedMigration>::new();
+        for step in self.steps(self.last_version, i64::MAX) {
+            result.push(PlannedMigration {
+                version: step.version,
+                checksum: crate::lock::checksum(&self.dir, step.version)?,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Applies an approved plan, provided the database and the migrations are still as planned.
+    /// Planner and approver are recorded with every applied version.
+    pub(crate) fn apply(&mut self, plan: &SignedPlan) -> Result<usize> {
+        let approved_by = plan.verify(&self.config.approval_key)?;
+        if plan.app != self.config.app
+            || plan.dbname != self.config.dbname
+            || plan.environment != self.config.environment
+        {
+            return Err(anyhow::anyhow!(
+                "plan is for {} of {} ({}), not for this config",
+                plan.app,
+                plan.dbname,
+                plan.environment
+            ));
+        }
+        if plan.from != self.last_version {
+            return Err(anyhow::anyhow!(
+                "plan was made at version {} but the database is at {}",
+                plan.from,
+                self.last_version
+            ));
+        }
+        if self.planned_migrations()? != plan.migrations {
+            return Err(anyhow::anyhow!(
+                "pending migrations changed since the plan was made"
+            ));
+        }
+        for m in plan.migrations.iter() {
+            self.run_migration(m.version, Direction::Up)?;
+            self.last_version = m.version;
+            self.client.execute(
+                "UPDATE schema_migrations SET planned_by = $1, approved_by = $2 WHERE version = $3",
+                &[&plan.planned_by, &approved_by, &m.version],
+            )?;
+        }
+        Ok(plan.migrations.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn hmac() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            super::hmac(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn approve_and_apply() {
+        let mut config = crate::tests::schema_config("__approval__");
+        config.approval_key = "secret".to_owned();
+        config.require_approval = true;
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./approval")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE a (id INT);",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_down.sql", version)),
+            b"DROP TABLE a;",
+        )
+        .unwrap();
+
+        let mut plan = m.signed_plan("alice").unwrap();
+        let unapproved = m.apply(&plan).is_err();
+        let self_approved = plan.approve("secret", "alice").is_err();
+        let wrong_key = plan.approve("guess", "bob").is_err();
+        plan.approve("secret", "bob").unwrap();
+        plan.migrations[0].checksum.push('0');
+        let tampered = m.apply(&plan).is_err();
+        plan.migrations[0].checksum.pop();
+        let applied = m.apply(&plan).unwrap();
+        let row = m
+            .client
+            .query_one(
+                "SELECT planned_by, approved_by FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+        let identities: (String, String) = (row.get(0), row.get(1));
+        let direct = m.check_approval_mode().is_err();
+        m.client
+            .batch_execute("DROP SCHEMA __approval__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./approval");
+
+        assert!(unapproved);
+        assert!(self_approved);
+        assert!(wrong_key);
+        assert!(tampered);
+        assert_eq!(applied, 1);
+        assert_eq!(identities, ("alice".to_owned(), "bob".to_owned()));
+        assert!(direct);
+    }
+
+    #[test]
+    fn auto_apply() {
+        let mut config = crate::tests::schema_config("__auto_apply__");
+        config.environment = "auto".to_owned();
+        config.require_approval = true;
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./auto_apply")).unwrap();
+        std::fs::write(
+            "./auto_apply/.architect.toml",
+            "[policy.auto]\nauto_apply = [\"additive\"]",
+        )
+        .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE a (id INT); CREATE INDEX a_id ON a (id);").unwrap();
+        let additive = m.check_approval_mode();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "DROP TABLE a;").unwrap();
+        let dropping = m.check_approval_mode();
+        m.client
+            .batch_execute("DROP SCHEMA __auto_apply__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./auto_apply");
+
+        additive.unwrap();
+        let e = dropping.unwrap_err().to_string();
+        assert!(e.contains("drop-table isn't auto-applied"), "{}", e);
+    }
+}
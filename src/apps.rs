@@ -0,0 +1,265 @@
+//! Orchestration of all apps of a project. The connection configs listed as `apps` in
+//! `.architect.toml` are planned and checked concurrently, at most `--jobs` at a time, so
+//! `plan --all-apps` over dozens of services takes seconds. `up --all-apps` migrates only once
+//! every app passed these preflight checks, one app after the other.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::output::info;
+use crate::plan::Plan;
+use crate::Migrator;
+
+/// Default of `--jobs`.
+pub(crate) const JOBS: usize = 8;
+
+/// The preflight result of an app.
+#[derive(Serialize)]
+pub(crate) struct AppPlan {
+    /// Connection config of the app, relative to the parent migration directory
+    pub(crate) config: std::path::PathBuf,
+    pub(crate) app: String,
+    pub(crate) plan: Option<Plan>,
+    /// Why the app couldn't be planned
+    pub(crate) error: Option<String>,
+}
+
+impl AppPlan {
+    /// Whether the app can't be migrated, `errors` telling if plan errors count.
+    fn failed(&self, errors: bool) -> bool {
+        match &self.plan {
+            Some(plan) if errors => plan.errors() > 0,
+            Some(plan) => crate::plan::enforce_policy(plan).is_err(),
+            None => true,
+        }
+    }
+}
+
+/// Plans the app of `config`, checking its lock file.
+fn preflight_app(migdir: &std::path::Path, config: &std::path::Path) -> Result<(String, Plan)> {
+    let config = crate::read_config_toml(&[migdir.join(config)])?;
+    let app = config.app.clone();
+    let mut m = Migrator::read_only(config, migdir.to_owned())?;
+    crate::lock::check(&m.dir)?;
+    Ok((app, m.plan()?))
+}
+
+/// The configs of the apps of the project in `migdir`.
+fn configs(migdir: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+    let apps = crate::project::Project::read(migdir)?.apps;
+    if apps.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no apps configured in {:?}",
+            migdir.join(crate::project::PROJECT_FILE)
+        ));
+    }
+    Ok(apps)
+}
+
+/// `f` applied to every item, `jobs` items at a time, in the order of `items`.
+pub(crate) fn concurrently<T: Send, R: Send>(
+    items: &mut [T],
+    jobs: usize,
+    f: impl Fn(&mut T) -> R + Sync,
+) -> Vec<R> {
+    let count = items.len();
+    let items: Vec<Mutex<&mut T>> = items.iter_mut().map(Mutex::new).collect();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::<(usize, R)>::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let item = match items.get(i) {
+                    Some(v) => v,
+                    None => break,
+                };
+                // every item is taken by a single thread
+                let result = match item.lock() {
+                    Ok(mut v) => f(&mut v),
+                    Err(_) => break,
+                };
+                if let Ok(mut results) = results.lock() {
+                    results.push((i, result));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_default();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Plans every app of the project in `migdir`, `jobs` at a time, in the order they're listed.
+pub(crate) fn preflight(migdir: &std::path::Path, jobs: usize) -> Result<Vec<AppPlan>> {
+    let mut configs = configs(migdir)?;
+    let plans = concurrently(&mut configs, jobs, |config| {
+        match preflight_app(migdir, config) {
+            Ok((app, plan)) => AppPlan {
+                config: config.clone(),
+                app,
+                plan: Some(plan),
+                error: None,
+            },
+            Err(e) => AppPlan {
+                config: config.clone(),
+                app: String::new(),
+                plan: None,
+                error: Some(e.to_string()),
+            },
+        }
+    });
+    if plans.len() != configs.len() {
+        return Err(anyhow::anyhow!("planning an app panicked"));
+    }
+    Ok(plans)
+}
+
+/// Prints the plans of the apps, failing if any has errors.
+pub(crate) fn print(plans: &[AppPlan]) -> Result<()> {
+    for (i, p) in plans.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("== {} ({:?})", p.app, p.config);
+        match (&p.plan, &p.error) {
+            // the errors are counted below
+            (Some(plan), _) => crate::plan::print(plan).unwrap_or_default(),
+            (None, Some(e)) => println!("{}", e),
+            (None, None) => {}
+        }
+    }
+    let failed = plans.iter().filter(|p| p.failed(true)).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} apps failed to plan",
+            failed,
+            plans.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Migrates up every app of the project in `migdir` once all passed their preflight checks, run
+/// `jobs` at a time. Returns the apps with the number of versions migrated.
+pub(crate) fn up(migdir: &std::path::Path, jobs: usize) -> Result<Vec<(String, usize)>> {
+    let plans = preflight(migdir, jobs)?;
+    let failed: Vec<&AppPlan> = plans.iter().filter(|p| p.failed(false)).collect();
+    for p in failed.iter() {
+        eprintln!(
+            "{} ({:?}): {}",
+            p.app,
+            p.config,
+            p.error.as_deref().unwrap_or("violates its policy")
+        );
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} of {} apps failed their preflight checks, nothing was migrated",
+            failed.len(),
+            plans.len()
+        ));
+    }
+    let mut result = Vec::<(String, usize)>::new();
+    for p in plans.iter() {
+        let config = crate::read_config_toml(&[migdir.join(&p.config)])?;
+        let mut m = Migrator::new(config, migdir.to_owned())?;
+        let migrated = (|| -> Result<usize> {
+            m.check_approval_mode()?;
+            m.require_downs()?;
+            m.verify_downs(false)?;
+            let count = m.migrate_up(false)?;
+            m.after_run()?;
+            Ok(count)
+        })();
+        let summary = match &migrated {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+        m.send_summary(&summary);
+        let count = migrated?;
+        info!("{}: migrated up {} versions", p.app, count);
+        result.push((p.app.clone(), count));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    fn write_config(path: &str, app: &str) {
+        let config = crate::tests::test_config_path().unwrap();
+        let mut value: toml::Value =
+            toml::from_str(&std::fs::read_to_string(config).unwrap()).unwrap();
+        value["app"] = toml::Value::String(app.to_owned());
+        std::fs::write(path, toml::to_string(&value).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn all_apps() {
+        let migdir = std::path::Path::new("./all_apps");
+        std::fs::create_dir_all(migdir).unwrap();
+        write_config("./all_apps/a.toml", "__apps_a__");
+        write_config("./all_apps/b.toml", "__apps_b__");
+        let mut versions = Vec::new();
+        for app in ["__apps_a__", "__apps_b__"] {
+            let mut config = crate::tests::test_config().unwrap();
+            config.app = app.to_owned();
+            let mut m = crate::Migrator::new(config, migdir.to_owned()).unwrap();
+            m.client
+                .batch_execute(&format!("DROP TABLE IF EXISTS {}", app))
+                .unwrap();
+            let (up, down) = m.new_migration().unwrap();
+            std::fs::write(&up, format!("CREATE TABLE {} (id INT);", app)).unwrap();
+            std::fs::write(&down, format!("DROP TABLE {};", app)).unwrap();
+            versions.push(*m.versions_up.last().unwrap());
+        }
+        let project = migdir.join(crate::project::PROJECT_FILE);
+        std::fs::write(
+            &project,
+            "apps = [\"a.toml\", \"b.toml\", \"missing.toml\"]",
+        )
+        .unwrap();
+
+        let plans = super::preflight(migdir, 2).unwrap();
+        let refused = super::up(migdir, 2);
+        let mut m =
+            crate::Migrator::new(crate::tests::test_config().unwrap(), migdir.into()).unwrap();
+        let exists = |m: &mut crate::Migrator| -> bool {
+            m.client
+                .query_one(
+                    "SELECT to_regclass('__apps_a__') IS NOT NULL \
+                    AND to_regclass('__apps_b__') IS NOT NULL",
+                    &[],
+                )
+                .unwrap()
+                .get(0)
+        };
+        let refused_exists = exists(&mut m);
+        std::fs::write(&project, "apps = [\"a.toml\", \"b.toml\"]").unwrap();
+        let migrated = super::up(migdir, 2);
+        let migrated_exists = exists(&mut m);
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __apps_a__, __apps_b__")
+            .unwrap();
+        for v in versions.iter() {
+            m.client
+                .execute("DELETE FROM schema_migrations WHERE version = $1", &[v])
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all(migdir);
+
+        assert_eq!(plans.len(), 3);
+        assert_eq!(plans[0].app, "__apps_a__");
+        assert_eq!(plans[1].plan.as_ref().unwrap().steps.len(), 1);
+        assert!(plans[2].error.is_some());
+        assert!(refused.is_err());
+        assert!(!refused_exists);
+        let migrated = migrated.unwrap();
+        assert_eq!(migrated.len(), 2);
+        assert!(migrated_exists);
+    }
+}
@@ -0,0 +1,92 @@
+//! Assertions in migrations. `-- architect:assert QUERY` runs the query after the statements of
+//! the migration in its transaction; unless it returns true the migration fails and is rolled
+//! back, so a file can state the invariants it establishes, like no orphan rows being left.
+
+use anyhow::Result;
+use postgres::GenericClient;
+use sqlparser::ast::Statement;
+
+pub(crate) const DIRECTIVE: &str = "assert";
+
+/// The assertions of `sql` with their line numbers.
+pub(crate) fn parse(sql: &str) -> Vec<(usize, String)> {
+    crate::directives::parse(sql)
+        .into_iter()
+        .filter(|d| d.name == DIRECTIVE)
+        .map(|d| (d.line, d.args))
+        .collect()
+}
+
+/// Errors of the assertions of `sql` that aren't a single query.
+pub(crate) fn check(sql: &str) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    for (line, query) in parse(sql) {
+        match crate::parse_ast(&query).as_deref() {
+            Ok([Statement::Query(_)]) => {}
+            Ok(_) => result.push(format!("assertion on line {} isn't a single query", line)),
+            Err(e) => result.push(format!("assertion on line {}: {}", line, e)),
+        }
+    }
+    result
+}
+
+/// Runs the assertions of `sql`, failing on the first one that doesn't return true.
+pub(crate) fn run(client: &mut impl GenericClient, sql: &str) -> Result<()> {
+    for (line, query) in parse(sql) {
+        let holds: Option<bool> = client.query_one(query.as_str(), &[])?.get(0);
+        if holds != Some(true) {
+            return Err(anyhow::anyhow!(
+                "assertion on line {} failed: {}",
+                line,
+                query
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn assertions() {
+        let config = crate::tests::schema_config("__assertions__");
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./assertions")).unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:assert SELECT count(*) = 1 FROM a\n\
+            CREATE TABLE a (id INT);\n\
+            INSERT INTO a VALUES (1), (2);",
+        )
+        .unwrap();
+        let failed = m.migrate_up(false);
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('a') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        let error = m.runs[0].error.clone();
+        std::fs::write(
+            &up,
+            "-- architect:assert SELECT count(*) = 2 FROM a\n\
+            CREATE TABLE a (id INT);\n\
+            INSERT INTO a VALUES (1), (2);",
+        )
+        .unwrap();
+        let migrated = m.migrate_up(false);
+        m.client
+            .batch_execute("DROP SCHEMA __assertions__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./assertions");
+
+        assert!(failed.is_err());
+        assert!(!exists);
+        assert!(error.unwrap().contains("assertion on line 1 failed"));
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(
+            super::check("-- architect:assert DELETE FROM a"),
+            vec!["assertion on line 1 isn't a single query"]
+        );
+    }
+}
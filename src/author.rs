@@ -0,0 +1,91 @@
+//! Authors of migrations, recorded in their files as `-- architect:author Name <email>` when they
+//! are created so ownership of a pending migration is obvious.
+
+use anyhow::Result;
+
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "author";
+
+/// `git config <key>` of the current repository, None if it isn't set or git isn't available.
+fn git_config(key: &str) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["config", key])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// The author of a new migration: `author` if given, else git's `user.name <user.email>`.
+pub(crate) fn resolve(author: Option<String>) -> Option<String> {
+    if author.is_some() {
+        return author;
+    }
+    match (git_config("user.name"), git_config("user.email")) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (Some(name), None) => Some(name),
+        (None, Some(email)) => Some(format!("<{}>", email)),
+        (None, None) => None,
+    }
+}
+
+/// The author recorded in a migration file, empty if there's none.
+pub(crate) fn read(path: &std::path::Path) -> Result<String> {
+    if path.extension().is_some_and(|v| v == "wasm") {
+        return Ok(String::new());
+    }
+    let sql = std::fs::read_to_string(path)?;
+    Ok(crate::directives::parse(&sql)
+        .into_iter()
+        .find(|v| v.name == DIRECTIVE)
+        .map(|v| v.args)
+        .unwrap_or_default())
+}
+
+impl Migrator {
+    /// Creates a new migration with the author recorded at the top of both files.
+    pub(crate) fn new_migration_by(
+        &mut self,
+        author: Option<String>,
+    ) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+        let (up, down) = self.new_migration()?;
+        if let Some(author) = resolve(author) {
+            let line = format!("-- architect:{} {}\n", DIRECTIVE, author);
+            std::fs::write(&up, &line)?;
+            std::fs::write(&down, &line)?;
+        }
+        Ok((up, down))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn author_is_recorded() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./author")).unwrap();
+        let (up, down) = m
+            .new_migration_by(Some("Jane Doe <jane@example.com>".to_owned()))
+            .unwrap();
+        let up_author = super::read(&up).unwrap();
+        let down_author = super::read(&down).unwrap();
+        let migrations = m.migrations().unwrap();
+
+        let _ = std::fs::remove_dir_all("./author");
+
+        assert_eq!(up_author, "Jane Doe <jane@example.com>");
+        assert_eq!(down_author, up_author);
+        assert_eq!(migrations[0].author, up_author);
+        assert_eq!(migrations[0].description, "");
+    }
+}
@@ -0,0 +1,181 @@
+//! Batched updates in schema migrations. A migration marked `-- architect:batch-update key=K
+//! size=N` consists of a single simple UPDATE, which is run once per batch of `N` rows ordered by
+//! the integer key `K`, each batch committed on its own, so rows of a hot table aren't locked for
+//! the whole update. The version is recorded after the last batch.
+
+use anyhow::Result;
+use sqlparser::ast::{Statement, TableFactor};
+
+use crate::data::Batch;
+use crate::direction::Direction;
+use crate::faults::Point;
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "batch-update";
+
+/// The `args` of the directive of `sql`, None if it isn't marked.
+pub(crate) fn directive(sql: &str) -> Option<String> {
+    crate::directives::parse(sql)
+        .into_iter()
+        .find(|v| v.name == DIRECTIVE)
+        .map(|v| v.args)
+}
+
+/// Rewrites the single UPDATE of `sql` to update one batch of keys, the key the batch starts
+/// after being `$1` and its last key `$2`. Updates joining other tables or returning rows can't
+/// be batched.
+pub(crate) fn rewrite(sql: &str, args: &str) -> Result<(Batch, String)> {
+    let statements = crate::parse_ast(sql)?;
+    let (table, assignments, selection) = match &statements[..] {
+        [Statement::Update {
+            table,
+            assignments,
+            from: None,
+            selection,
+            returning: None,
+        }] if table.joins.is_empty() => (table, assignments, selection),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "{} needs a single UPDATE of one table without FROM or RETURNING",
+                DIRECTIVE
+            ))
+        }
+    };
+    let name = match &table.relation {
+        TableFactor::Table { name, .. } => name.to_string(),
+        _ => return Err(anyhow::anyhow!("{} needs an UPDATE of a table", DIRECTIVE)),
+    };
+    let batch = Batch::parse(args, Some(&name))?;
+    let keys = format!("{key} > $1 AND {key} <= $2", key = batch.key);
+    let selection = match selection {
+        Some(v) => format!("({}) AND {}", v, keys),
+        None => keys,
+    };
+    let assignments = assignments
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let update = format!(
+        "UPDATE {} SET {} WHERE {}",
+        table.relation, assignments, selection
+    );
+    Ok((batch, update))
+}
+
+impl Migrator {
+    /// Applies a migration marked with the directive, a transaction per batch, recording the
+    /// version after the last one. An interrupted migration starts over when it's run again.
+    pub(crate) fn apply_batch_update(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        sql: &str,
+        args: &str,
+    ) -> Result<Vec<crate::email::StatementRun>> {
+        let (batch, update) = rewrite(sql, args)?;
+        let start = std::time::Instant::now();
+        let label = format!("{}_{}.sql", version, direction);
+        let mut total = 0;
+        let mut batches = 0;
+        let faults = self.faults.clone();
+        crate::data::run_batches(
+            &mut self.client,
+            &batch,
+            None,
+            &update,
+            &label,
+            |_, rows, _| {
+                // the batch before is committed by now
+                if batches > 0 {
+                    crate::faults::inject(&faults, version, Point::Statement(batches))?;
+                }
+                batches += 1;
+                total += rows;
+                Ok(total)
+            },
+        )?;
+        let duration_ms = start.elapsed().as_millis();
+        crate::faults::inject(&faults, version, Point::Statement(batches))?;
+        crate::assertions::run(&mut self.client, sql)?;
+        crate::faults::inject(&faults, version, Point::Record)?;
+        self.client
+            .batch_execute(&crate::record_query(version, direction, self.batch))?;
+        if direction == Direction::Up {
+            self.client.execute(
+                "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+                &[&(duration_ms as i64), &version],
+            )?;
+        }
+        crate::faults::inject(&faults, version, Point::Commit)?;
+        info!("{}: {} rows in total", label, total);
+        Ok(vec![crate::email::StatementRun {
+            statement: update,
+            duration_ms,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn batch_update() {
+        let (batch, update) = super::rewrite(
+            "UPDATE users SET email = lower(email) WHERE email <> lower(email);",
+            "key=id size=500",
+        )
+        .unwrap();
+        let joined = super::rewrite("UPDATE a SET x = b.x FROM b WHERE a.id = b.id", "key=id");
+
+        let config = test_config().unwrap();
+        let mut m =
+            crate::Migrator::new(config, std::path::PathBuf::from("./batch_update")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __batch_update__;
+                CREATE TABLE __batch_update__ (id INT PRIMARY KEY, n INT DEFAULT 0);
+                INSERT INTO __batch_update__ (id) SELECT generate_series(1, 25);",
+            )
+            .unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            "-- architect:batch-update key=id size=10\nUPDATE __batch_update__ SET n = n + 1;",
+        )
+        .unwrap();
+        let migrated = m.migrate_up(false);
+        let updated: i64 = m
+            .client
+            .query_one("SELECT count(*) FROM __batch_update__ WHERE n = 1", &[])
+            .unwrap()
+            .get(0);
+        let applied = m.applied_versions().unwrap();
+        m.client
+            .batch_execute("DROP TABLE __batch_update__")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./batch_update");
+
+        assert_eq!(batch.table, "users");
+        assert_eq!(batch.size, 500);
+        assert_eq!(
+            update,
+            "UPDATE users SET email = lower(email) WHERE (email <> lower(email)) \
+            AND id > $1 AND id <= $2"
+        );
+        assert!(joined.is_err());
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(updated, 25);
+        assert!(applied.contains(&version));
+    }
+}
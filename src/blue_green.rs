@@ -0,0 +1,288 @@
+//! Blue/green schema deployments, configured in the `[blue_green]` table of the config. The tables
+//! live in the `schema` schema and the app reads and writes them through the views of a versioned
+//! schema, `<schema>_v1`, `<schema>_v2` and so on. Every run applying migrations builds the next
+//! versioned schema alongside the current one, its views selecting the columns the tables have
+//! now, while the views of the current one keep the columns they had, so the old release of the
+//! app keeps working until `blue-green cutover` switches the search_path of the app's `role` to
+//! the new schema. `blue-green rollback` flips back to the previous one. The versions are recorded
+//! in `schema_blue_green`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::output::info;
+use crate::Migrator;
+
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct BlueGreen {
+    /// Schema of the tables. Blue/green deployment is off when empty.
+    #[serde(default)]
+    pub(crate) schema: String,
+    /// Role of the app whose search_path is switched by a cutover
+    #[serde(default)]
+    pub(crate) role: String,
+}
+
+/// A versioned schema as recorded in `schema_blue_green`.
+#[derive(Serialize)]
+pub(crate) struct Version {
+    pub(crate) version: i32,
+    pub(crate) schema: String,
+    /// Last migration applied when the schema was built
+    pub(crate) migration: i64,
+    /// RFC 3339
+    pub(crate) built_at: String,
+    pub(crate) active: bool,
+}
+
+impl BlueGreen {
+    pub(crate) fn versioned(&self, version: i32) -> String {
+        format!("{}_v{}", self.schema, version)
+    }
+}
+
+impl Migrator {
+    fn blue_green(&self) -> Result<BlueGreen> {
+        let blue_green = self.config.blue_green.clone();
+        if blue_green.schema.is_empty() {
+            return Err(anyhow::anyhow!(
+                "blue/green deployment isn't configured, set schema in [blue_green]"
+            ));
+        }
+        Ok(blue_green)
+    }
+
+    fn init_blue_green(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_blue_green (
+                version INT PRIMARY KEY,
+                migration BIGINT NOT NULL,
+                built_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                active BOOLEAN NOT NULL DEFAULT false,
+                activated_at TIMESTAMPTZ
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    /// The versioned schemas, oldest first.
+    pub(crate) fn blue_green_versions(&mut self) -> Result<Vec<Version>> {
+        let blue_green = self.blue_green()?;
+        self.init_blue_green()?;
+        let rows = self.client.query(
+            "SELECT version, migration, built_at, active FROM schema_blue_green ORDER BY version",
+            &[],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|r| Version {
+                version: r.get(0),
+                schema: blue_green.versioned(r.get(0)),
+                migration: r.get(1),
+                built_at: r.get::<_, chrono::DateTime<chrono::Utc>>(2).to_rfc3339(),
+                active: r.get(3),
+            })
+            .collect())
+    }
+
+    /// Builds the next versioned schema with a view of every table of the schema, except the
+    /// tracking tables. Returns its version.
+    pub(crate) fn build_next_schema(&mut self) -> Result<i32> {
+        let blue_green = self.blue_green()?;
+        self.init_blue_green()?;
+        let migration = self.last_version;
+        let mut t = self.client.transaction()?;
+        let version: i32 = t
+            .query_one(
+                "SELECT coalesce(max(version), 0) + 1 FROM schema_blue_green",
+                &[],
+            )?
+            .get(0);
+        let schema = blue_green.versioned(version);
+        let mut sql = format!("CREATE SCHEMA {};\n", schema);
+        for row in t.query(
+            "SELECT quote_ident(tablename) FROM pg_tables
+            WHERE schemaname = $1 AND tablename NOT LIKE 'schema\\_%'
+            ORDER BY tablename",
+            &[&blue_green.schema],
+        )? {
+            let table: String = row.get(0);
+            sql.push_str(&format!(
+                "CREATE VIEW {schema}.{table} AS SELECT * FROM {}.{table};\n",
+                blue_green.schema
+            ));
+        }
+        t.batch_execute(&sql)?;
+        t.execute(
+            "INSERT INTO schema_blue_green (version, migration) VALUES ($1, $2)",
+            &[&version, &migration],
+        )?;
+        t.commit()?;
+        info!("built schema {} at version {}", schema, migration);
+        Ok(version)
+    }
+
+    /// Builds the next versioned schema if blue/green deployment is configured and the run
+    /// migrated up or down.
+    pub(crate) fn build_next_schema_after_run(&mut self) -> Result<Option<i32>> {
+        if self.config.blue_green.schema.is_empty() || !self.migrated_in_run() {
+            return Ok(None);
+        }
+        let built = self.blue_green_versions()?.last().map(|v| v.migration);
+        if built == Some(self.last_version) {
+            return Ok(None);
+        }
+        self.build_next_schema().map(Some)
+    }
+
+    /// Switches the app to the versioned schema `version`, the last one built by default, by
+    /// setting the search_path of its role. Sessions connecting afterwards use it.
+    pub(crate) fn blue_green_cutover(&mut self, version: Option<i32>) -> Result<String> {
+        let blue_green = self.blue_green()?;
+        let versions = self.blue_green_versions()?;
+        let target = match version {
+            Some(v) => versions.iter().find(|s| s.version == v),
+            None => versions.last(),
+        };
+        let target = match target {
+            Some(v) => v,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "no schema {} built, run up first",
+                    version
+                        .map(|v| blue_green.versioned(v))
+                        .unwrap_or_else(|| "version".to_owned())
+                ))
+            }
+        };
+        let mut t = self.client.transaction()?;
+        if !blue_green.role.is_empty() {
+            t.batch_execute(&format!(
+                "ALTER ROLE {} SET search_path = {}",
+                blue_green.role, target.schema
+            ))?;
+        }
+        t.execute(
+            "UPDATE schema_blue_green SET active = (version = $1),
+            activated_at = CASE WHEN version = $1 THEN now() ELSE activated_at END",
+            &[&target.version],
+        )?;
+        t.commit()?;
+        Ok(target.schema.clone())
+    }
+
+    /// Flips back to the versioned schema before the active one.
+    pub(crate) fn blue_green_rollback(&mut self) -> Result<String> {
+        let versions = self.blue_green_versions()?;
+        let active = match versions.iter().position(|v| v.active) {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("no schema is active, nothing to roll back")),
+        };
+        if active == 0 {
+            return Err(anyhow::anyhow!(
+                "{} is the first schema, there's none to roll back to",
+                versions[active].schema
+            ));
+        }
+        let previous = versions[active - 1].version;
+        self.blue_green_cutover(Some(previous))
+    }
+}
+
+pub(crate) fn print(versions: &[Version]) {
+    println!(
+        "{:<24} {:<15} {:<20}  ACTIVE",
+        "SCHEMA", "MIGRATION", "BUILT AT"
+    );
+    for v in versions {
+        println!(
+            "{:<24} {:<15} {:<20}  {}",
+            v.schema,
+            v.migration,
+            v.built_at.get(..19).unwrap_or_default().replace('T', " "),
+            if v.active { "*" } else { "" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn blue_green() {
+        let dir = std::path::PathBuf::from("./blue_green");
+        let mut config = crate::tests::schema_config("__bg__");
+        config.blue_green.schema = "__bg__".to_owned();
+        config.blue_green.role = "__bg_app__".to_owned();
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        m.client
+            .batch_execute(
+                "DROP ROLE IF EXISTS __bg_app__; CREATE ROLE __bg_app__;
+                DROP SCHEMA IF EXISTS __bg___v1 CASCADE; DROP SCHEMA IF EXISTS __bg___v2 CASCADE;
+                DROP SCHEMA IF EXISTS __bg___v3 CASCADE;",
+            )
+            .unwrap();
+        for (sql, down_sql) in [
+            (
+                "CREATE TABLE items (id INT, name TEXT);",
+                "DROP TABLE items;",
+            ),
+            (
+                "ALTER TABLE items ADD COLUMN price INT;",
+                "ALTER TABLE items DROP COLUMN price CASCADE;",
+            ),
+        ] {
+            let (up, down) = m.new_migration().unwrap();
+            std::fs::write(&up, sql).unwrap();
+            std::fs::write(&down, down_sql).unwrap();
+            m.migrate_up_n(1, false).unwrap();
+            m.after_run().unwrap();
+        }
+        let versions = m.blue_green_versions().unwrap();
+        let columns = |m: &mut crate::Migrator, schema: &str| -> i64 {
+            m.client
+                .query_one(
+                    "SELECT count(*) FROM information_schema.columns
+                    WHERE table_schema = $1 AND table_name = 'items'",
+                    &[&schema],
+                )
+                .unwrap()
+                .get(0)
+        };
+        let (v1, v2) = (columns(&mut m, "__bg___v1"), columns(&mut m, "__bg___v2"));
+        let search_path = |m: &mut crate::Migrator| -> Vec<String> {
+            m.client
+                .query_one(
+                    "SELECT setconfig FROM pg_db_role_setting WHERE setrole = '__bg_app__'::regrole",
+                    &[],
+                )
+                .unwrap()
+                .get(0)
+        };
+        let cutover = m.blue_green_cutover(None).unwrap();
+        let switched = search_path(&mut m);
+        let rollback = m.blue_green_rollback().unwrap();
+        let flipped = search_path(&mut m);
+        let first = m.blue_green_rollback();
+        m.migrate_down_n(1, false).unwrap();
+        m.after_run().unwrap();
+        let v3 = columns(&mut m, "__bg___v3");
+        m.client
+            .batch_execute(
+                "DROP SCHEMA __bg__, __bg___v1, __bg___v2, __bg___v3 CASCADE; DROP ROLE __bg_app__;",
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(versions.len(), 2);
+        assert_eq!((v1, v2), (2, 3));
+        assert_eq!(cutover, "__bg___v2");
+        assert_eq!(switched, vec!["search_path=__bg___v2"]);
+        assert_eq!(rollback, "__bg___v1");
+        assert_eq!(flipped, vec!["search_path=__bg___v1"]);
+        assert!(first.is_err());
+        assert_eq!(v3, 2);
+    }
+}
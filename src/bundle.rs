@@ -0,0 +1,157 @@
+//! Installer bundles for shipping schema upgrades to installations without access to the
+//! repository, like on-prem customers. `bundle --out DIR` writes the architect binary, the app's
+//! migration directory with its `.architect.toml`, a config template taking the connection from
+//! the environment and an `install.sh` applying the migrations into `DIR`, ready to be archived
+//! and shipped. The migrations are validated first so broken ones aren't shipped.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::lint::Severity;
+
+const CONFIG: &str = "architect.toml";
+const INSTALL: &str = "install.sh";
+const MIGRATIONS: &str = "migrations";
+
+/// The config of the bundle. Connection values come from the environment, or a `.env` file next
+/// to `install.sh`.
+fn config_template(app: &str) -> String {
+    format!(
+        "# Connection of the {app} installer. Values of the form ${{VAR}} are taken from the \
+        environment\n\
+        # or a .env file next to install.sh.\n\
+        app = \"{app}\"\n\
+        host = \"${{ARCHITECT_HOST}}\"\n\
+        port = \"${{ARCHITECT_PORT}}\"\n\
+        dbname = \"${{ARCHITECT_DBNAME}}\"\n\
+        user = \"${{ARCHITECT_USER}}\"\n\
+        password = \"${{ARCHITECT_PASSWORD}}\"\n\
+        # ssl = true\n"
+    )
+}
+
+fn install_script(app: &str) -> String {
+    format!(
+        "#!/bin/sh\n\
+        # Applies the bundled migrations of {app}. Set ARCHITECT_HOST, ARCHITECT_PORT,\n\
+        # ARCHITECT_DBNAME, ARCHITECT_USER and ARCHITECT_PASSWORD, or edit {CONFIG}. Arguments\n\
+        # are passed to `up`.\n\
+        set -eu\n\
+        cd \"$(dirname \"$0\")\"\n\
+        ./architect --migdir {MIGRATIONS} --config {CONFIG} status\n\
+        ./architect --migdir {MIGRATIONS} --config {CONFIG} up \"$@\"\n"
+    )
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<usize> {
+    std::fs::create_dir_all(to)?;
+    let mut copied = 0;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copied += copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Writes the bundle of the migrations in `dir` of `app` into `out`, which has to be empty or not
+/// exist. Returns the number of migration files bundled.
+pub(crate) fn run(app: &str, dir: &Path, plugins: &[String], out: &Path) -> Result<usize> {
+    if out.exists() && std::fs::read_dir(out)?.next().is_some() {
+        return Err(anyhow::anyhow!(
+            "{:?} isn't empty, bundle into a new directory",
+            out
+        ));
+    }
+    let errors = crate::validate::validate(dir, plugins)?
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow::anyhow!(
+            "the migrations have {} errors, see validate",
+            errors
+        ));
+    }
+    let files = copy_dir(dir, &out.join(MIGRATIONS).join(app))?;
+    // the policy, grants and other settings of the project apply to the installation as well
+    if let Some(project) = dir
+        .parent()
+        .map(|v| v.join(crate::project::PROJECT_FILE))
+        .filter(|v| v.exists())
+    {
+        std::fs::copy(
+            project,
+            out.join(MIGRATIONS).join(crate::project::PROJECT_FILE),
+        )?;
+    }
+    let binary = out.join("architect");
+    std::fs::copy(std::env::current_exe()?, &binary)?;
+    make_executable(&binary)?;
+    std::fs::write(out.join(CONFIG), config_template(app))?;
+    let install = out.join(INSTALL);
+    std::fs::write(&install, install_script(app))?;
+    make_executable(&install)?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bundle() {
+        let dir = std::path::PathBuf::from("./bundle/migrations/shop");
+        let out = std::path::PathBuf::from("./bundle/out");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1_up.sql"), "CREATE TABLE a (id INT);").unwrap();
+        std::fs::write(dir.join("1_down.sql"), "DROP TABLE a;").unwrap();
+        std::fs::write(
+            dir.parent().unwrap().join(crate::project::PROJECT_FILE),
+            "require_down = true\n",
+        )
+        .unwrap();
+        let bundled = super::run("shop", &dir, &[], &out);
+        let again = super::run("shop", &dir, &[], &out);
+        let config = std::fs::read_to_string(out.join(super::CONFIG)).unwrap();
+        let template: toml::Value = toml::from_str(&config).unwrap();
+        let port = toml::from_str::<crate::Config>(&config.replace("${ARCHITECT_PORT}", "6432"))
+            .unwrap()
+            .port;
+        let project = out.join("migrations").join(crate::project::PROJECT_FILE);
+        let project = std::fs::read_to_string(project).unwrap_or_default();
+        let copied = out.join("migrations/shop/1_up.sql").exists();
+        let binary = out.join("architect").exists();
+        let install = std::fs::read_to_string(out.join(super::INSTALL)).unwrap();
+        std::fs::write(dir.join("2_up.sql"), "CREATE TABLE b (id INT);").unwrap();
+        let invalid = super::run("shop", &dir, &[], std::path::Path::new("./bundle/invalid"));
+
+        let _ = std::fs::remove_dir_all("./bundle");
+
+        assert_eq!(bundled.unwrap(), 2);
+        assert!(again.unwrap_err().to_string().contains("isn't empty"));
+        assert_eq!(template["app"].as_str(), Some("shop"));
+        assert_eq!(template["host"].as_str(), Some("${ARCHITECT_HOST}"));
+        assert_eq!(port, 6432);
+        assert_eq!(project, "require_down = true\n");
+        assert!(copied && binary);
+        assert!(install.contains("--migdir migrations --config architect.toml up \"$@\""));
+        assert!(invalid.unwrap_err().to_string().contains("errors"));
+    }
+}
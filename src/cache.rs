@@ -0,0 +1,123 @@
+//! Cache of parsed migrations. Parsing thousands of unchanged files on every `plan` or `validate`
+//! is slow in large repositories, so the statements and lint findings of a file are kept in
+//! `.architect/cache`, keyed by the checksum of its name, its contents and the version of
+//! architect. Changed files get a new key, so entries are never invalidated, and the directory can
+//! be deleted at any time. Setting ARCHITECT_NO_CACHE bypasses it.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlparser::ast::Statement;
+
+use crate::direction::Direction;
+use crate::lint::Finding;
+
+pub(crate) const DIR: &str = ".architect/cache";
+
+/// Env variable bypassing the cache when set.
+pub(crate) const DISABLE_ENV: &str = "ARCHITECT_NO_CACHE";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Parsed {
+    pub(crate) statements: Vec<Statement>,
+    /// Findings of `lint::lint`
+    pub(crate) lint: Vec<Finding>,
+}
+
+/// Key of an entry made of `parts` and the version of architect.
+pub(crate) fn checksum(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in [env!("CARGO_PKG_VERSION")].iter().chain(parts) {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+fn key(file: &str, sql: &str) -> String {
+    checksum(&[file, sql])
+}
+
+/// Whether the cache is in use, see `DISABLE_ENV`.
+pub(crate) fn enabled() -> bool {
+    std::env::var_os(DISABLE_ENV).is_none()
+}
+
+/// Writes the entry at `path`.
+pub(crate) fn write(path: &std::path::Path, entry: &impl Serialize) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // renamed into place so concurrent runs never read a partial entry
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec(entry)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The statements and lint findings of the migration `file` with the contents `sql`, from the
+/// cache in `dir` if it has them.
+fn parse_in(dir: &std::path::Path, file: &str, direction: Direction, sql: &str) -> Result<Parsed> {
+    let enabled = enabled();
+    let path = dir.join(format!("{}.json", key(file, sql)));
+    if enabled {
+        // unreadable entries are parsed again and overwritten
+        if let Some(parsed) = std::fs::read(&path)
+            .ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+        {
+            return Ok(parsed);
+        }
+    }
+    let statements = crate::parse_ast(sql)?;
+    let parsed = Parsed {
+        lint: crate::lint::lint(file, direction, &statements),
+        statements,
+    };
+    if enabled {
+        if let Err(e) = write(&path, &parsed) {
+            crate::output::info!("caching {} failed: {}", file, e);
+        }
+    }
+    Ok(parsed)
+}
+
+/// The statements and lint findings of the migration `file` with the contents `sql`.
+pub(crate) fn parse(file: &str, direction: Direction, sql: &str) -> Result<Parsed> {
+    parse_in(std::path::Path::new(DIR), file, direction, sql)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::direction::Direction;
+
+    #[test]
+    fn cache() {
+        let dir = std::path::Path::new("./cache_test");
+        let sql = "CREATE TABLE a (id INT);";
+        let parsed = super::parse_in(dir, "1_up.sql", Direction::Up, sql).unwrap();
+        let entries = std::fs::read_dir(dir).unwrap().count();
+        // a tampered entry shows the cache is read
+        let path = dir.join(format!("{}.json", super::key("1_up.sql", sql)));
+        let mut tampered: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        tampered["statements"] = serde_json::json!([]);
+        std::fs::write(&path, tampered.to_string()).unwrap();
+        let cached = super::parse_in(dir, "1_up.sql", Direction::Up, sql).unwrap();
+        let changed = super::parse_in(dir, "1_up.sql", Direction::Up, "SELECT 1;").unwrap();
+        std::fs::write(&path, "not json").unwrap();
+        let corrupt = super::parse_in(dir, "1_up.sql", Direction::Up, sql).unwrap();
+
+        let _ = std::fs::remove_dir_all(dir);
+
+        assert_eq!(parsed.statements.len(), 1);
+        assert_eq!(entries, 1);
+        assert!(cached.statements.is_empty());
+        assert_eq!(changed.statements[0].to_string(), "SELECT 1");
+        assert_eq!(corrupt.statements.len(), 1);
+    }
+}
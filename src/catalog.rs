@@ -0,0 +1,191 @@
+//! Export of the schema catalog, its tables with columns, constraints, indexes and comments and
+//! its views, as JSON or CSV for data catalogs and other downstream tooling.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::schema::{Schema, Table, View};
+
+#[derive(Serialize)]
+pub(crate) struct Catalog<'a> {
+    pub(crate) app: String,
+    pub(crate) dbname: String,
+    pub(crate) schema: String,
+    /// Version the schema is at
+    pub(crate) version: i64,
+    pub(crate) exported_at: String,
+    pub(crate) tables: Vec<&'a Table>,
+    pub(crate) views: &'a [View],
+}
+
+impl<'a> Catalog<'a> {
+    pub(crate) fn new(schema: &'a Schema, app: &str, dbname: &str, version: i64) -> Self {
+        Catalog {
+            app: app.to_owned(),
+            dbname: dbname.to_owned(),
+            schema: schema.name.clone(),
+            version,
+            exported_at: chrono::Utc::now().to_rfc3339(),
+            tables: schema.app_tables().collect(),
+            views: &schema.views,
+        }
+    }
+
+    pub(crate) fn json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)? + "\n")
+    }
+
+    /// A row per table, column, constraint, index and view, see `CSV_HEADER`.
+    pub(crate) fn csv(&self) -> String {
+        let mut result = CSV_HEADER.to_owned();
+        for t in self.tables.iter() {
+            result.push_str(
+                &Row {
+                    kind: "table",
+                    table: &t.name,
+                    name: &t.name,
+                    comment: t.comment.as_deref().unwrap_or_default(),
+                    ..Default::default()
+                }
+                .csv(),
+            );
+            for c in t.columns.iter() {
+                result.push_str(
+                    &Row {
+                        kind: "column",
+                        table: &t.name,
+                        name: &c.name,
+                        data_type: &c.data_type,
+                        nullable: if c.nullable { "true" } else { "false" },
+                        default: c.default.as_deref().unwrap_or_default(),
+                        comment: c.comment.as_deref().unwrap_or_default(),
+                        ..Default::default()
+                    }
+                    .csv(),
+                );
+            }
+            for c in t.constraints.iter() {
+                let kind = match c.kind.as_str() {
+                    "p" => "primary_key",
+                    "f" => "foreign_key",
+                    "u" => "unique",
+                    "c" => "check",
+                    _ => "constraint",
+                };
+                result.push_str(
+                    &Row {
+                        kind,
+                        table: &t.name,
+                        name: &c.name,
+                        definition: &c.definition,
+                        ..Default::default()
+                    }
+                    .csv(),
+                );
+            }
+            for i in t.indexes.iter() {
+                result.push_str(
+                    &Row {
+                        kind: "index",
+                        table: &t.name,
+                        name: &i.name,
+                        definition: &i.definition,
+                        ..Default::default()
+                    }
+                    .csv(),
+                );
+            }
+        }
+        for v in self.views.iter() {
+            result.push_str(
+                &Row {
+                    kind: "view",
+                    name: &v.name,
+                    definition: v.definition.trim(),
+                    ..Default::default()
+                }
+                .csv(),
+            );
+        }
+        result
+    }
+}
+
+const CSV_HEADER: &str = "kind,table,name,type,nullable,default,definition,comment\n";
+
+/// A line of the CSV export. Fields that don't apply to the kind of object are empty.
+#[derive(Default)]
+struct Row<'a> {
+    kind: &'a str,
+    table: &'a str,
+    name: &'a str,
+    data_type: &'a str,
+    nullable: &'a str,
+    default: &'a str,
+    definition: &'a str,
+    comment: &'a str,
+}
+
+impl Row<'_> {
+    fn csv(&self) -> String {
+        let fields = [
+            self.kind,
+            self.table,
+            self.name,
+            self.data_type,
+            self.nullable,
+            self.default,
+            self.definition,
+            self.comment,
+        ];
+        fields.map(csv_field).join(",") + "\n"
+    }
+}
+
+/// Quotes a field containing separators, quotes or line breaks, doubling quotes, as RFC 4180 does.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn catalog_at_version() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./catalog")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE users (id INT PRIMARY KEY, name VARCHAR(20) NOT NULL DEFAULT 'a, b');
+            COMMENT ON COLUMN users.name IS 'the \"display\" name';",
+        )
+        .unwrap();
+
+        let schema = m.schema_at(version).unwrap();
+        let catalog = super::Catalog::new(&schema, "test", "db", version);
+        let json: serde_json::Value = serde_json::from_str(&catalog.json().unwrap()).unwrap();
+        let csv = catalog.csv();
+
+        let _ = std::fs::remove_dir_all("./catalog");
+
+        assert_eq!(json["version"], version);
+        assert_eq!(json["tables"][0]["name"], "users");
+        assert_eq!(
+            json["tables"][0]["columns"][1]["comment"],
+            "the \"display\" name"
+        );
+        assert!(csv.starts_with("kind,table,name,type,nullable,default,definition,comment\n"));
+        assert!(csv.contains(
+            "column,users,name,character varying(20),false,\"'a, b'::character varying\",,\
+            \"the \"\"display\"\" name\"\n"
+        ));
+        assert!(csv.contains("primary_key,users,users_pkey,,,,PRIMARY KEY (id),\n"));
+    }
+}
@@ -0,0 +1,215 @@
+//! Statements gated on the catalog. The statements between `-- architect:if <query>` and
+//! `-- architect:end-if` only run when the query, evaluated in the migration's transaction right
+//! before the first of them, returns true. E.g. an index using an extension is only created where
+//! `-- architect:if SELECT EXISTS (SELECT FROM pg_extension WHERE extname = 'pg_trgm')` holds, so
+//! one migration directory serves installations that differ in extensions, roles or settings.
+//! Blocks don't nest and don't work in verbatim files.
+
+use anyhow::Result;
+use postgres::GenericClient;
+
+pub(crate) const DIRECTIVE: &str = "if";
+pub(crate) const END: &str = "end-if";
+
+/// The `if` blocks of a migration.
+#[derive(Default)]
+pub(crate) struct Blocks {
+    conditions: Vec<String>,
+    /// Whether the condition of each block held, once evaluated
+    held: Vec<Option<bool>>,
+    /// The block of each statement, in the order of `parse_statements`
+    statements: Vec<Option<usize>>,
+}
+
+impl Blocks {
+    pub(crate) fn parse(sql: &str) -> Result<Blocks> {
+        let directives: std::collections::HashMap<usize, crate::directives::Directive> =
+            crate::directives::parse(sql)
+                .into_iter()
+                .filter(|d| d.name == DIRECTIVE || d.name == END)
+                .map(|d| (d.line, d))
+                .collect();
+        let mut blocks = Blocks::default();
+        if directives.is_empty() {
+            return Ok(blocks);
+        }
+        if crate::directives::has(sql, crate::directives::VERBATIM) {
+            return Err(anyhow::anyhow!(
+                "{} blocks don't work in {} files",
+                DIRECTIVE,
+                crate::directives::VERBATIM
+            ));
+        }
+        // the text between the directives, with the block it's in
+        let mut segments = Vec::<(Option<usize>, String)>::new();
+        let mut open: Option<(usize, usize)> = None;
+        let mut text = String::new();
+        for (i, line) in sql.lines().enumerate() {
+            let d = match directives.get(&(i + 1)) {
+                Some(v) => v,
+                None => {
+                    text.push_str(line);
+                    text.push('\n');
+                    continue;
+                }
+            };
+            segments.push((open.map(|v| v.0), std::mem::take(&mut text)));
+            match (d.name == DIRECTIVE, open) {
+                (true, Some((_, line))) => {
+                    return Err(anyhow::anyhow!(
+                        "{} on line {} is inside the block of line {}, blocks don't nest",
+                        DIRECTIVE,
+                        d.line,
+                        line
+                    ))
+                }
+                (true, None) => {
+                    if d.args.is_empty() {
+                        return Err(anyhow::anyhow!(
+                            "{} on line {} needs a query returning a boolean",
+                            DIRECTIVE,
+                            d.line
+                        ));
+                    }
+                    open = Some((blocks.conditions.len(), d.line));
+                    blocks.conditions.push(d.args.clone());
+                    blocks.held.push(None);
+                }
+                (false, None) => {
+                    return Err(anyhow::anyhow!(
+                        "{} on line {} has no {} before it",
+                        END,
+                        d.line,
+                        DIRECTIVE
+                    ))
+                }
+                (false, Some(_)) => open = None,
+            }
+        }
+        if let Some((_, line)) = open {
+            return Err(anyhow::anyhow!(
+                "{} on line {} isn't closed by {}",
+                DIRECTIVE,
+                line,
+                END
+            ));
+        }
+        segments.push((None, text));
+        let statements = crate::parse_statements(sql)?.len();
+        let split = || {
+            anyhow::anyhow!(
+                "a statement starts or ends inside an {} block but not both",
+                DIRECTIVE
+            )
+        };
+        for (block, text) in segments.iter() {
+            let n = crate::parse_statements(text).map_err(|_| split())?.len();
+            blocks.statements.extend(std::iter::repeat_n(*block, n));
+        }
+        if blocks.statements.len() != statements {
+            return Err(split());
+        }
+        Ok(blocks)
+    }
+
+    /// Whether the statement `i` runs, evaluating the condition of its block the first time.
+    pub(crate) fn runs(&mut self, client: &mut impl GenericClient, i: usize) -> Result<bool> {
+        let block = match self.statements.get(i).copied().flatten() {
+            Some(v) => v,
+            None => return Ok(true),
+        };
+        if let Some(v) = self.held[block] {
+            return Ok(v);
+        }
+        let condition = &self.conditions[block];
+        let held = client
+            .query_one(condition.as_str(), &[])
+            .map_err(|e| anyhow::anyhow!("condition \"{}\" failed: {}", condition, e))?
+            .try_get::<_, bool>(0)
+            .map_err(|_| anyhow::anyhow!("condition \"{}\" has to return a boolean", condition))?;
+        self.held[block] = Some(held);
+        Ok(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Blocks;
+
+    #[test]
+    fn conditions() {
+        let blocks = Blocks::parse(
+            "CREATE TABLE a (id INT);\n\
+            -- architect:if SELECT true\n\
+            CREATE TABLE b (id INT);\n\
+            CREATE TABLE c (id INT);\n\
+            -- architect:end-if\n\
+            CREATE TABLE d (id INT);",
+        )
+        .unwrap();
+        assert_eq!(blocks.statements, vec![None, Some(0), Some(0), None]);
+        for (sql, error) in [
+            (
+                "-- architect:if\nSELECT 1;\n-- architect:end-if",
+                "needs a query",
+            ),
+            ("-- architect:if SELECT true\nSELECT 1;", "isn't closed"),
+            ("SELECT 1;\n-- architect:end-if", "has no if"),
+            (
+                "-- architect:if SELECT true\n-- architect:if SELECT true\n-- architect:end-if",
+                "don't nest",
+            ),
+            (
+                "SELECT\n-- architect:if SELECT true\n1;\n-- architect:end-if",
+                "inside an if block",
+            ),
+        ] {
+            let e = Blocks::parse(sql).err().unwrap().to_string();
+            assert!(e.contains(error), "{}: {}", sql, e);
+        }
+
+        let dir = std::path::PathBuf::from("./conditions");
+        let mut m =
+            crate::Migrator::new(crate::tests::schema_config("__conditions__"), dir.clone())
+                .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:if SELECT NOT EXISTS (SELECT FROM pg_extension WHERE extname = 'plpgsql')\n\
+            CREATE TABLE without_plpgsql (id INT);\n\
+            -- architect:end-if\n\
+            -- architect:if SELECT EXISTS (SELECT FROM pg_extension WHERE extname = 'plpgsql')\n\
+            CREATE TABLE with_plpgsql (id INT);\n\
+            -- architect:end-if\n\
+            CREATE TABLE always (id INT);",
+        )
+        .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:if SELECT 1\nSELECT 1;\n-- architect:end-if",
+        )
+        .unwrap();
+        m.migrate_up_n(1, false).unwrap();
+        let tables: Vec<String> = m
+            .client
+            .query(
+                "SELECT tablename::text FROM pg_tables WHERE schemaname = '__conditions__'
+                AND tablename NOT LIKE 'schema\\_%' ORDER BY tablename",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|r| r.get(0))
+            .collect();
+        let not_boolean = m.migrate_up_n(1, false);
+        m.client
+            .batch_execute("DROP SCHEMA __conditions__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(tables, vec!["always", "with_plpgsql"]);
+        assert!(format!("{:#}", not_boolean.unwrap_err()).contains("has to return a boolean"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Separate credentials for commands that only read, configured in the `[verifier]` table, so the
+//! DDL capable `user` of the config is only used to migrate. Commands like `list`, `plan` or
+//! `verify-grants` connect as the verifier when one is configured, see `Migrator::read_only`.
+
+use serde::Deserialize;
+
+use crate::Config;
+
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Credentials {
+    /// Read only user. The `user` of the config is used for everything when empty.
+    #[serde(default)]
+    pub(crate) user: String,
+    /// Its password, falling back to `PGPASSWORD`, the password file and plugins like `password`
+    #[serde(default)]
+    pub(crate) password: String,
+}
+
+impl Config {
+    /// Switches to the `[verifier]` credentials, if configured.
+    pub(crate) fn as_verifier(&mut self) {
+        if self.verifier.user.is_empty() {
+            return;
+        }
+        self.user = self.verifier.user.clone();
+        self.password = self.verifier.password.clone();
+    }
+}
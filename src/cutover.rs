@@ -0,0 +1,500 @@
+//! Online cutovers to a rebuilt table, for changes too big for a migration, e.g. changing the
+//! type of a primary key of a large table. `cutover start` records the cutover in
+//! `schema_cutovers` and runs its phases, each recorded when done, so `cutover run` resumes an
+//! interrupted cutover where it stopped:
+//!
+//! 1. schema: create `TABLE_new` like the table, with its indexes, and apply the `--alter`
+//!    statements to it
+//! 2. sync: a trigger mirrors every write to the table into `TABLE_new`
+//! 3. backfill: copy the existing rows in batches of the integer key, each in a transaction
+//!    recording the progress
+//! 4. swap, with `--swap`: once no row is missing, rename the table to `TABLE_old` and
+//!    `TABLE_new` to the table in a single transaction
+//!
+//! `cutover finish` drops `TABLE_old` once the app runs fine on the new table, `cutover abort`
+//! drops `TABLE_new` and the trigger before the swap. Logical replication isn't used as it needs
+//! a second database; the trigger keeps both tables in the same transaction instead.
+
+use anyhow::Result;
+use postgres::Row;
+use serde::Serialize;
+
+use crate::output::info;
+use crate::Migrator;
+
+/// Placeholder of the new table in `--alter` statements.
+pub(crate) const PLACEHOLDER: &str = "{table}";
+
+/// A cutover as recorded in `schema_cutovers`.
+#[derive(Serialize, Debug)]
+pub(crate) struct Cutover {
+    pub(crate) name: String,
+    pub(crate) table: String,
+    /// Statements applied to the new table, see `PLACEHOLDER`
+    pub(crate) alter: String,
+    pub(crate) key: String,
+    pub(crate) size: i64,
+    /// `schema`, `sync`, `backfill`, `synced`, `swapped` or `finished`
+    pub(crate) phase: String,
+    pub(crate) last_key: Option<i64>,
+    pub(crate) rows_copied: i64,
+}
+
+impl Cutover {
+    fn from_row(row: &Row) -> Cutover {
+        Cutover {
+            name: row.get(0),
+            table: row.get(1),
+            alter: row.get(2),
+            key: row.get(3),
+            size: row.get(4),
+            phase: row.get(5),
+            last_key: row.get(6),
+            rows_copied: row.get(7),
+        }
+    }
+
+    /// The schema prefix of the table, with its dot, and its name.
+    fn split(&self) -> (&str, &str) {
+        match self.table.rsplit_once('.') {
+            Some((schema, _)) => self.table.split_at(schema.len() + 1),
+            None => ("", &self.table),
+        }
+    }
+
+    /// The table's name with `suffix`, qualified and bare.
+    fn renamed(&self, suffix: &str) -> (String, String) {
+        let (schema, name) = self.split();
+        let bare = format!("{}_{}", name, suffix);
+        (format!("{}{}", schema, bare), bare)
+    }
+
+    fn new_table(&self) -> String {
+        self.renamed("new").0
+    }
+
+    /// Name of the trigger and its function.
+    fn trigger(&self) -> (String, String) {
+        self.renamed("cutover_sync")
+    }
+
+    fn create_trigger(&self, columns: &[String]) -> String {
+        let (function, trigger) = self.trigger();
+        let new = self.new_table();
+        let values: Vec<String> = columns.iter().map(|c| format!("NEW.{}", c)).collect();
+        format!(
+            "CREATE FUNCTION {function}() RETURNS trigger LANGUAGE plpgsql AS $$\n\
+            BEGIN\n    \
+            IF TG_OP IN ('UPDATE', 'DELETE') THEN\n        \
+            DELETE FROM {new} WHERE {key} = OLD.{key};\n    \
+            END IF;\n    \
+            IF TG_OP IN ('INSERT', 'UPDATE') THEN\n        \
+            INSERT INTO {new} ({columns}) VALUES ({values});\n    \
+            END IF;\n    \
+            RETURN NULL;\n\
+            END\n$$;\n\
+            CREATE TRIGGER {trigger} AFTER INSERT OR UPDATE OR DELETE ON {table}\n    \
+            FOR EACH ROW EXECUTE FUNCTION {function}();\n",
+            key = self.key,
+            columns = columns.join(", "),
+            values = values.join(", "),
+            table = self.table,
+        )
+    }
+
+    fn drop_trigger(&self) -> String {
+        let (function, trigger) = self.trigger();
+        format!(
+            "DROP TRIGGER IF EXISTS {trigger} ON {table};\nDROP FUNCTION IF EXISTS {function}();\n",
+            table = self.table
+        )
+    }
+}
+
+const COLUMNS: &str = "name, table_name, alter_sql, key, size, phase, last_key, rows_copied";
+
+impl Migrator {
+    fn init_cutovers(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_cutovers (
+                name VARCHAR(255) PRIMARY KEY,
+                table_name TEXT NOT NULL,
+                alter_sql TEXT NOT NULL,
+                key TEXT NOT NULL,
+                size BIGINT NOT NULL,
+                phase VARCHAR(16) NOT NULL,
+                last_key BIGINT,
+                rows_copied BIGINT NOT NULL DEFAULT 0,
+                started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                started_by VARCHAR(255),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    /// The cutovers, in the order they were started.
+    pub(crate) fn cutovers(&mut self) -> Result<Vec<Cutover>> {
+        self.init_cutovers()?;
+        let rows = self.client.query(
+            &format!(
+                "SELECT {} FROM schema_cutovers ORDER BY started_at, name",
+                COLUMNS
+            ),
+            &[],
+        )?;
+        Ok(rows.iter().map(Cutover::from_row).collect())
+    }
+
+    fn cutover(&mut self, name: &str) -> Result<Cutover> {
+        self.init_cutovers()?;
+        match self.client.query_opt(
+            &format!("SELECT {} FROM schema_cutovers WHERE name = $1", COLUMNS),
+            &[&name],
+        )? {
+            Some(row) => Ok(Cutover::from_row(&row)),
+            None => Err(anyhow::anyhow!("no cutover named \"{}\"", name)),
+        }
+    }
+
+    /// Records the cutover `name` of `table` and runs it up to the swap, see `run_cutover`.
+    pub(crate) fn start_cutover(
+        &mut self,
+        name: &str,
+        table: &str,
+        alter: &[String],
+        key: &str,
+        size: i64,
+    ) -> Result<Cutover> {
+        self.init_cutovers()?;
+        if size < 1 {
+            return Err(anyhow::anyhow!("invalid batch size {}", size));
+        }
+        let exists: bool = self
+            .client
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])?
+            .get(0);
+        if !exists {
+            return Err(anyhow::anyhow!("table {} doesn't exist", table));
+        }
+        let alter = alter
+            .iter()
+            .map(|v| format!("{};", v.trim().trim_end_matches(';')))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let inserted = self.client.execute(
+            "INSERT INTO schema_cutovers (name, table_name, alter_sql, key, size, phase, started_by)
+            VALUES ($1, $2, $3, $4, $5, 'schema', current_user) ON CONFLICT (name) DO NOTHING",
+            &[&name, &table, &alter, &key, &size],
+        )?;
+        if inserted == 0 {
+            return Err(anyhow::anyhow!(
+                "cutover \"{}\" exists already, continue it with cutover run",
+                name
+            ));
+        }
+        self.run_cutover(name, false)
+    }
+
+    /// The columns the table and the new table have in common, quoted.
+    fn cutover_columns(&mut self, c: &Cutover) -> Result<Vec<String>> {
+        let rows = self.client.query(
+            "SELECT quote_ident(a.attname) FROM pg_attribute a
+            WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped
+                AND a.attgenerated = ''
+                AND a.attname IN (SELECT attname FROM pg_attribute
+                    WHERE attrelid = to_regclass($2) AND attnum > 0 AND NOT attisdropped
+                        AND attgenerated = '')
+            ORDER BY a.attnum",
+            &[&c.table, &c.new_table()],
+        )?;
+        Ok(rows.iter().map(|r| r.get(0)).collect())
+    }
+
+    /// Moves the cutover to `phase` along with `sql`, in a single transaction.
+    fn advance_cutover(&mut self, c: &mut Cutover, sql: &str, phase: &str) -> Result<()> {
+        let mut t = self.client.transaction()?;
+        t.batch_execute(sql)?;
+        t.execute(
+            "UPDATE schema_cutovers SET phase = $2, updated_at = now() WHERE name = $1",
+            &[&c.name, &phase],
+        )?;
+        t.commit()?;
+        info!("cutover {}: {} done", c.name, c.phase);
+        c.phase = phase.to_owned();
+        Ok(())
+    }
+
+    /// Copies the next batch of rows to the new table, returning false once all are copied.
+    fn backfill_cutover(&mut self, c: &mut Cutover, columns: &[String]) -> Result<bool> {
+        let (table, key, new) = (c.table.clone(), c.key.clone(), c.new_table());
+        let last_key = c.last_key.unwrap_or(i64::MIN);
+        let mut t = self.client.transaction()?;
+        // the rows of the batch can't change until they're copied
+        let row = t.query_one(
+            &format!(
+                "SELECT max({key})::bigint, count(*) FROM
+                (SELECT {key} FROM {table} WHERE {key} > $1::bigint
+                ORDER BY {key} LIMIT $2 FOR SHARE) b"
+            ),
+            &[&last_key, &c.size],
+        )?;
+        let (max, count): (Option<i64>, i64) = (row.get(0), row.get(1));
+        let max = match max {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let columns = columns.join(", ");
+        let copied = t.execute(
+            &format!(
+                "INSERT INTO {new} ({columns}) SELECT {columns} FROM {table}
+                WHERE {key} > $1::bigint AND {key} <= $2::bigint ON CONFLICT DO NOTHING"
+            ),
+            &[&last_key, &max],
+        )?;
+        t.execute(
+            "UPDATE schema_cutovers SET last_key = $2, rows_copied = rows_copied + $3,
+            updated_at = now() WHERE name = $1",
+            &[&c.name, &max, &(copied as i64)],
+        )?;
+        t.commit()?;
+        c.last_key = Some(max);
+        c.rows_copied += copied as i64;
+        info!(
+            "cutover {}: copied {} rows up to {} {}",
+            c.name, c.rows_copied, key, max
+        );
+        Ok(count == c.size)
+    }
+
+    /// Rows of the table missing in the new table.
+    fn cutover_lag(&mut self, c: &Cutover) -> Result<i64> {
+        Ok(self
+            .client
+            .query_one(
+                &format!(
+                    "SELECT count(*) FROM {table} t
+                    WHERE NOT EXISTS (SELECT FROM {new} n WHERE n.{key} = t.{key})",
+                    table = c.table,
+                    new = c.new_table(),
+                    key = c.key
+                ),
+                &[],
+            )?
+            .get(0))
+    }
+
+    /// Runs the remaining phases of the cutover `name`, up to the swap unless `swap`.
+    pub(crate) fn run_cutover(&mut self, name: &str, swap: bool) -> Result<Cutover> {
+        let mut c = self.cutover(name)?;
+        loop {
+            match c.phase.as_str() {
+                "schema" => {
+                    let new = c.new_table();
+                    let sql = format!(
+                        "CREATE TABLE {new} (LIKE {} INCLUDING ALL);\n{}",
+                        c.table,
+                        c.alter.replace(PLACEHOLDER, &new)
+                    );
+                    self.advance_cutover(&mut c, &sql, "sync")?;
+                }
+                "sync" => {
+                    let columns = self.cutover_columns(&c)?;
+                    let sql = c.create_trigger(&columns);
+                    self.advance_cutover(&mut c, &sql, "backfill")?;
+                }
+                "backfill" => {
+                    let columns = self.cutover_columns(&c)?;
+                    while self.backfill_cutover(&mut c, &columns)? {}
+                    self.advance_cutover(&mut c, "", "synced")?;
+                }
+                "synced" if swap => self.swap_cutover(&mut c)?,
+                _ => return Ok(c),
+            }
+        }
+    }
+
+    /// Swaps in the new table once no row is missing in it.
+    fn swap_cutover(&mut self, c: &mut Cutover) -> Result<()> {
+        let (_, old) = c.renamed("old");
+        let (_, bare) = c.split();
+        let (new, table) = (c.new_table(), c.table.clone());
+        let mut t = self.client.transaction()?;
+        t.batch_execute(&format!(
+            "LOCK TABLE {table}, {new} IN ACCESS EXCLUSIVE MODE"
+        ))?;
+        let missing: i64 = t
+            .query_one(
+                &format!(
+                    "SELECT (SELECT count(*) FROM {table} t
+                        WHERE NOT EXISTS (SELECT FROM {new} n WHERE n.{key} = t.{key}))
+                    + (SELECT count(*) FROM {new} n
+                        WHERE NOT EXISTS (SELECT FROM {table} t WHERE t.{key} = n.{key}))",
+                    key = c.key
+                ),
+                &[],
+            )?
+            .get(0);
+        if missing > 0 {
+            return Err(anyhow::anyhow!(
+                "{} rows differ between {} and {}, nothing was swapped",
+                missing,
+                table,
+                new
+            ));
+        }
+        t.batch_execute(&format!(
+            "{}ALTER TABLE {table} RENAME TO {old};\nALTER TABLE {new} RENAME TO {bare};\n",
+            c.drop_trigger()
+        ))?;
+        t.execute(
+            "UPDATE schema_cutovers SET phase = 'swapped', updated_at = now() WHERE name = $1",
+            &[&c.name],
+        )?;
+        t.commit()?;
+        info!("cutover {}: swapped {} in for {}", c.name, new, table);
+        c.phase = "swapped".to_owned();
+        Ok(())
+    }
+
+    /// Drops the old table of a swapped cutover.
+    pub(crate) fn finish_cutover(&mut self, name: &str) -> Result<Cutover> {
+        let mut c = self.cutover(name)?;
+        if c.phase != "swapped" {
+            return Err(anyhow::anyhow!(
+                "cutover \"{}\" is in phase {}, only swapped cutovers are finished",
+                name,
+                c.phase
+            ));
+        }
+        let sql = format!("DROP TABLE {};", c.renamed("old").0);
+        self.advance_cutover(&mut c, &sql, "finished")?;
+        Ok(c)
+    }
+
+    /// Drops the new table and the trigger of a cutover that isn't swapped yet and forgets it.
+    pub(crate) fn abort_cutover(&mut self, name: &str) -> Result<()> {
+        let c = self.cutover(name)?;
+        if c.phase == "swapped" || c.phase == "finished" {
+            return Err(anyhow::anyhow!(
+                "cutover \"{}\" is {} already, it can't be aborted",
+                name,
+                c.phase
+            ));
+        }
+        let mut t = self.client.transaction()?;
+        t.batch_execute(&format!(
+            "{}DROP TABLE IF EXISTS {};",
+            c.drop_trigger(),
+            c.new_table()
+        ))?;
+        t.execute("DELETE FROM schema_cutovers WHERE name = $1", &[&name])?;
+        t.commit()?;
+        Ok(())
+    }
+
+    /// The cutovers with the rows missing in the new tables of those being synced.
+    pub(crate) fn cutover_status(&mut self) -> Result<Vec<(Cutover, Option<i64>)>> {
+        let mut result = Vec::<(Cutover, Option<i64>)>::new();
+        for c in self.cutovers()? {
+            let lag = match c.phase.as_str() {
+                "backfill" | "synced" => Some(self.cutover_lag(&c)?),
+                _ => None,
+            };
+            result.push((c, lag));
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn print(cutovers: &[(Cutover, Option<i64>)]) {
+    println!(
+        "{:<20} {:<30} {:<9} {:>10} {:>10}",
+        "NAME", "TABLE", "PHASE", "COPIED", "MISSING"
+    );
+    for (c, lag) in cutovers {
+        println!(
+            "{:<20} {:<30} {:<9} {:>10} {:>10}",
+            c.name,
+            c.table,
+            c.phase,
+            c.rows_copied,
+            lag.map(|v| v.to_string()).unwrap_or_default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn cutover() {
+        let dir = std::path::PathBuf::from("./cutover");
+        let mut m =
+            crate::Migrator::new(crate::tests::schema_config("__cutover__"), dir.clone()).unwrap();
+        m.client
+            .batch_execute(
+                "CREATE TABLE orders (id INT PRIMARY KEY, amount INT);
+                INSERT INTO orders SELECT i, i * 10 FROM generate_series(1, 25) i;",
+            )
+            .unwrap();
+        let alter = vec!["ALTER TABLE {table} ALTER COLUMN id TYPE bigint".to_owned()];
+        let started = m
+            .start_cutover("orders_id", "orders", &alter, "id", 10)
+            .unwrap();
+        let again = m.start_cutover("orders_id", "orders", &alter, "id", 10);
+        // the app writes while the cutover is in progress
+        m.client
+            .batch_execute(
+                "INSERT INTO orders VALUES (26, 260);
+                UPDATE orders SET amount = 0 WHERE id = 1;
+                DELETE FROM orders WHERE id = 2;",
+            )
+            .unwrap();
+        let status = m.cutover_status().unwrap();
+        m.client
+            .batch_execute("DELETE FROM orders_new WHERE id = 3")
+            .unwrap();
+        let refused = m.run_cutover("orders_id", true);
+        m.client
+            .batch_execute("INSERT INTO orders_new VALUES (3, 30)")
+            .unwrap();
+        let swapped = m.run_cutover("orders_id", true).unwrap();
+        let row = m
+            .client
+            .query_one(
+                "SELECT sum(amount)::bigint, count(*), pg_typeof(min(id))::text FROM orders",
+                &[],
+            )
+            .unwrap();
+        let (sum, count, id_type): (i64, i64, String) = (row.get(0), row.get(1), row.get(2));
+        // writes to the swapped in table aren't mirrored anymore
+        m.client
+            .batch_execute("INSERT INTO orders VALUES (27, 270)")
+            .unwrap();
+        let finished = m.finish_cutover("orders_id").unwrap();
+        let old_exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('orders_old') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        m.client
+            .batch_execute("DROP SCHEMA __cutover__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(started.phase, "synced");
+        assert_eq!(started.rows_copied, 25);
+        assert!(again.is_err());
+        assert_eq!(status[0].1, Some(0));
+        assert!(refused.unwrap_err().to_string().contains("1 rows differ"));
+        assert_eq!(swapped.phase, "swapped");
+        assert_eq!(sum, 3250 - 10 - 20 + 260);
+        assert_eq!(count, 25);
+        assert_eq!(id_type, "bigint");
+        assert_eq!(finished.phase, "finished");
+        assert!(!old_exists);
+    }
+}
@@ -0,0 +1,366 @@
+//! Data migrations: backfills and other long running data changes, kept apart from schema
+//! migrations in the app's `data` directory and tracked in `schema_data_migrations`. A data
+//! migration marked `-- architect:batch table=T key=K size=N` runs its statement once per batch of
+//! `N` rows of `T` ordered by the integer key `K`, each batch in its own transaction recording the
+//! progress, so an interrupted run resumes after the last finished batch.
+
+use anyhow::Result;
+use postgres::types::Type;
+use postgres::{Client, Transaction};
+
+use crate::output::info;
+use crate::Migrator;
+
+/// Directory of the data migrations in the app's migration directory.
+pub(crate) const DATA_DIR: &str = "data";
+
+pub(crate) const DIRECTIVE: &str = "batch";
+
+/// Batching of a data migration, see the module docs.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Batch {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    pub(crate) size: i64,
+}
+
+impl Batch {
+    /// Parses the `key=value` arguments of the directive, `table` unless they name one.
+    pub(crate) fn parse(args: &str, table: Option<&str>) -> Result<Batch> {
+        let (mut table, mut key, mut size) = (table.map(str::to_owned), None, 1000);
+        for arg in args.split_whitespace() {
+            match arg.split_once('=') {
+                Some(("table", v)) => table = Some(v.to_owned()),
+                Some(("key", v)) => key = Some(v.to_owned()),
+                Some(("size", v)) => match v.parse() {
+                    Ok(v) if v > 0 => size = v,
+                    _ => return Err(anyhow::anyhow!("invalid batch size \"{}\"", v)),
+                },
+                _ => return Err(anyhow::anyhow!("unknown batch argument \"{}\"", arg)),
+            }
+        }
+        match (table, key) {
+            (Some(table), Some(key)) => Ok(Batch { table, key, size }),
+            _ => Err(anyhow::anyhow!(
+                "batch needs table and key, e.g. table=users key=id size=1000"
+            )),
+        }
+    }
+}
+
+pub(crate) struct DataMigration {
+    pub(crate) version: i64,
+    pub(crate) description: String,
+    pub(crate) batch: Option<Batch>,
+    pub(crate) sql: String,
+}
+
+/// Progress of a data migration as recorded in `schema_data_migrations`.
+pub(crate) struct Progress {
+    pub(crate) rows_processed: i64,
+    pub(crate) last_key: Option<i64>,
+    pub(crate) finished: bool,
+}
+
+/// The data migrations in `dir`, `<version>.sql` files, ordered by version.
+pub(crate) fn scan(dir: &std::path::Path) -> Result<Vec<DataMigration>> {
+    let mut result = Vec::<DataMigration>::new();
+    if !dir.exists() {
+        return Ok(result);
+    }
+    for f in std::fs::read_dir(dir)? {
+        let path = f?.path();
+        let version = match path.file_name().and_then(|v| v.to_str()) {
+            Some(name) => match name.strip_suffix(".sql").map(str::parse::<i64>) {
+                Some(Ok(v)) => v,
+                _ => continue,
+            },
+            None => continue,
+        };
+        let sql = std::fs::read_to_string(&path)?;
+        let batch = match crate::directives::parse(&sql)
+            .into_iter()
+            .find(|v| v.name == DIRECTIVE)
+        {
+            Some(d) => match Batch::parse(&d.args, None) {
+                Ok(v) => Some(v),
+                Err(e) => return Err(anyhow::anyhow!("{:?}: {}", path, e)),
+            },
+            None => None,
+        };
+        result.push(DataMigration {
+            version,
+            description: crate::list::description(&path)?,
+            batch,
+            sql,
+        });
+    }
+    result.sort_by_key(|v| v.version);
+    Ok(result)
+}
+
+impl Migrator {
+    fn data_dir(&self) -> std::path::PathBuf {
+        self.dir.join(DATA_DIR)
+    }
+
+    fn init_data(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_data_migrations (
+                version BIGINT PRIMARY KEY,
+                rows_processed BIGINT NOT NULL DEFAULT 0,
+                last_key BIGINT,
+                started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                finished_at TIMESTAMPTZ
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    /// Creates an empty data migration, returning its path.
+    pub(crate) fn new_data_migration(&mut self) -> Result<std::path::PathBuf> {
+        let dir = self.data_dir();
+        std::fs::create_dir_all(&dir)?;
+        let mut version = chrono::Utc::now().timestamp_millis();
+        loop {
+            let path = dir.join(format!("{}.sql", version));
+            if crate::create_new(&path)? {
+                return Ok(path);
+            }
+            version += 1;
+        }
+    }
+
+    /// The data migrations with their progress, None if they haven't started.
+    pub(crate) fn data_migrations(&mut self) -> Result<Vec<(DataMigration, Option<Progress>)>> {
+        self.init_data()?;
+        let mut result = Vec::<(DataMigration, Option<Progress>)>::new();
+        for d in scan(&self.data_dir())? {
+            let progress = self
+                .client
+                .query_opt(
+                    "SELECT rows_processed, last_key, finished_at IS NOT NULL \
+                    FROM schema_data_migrations WHERE version = $1",
+                    &[&d.version],
+                )?
+                .map(|row| Progress {
+                    rows_processed: row.get(0),
+                    last_key: row.get(1),
+                    finished: row.get(2),
+                });
+            result.push((d, progress));
+        }
+        Ok(result)
+    }
+
+    /// Runs the unfinished data migrations in order, resuming started ones. Data migrations newer
+    /// than the last applied schema migration wait for it. Returns the number finished.
+    pub(crate) fn run_data_migrations(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for (d, progress) in self.data_migrations()? {
+            if progress.as_ref().is_some_and(|v| v.finished) {
+                continue;
+            }
+            if d.version > self.last_version {
+                info!(
+                    "{}.sql waits for the schema migrations up to its version",
+                    d.version
+                );
+                break;
+            }
+            self.client.execute(
+                "INSERT INTO schema_data_migrations (version) VALUES ($1) \
+                ON CONFLICT (version) DO NOTHING",
+                &[&d.version],
+            )?;
+            let result = match &d.batch {
+                Some(batch) => {
+                    let last_key = progress.and_then(|v| v.last_key);
+                    self.run_batches(&d, batch, last_key)
+                }
+                None => self.run_data_once(&d),
+            };
+            if let Err(e) = result {
+                return Err(anyhow::anyhow!("data migration {}.sql: {}", d.version, e));
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Runs all statements of an unbatched data migration in one transaction.
+    fn run_data_once(&mut self, d: &DataMigration) -> Result<()> {
+        let statements = crate::parse_statements(&d.sql)?;
+        let mut t = self.client.transaction()?;
+        let mut rows = 0;
+        for s in statements.iter() {
+            rows += t.execute(s.as_str(), &[])? as i64;
+        }
+        t.execute(
+            "UPDATE schema_data_migrations SET rows_processed = $1, finished_at = now() \
+            WHERE version = $2",
+            &[&rows, &d.version],
+        )?;
+        t.commit()?;
+        info!("{}.sql: {} rows", d.version, rows);
+        Ok(())
+    }
+
+    /// Runs the statement of a batched data migration once per batch of keys after `last_key`.
+    fn run_batches(
+        &mut self,
+        d: &DataMigration,
+        batch: &Batch,
+        last_key: Option<i64>,
+    ) -> Result<()> {
+        let statements = crate::parse_statements(&d.sql)?;
+        if statements.len() != 1 {
+            return Err(anyhow::anyhow!(
+                "a batched data migration has exactly one statement, found {}",
+                statements.len()
+            ));
+        }
+        let label = format!("{}.sql", d.version);
+        let version = d.version;
+        run_batches(
+            &mut self.client,
+            batch,
+            last_key,
+            &statements[0],
+            &label,
+            |t, rows, upper| {
+                Ok(t.query_one(
+                    "UPDATE schema_data_migrations \
+                    SET rows_processed = rows_processed + $1, last_key = $2 \
+                    WHERE version = $3 RETURNING rows_processed",
+                    &[&rows, &upper, &version],
+                )?
+                .get(0))
+            },
+        )?;
+        self.client.execute(
+            "UPDATE schema_data_migrations SET finished_at = now() WHERE version = $1",
+            &[&d.version],
+        )?;
+        info!("{}: finished", label);
+        Ok(())
+    }
+}
+
+/// Runs `statement` once per batch of keys after `start`, each batch in its own transaction, with
+/// the key the batch starts after as `$1` and its last key as `$2`. `progress` runs in the
+/// transaction of every batch with the rows the statement changed and the batch's last key, and
+/// returns the rows changed in total, which is logged with `label`.
+pub(crate) fn run_batches(
+    client: &mut Client,
+    batch: &Batch,
+    start: Option<i64>,
+    statement: &str,
+    label: &str,
+    mut progress: impl FnMut(&mut Transaction, i64, i64) -> Result<i64>,
+) -> Result<()> {
+    let next = format!(
+        "SELECT max({key})::bigint FROM (SELECT {key} FROM {table} WHERE {key} > $1::bigint \
+        ORDER BY {key} LIMIT {size}) batch",
+        key = batch.key,
+        table = batch.table,
+        size = batch.size
+    );
+    let mut last_key = start.unwrap_or(i64::MIN);
+    loop {
+        let mut t = client.transaction()?;
+        let upper: i64 = match t.query_one(next.as_str(), &[&last_key])?.get(0) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let prepared = t.prepare_typed(statement, &[Type::INT8, Type::INT8])?;
+        let rows = t.execute(&prepared, &[&last_key, &upper])? as i64;
+        let total = progress(&mut t, rows, upper)?;
+        t.commit()?;
+        info!(
+            "{}: {} rows up to {} {}, {} in total",
+            label, rows, batch.key, upper, total
+        );
+        last_key = upper;
+    }
+}
+
+pub(crate) fn print(migrations: &[(DataMigration, Option<Progress>)]) {
+    println!(
+        "{:<15} {:<8} {:>10} {:>12}  DESCRIPTION",
+        "VERSION", "STATE", "ROWS", "LAST KEY"
+    );
+    for (d, progress) in migrations {
+        let (state, rows, last_key) = match progress {
+            Some(p) => (
+                if p.finished { "done" } else { "started" },
+                p.rows_processed.to_string(),
+                p.last_key.map(|v| v.to_string()).unwrap_or_default(),
+            ),
+            None => ("pending", String::new(), String::new()),
+        };
+        println!(
+            "{:<15} {:<8} {:>10} {:>12}  {}",
+            d.version, state, rows, last_key, d.description
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn batched_data_migration() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./data")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __data_batches__;
+                CREATE TABLE __data_batches__ (id INT PRIMARY KEY, done BOOLEAN DEFAULT false);
+                INSERT INTO __data_batches__ (id) SELECT generate_series(1, 25);",
+            )
+            .unwrap();
+        let path = m.new_data_migration().unwrap();
+        std::fs::write(
+            &path,
+            "-- mark all rows done\n\
+            -- architect:batch table=__data_batches__ key=id size=10\n\
+            UPDATE __data_batches__ SET done = true WHERE id > $1 AND id <= $2;",
+        )
+        .unwrap();
+        m.last_version = i64::MAX;
+
+        let finished = m.run_data_migrations();
+        let migrations = m.data_migrations();
+        let done: i64 = m
+            .client
+            .query_one("SELECT count(*) FROM __data_batches__ WHERE done", &[])
+            .unwrap()
+            .get(0);
+        let version = migrations.as_ref().map(|v| v[0].0.version).unwrap_or(0);
+        m.client
+            .execute(
+                "DELETE FROM schema_data_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+        m.client
+            .batch_execute("DROP TABLE __data_batches__")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./data");
+
+        assert_eq!(finished.unwrap(), 1);
+        let migrations = migrations.unwrap();
+        assert_eq!(migrations[0].0.description, "mark all rows done");
+        let progress = migrations[0].1.as_ref().unwrap();
+        assert_eq!(progress.rows_processed, 25);
+        assert_eq!(progress.last_key, Some(25));
+        assert!(progress.finished);
+        assert_eq!(done, 25);
+        assert!(super::Batch::parse("table=a", None).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Change {
+    Unchanged(String),
+    Added(String),
+    Removed(String),
+    /// A statement replaced by one of the same kind, e.g. a column type changed in `CREATE TABLE`
+    Modified(String, String),
+}
+
+/// A statement level diff of two migrations, based on their longest common subsequence.
+pub(crate) fn diff(old: &[String], new: &[String]) -> Vec<Change> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::<Change>::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            result.push(Change::Unchanged(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            result.push(Change::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            result.push(Change::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    for v in old[i..].iter() {
+        result.push(Change::Removed(v.clone()));
+    }
+    for v in new[j..].iter() {
+        result.push(Change::Added(v.clone()));
+    }
+    result
+}
+
+/// The statement kind `policy::kind` reports, `None` for statements that don't parse.
+fn kind(statement: &str) -> Option<&'static str> {
+    crate::parse_ast(statement)
+        .ok()?
+        .first()
+        .map(crate::policy::kind)
+}
+
+/// A statement level diff like `diff` that reports statements replaced by one of the same kind
+/// as modified rather than as removed and added.
+pub(crate) fn semantic(old: &[String], new: &[String]) -> Vec<Change> {
+    let mut result = Vec::<Change>::new();
+    let mut removed = Vec::<String>::new();
+    let mut added = Vec::<String>::new();
+    let flush = |result: &mut Vec<Change>, removed: &mut Vec<String>, added: &mut Vec<String>| {
+        let mut added = std::mem::take(added).into_iter().peekable();
+        for r in std::mem::take(removed) {
+            match added.peek() {
+                Some(a) if kind(a).is_some() && kind(a) == kind(&r) => {
+                    result.push(Change::Modified(r, added.next().unwrap()));
+                }
+                _ => result.push(Change::Removed(r)),
+            }
+        }
+        result.extend(added.map(Change::Added));
+    };
+    for c in diff(old, new) {
+        match c {
+            Change::Removed(v) => removed.push(v),
+            Change::Added(v) => added.push(v),
+            c => {
+                flush(&mut result, &mut removed, &mut added);
+                result.push(c);
+            }
+        }
+    }
+    flush(&mut result, &mut removed, &mut added);
+    result
+}
+
+/// A line per change, `suffix` ending every statement.
+pub(crate) fn lines(changes: &[Change], suffix: &str) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    for c in changes {
+        match c {
+            Change::Unchanged(v) => result.push(format!("  {}{}", v, suffix)),
+            Change::Added(v) => result.push(format!("+ {}{}", v, suffix)),
+            Change::Removed(v) => result.push(format!("- {}{}", v, suffix)),
+            Change::Modified(old, new) => {
+                result.push(format!("~ {}{}", old, suffix));
+                result.push(format!("  => {}{}", new, suffix));
+            }
+        }
+    }
+    result
+}
+
+/// A summary like `1 added, 2 modified`, empty if nothing changed.
+pub(crate) fn summary(changes: &[Change]) -> String {
+    let count = |f: fn(&Change) -> bool| changes.iter().filter(|c| f(c)).count();
+    let counts = [
+        (count(|c| matches!(c, Change::Added(_))), "added"),
+        (count(|c| matches!(c, Change::Removed(_))), "removed"),
+        (count(|c| matches!(c, Change::Modified(..))), "modified"),
+    ];
+    counts
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, label)| format!("{} {}", n, label))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub(crate) fn print(changes: &[Change]) {
+    for line in lines(changes, ";") {
+        println!("{}", line);
+    }
+}
+
+/// The contents of the file `name` in `dir` as of the git revision `rev`.
+pub(crate) fn file_at_revision(dir: &std::path::Path, name: &str, rev: &str) -> Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(format!("{}:./{}", rev, name))
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git show failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl Migrator {
+    /// The contents of a migration file in the working tree.
+    pub(crate) fn sql(&self, version: i64, direction: Direction) -> Result<String> {
+        let f = self.migration_path(version, direction);
+        if !f.exists() {
+            return Err(anyhow::anyhow!(format!(
+                "migration: \"{}_{}.sql\" does not exist",
+                version, direction
+            )));
+        }
+        if f.extension().is_some_and(|v| v == "wasm") {
+            return Err(anyhow::anyhow!(
+                "migration: {:?} is a compiled WASM module",
+                f
+            ));
+        }
+        Ok(std::fs::read_to_string(f)?)
+    }
+
+    /// The contents of a migration file as of the git revision `rev`.
+    pub(crate) fn sql_at_revision(
+        &self,
+        version: i64,
+        direction: Direction,
+        rev: &str,
+    ) -> Result<String> {
+        file_at_revision(&self.dir, &format!("{}_{}.sql", version, direction), rev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Change;
+
+    #[test]
+    fn diff_statements() {
+        let old = crate::parse_statements(
+            "CREATE TABLE a (id INT);
+            CREATE TABLE b (id INT);
+            CREATE INDEX a_id ON a (id);",
+        )
+        .unwrap();
+        let new = crate::parse_statements(
+            "-- only formatting changed for a
+            create   table a (id int);
+            CREATE TABLE c (id INT);
+            CREATE INDEX a_id ON a (id);",
+        )
+        .unwrap();
+
+        let changes = super::diff(&old, &new);
+
+        assert_eq!(
+            changes,
+            vec![
+                Change::Unchanged("CREATE TABLE a (id INT)".to_owned()),
+                Change::Removed("CREATE TABLE b (id INT)".to_owned()),
+                Change::Added("CREATE TABLE c (id INT)".to_owned()),
+                Change::Unchanged("CREATE INDEX a_id ON a(id)".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn semantic_diff() {
+        let old = crate::parse_statements(
+            "CREATE TABLE a (id INT);
+            CREATE TABLE b (id INT);
+            INSERT INTO a VALUES (1);",
+        )
+        .unwrap();
+        let new = crate::parse_statements(
+            "CREATE TABLE a (id BIGINT);
+            DROP TABLE c;
+            CREATE TABLE b (id INT);",
+        )
+        .unwrap();
+
+        let changes = super::semantic(&old, &new);
+
+        assert_eq!(
+            changes,
+            vec![
+                Change::Modified(
+                    "CREATE TABLE a (id INT)".to_owned(),
+                    "CREATE TABLE a (id BIGINT)".to_owned()
+                ),
+                Change::Added("DROP TABLE c".to_owned()),
+                Change::Unchanged("CREATE TABLE b (id INT)".to_owned()),
+                Change::Removed("INSERT INTO a VALUES (1)".to_owned()),
+            ]
+        );
+        assert_eq!(super::summary(&changes), "1 added, 1 removed, 1 modified");
+    }
+}
@@ -0,0 +1,208 @@
+//! Migration directions and the planner shared by the migrate methods. `steps` walks from one
+//! version to another, up through the up files or down through the down files, so a method can't
+//! run a file in the wrong direction.
+
+use serde::Serialize;
+
+use crate::Migrator;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    Up,
+    Down,
+}
+
+impl Direction {
+    /// Name used in file names and recorded runs.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Direction> {
+        match s {
+            "up" => Some(Direction::Up),
+            "down" => Some(Direction::Down),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A migration to run in a direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Step {
+    pub(crate) version: i64,
+    pub(crate) direction: Direction,
+}
+
+impl Migrator {
+    /// The steps from version `from` to `to`, the up migrations after `from` up to `to` in order,
+    /// or the down migrations from `from` down to the one after `to` in reverse order. Skipped
+    /// versions are left out.
+    pub(crate) fn steps(&self, from: i64, to: i64) -> Vec<Step> {
+        if to >= from {
+            self.versions_up
+                .iter()
+                .filter(|v| **v > from && **v <= to && !self.skipped.contains_key(v))
+                .map(|v| Step {
+                    version: *v,
+                    direction: Direction::Up,
+                })
+                .collect()
+        } else {
+            self.versions_down
+                .iter()
+                .rev()
+                .filter(|v| **v > to && **v <= from && !self.skipped.contains_key(v))
+                .map(|v| Step {
+                    version: *v,
+                    direction: Direction::Down,
+                })
+                .collect()
+        }
+    }
+
+    /// The version preceding `version`, 0 if none does. Skipped versions don't count.
+    pub(crate) fn previous_version(&self, version: i64) -> i64 {
+        self.versions_up
+            .iter()
+            .rev()
+            .find(|v| **v < version && !self.skipped.contains_key(v))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Migrates up the pending versions created before `timestamp`, in unix milliseconds like
+    /// generated versions.
+    pub(crate) fn migrate_up_before(
+        &mut self,
+        timestamp: i64,
+        test: bool,
+    ) -> anyhow::Result<usize> {
+        let target = self.previous_version(timestamp);
+        if target <= self.last_version {
+            return Ok(0);
+        }
+        let steps = self.steps(self.last_version, target);
+        self.run_steps(&steps, test)
+    }
+
+    /// Migrates down the applied versions created at or after `timestamp`, so only versions
+    /// created before it stay applied.
+    pub(crate) fn migrate_down_from(
+        &mut self,
+        timestamp: i64,
+        test: bool,
+    ) -> anyhow::Result<usize> {
+        let target = self.previous_version(timestamp);
+        if target >= self.last_version {
+            return Ok(0);
+        }
+        let steps = self.steps(self.last_version, target);
+        self.run_steps(&steps, test)
+    }
+
+    /// Runs `steps`, keeping track of the version. With `test` the files are only loaded, see
+    /// `dryrun`.
+    pub(crate) fn run_steps(&mut self, steps: &[Step], test: bool) -> anyhow::Result<usize> {
+        let steps = &self.check_requirements(steps)?;
+        if test {
+            self.check_steps(steps)?;
+        } else {
+            self.check_safety(steps)?;
+        }
+        for s in steps.iter() {
+            if !test {
+                self.run_step(s)?;
+            }
+            self.last_version = match s.direction {
+                Direction::Up => s.version,
+                Direction::Down => self.previous_version(s.version),
+            };
+        }
+        if !test && steps.iter().any(|s| s.direction == Direction::Up) {
+            self.record_skipped()?;
+        }
+        Ok(steps.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, Step};
+    use crate::list::parse_timestamp;
+    use crate::tests::test_config;
+
+    #[test]
+    fn date_selectors() {
+        let dir = std::path::PathBuf::from("./date_selectors");
+        let mut m = crate::Migrator::new(
+            crate::tests::schema_config("__date_selectors__"),
+            dir.clone(),
+        )
+        .unwrap();
+        let mut versions = Vec::new();
+        for date in ["2020-01-01 10:00", "2020-01-02 10:00", "2020-01-03 10:00"] {
+            let version = parse_timestamp(date).unwrap();
+            std::fs::write(m.dir.join(format!("{}_up.sql", version)), "SELECT 1;").unwrap();
+            std::fs::write(m.dir.join(format!("{}_down.sql", version)), "SELECT 1;").unwrap();
+            versions.push(version);
+        }
+        m.available_versions().unwrap();
+
+        let up = m.migrate_up_before(parse_timestamp("2020-01-03").unwrap(), false);
+        let up_version = m.last_version;
+        let again = m.migrate_up_before(parse_timestamp("2020-01-02").unwrap(), false);
+        m.migrate_up(false).unwrap();
+        let down = m.migrate_down_from(parse_timestamp("2020-01-02T00:00:00Z").unwrap(), false);
+        let applied = m.applied_versions().unwrap();
+        m.client
+            .batch_execute("DROP SCHEMA __date_selectors__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(up.unwrap(), 2);
+        assert_eq!(up_version, versions[1]);
+        assert_eq!(again.unwrap(), 0);
+        assert_eq!(down.unwrap(), 2);
+        assert_eq!(applied, vec![versions[0]]);
+    }
+
+    #[test]
+    fn steps() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./steps")).unwrap();
+        let _ = std::fs::remove_dir_all("./steps");
+        m.versions_up = vec![1, 2, 3];
+        m.versions_down = vec![1, 2, 3];
+
+        let step = |version, direction| Step { version, direction };
+        assert_eq!(
+            m.steps(1, 3),
+            vec![step(2, Direction::Up), step(3, Direction::Up)]
+        );
+        assert_eq!(
+            m.steps(3, 0),
+            vec![
+                step(3, Direction::Down),
+                step(2, Direction::Down),
+                step(1, Direction::Down)
+            ]
+        );
+        assert_eq!(m.steps(2, 2), vec![]);
+        assert_eq!(m.previous_version(3), 2);
+        assert_eq!(m.previous_version(1), 0);
+        assert_eq!(Direction::parse("down"), Some(Direction::Down));
+        assert_eq!(Direction::Up.to_string(), "up");
+    }
+}
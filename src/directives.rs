@@ -0,0 +1,84 @@
+//! Directives are sql comments of the form `-- architect:<name> <args>` that change how architect
+//! handles a migration file.
+
+/// Marks a file that is sent to the server as it is instead of split into statements, for sql
+/// architect can't parse, like `CREATE TRIGGER` or `DO` blocks. Its statements can't be checked.
+pub(crate) const VERBATIM: &str = "verbatim";
+
+/// Directives architect understands.
+pub(crate) const KNOWN: &[&str] = &[
+    VERBATIM,
+    crate::author::DIRECTIVE,
+    crate::irreversible::DIRECTIVE,
+    crate::data::DIRECTIVE,
+    crate::batch_update::DIRECTIVE,
+    crate::foreign_key::DIRECTIVE,
+    crate::refresh::DIRECTIVE,
+    crate::sequences::DIRECTIVE,
+    crate::assertions::DIRECTIVE,
+    crate::parallel::DIRECTIVE,
+    crate::post::DIRECTIVE,
+    crate::requires::DIRECTIVE,
+    crate::conditions::DIRECTIVE,
+    crate::conditions::END,
+];
+
+const PREFIX: &str = "architect:";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Directive {
+    pub(crate) name: String,
+    pub(crate) args: String,
+    /// 1 based line number of the directive in the file
+    pub(crate) line: usize,
+}
+
+/// All directives in `sql`, in the order they appear.
+pub(crate) fn parse(sql: &str) -> Vec<Directive> {
+    let mut result = Vec::<Directive>::new();
+    for (i, line) in sql.lines().enumerate() {
+        let comment = match line.trim().strip_prefix("--") {
+            Some(v) => v.trim(),
+            None => continue,
+        };
+        let directive = match comment.strip_prefix(PREFIX) {
+            Some(v) => v,
+            None => continue,
+        };
+        let (name, args) = match directive.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (directive, ""),
+        };
+        result.push(Directive {
+            name: name.to_owned(),
+            args: args.to_owned(),
+            line: i + 1,
+        });
+    }
+    result
+}
+
+/// Whether `sql` has the directive `name`.
+pub(crate) fn has(sql: &str, name: &str) -> bool {
+    parse(sql).iter().any(|v| v.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_directives() {
+        let directives = super::parse(
+            "-- plain comment
+            --architect:first
+            CREATE TABLE a (id INT); -- architect:ignored trailing comment
+            -- architect:second  some args ",
+        );
+
+        assert_eq!(directives.len(), 2);
+        assert_eq!(directives[0].name, "first");
+        assert_eq!(directives[0].args, "");
+        assert_eq!(directives[0].line, 2);
+        assert_eq!(directives[1].name, "second");
+        assert_eq!(directives[1].args, "some args");
+    }
+}
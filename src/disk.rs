@@ -0,0 +1,254 @@
+//! Disk space check of pending migrations. Rewriting a table or building an index writes a new
+//! copy next to the old one, which is only freed when the migration commits, so a migration can
+//! run out of space most of the way through. `plan` and `up` estimate the space each pending
+//! migration needs from the sizes and statistics of the tables it rewrites or indexes and warn
+//! when it's more than is free on the server.
+//!
+//! The free space is queried with `disk_free_query` if configured, e.g. from a monitoring
+//! extension, or else found with `df` for the data directory when the server runs on this host.
+
+use anyhow::Result;
+use sqlparser::ast::{AlterColumnOperation, AlterTableOperation, Expr, Statement};
+
+use crate::lint::{Finding, Severity};
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const RULE: &str = "disk";
+
+/// Space a statement needs while its migration runs.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Need {
+    /// The table and its indexes are written again, e.g. by changing a column's type
+    Rewrite(String),
+    /// An index of the columns of the table is built
+    Index { table: String, columns: Vec<String> },
+}
+
+/// What the statements of a migration write next to the existing tables.
+pub(crate) fn needs(statements: &[Statement]) -> Vec<Need> {
+    let mut result = Vec::<Need>::new();
+    for s in statements.iter() {
+        match s {
+            Statement::AlterTable {
+                name,
+                operation:
+                    AlterTableOperation::AlterColumn {
+                        op: AlterColumnOperation::SetDataType { .. },
+                        ..
+                    },
+            } => result.push(Need::Rewrite(crate::owners::object_name(name))),
+            Statement::CreateIndex {
+                table_name,
+                columns,
+                ..
+            } => result.push(Need::Index {
+                table: crate::owners::object_name(table_name),
+                columns: columns
+                    .iter()
+                    .filter_map(|c| match &c.expr {
+                        Expr::Identifier(v) => Some(crate::naming::ident(v)),
+                        _ => None,
+                    })
+                    .collect(),
+            }),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// `bytes` rounded to the largest unit.
+pub(crate) fn pretty(bytes: i64) -> String {
+    let units = ["bytes", "kB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// Whether every host of `config` is this one.
+fn local(config: &crate::Config) -> bool {
+    config
+        .host_ports()
+        .iter()
+        .all(|(host, _)| host.starts_with('/') || ["localhost", "127.0.0.1", "::1"].contains(host))
+}
+
+/// Free bytes of the file system of `dir` according to `df`.
+fn df(dir: &str) -> Option<i64> {
+    let output = std::process::Command::new("df")
+        .args(["-Pk", dir])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let available: i64 = stdout
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(available * 1024)
+}
+
+impl Migrator {
+    /// Bytes `need` writes, 0 for tables that don't exist yet.
+    fn estimate(&mut self, need: &Need) -> Result<i64> {
+        let row = match need {
+            Need::Rewrite(table) => self
+                .client
+                .query_opt("SELECT pg_total_relation_size(to_regclass($1))", &[table])?,
+            // rows times the width of the indexed values and the tuple overhead, or the size of
+            // the table without statistics
+            Need::Index { table, columns } => self.client.query_opt(
+                "SELECT coalesce(
+                    (SELECT (greatest(c.reltuples, 0) * (sum(s.avg_width) + 16))::bigint
+                    FROM pg_stats s
+                    WHERE s.schemaname = n.nspname AND s.tablename = c.relname
+                        AND s.attname = ANY($2)),
+                    pg_relation_size(c.oid))
+                FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.oid = to_regclass($1)",
+                &[table, columns],
+            )?,
+        };
+        Ok(row.and_then(|v| v.get::<_, Option<i64>>(0)).unwrap_or(0))
+    }
+
+    /// Free bytes on the server's disk, None if they can't be found out.
+    pub(crate) fn free_space(&mut self) -> Option<i64> {
+        if !self.config.disk_free_query.is_empty() {
+            let query = self.config.disk_free_query.clone();
+            return match self.client.query_one(query.as_str(), &[]) {
+                Ok(row) => row.try_get::<_, i64>(0).ok(),
+                Err(e) => {
+                    info!("disk_free_query failed: {}", e);
+                    None
+                }
+            };
+        }
+        if !local(&self.config) {
+            return None;
+        }
+        // needs superuser or pg_read_all_settings
+        let dir: String = self
+            .client
+            .query_one("SELECT current_setting('data_directory')", &[])
+            .ok()?
+            .get(0);
+        df(&dir)
+    }
+
+    /// Warnings for the migrations, with the `needs` of their statements, needing more space
+    /// than is free.
+    pub(crate) fn disk_findings(&mut self, needs: &[(String, Vec<Need>)]) -> Result<Vec<Finding>> {
+        let mut result = Vec::<Finding>::new();
+        if needs.iter().all(|(_, v)| v.is_empty()) {
+            return Ok(result);
+        }
+        let free = match self.free_space() {
+            Some(v) => v,
+            None => return Ok(result),
+        };
+        for (file, needs) in needs.iter() {
+            let mut total = 0;
+            for need in needs.iter() {
+                total += self.estimate(need)?;
+            }
+            if total > free {
+                result.push(Finding::new(
+                    file,
+                    RULE,
+                    Severity::Warning,
+                    format!(
+                        "needs about {} of disk space for rewriting tables and building \
+                        indexes, {} are free",
+                        pretty(total),
+                        pretty(free)
+                    ),
+                ));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Warns about the disk findings of `plan` on stderr.
+pub(crate) fn warn(plan: &crate::plan::Plan) {
+    for f in plan.findings.iter().filter(|f| f.rule == RULE) {
+        info!("warning: {}", f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Need;
+
+    #[test]
+    fn needs() {
+        let statements = crate::parse_ast(
+            "ALTER TABLE a ALTER COLUMN b TYPE BIGINT;
+            CREATE INDEX a_c ON a (c, lower(d));
+            ALTER TABLE a ADD COLUMN e INT;",
+        )
+        .unwrap();
+        assert_eq!(
+            super::needs(&statements),
+            vec![
+                Need::Rewrite("a".to_owned()),
+                Need::Index {
+                    table: "a".to_owned(),
+                    columns: vec!["c".to_owned()]
+                }
+            ]
+        );
+        assert_eq!(super::pretty(512), "512 bytes");
+        assert_eq!(super::pretty(3 * 1024 * 1024 / 2), "1.5 MB");
+    }
+
+    #[test]
+    fn disk_space() {
+        let dir = std::path::PathBuf::from("./disk_space");
+        let mut config = crate::tests::schema_config("__disk__");
+        config.disk_free_query = "SELECT 1024::bigint".to_owned();
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        m.client
+            .batch_execute(
+                "CREATE TABLE big (id INT, name TEXT);
+                INSERT INTO big SELECT i, md5(i::text) FROM generate_series(1, 1000) i;
+                ANALYZE big;",
+            )
+            .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE INDEX big_name ON big (name);").unwrap();
+        let plan = m.plan().unwrap();
+        let findings: Vec<String> = plan
+            .findings
+            .iter()
+            .filter(|f| f.rule == super::RULE)
+            .map(|f| f.message.clone())
+            .collect();
+        m.config.disk_free_query = "SELECT 1024::bigint * 1024 * 1024".to_owned();
+        let enough = m.plan().unwrap();
+        m.client
+            .batch_execute("DROP SCHEMA __disk__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].contains("1.0 kB are free"), "{}", findings[0]);
+        assert!(!enough.findings.iter().any(|f| f.rule == super::RULE));
+    }
+}
@@ -0,0 +1,268 @@
+//! Schema documentation: a page per table with its columns, constraints, foreign keys, indexes
+//! and comments, and an index page, as Markdown or HTML.
+
+use anyhow::Result;
+
+use crate::schema::{Constraint, Schema, Table};
+
+/// Directory of the docs in the app's migration directory, unless `docs --out` is given.
+pub(crate) const DOCS_DIR: &str = "_docs";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub(crate) fn parse(s: &str) -> Result<Format> {
+        match s {
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            _ => Err(anyhow::anyhow!(
+                "unknown docs format \"{}\". Expected markdown or html",
+                s
+            )),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// Rows under headers. With `linked` the first column holds names of pages to link to.
+struct Section {
+    title: &'static str,
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+    linked: bool,
+}
+
+impl Section {
+    fn new(title: &'static str, headers: Vec<&'static str>, rows: Vec<Vec<String>>) -> Self {
+        Section {
+            title,
+            headers,
+            rows,
+            linked: false,
+        }
+    }
+}
+
+/// A page with a title, an optional description and sections, empty sections are left out.
+struct Page {
+    title: String,
+    description: Option<String>,
+    sections: Vec<Section>,
+}
+
+fn markdown_cell(s: &str) -> String {
+    s.replace('|', "\\|").replace('\n', " ")
+}
+
+pub(crate) fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Page {
+    fn markdown(&self) -> String {
+        let mut result = format!("# {}\n", self.title);
+        if let Some(d) = &self.description {
+            result.push_str(&format!("\n{}\n", d));
+        }
+        for s in self.sections.iter().filter(|v| !v.rows.is_empty()) {
+            result.push_str(&format!("\n## {}\n\n", s.title));
+            result.push_str(&format!("| {} |\n", s.headers.join(" | ")));
+            result.push_str(&format!("|{}\n", " --- |".repeat(s.headers.len())));
+            for row in s.rows.iter() {
+                let mut cells: Vec<String> = row.iter().map(|v| markdown_cell(v)).collect();
+                if s.linked {
+                    cells[0] = format!("[{}]({}.md)", cells[0], row[0]);
+                }
+                result.push_str(&format!("| {} |\n", cells.join(" | ")));
+            }
+        }
+        result
+    }
+
+    fn html(&self) -> String {
+        let title = html_escape(&self.title);
+        let mut result = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n\
+            <body>\n<h1>{}</h1>\n",
+            title, title
+        );
+        if let Some(d) = &self.description {
+            result.push_str(&format!("<p>{}</p>\n", html_escape(d)));
+        }
+        for s in self.sections.iter().filter(|v| !v.rows.is_empty()) {
+            result.push_str(&format!("<h2>{}</h2>\n<table>\n<tr>", s.title));
+            for h in s.headers.iter() {
+                result.push_str(&format!("<th>{}</th>", h));
+            }
+            result.push_str("</tr>\n");
+            for row in s.rows.iter() {
+                result.push_str("<tr>");
+                for (i, cell) in row.iter().enumerate() {
+                    let cell = html_escape(cell);
+                    if i == 0 && s.linked {
+                        result
+                            .push_str(&format!("<td><a href=\"{}.html\">{}</a></td>", cell, cell));
+                    } else {
+                        result.push_str(&format!("<td>{}</td>", cell));
+                    }
+                }
+                result.push_str("</tr>\n");
+            }
+            result.push_str("</table>\n");
+        }
+        result.push_str("</body>\n</html>\n");
+        result
+    }
+
+    fn render(&self, format: Format) -> String {
+        match format {
+            Format::Markdown => self.markdown(),
+            Format::Html => self.html(),
+        }
+    }
+}
+
+fn table_page(t: &Table) -> Page {
+    let columns = t
+        .columns
+        .iter()
+        .map(|c| {
+            vec![
+                c.name.clone(),
+                c.data_type.clone(),
+                if c.nullable { "yes" } else { "no" }.to_owned(),
+                c.default.clone().unwrap_or_default(),
+                c.comment.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let (foreign_keys, constraints): (Vec<&Constraint>, Vec<&Constraint>) =
+        t.constraints.iter().partition(|c| c.kind == "f");
+    let rows = |v: Vec<&Constraint>| -> Vec<Vec<String>> {
+        v.iter()
+            .map(|c| vec![c.name.clone(), c.definition.clone()])
+            .collect()
+    };
+    let indexes = t
+        .indexes
+        .iter()
+        .map(|i| vec![i.name.clone(), i.definition.clone()])
+        .collect();
+    Page {
+        title: t.name.clone(),
+        description: t.comment.clone(),
+        sections: vec![
+            Section::new(
+                "Columns",
+                vec!["Name", "Type", "Nullable", "Default", "Comment"],
+                columns,
+            ),
+            Section::new("Constraints", vec!["Name", "Definition"], rows(constraints)),
+            Section::new(
+                "Foreign keys",
+                vec!["Name", "Definition"],
+                rows(foreign_keys),
+            ),
+            Section::new("Indexes", vec!["Name", "Definition"], indexes),
+        ],
+    }
+}
+
+fn index_page(schema: &Schema, title: &str) -> Page {
+    let tables = schema
+        .app_tables()
+        .map(|t| vec![t.name.clone(), t.comment.clone().unwrap_or_default()])
+        .collect();
+    let views = schema
+        .views
+        .iter()
+        .map(|v| vec![v.name.clone(), v.definition.trim().to_owned()])
+        .collect();
+    Page {
+        title: title.to_owned(),
+        description: None,
+        sections: vec![
+            Section {
+                linked: true,
+                ..Section::new("Tables", vec!["Name", "Comment"], tables)
+            },
+            Section::new("Views", vec!["Name", "Definition"], views),
+        ],
+    }
+}
+
+/// Writes a page per table and `index.<ext>`, titled `title`, into `dir`, returning the number
+/// of tables.
+pub(crate) fn write(
+    schema: &Schema,
+    title: &str,
+    dir: &std::path::Path,
+    format: Format,
+) -> Result<usize> {
+    std::fs::create_dir_all(dir)?;
+    let extension = format.extension();
+    let mut count = 0;
+    for t in schema.app_tables() {
+        count += 1;
+        std::fs::write(
+            dir.join(format!("{}.{}", t.name, extension)),
+            table_page(t).render(format),
+        )?;
+    }
+    std::fs::write(
+        dir.join(format!("index.{}", extension)),
+        index_page(schema, title).render(format),
+    )?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn docs_at_version() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./docs")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE teams (id INT PRIMARY KEY);
+            CREATE TABLE users (id INT PRIMARY KEY, team_id INT REFERENCES teams (id));
+            COMMENT ON TABLE users IS 'people | bots';
+            COMMENT ON COLUMN users.team_id IS 'owning <team>';",
+        )
+        .unwrap();
+
+        let schema = m.schema_at(version).unwrap();
+        let dir = m.dir.join(super::DOCS_DIR);
+        let count = super::write(&schema, "test", &dir, super::Format::Markdown).unwrap();
+        super::write(&schema, "test", &dir, super::Format::Html).unwrap();
+        let markdown = std::fs::read_to_string(dir.join("users.md")).unwrap();
+        let html = std::fs::read_to_string(dir.join("users.html")).unwrap();
+        let index = std::fs::read_to_string(dir.join("index.md")).unwrap();
+
+        let _ = std::fs::remove_dir_all("./docs");
+
+        assert_eq!(count, 2);
+        assert!(markdown.starts_with("# users\n\npeople | bots\n"));
+        assert!(markdown.contains("| team_id | integer | yes |  | owning <team> |"));
+        assert!(markdown.contains("## Foreign keys"));
+        assert!(html.contains("<td>owning &lt;team&gt;</td>"));
+        assert!(index.contains("| [teams](teams.md) |  |"));
+    }
+}
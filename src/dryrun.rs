@@ -0,0 +1,133 @@
+//! Dry runs. Instead of executing them, the files of the steps are loaded the way they would be
+//! run: the sql is read and split into statements and its directives are resolved, so broken sql
+//! is caught before it's deployed. Scripts are only checked to exist.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::direction::Step;
+use crate::output::info;
+use crate::Migrator;
+
+/// A migration file loaded by a dry run.
+#[derive(Serialize, Debug)]
+pub(crate) struct Checked {
+    pub(crate) file: String,
+    /// Statements that would run, None for scripts
+    pub(crate) statements: Option<usize>,
+    pub(crate) error: Option<String>,
+}
+
+impl Migrator {
+    /// The number of statements `step` would run.
+    fn load(&self, step: &Step) -> Result<Option<usize>> {
+        if let Some(path) = self.script_path(step.version, step.direction) {
+            std::fs::metadata(path)?;
+            return Ok(None);
+        }
+        if let Some(path) = self.streamed(step.version, step.direction) {
+            return Ok(Some(self.count_streamed(&path)?));
+        }
+        let sql = self.sql(step.version, step.direction)?;
+        let errors = crate::assertions::check(&sql);
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!("{}", errors.join(", ")));
+        }
+        crate::refresh::directives(&sql)?;
+        crate::post::directives(&sql)?;
+        crate::requires::requirements(&sql)?;
+        crate::conditions::Blocks::parse(&sql)?;
+        if let Some(args) = crate::foreign_key::directive(&sql) {
+            crate::foreign_key::Validate::parse(&args)?;
+            return Ok(Some(1));
+        }
+        if let Some(args) = crate::batch_update::directive(&sql) {
+            crate::batch_update::rewrite(&sql, &args)?;
+            return Ok(Some(1));
+        }
+        Ok(Some(crate::parse_statements(&sql)?.len()))
+    }
+
+    /// Loads the files of `steps` without running them.
+    pub(crate) fn dry_run(&self, steps: &[Step]) -> Vec<Checked> {
+        steps
+            .iter()
+            .map(|s| {
+                let file = self
+                    .migration_path(s.version, s.direction)
+                    .file_name()
+                    .map(|v| v.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let (statements, error) = match self.load(s) {
+                    Ok(v) => (v, None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                Checked {
+                    file,
+                    statements,
+                    error,
+                }
+            })
+            .collect()
+    }
+
+    /// Dry runs `steps`, reporting each file and failing if any didn't load.
+    pub(crate) fn check_steps(&self, steps: &[Step]) -> Result<()> {
+        let checked = self.dry_run(steps);
+        for c in checked.iter() {
+            match (&c.error, c.statements) {
+                (Some(e), _) => info!("{}: {}", c.file, e),
+                (None, Some(n)) => info!("{}: {} statements", c.file, n),
+                (None, None) => info!("{}: script, not checked", c.file),
+            }
+        }
+        let failed = checked.iter().filter(|c| c.error.is_some()).count();
+        if failed > 0 {
+            return Err(anyhow::anyhow!(
+                "{} of {} migrations failed to load",
+                failed,
+                checked.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn dry_run() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./dry_run")).unwrap();
+        let (good, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &good,
+            "CREATE TABLE __dry_run__ (id INT);\nDROP TABLE __dry_run__;",
+        )
+        .unwrap();
+        let (bad, _) = m.new_migration().unwrap();
+        std::fs::write(&bad, "CREATE TABLE (;").unwrap();
+        let last_version = m.last_version;
+
+        let steps = m.steps(m.last_version, i64::MAX);
+        let checked = m.dry_run(&steps);
+        let migrated = m.migrate_up(true);
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('__dry_run__') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+
+        let _ = std::fs::remove_dir_all("./dry_run");
+
+        assert_eq!(checked.len(), 2);
+        assert_eq!(checked[0].statements, Some(2));
+        assert!(checked[0].error.is_none());
+        assert!(checked[1].error.is_some());
+        assert!(migrated.is_err());
+        assert_eq!(m.last_version, last_version);
+        assert!(!exists);
+    }
+}
@@ -0,0 +1,93 @@
+//! Opening files in the user's editor, e.g. new migrations with `new --edit`.
+
+use anyhow::Result;
+
+/// The editor command: `VISUAL`, else `EDITOR`.
+fn command() -> Result<String> {
+    for key in ["VISUAL", "EDITOR"] {
+        if let Ok(v) = std::env::var(key) {
+            if !v.trim().is_empty() {
+                return Ok(v);
+            }
+        }
+    }
+    Err(anyhow::anyhow!("set VISUAL or EDITOR to edit files"))
+}
+
+/// The words of `command`, split at whitespace outside of single or double quotes.
+fn words(command: &str) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    let (mut word, mut quote, mut started) = (String::new(), None, false);
+    for c in command.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => (quote, started) = (Some(c), true),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if started {
+                    result.push(std::mem::take(&mut word));
+                }
+                started = false;
+            }
+            (c, _) => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        result.push(word);
+    }
+    result
+}
+
+/// Opens the files with `editor`, all at once so editors like vim show them as buffers. The
+/// editor can have arguments, e.g. `code --wait`, and is run directly rather than by a shell, so
+/// it works on Windows as well.
+fn run(editor: &str, files: &[&std::path::Path]) -> Result<()> {
+    let words = words(editor);
+    let (program, args) = match words.split_first() {
+        Some(v) => v,
+        None => return Err(anyhow::anyhow!("the editor command is empty")),
+    };
+    let status = std::process::Command::new(program)
+        .args(args)
+        .args(files)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("editor {} failed: {}", editor, status));
+    }
+    Ok(())
+}
+
+/// Opens the files in the user's editor and waits for it to exit.
+pub(crate) fn open(files: &[&std::path::Path]) -> Result<()> {
+    run(&command()?, files)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn run() {
+        let _ = std::fs::create_dir_all("./editor");
+        let up = std::path::Path::new("./editor/1_up.sql");
+        let down = std::path::Path::new("./editor/1_down.sql");
+
+        let result = super::run("touch -c -m", &[up, down]);
+        let created = up.exists() || down.exists();
+        super::run("touch", &[up, down]).unwrap();
+        let edited = up.exists() && down.exists();
+        let failed = super::run("false", &[up]).is_err();
+        let words = super::words(r#"'C:\Program Files\Editor\editor.exe' -w "" --x"#);
+
+        let _ = std::fs::remove_dir_all("./editor");
+
+        result.unwrap();
+        assert!(!created);
+        assert!(edited);
+        assert!(failed);
+        assert_eq!(
+            words,
+            vec![r"C:\Program Files\Editor\editor.exe", "-w", "", "--x"]
+        );
+    }
+}
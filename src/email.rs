@@ -0,0 +1,226 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+/// SMTP settings for the summary email sent when a run that migrated anything finishes,
+/// configured in the `[email]` table of the config.
+#[derive(Deserialize, Default, Clone)]
+#[cfg_attr(not(feature = "email"), allow(dead_code))]
+pub(crate) struct Email {
+    #[serde(default)]
+    pub(crate) smtp_host: String,
+    /// Defaults to the port of the `tls` mode
+    #[serde(default)]
+    pub(crate) smtp_port: u16,
+    /// `starttls` (default), `tls` or `none`
+    #[serde(default)]
+    pub(crate) tls: String,
+    #[serde(default)]
+    pub(crate) username: String,
+    #[serde(default)]
+    pub(crate) password: String,
+    #[serde(default)]
+    pub(crate) from: String,
+    /// Recipients. No email is sent when empty.
+    #[serde(default)]
+    pub(crate) to: Vec<String>,
+}
+
+/// A migration run by this process.
+#[derive(Serialize)]
+pub(crate) struct Run {
+    pub(crate) version: i64,
+    pub(crate) direction: Direction,
+    pub(crate) duration_ms: u128,
+    pub(crate) error: Option<String>,
+    /// Timings of the statements of sql migrations
+    pub(crate) statements: Vec<StatementRun>,
+    /// Post steps run after the migration committed
+    pub(crate) post: Vec<crate::post::PostRun>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StatementRun {
+    pub(crate) statement: String,
+    pub(crate) duration_ms: u128,
+}
+
+impl StatementRun {
+    /// The statement on a single line, shortened for progress output.
+    pub(crate) fn short(&self) -> String {
+        let line = self
+            .statement
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if line.chars().count() > 80 {
+            format!("{}...", line.chars().take(77).collect::<String>())
+        } else {
+            line
+        }
+    }
+}
+
+/// Subject and body of the summary email.
+pub(crate) fn summary(
+    app: &str,
+    dbname: &str,
+    runs: &[Run],
+    error: Option<&str>,
+) -> (String, String) {
+    let failed = error.is_some() || runs.iter().any(|v| v.error.is_some());
+    let subject = format!(
+        "[architect] {} on {}: {} migrations {}",
+        app,
+        dbname,
+        runs.iter().filter(|v| v.error.is_none()).count(),
+        if failed { "run, FAILED" } else { "run" }
+    );
+    let mut body = format!("app: {}\ndatabase: {}\n\n", app, dbname);
+    let mut total = 0;
+    for r in runs.iter() {
+        total += r.duration_ms;
+        body.push_str(&format!(
+            "{} {:<4} {:>8}ms {}\n",
+            r.version,
+            r.direction,
+            r.duration_ms,
+            match &r.error {
+                Some(e) => format!("FAILED: {}", e),
+                None => "ok".to_owned(),
+            }
+        ));
+        for p in r.post.iter() {
+            total += p.duration_ms;
+            body.push_str(&format!(
+                "  post {:>8}ms {} {}\n",
+                p.duration_ms,
+                p.statement,
+                match &p.error {
+                    Some(e) => format!("FAILED: {}", e),
+                    None => "ok".to_owned(),
+                }
+            ));
+        }
+    }
+    body.push_str(&format!("\ntotal: {}ms\n", total));
+    if let Some(e) = error {
+        body.push_str(&format!("\nrun failed: {}\n", e));
+    }
+    (subject, body)
+}
+
+#[cfg(not(feature = "email"))]
+fn send(_email: &Email, _subject: &str, _body: &str) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "email is configured but architect was built without the email feature"
+    ))
+}
+
+#[cfg(feature = "email")]
+fn send(email: &Email, subject: &str, body: &str) -> Result<()> {
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{Message, SmtpTransport, Transport};
+
+    let mut message = Message::builder()
+        .from(email.from.parse()?)
+        .subject(subject);
+    for to in email.to.iter() {
+        message = message.to(to.parse()?);
+    }
+    let message = message.body(body.to_owned())?;
+
+    let mut transport = match email.tls.as_str() {
+        "" | "starttls" => SmtpTransport::starttls_relay(&email.smtp_host)?,
+        "tls" => SmtpTransport::relay(&email.smtp_host)?,
+        "none" => SmtpTransport::builder_dangerous(&email.smtp_host),
+        v => return Err(anyhow::anyhow!("unknown email tls mode \"{}\"", v)),
+    };
+    if email.smtp_port != 0 {
+        transport = transport.port(email.smtp_port);
+    }
+    if !email.username.is_empty() {
+        transport = transport.credentials(Credentials::new(
+            email.username.clone(),
+            email.password.clone(),
+        ));
+    }
+    transport.build().send(&message)?;
+    Ok(())
+}
+
+impl Migrator {
+    /// Emails a summary of the migrations run by this process if email is configured and
+    /// anything was migrated. Failing to send is only reported.
+    pub(crate) fn send_summary(&self, result: &Result<()>) {
+        if self.config.email.to.is_empty() || self.runs.is_empty() {
+            return;
+        }
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let (subject, body) = summary(
+            &self.config.app,
+            &self.config.dbname,
+            &self.runs,
+            error.as_deref(),
+        );
+        if let Err(e) = send(&self.config.email, &subject, &body) {
+            eprintln!("failed to send summary email: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Run;
+    use crate::direction::Direction;
+
+    #[test]
+    fn summary() {
+        let runs = vec![
+            Run {
+                version: 1,
+                direction: Direction::Up,
+                duration_ms: 20,
+                error: None,
+                statements: Vec::new(),
+                post: vec![crate::post::PostRun {
+                    statement: "ANALYZE a".to_owned(),
+                    duration_ms: 3,
+                    error: None,
+                }],
+            },
+            Run {
+                version: 2,
+                direction: Direction::Up,
+                duration_ms: 5,
+                error: Some("relation \"a\" does not exist".to_owned()),
+                statements: Vec::new(),
+                post: Vec::new(),
+            },
+        ];
+        let (subject, body) = super::summary("app", "db", &runs, Some("error running 2_up.sql"));
+
+        assert_eq!(subject, "[architect] app on db: 1 migrations run, FAILED");
+        assert!(body.contains("1 up         20ms ok\n"));
+        assert!(body.contains("2 up          5ms FAILED: relation \"a\" does not exist\n"));
+        assert!(body.contains("  post        3ms ANALYZE a ok\n"));
+        assert!(body.contains("total: 28ms"));
+        assert!(body.contains("run failed: error running 2_up.sql"));
+    }
+
+    #[test]
+    fn short_statement() {
+        let statement = |v: &str| super::StatementRun {
+            statement: v.to_owned(),
+            duration_ms: 0,
+        };
+
+        assert_eq!(
+            statement("ALTER TABLE a\n    ADD COLUMN b INT").short(),
+            "ALTER TABLE a ADD COLUMN b INT"
+        );
+        assert_eq!(statement(&"x".repeat(100)).short().len(), 80);
+    }
+}
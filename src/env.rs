@@ -0,0 +1,202 @@
+//! Environment for the config: `.env` files and `${VAR}` interpolation of config values.
+
+use anyhow::Result;
+
+/// Parses a dotenv file: `KEY=value` lines, optionally prefixed with `export`. Values can be
+/// single quoted, taken literally, or double quoted, where `\n`, `\"` and `\\` are escapes.
+/// Unquoted values end at ` #`.
+fn parse(content: &str) -> Result<Vec<(String, String)>> {
+    let mut result = Vec::<(String, String)>::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => (k.trim(), v.trim()),
+            _ => return Err(anyhow::anyhow!("line {}: expected KEY=value", i + 1)),
+        };
+        let value = if let Some(v) = value.strip_prefix('\'') {
+            match v.strip_suffix('\'') {
+                Some(v) => v.to_owned(),
+                None => return Err(anyhow::anyhow!("line {}: unterminated quote", i + 1)),
+            }
+        } else if let Some(v) = value.strip_prefix('"') {
+            match v.strip_suffix('"') {
+                Some(v) => unescape(v),
+                None => return Err(anyhow::anyhow!("line {}: unterminated quote", i + 1)),
+            }
+        } else {
+            match value.split_once(" #") {
+                Some((v, _)) => v.trim_end().to_owned(),
+                None => value.to_owned(),
+            }
+        };
+        result.push((key.to_owned(), value));
+    }
+    Ok(result)
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(v) => result.push(v),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Loads a dotenv file into the environment. Variables that are set already are kept, like
+/// other dotenv loaders do. A missing file is an error only if it's `required`.
+pub(crate) fn load_dotenv(path: &std::path::Path, required: bool) -> Result<()> {
+    if !path.is_file() {
+        if required {
+            return Err(anyhow::anyhow!("env file {:?} does not exist", path));
+        }
+        return Ok(());
+    }
+    let vars = match parse(&std::fs::read_to_string(path)?) {
+        Ok(v) => v,
+        Err(e) => return Err(anyhow::anyhow!("invalid env file {:?}: {}", path, e)),
+    };
+    for (key, value) in vars {
+        if std::env::var_os(&key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` with the value of the environment variable `VAR`. `$${` is a literal `${`.
+pub(crate) fn interpolate(value: &str) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            result.push_str(&rest[..i - 1]);
+            result.push_str("${");
+            rest = &rest[i + 2..];
+            continue;
+        }
+        result.push_str(&rest[..i]);
+        let end = match rest[i..].find('}') {
+            Some(v) => i + v,
+            None => return Err(anyhow::anyhow!("unterminated ${{ in \"{}\"", value)),
+        };
+        let name = &rest[i + 2..end];
+        match std::env::var(name) {
+            Ok(v) => result.push_str(&v),
+            Err(_) => {
+                return Err(anyhow::anyhow!(
+                    "the config references ${{{}}}, which isn't set",
+                    name
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Interpolates the strings of a config. `[hooks]` is left to the shell running them, which
+/// expands variables like `${ARCHITECT_VERSION}` itself.
+pub(crate) fn interpolate_config(config: &mut toml::Value) -> Result<()> {
+    if let Some(table) = config.as_table_mut() {
+        for (key, value) in table.iter_mut() {
+            if key != "hooks" {
+                interpolate_toml(value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deserializes a port given as a number or as a string, so it can be interpolated from `${VAR}`.
+/// An empty string is the default port.
+pub(crate) fn port<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<u16, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        String(String),
+    }
+    match serde::Deserialize::deserialize(d)? {
+        Port::Number(v) => Ok(v),
+        Port::String(v) if v.trim().is_empty() => Ok(0),
+        Port::String(v) => v
+            .trim()
+            .parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid port \"{}\"", v))),
+    }
+}
+
+fn interpolate_toml(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(v) => *v = interpolate(v)?,
+        toml::Value::Array(v) => {
+            for item in v.iter_mut() {
+                interpolate_toml(item)?;
+            }
+        }
+        toml::Value::Table(v) => {
+            for (_, item) in v.iter_mut() {
+                interpolate_toml(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse() {
+        let vars = super::parse(
+            "# local credentials
+            PGPASSWORD=secret # comment
+            export PGUSER = 'admin # not a comment'
+            MOTD=\"two\\nlines\"
+
+            EMPTY=",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vars,
+            vec![
+                ("PGPASSWORD".to_owned(), "secret".to_owned()),
+                ("PGUSER".to_owned(), "admin # not a comment".to_owned()),
+                ("MOTD".to_owned(), "two\nlines".to_owned()),
+                ("EMPTY".to_owned(), "".to_owned()),
+            ]
+        );
+        assert!(super::parse("NO_VALUE").is_err());
+    }
+
+    #[test]
+    fn interpolate() {
+        std::env::set_var("ARCHITECT_TEST_INTERPOLATE", "db.internal");
+
+        assert_eq!(
+            super::interpolate("postgres://${ARCHITECT_TEST_INTERPOLATE}:5432").unwrap(),
+            "postgres://db.internal:5432"
+        );
+        assert_eq!(
+            super::interpolate("pa$$word $${VAR}").unwrap(),
+            "pa$$word ${VAR}"
+        );
+        assert!(super::interpolate("${ARCHITECT_TEST_UNSET}").is_err());
+        assert!(super::interpolate("${ARCHITECT_TEST_INTERPOLATE").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Entity-relationship diagrams of a schema as Mermaid or Graphviz dot, with the columns of every
+//! table and an edge per foreign key.
+
+use anyhow::Result;
+
+use crate::docs::html_escape;
+use crate::schema::{Schema, Table};
+
+/// A foreign key read from its constraint definition.
+struct ForeignKey {
+    columns: String,
+    table: String,
+}
+
+/// Parses `FOREIGN KEY (a, b) REFERENCES t(x, y) ...`.
+fn foreign_key(definition: &str) -> Option<ForeignKey> {
+    let reg = regex::Regex::new(r"^FOREIGN KEY \(([^)]*)\) REFERENCES ([^\s(]+)").ok()?;
+    let caps = reg.captures(definition)?;
+    Some(ForeignKey {
+        columns: caps[1].to_owned(),
+        table: caps[2].trim_matches('"').to_owned(),
+    })
+}
+
+/// Columns of the primary key and of foreign keys.
+fn key_columns(t: &Table) -> (Vec<String>, Vec<String>) {
+    let reg = regex::Regex::new(r"^PRIMARY KEY \(([^)]*)\)").unwrap();
+    let mut primary = Vec::<String>::new();
+    let mut foreign = Vec::<String>::new();
+    for c in t.constraints.iter() {
+        let columns = match (c.kind.as_str(), reg.captures(&c.definition)) {
+            ("p", Some(caps)) => caps[1].to_owned(),
+            ("f", _) => match foreign_key(&c.definition) {
+                Some(v) => v.columns,
+                None => continue,
+            },
+            _ => continue,
+        };
+        let columns = columns
+            .split(',')
+            .map(|v| v.trim().trim_matches('"').to_owned());
+        if c.kind == "p" {
+            primary.extend(columns);
+        } else {
+            foreign.extend(columns);
+        }
+    }
+    (primary, foreign)
+}
+
+fn foreign_keys(t: &Table) -> Vec<ForeignKey> {
+    t.constraints
+        .iter()
+        .filter(|c| c.kind == "f")
+        .filter_map(|c| foreign_key(&c.definition))
+        .collect()
+}
+
+/// Mermaid only allows a restricted set of characters in types, e.g. `character_varying(20)`.
+fn mermaid_type(data_type: &str) -> String {
+    data_type
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_()[]".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn mermaid(schema: &Schema) -> String {
+    let mut lines = vec!["erDiagram".to_owned()];
+    for t in schema.app_tables() {
+        let (primary, foreign) = key_columns(t);
+        lines.push(format!("    {} {{", t.name));
+        for c in t.columns.iter() {
+            let mut keys = Vec::<&str>::new();
+            if primary.contains(&c.name) {
+                keys.push("PK");
+            }
+            if foreign.contains(&c.name) {
+                keys.push("FK");
+            }
+            let mut line = format!("        {} {}", mermaid_type(&c.data_type), c.name);
+            if !keys.is_empty() {
+                line.push_str(&format!(" {}", keys.join(",")));
+            }
+            lines.push(line);
+        }
+        lines.push("    }".to_owned());
+    }
+    for t in schema.app_tables() {
+        for fk in foreign_keys(t) {
+            lines.push(format!(
+                "    {} ||--o{{ {} : \"{}\"",
+                fk.table,
+                t.name,
+                fk.columns.replace('"', "")
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+pub(crate) fn dot(schema: &Schema) -> String {
+    let mut lines = vec![
+        "digraph erd {".to_owned(),
+        "    rankdir=LR;".to_owned(),
+        "    node [shape=plaintext];".to_owned(),
+    ];
+    for t in schema.app_tables() {
+        let (primary, _) = key_columns(t);
+        let mut rows = format!("<tr><td><b>{}</b></td></tr>", html_escape(&t.name));
+        for c in t.columns.iter() {
+            let name = if primary.contains(&c.name) {
+                format!("<u>{}</u>", html_escape(&c.name))
+            } else {
+                html_escape(&c.name)
+            };
+            rows.push_str(&format!(
+                "<tr><td align=\"left\">{} {}</td></tr>",
+                name,
+                html_escape(&c.data_type)
+            ));
+        }
+        lines.push(format!(
+            "    \"{}\" [label=<<table border=\"0\" cellborder=\"1\" cellspacing=\"0\">{}</table>>];",
+            t.name, rows
+        ));
+    }
+    for t in schema.app_tables() {
+        for fk in foreign_keys(t) {
+            lines.push(format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                t.name,
+                fk.table,
+                fk.columns.replace('"', "")
+            ));
+        }
+    }
+    lines.push("}".to_owned());
+    lines.join("\n")
+}
+
+/// The diagram in `format`, `mermaid` or `dot`.
+pub(crate) fn render(schema: &Schema, format: &str) -> Result<String> {
+    match format {
+        "mermaid" => Ok(mermaid(schema)),
+        "dot" => Ok(dot(schema)),
+        _ => Err(anyhow::anyhow!(
+            "unknown erd format \"{}\". Expected mermaid or dot",
+            format
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn erd_at_version() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./erd")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE teams (id INT PRIMARY KEY, name VARCHAR(20));
+            CREATE TABLE users (id INT PRIMARY KEY, team_id INT REFERENCES teams (id));",
+        )
+        .unwrap();
+
+        let schema = m.schema_at(version).unwrap();
+        let mermaid = super::render(&schema, "mermaid").unwrap();
+        let dot = super::render(&schema, "dot").unwrap();
+
+        let _ = std::fs::remove_dir_all("./erd");
+
+        assert!(mermaid.starts_with("erDiagram\n    teams {\n        integer id PK\n"));
+        assert!(mermaid.contains("        character_varying(20) name\n"));
+        assert!(mermaid.contains("        integer team_id FK\n"));
+        assert!(mermaid.ends_with("    teams ||--o{ users : \"team_id\""));
+        assert!(dot.contains("    \"users\" -> \"teams\" [label=\"team_id\"];"));
+        assert!(super::render(&schema, "svg").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! Errors callers react to. They travel as `anyhow::Error` like any other error and can be told
+//! apart with `downcast_ref::<ArchitectError>()`; the CLI exits with the code of the variant.
+
+use crate::direction::Direction;
+
+/// Exit code of failures that aren't an `ArchitectError`.
+pub(crate) const EXIT_FAILURE: i32 = 1;
+
+#[derive(Debug)]
+pub(crate) enum ArchitectError {
+    /// The config is missing, doesn't parse or lacks required settings
+    Config(String),
+    /// The database couldn't be connected to
+    Connection(String),
+    /// The latest version is marked dirty by a failed run
+    DirtyState { version: i64 },
+    /// A migration failed and was rolled back, `statement` being the one that failed if known
+    MigrationFailed {
+        version: i64,
+        direction: Direction,
+        statement: Option<String>,
+        source: anyhow::Error,
+    },
+    /// The migrations don't match the lock file
+    LockMismatch(String),
+    /// Pending statements violate the policy of the environment
+    PolicyViolation(String),
+    /// The database only accepts approved plans
+    ApprovalRequired(String),
+}
+
+impl ArchitectError {
+    /// Exit code of the CLI, 2 being taken by usage errors.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            ArchitectError::Config(_) => 3,
+            ArchitectError::Connection(_) => 4,
+            ArchitectError::DirtyState { .. } => 5,
+            ArchitectError::MigrationFailed { .. } => 6,
+            ArchitectError::LockMismatch(_) => 7,
+            ArchitectError::PolicyViolation(_) => 8,
+            ArchitectError::ApprovalRequired(_) => 9,
+        }
+    }
+}
+
+impl std::fmt::Display for ArchitectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchitectError::Config(v)
+            | ArchitectError::Connection(v)
+            | ArchitectError::LockMismatch(v)
+            | ArchitectError::PolicyViolation(v)
+            | ArchitectError::ApprovalRequired(v) => write!(f, "{}", v),
+            ArchitectError::DirtyState { version } => write!(
+                f,
+                "last version {} is dirty. migration had failed previously",
+                version
+            ),
+            ArchitectError::MigrationFailed {
+                version,
+                direction,
+                source,
+                ..
+            } => write!(
+                f,
+                "error running migration {}_{}.sql: {}",
+                version, direction, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArchitectError {}
+
+/// `error` of running `version` as a `MigrationFailed`, unless it's one already.
+pub(crate) fn migration_failed(
+    version: i64,
+    direction: Direction,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    match error.downcast_ref::<ArchitectError>() {
+        Some(ArchitectError::MigrationFailed { .. }) => error,
+        _ => ArchitectError::MigrationFailed {
+            version,
+            direction,
+            statement: None,
+            source: error,
+        }
+        .into(),
+    }
+}
+
+/// Exit code for `error`.
+pub(crate) fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<ArchitectError>() {
+        Some(e) => e.exit_code(),
+        None => EXIT_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArchitectError;
+    use crate::tests::test_config;
+
+    #[test]
+    fn exit_codes() {
+        std::fs::create_dir_all("./error").unwrap();
+        std::fs::write("./error/invalid.toml", "host = [").unwrap();
+        let config = crate::read_config_toml(&["./error/invalid.toml".into()]);
+
+        let mut m =
+            crate::Migrator::new(test_config().unwrap(), std::path::PathBuf::from("./error"))
+                .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "CREATE TABLE __error__ (id INT);\nSELECT id FROM __error_missing__;",
+        )
+        .unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let migrated = m.migrate_up(false);
+
+        let _ = std::fs::remove_dir_all("./error");
+
+        assert_eq!(super::exit_code(&config.err().unwrap()), 3);
+        let e = migrated.unwrap_err();
+        assert_eq!(super::exit_code(&e), 6);
+        match e.downcast_ref::<ArchitectError>() {
+            Some(ArchitectError::MigrationFailed {
+                version: v,
+                direction,
+                statement,
+                ..
+            }) => {
+                assert_eq!(*v, version);
+                assert_eq!(*direction, crate::direction::Direction::Up);
+                assert_eq!(
+                    statement.as_deref(),
+                    Some("SELECT id FROM __error_missing__")
+                );
+            }
+            _ => panic!("not a failed migration: {}", e),
+        }
+        assert_eq!(super::exit_code(&anyhow::anyhow!("other")), 1);
+    }
+}
@@ -0,0 +1,289 @@
+//! Data expectations, post-migration sanity checks without pgTAP. The `.toml` files in the app's
+//! `expect` directory list checks of a query and the rows it's expected to return, either as rows
+//! of values in column order or as JSON objects by column name. `verify-data` runs them in a read
+//! only transaction.
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Migrator;
+
+/// Directory of the expectation files in the app's migration directory.
+pub(crate) const EXPECT_DIR: &str = "expect";
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Check {
+    pub(crate) name: String,
+    pub(crate) query: String,
+    /// Expected rows as values in column order
+    pub(crate) rows: Option<Vec<Vec<Value>>>,
+    /// Expected rows as objects by column name
+    pub(crate) json: Option<Vec<Value>>,
+}
+
+#[derive(Deserialize)]
+struct File {
+    #[serde(default)]
+    check: Vec<Check>,
+}
+
+/// The result of a check, `error` describing how it failed.
+#[derive(serde::Serialize)]
+pub(crate) struct Outcome {
+    pub(crate) file: String,
+    pub(crate) name: String,
+    pub(crate) error: Option<String>,
+}
+
+/// The checks of the files in `dir`, ordered by file name.
+pub(crate) fn scan(dir: &std::path::Path) -> Result<Vec<(String, Check)>> {
+    let mut result = Vec::<(String, Check)>::new();
+    if !dir.exists() {
+        return Ok(result);
+    }
+    let mut paths = Vec::<std::path::PathBuf>::new();
+    for f in std::fs::read_dir(dir)? {
+        let path = f?.path();
+        if path.extension().is_some_and(|e| e == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    for path in paths {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let file: File = match toml::from_str(&std::fs::read_to_string(&path)?) {
+            Ok(v) => v,
+            Err(e) => return Err(anyhow::anyhow!("invalid {:?}: {}", path, e)),
+        };
+        for c in file.check {
+            if c.rows.is_some() == c.json.is_some() {
+                return Err(anyhow::anyhow!(
+                    "check \"{}\" in {:?} needs either rows or json",
+                    c.name,
+                    path
+                ));
+            }
+            result.push((name.clone(), c));
+        }
+    }
+    Ok(result)
+}
+
+/// How the rows `actual` returned by a query with `columns` differ from the expected ones, None
+/// if they don't.
+pub(crate) fn compare(check: &Check, columns: &[String], actual: &[Value]) -> Option<String> {
+    let (expected, actual) = match (&check.rows, &check.json) {
+        (Some(rows), _) => {
+            let actual: Vec<Value> = actual
+                .iter()
+                .map(|row| {
+                    Value::Array(
+                        columns
+                            .iter()
+                            .map(|c| row.get(c).cloned().unwrap_or(Value::Null))
+                            .collect(),
+                    )
+                })
+                .collect();
+            let expected: Vec<Value> = rows.iter().map(|v| Value::Array(v.clone())).collect();
+            (expected, actual)
+        }
+        (None, Some(json)) => (json.clone(), actual.to_vec()),
+        (None, None) => return Some("no expected rows".to_owned()),
+    };
+    if expected == actual {
+        None
+    } else {
+        Some(format!(
+            "expected {}, got {}",
+            Value::Array(expected),
+            Value::Array(actual)
+        ))
+    }
+}
+
+impl Migrator {
+    /// Runs the data expectations after the up migrations of this run and migrates those down
+    /// again if any check fails, provided all of them can be reversed. Fails if a check failed.
+    pub(crate) fn verify_or_roll_back(&mut self) -> Result<()> {
+        let outcomes = self.verify_data()?;
+        let failed: Vec<&Outcome> = outcomes.iter().filter(|o| o.error.is_some()).collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        for o in failed.iter() {
+            eprintln!(
+                "{}: {}: {}",
+                o.file,
+                o.name,
+                o.error.as_deref().unwrap_or_default()
+            );
+        }
+        let applied = self.applied_in_run();
+        if applied.is_empty() {
+            return Err(anyhow::anyhow!("{} checks failed", failed.len()));
+        }
+        let irreversible = crate::irreversible::irreversible(&self.dir, &applied)?;
+        if !irreversible.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} checks failed, not rolling back as {:?} can't be reversed",
+                failed.len(),
+                irreversible
+            ));
+        }
+        let count = self.migrate_down_n(applied.len(), false)?;
+        Err(anyhow::anyhow!(
+            "{} checks failed, rolled back {} migrations to version {}",
+            failed.len(),
+            count,
+            self.last_version
+        ))
+    }
+
+    /// Runs the checks of the app's expectation files, each in a read only transaction.
+    pub(crate) fn verify_data(&mut self) -> Result<Vec<Outcome>> {
+        let mut result = Vec::<Outcome>::new();
+        for (file, check) in scan(&self.dir.join(EXPECT_DIR))? {
+            let mut t = self.client.transaction()?;
+            t.batch_execute("SET TRANSACTION READ ONLY")?;
+            let ran = t.prepare(&check.query).and_then(|statement| {
+                let columns: Vec<String> = statement
+                    .columns()
+                    .iter()
+                    .map(|c| c.name().to_owned())
+                    .collect();
+                let json: String = t
+                    .query_one(
+                        format!(
+                            "SELECT coalesce(json_agg(t), '[]')::text FROM ({}) t",
+                            check.query.trim().trim_end_matches(';')
+                        )
+                        .as_str(),
+                        &[],
+                    )?
+                    .get(0);
+                Ok((columns, json))
+            });
+            let error = match ran {
+                Ok((columns, json)) => {
+                    let actual: Vec<Value> = serde_json::from_str(&json)?;
+                    compare(&check, &columns, &actual)
+                }
+                Err(e) => Some(e.to_string()),
+            };
+            t.rollback()?;
+            result.push(Outcome {
+                file,
+                name: check.name,
+                error,
+            });
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn print(outcomes: &[Outcome]) {
+    for o in outcomes {
+        match &o.error {
+            None => println!("ok      {}: {}", o.file, o.name),
+            Some(e) => println!("FAILED  {}: {}: {}", o.file, o.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn verify_data() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./expect")).unwrap();
+        std::fs::create_dir_all(m.dir.join(super::EXPECT_DIR)).unwrap();
+        std::fs::write(
+            m.dir.join(super::EXPECT_DIR).join("checks.toml"),
+            r#"
+            [[check]]
+            name = "rows"
+            query = "SELECT 1 AS a, 'x' AS b UNION ALL SELECT 2, 'y'"
+            rows = [[1, "x"], [2, "y"]]
+
+            [[check]]
+            name = "json"
+            query = "SELECT true AS admin, NULL::text AS note;"
+            json = [{ admin = true, note = "missing" }]
+
+            [[check]]
+            name = "read only"
+            query = "CREATE TABLE __expect__ (id INT)"
+            rows = []
+            "#,
+        )
+        .unwrap();
+
+        let outcomes = m.verify_data();
+
+        let _ = std::fs::remove_dir_all("./expect");
+
+        let outcomes = outcomes.unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes[0].error.is_none());
+        assert_eq!(
+            outcomes[1].error.as_deref(),
+            Some(
+                "expected [{\"admin\":true,\"note\":\"missing\"}], \
+                got [{\"admin\":true,\"note\":null}]"
+            )
+        );
+        assert!(outcomes[2].error.is_some());
+    }
+
+    #[test]
+    fn verify_or_roll_back() {
+        let config = test_config().unwrap();
+        let mut m =
+            crate::Migrator::new(config, std::path::PathBuf::from("./expect_rollback")).unwrap();
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __expect_rollback__")
+            .unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE __expect_rollback__ (id INT);").unwrap();
+        std::fs::write(&down, "DROP TABLE __expect_rollback__;").unwrap();
+        std::fs::create_dir_all(m.dir.join(super::EXPECT_DIR)).unwrap();
+        std::fs::write(
+            m.dir.join(super::EXPECT_DIR).join("checks.toml"),
+            "[[check]]
+name = \"has rows\"
+\
+            query = \"SELECT count(*) > 0 AS ok FROM __expect_rollback__\"
+rows = [[true]]
+",
+        )
+        .unwrap();
+
+        let migrated = m.migrate_up(false);
+        let rolled_back = m.verify_or_roll_back();
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('__expect_rollback__') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        let applied = m.applied_versions().unwrap();
+        let version = *m.versions_up.last().unwrap();
+
+        let _ = std::fs::remove_dir_all("./expect_rollback");
+
+        assert_eq!(migrated.unwrap(), 1);
+        assert!(rolled_back
+            .unwrap_err()
+            .to_string()
+            .contains("rolled back 1 migrations"));
+        assert!(!exists);
+        assert!(!applied.contains(&version));
+    }
+}
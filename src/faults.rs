@@ -0,0 +1,154 @@
+//! Fault injection for exercising how failures are handled, e.g. in CI. Built with the `faults`
+//! feature, `ARCHITECT_FAULT` lists the points migrations fail at, separated by commas:
+//! `V:statement:K` after statement K of version V (after batch K of a batch update),
+//! `V:record` before version V is recorded and `V:commit` once its transaction committed, as
+//! if the connection was lost before the client saw the commit go through.
+
+use anyhow::Result;
+
+pub(crate) const ENV: &str = "ARCHITECT_FAULT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Point {
+    /// After the given statement, counting from 1
+    Statement(usize),
+    Record,
+    Commit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Fault {
+    pub(crate) version: i64,
+    pub(crate) point: Point,
+}
+
+/// The faults of a comma separated `spec`.
+pub(crate) fn parse(spec: &str) -> Result<Vec<Fault>> {
+    let mut result = Vec::<Fault>::new();
+    for s in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let parts: Vec<&str> = s.split(':').collect();
+        let point = match parts[1..] {
+            ["statement", k] => match k.parse::<usize>() {
+                Ok(k) if k > 0 => Some(Point::Statement(k)),
+                _ => None,
+            },
+            ["record"] => Some(Point::Record),
+            ["commit"] => Some(Point::Commit),
+            _ => None,
+        };
+        match (parts[0].parse::<i64>(), point) {
+            (Ok(version), Some(point)) => result.push(Fault { version, point }),
+            _ => return Err(anyhow::anyhow!("invalid fault {:?} in {}", s, ENV)),
+        }
+    }
+    Ok(result)
+}
+
+/// The faults of `ARCHITECT_FAULT`, none unless built with the `faults` feature.
+pub(crate) fn from_env() -> Result<Vec<Fault>> {
+    if !cfg!(feature = "faults") {
+        return Ok(Vec::new());
+    }
+    match std::env::var(ENV) {
+        Ok(spec) => {
+            let faults = parse(&spec)?;
+            if !faults.is_empty() {
+                eprintln!("injecting faults: {}", spec);
+            }
+            Ok(faults)
+        }
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Fails if `faults` include `point` of `version`.
+pub(crate) fn inject(faults: &[Fault], version: i64, point: Point) -> Result<()> {
+    if !faults
+        .iter()
+        .any(|f| f.version == version && f.point == point)
+    {
+        return Ok(());
+    }
+    Err(match point {
+        Point::Statement(k) => {
+            anyhow::anyhow!("injected fault after statement {} of {}", k, version)
+        }
+        Point::Record => anyhow::anyhow!("injected fault before recording {}", version),
+        Point::Commit => anyhow::anyhow!("injected fault: connection lost committing {}", version),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, Point};
+    use crate::tests::test_config;
+
+    #[test]
+    fn inject_faults() {
+        assert_eq!(
+            super::parse("1:statement:2, 3:record,4:commit").unwrap(),
+            vec![
+                Fault {
+                    version: 1,
+                    point: Point::Statement(2)
+                },
+                Fault {
+                    version: 3,
+                    point: Point::Record
+                },
+                Fault {
+                    version: 4,
+                    point: Point::Commit
+                },
+            ]
+        );
+        assert!(super::parse("1:statement:0").is_err());
+        assert!(super::parse("x:record").is_err());
+
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./faults")).unwrap();
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __faults__")
+            .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "CREATE TABLE __faults__ (id INT);\nINSERT INTO __faults__ VALUES (1);",
+        )
+        .unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let mut outcomes = Vec::new();
+        for point in [Point::Statement(1), Point::Record, Point::Commit] {
+            m.faults = vec![Fault { version, point }];
+            let migrated = m.migrate_up(false);
+            let applied = m.applied_versions().unwrap().contains(&version);
+            let exists: bool = m
+                .client
+                .query_one("SELECT to_regclass('__faults__') IS NOT NULL", &[])
+                .unwrap()
+                .get(0);
+            outcomes.push((migrated.is_ok(), applied, exists));
+        }
+        m.client.batch_execute("DROP TABLE __faults__").unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+        let errors: Vec<Option<String>> = m.runs.iter().map(|r| r.error.clone()).collect();
+
+        let _ = std::fs::remove_dir_all("./faults");
+
+        // rolled back, rolled back, committed though reported as failed
+        assert_eq!(
+            outcomes,
+            vec![
+                (false, false, false),
+                (false, false, false),
+                (false, true, true)
+            ]
+        );
+        assert!(errors[0].as_deref().unwrap().contains("after statement 1"));
+    }
+}
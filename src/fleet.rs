@@ -0,0 +1,270 @@
+//! Status of a fleet of databases sharing the migrations of an app, e.g. its shards. Every
+//! database is checked with a single prepared query over its own connection, `--jobs` at a time,
+//! and the result is summarized by version, so hundreds of shards are checked in seconds. With
+//! `--watch` the check repeats, reusing the connections and prepared statements.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use postgres::{Client, Statement};
+use serde::Serialize;
+
+use crate::Config;
+
+/// The status of a database in a single round trip. `$1` are the versions of the migration
+/// directory.
+const QUERY: &str = "SELECT max(version), count(*) FILTER (WHERE version = ANY($1)),
+    coalesce(array_agg(version ORDER BY version) FILTER (WHERE version <> ALL($1)), '{}'),
+    coalesce(bool_or(dirty), false)
+    FROM schema_migrations";
+
+/// A database of the fleet, with its connection once connected.
+pub(crate) struct Target {
+    pub(crate) config_path: std::path::PathBuf,
+    config: Config,
+    client: Option<(Client, Statement)>,
+}
+
+impl Target {
+    /// Reads the connection config at `path`.
+    pub(crate) fn new(path: &std::path::Path) -> Result<Self> {
+        Ok(Target {
+            config_path: path.to_owned(),
+            config: crate::read_config_toml(&[path.to_owned()])?,
+            client: None,
+        })
+    }
+
+    /// Queries the status, connecting and preparing the query the first time.
+    fn check(&mut self, versions: &[i64]) -> Result<Status> {
+        if self.client.is_none() {
+            let mut client = self.config.connect()?;
+            let statement = client.prepare(QUERY)?;
+            self.client = Some((client, statement));
+        }
+        let result = match self.client.as_mut() {
+            Some((client, statement)) => client.query_one(&*statement, &[&versions]),
+            None => return Err(anyhow::anyhow!("not connected")),
+        };
+        let row = match result {
+            Ok(v) => v,
+            Err(e) => {
+                // connected again on the next check
+                self.client = None;
+                return Err(e.into());
+            }
+        };
+        let known: i64 = row.get(1);
+        Ok(Status {
+            version: row.get::<_, Option<i64>>(0).unwrap_or(0),
+            pending: versions.len().saturating_sub(known as usize),
+            unknown: row.get(2),
+            dirty: row.get(3),
+        })
+    }
+}
+
+/// The migration state of a database.
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Status {
+    /// Last applied version, 0 if none
+    pub(crate) version: i64,
+    /// Versions of the migration directory that aren't applied
+    pub(crate) pending: usize,
+    /// Applied versions that aren't in the migration directory
+    pub(crate) unknown: Vec<i64>,
+    pub(crate) dirty: bool,
+}
+
+/// The result of checking a database.
+#[derive(Serialize)]
+pub(crate) struct Checked {
+    pub(crate) config: std::path::PathBuf,
+    pub(crate) dbname: String,
+    pub(crate) status: Option<Status>,
+    /// Why the database couldn't be checked
+    pub(crate) error: Option<String>,
+}
+
+impl Checked {
+    fn up_to_date(&self) -> bool {
+        matches!(&self.status, Some(s) if s.pending == 0 && s.unknown.is_empty() && !s.dirty)
+    }
+}
+
+/// Checks `targets` against the `versions` of the migration directory, `jobs` at a time.
+pub(crate) fn check(targets: &mut [Target], versions: &[i64], jobs: usize) -> Vec<Checked> {
+    crate::apps::concurrently(targets, jobs, |t| {
+        let (status, error) = match t.check(versions) {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Checked {
+            config: t.config_path.clone(),
+            dbname: t.config.dbname.clone(),
+            status,
+            error,
+        }
+    })
+}
+
+/// The number of databases per last applied version.
+pub(crate) fn by_version(checked: &[Checked]) -> BTreeMap<i64, usize> {
+    let mut result = BTreeMap::<i64, usize>::new();
+    for s in checked.iter().filter_map(|c| c.status.as_ref()) {
+        *result.entry(s.version).or_default() += 1;
+    }
+    result
+}
+
+/// Prints the summary of the fleet, followed by the databases that aren't up to date, or all of
+/// them with `all`.
+pub(crate) fn print(checked: &[Checked], all: bool) {
+    let unreachable = checked.iter().filter(|c| c.status.is_none()).count();
+    let dirty = checked
+        .iter()
+        .filter(|c| matches!(&c.status, Some(s) if s.dirty))
+        .count();
+    let up_to_date = checked.iter().filter(|c| c.up_to_date()).count();
+    println!(
+        "{} databases: {} up to date, {} behind or ahead, {} dirty, {} unreachable",
+        checked.len(),
+        up_to_date,
+        checked.len() - up_to_date - unreachable,
+        dirty,
+        unreachable
+    );
+    println!();
+    println!("{:<15} {:>9}", "VERSION", "DATABASES");
+    for (version, count) in by_version(checked).iter().rev() {
+        println!("{:<15} {:>9}", version, count);
+    }
+    let listed: Vec<&Checked> = checked.iter().filter(|c| all || !c.up_to_date()).collect();
+    if listed.is_empty() {
+        return;
+    }
+    println!();
+    println!(
+        "{:<32} {:<20} {:<15} {:>7}  STATE",
+        "CONFIG", "DATABASE", "VERSION", "PENDING"
+    );
+    for c in listed {
+        let config = c.config.to_string_lossy();
+        match (&c.status, &c.error) {
+            (Some(s), _) => {
+                let mut state = Vec::<String>::new();
+                if s.dirty {
+                    state.push("dirty".to_owned());
+                }
+                if !s.unknown.is_empty() {
+                    state.push(format!("{} unknown versions applied", s.unknown.len()));
+                }
+                println!(
+                    "{:<32} {:<20} {:<15} {:>7}  {}",
+                    config,
+                    c.dbname,
+                    s.version,
+                    s.pending,
+                    state.join(", ")
+                );
+            }
+            (None, e) => println!(
+                "{:<32} {:<20} {:<15} {:>7}  {}",
+                config,
+                c.dbname,
+                "-",
+                "-",
+                e.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Checks the databases of `configs` against the migration directory `dir` and prints their
+/// status, every `watch` seconds if given. Fails if any isn't up to date, unless watching.
+pub(crate) fn run(
+    dir: &std::path::Path,
+    configs: &[std::path::PathBuf],
+    jobs: usize,
+    all: bool,
+    watch: Option<u64>,
+) -> Result<()> {
+    let mut targets = configs
+        .iter()
+        .map(|v| Target::new(v))
+        .collect::<Result<Vec<Target>>>()?;
+    loop {
+        // files may have changed since the last check
+        let (versions, _) = crate::index::versions(dir)?;
+        let checked = check(&mut targets, &versions, jobs);
+        if crate::output::quiet() {
+            crate::output::result("", serde_json::json!({ "databases": checked }));
+        } else {
+            print(&checked, all);
+        }
+        match watch {
+            Some(seconds) => {
+                if !crate::output::quiet() {
+                    println!();
+                }
+                std::thread::sleep(std::time::Duration::from_secs(seconds));
+            }
+            None => {
+                let failed = checked.iter().filter(|c| !c.up_to_date()).count();
+                if failed > 0 {
+                    return Err(anyhow::anyhow!(
+                        "{} of {} databases aren't up to date",
+                        failed,
+                        checked.len()
+                    ));
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fleet() {
+        let dir = std::path::Path::new("./fleet");
+        let mut m =
+            crate::Migrator::new(crate::tests::test_config().unwrap(), dir.to_owned()).unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "SELECT 1;").unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let config = crate::tests::test_config_path().unwrap();
+        let missing = dir.join("missing.toml");
+        std::fs::write(
+            &missing,
+            "app = \"test\"\nhost = \"127.0.0.1\"\nport = 1\ndbname = \"x\"\nuser = \"x\"",
+        )
+        .unwrap();
+        let mut targets = vec![
+            super::Target::new(&config).unwrap(),
+            super::Target::new(&missing).unwrap(),
+        ];
+
+        let pending = super::check(&mut targets, &[version], 2);
+        m.migrate_up(false).unwrap();
+        let (prepared, unreachable) = (targets[0].client.is_some(), targets[1].client.is_none());
+        let applied = super::check(&mut targets, &[version], 2);
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(dir);
+
+        assert_eq!(pending[0].status.as_ref().unwrap().pending, 1);
+        assert!(pending[1].error.is_some());
+        assert!(prepared && unreachable);
+        let status = applied[0].status.as_ref().unwrap();
+        assert_eq!(status.pending, 0);
+        assert!(!status.unknown.contains(&version));
+        assert_eq!(super::by_version(&applied).values().sum::<usize>(), 1);
+    }
+}
@@ -0,0 +1,192 @@
+//! Canonical formatting of sql migrations: every statement is printed back from its parsed AST on
+//! a line of its own. Comments between statements, like descriptions and directives, are kept
+//! where they are, as are single blank lines. Statements with comments inside them or that don't
+//! parse are kept as they are written, so formatting never loses anything. Projects requiring
+//! idempotent DDL get statements rewritten to their idempotent form, see `idempotent::rewrite`.
+
+use anyhow::Result;
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+
+use crate::output::info;
+
+/// A statement as written, along with whether comments are inside of it.
+struct Statement {
+    tokens: Vec<Token>,
+    commented: bool,
+}
+
+impl Statement {
+    fn format(&self, idempotent: bool) -> String {
+        let source: String = self.tokens.iter().map(|t| t.to_string()).collect();
+        let source = source.trim();
+        if !self.commented {
+            if let Ok(mut statements) = crate::parse_ast(source) {
+                if statements.len() == 1 {
+                    if idempotent {
+                        crate::idempotent::rewrite(&mut statements[0]);
+                    }
+                    return format!("{};", statements[0]);
+                }
+            }
+        }
+        format!("{};", source)
+    }
+}
+
+/// The canonical formatting of `sql`, with `idempotent` DDL in its idempotent form.
+pub(crate) fn format(sql: &str, idempotent: bool) -> Result<String> {
+    let dialect = sqlparser::dialect::PostgreSqlDialect {};
+    let tokens = match Tokenizer::new(&dialect, sql).tokenize() {
+        Ok(v) => v,
+        Err(e) => return Err(anyhow::anyhow!("{}", e)),
+    };
+    let mut lines = Vec::<String>::new();
+    let mut statement: Option<Statement> = None;
+    // newlines since the last line, to keep blank lines and trailing comments
+    let mut newlines = 0;
+    for token in tokens {
+        if let Some(s) = statement.as_mut() {
+            match token {
+                Token::SemiColon => {
+                    lines.push(s.format(idempotent));
+                    statement = None;
+                    newlines = 0;
+                }
+                Token::Whitespace(
+                    Whitespace::SingleLineComment { .. } | Whitespace::MultiLineComment(_),
+                ) => {
+                    s.commented = true;
+                    s.tokens.push(token);
+                }
+                _ => s.tokens.push(token),
+            }
+            continue;
+        }
+        match token {
+            Token::Whitespace(Whitespace::Newline) => newlines += 1,
+            Token::Whitespace(Whitespace::Space | Whitespace::Tab) | Token::SemiColon => {}
+            Token::Whitespace(Whitespace::SingleLineComment { comment, prefix }) => {
+                let comment = format!("{}{}", prefix, comment.trim_end());
+                match lines.last_mut() {
+                    // a comment trailing a statement stays on its line
+                    Some(last) if newlines == 0 => {
+                        last.push(' ');
+                        last.push_str(&comment);
+                    }
+                    _ => {
+                        if newlines > 1 && !lines.is_empty() {
+                            lines.push(String::new());
+                        }
+                        lines.push(comment);
+                    }
+                }
+                // the comment includes the newline ending it
+                newlines = 1;
+            }
+            Token::Whitespace(Whitespace::MultiLineComment(comment)) => {
+                if newlines > 1 && !lines.is_empty() {
+                    lines.push(String::new());
+                }
+                lines.push(format!("/*{}*/", comment));
+                newlines = 0;
+            }
+            _ => {
+                if newlines > 1 && !lines.is_empty() {
+                    lines.push(String::new());
+                }
+                statement = Some(Statement {
+                    tokens: vec![token],
+                    commented: false,
+                });
+            }
+        }
+    }
+    if let Some(s) = statement {
+        lines.push(s.format(idempotent));
+    }
+    if lines.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Formats the sql migrations in `dir`, returning the names of the files that weren't formatted.
+/// With `check` the files are left as they are. Verbatim files and files that can't be tokenized
+/// are skipped.
+pub(crate) fn run(dir: &std::path::Path, check: bool) -> Result<Vec<String>> {
+    let reg = regex::Regex::new(crate::MIGRATION_FILE)?;
+    let idempotent = match dir.parent() {
+        Some(v) => crate::project::Project::read(v)?.idempotent,
+        None => false,
+    };
+    let mut names = Vec::<String>::new();
+    for f in std::fs::read_dir(dir)? {
+        let name = f?.file_name().to_string_lossy().to_string();
+        if reg.is_match(&name) && name.ends_with(".sql") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    let mut result = Vec::<String>::new();
+    for name in names {
+        let path = dir.join(&name);
+        let sql = std::fs::read_to_string(&path)?;
+        if crate::directives::has(&sql, crate::directives::VERBATIM) {
+            continue;
+        }
+        let formatted = match format(&sql, idempotent) {
+            Ok(v) => v,
+            Err(e) => {
+                info!("skipping {}, it can't be tokenized: {}", name, e);
+                continue;
+            }
+        };
+        if formatted != sql {
+            if !check {
+                std::fs::write(&path, formatted)?;
+            }
+            result.push(name);
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn format() {
+        let sql = "-- architect:author Jane
+-- create users
+
+
+create table users (id int primary key,
+    name text);   -- the users
+insert into users values (1, 'a;b');;
+/* kept */
+UPDATE users SET name = 'b' -- inside
+WHERE id = 1;
+select 1";
+
+        let formatted = super::format(sql, false).unwrap();
+
+        assert_eq!(
+            formatted,
+            "-- architect:author Jane
+-- create users
+
+CREATE TABLE users (id INT PRIMARY KEY, name TEXT); -- the users
+INSERT INTO users VALUES (1, 'a;b');
+/* kept */
+UPDATE users SET name = 'b' -- inside
+WHERE id = 1;
+SELECT 1;
+"
+        );
+        assert_eq!(super::format(&formatted, false).unwrap(), formatted);
+        assert_eq!(super::format("\n\n", false).unwrap(), "");
+        assert_eq!(
+            super::format("drop table users; -- gone", true).unwrap(),
+            "DROP TABLE IF EXISTS users; -- gone\n"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Foreign keys added to big tables without locking them. Adding a foreign key checks every
+//! existing row while holding a lock blocking writes, so `add-foreign-key` writes it as two
+//! migrations, each in a deploy of its own:
+//!
+//! 1. add: add the constraint `NOT VALID`, which only checks new rows and takes a moment
+//! 2. validate: check the existing rows with `VALIDATE CONSTRAINT`, which doesn't block writes
+//!
+//! A migration marked `-- architect:validate-constraint table=T constraint=C` validates the
+//! constraint. The lock it needs is awaited for at most `lock_timeout_ms`, so it doesn't queue
+//! the app's queries behind it, and it's tried again `retries` times after `pause_ms`.
+
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::faults::Point;
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "validate-constraint";
+
+/// SQLSTATE of a lock not acquired within `lock_timeout`.
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+/// The arguments of the directive.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Validate {
+    pub(crate) table: String,
+    pub(crate) constraint: String,
+    pub(crate) lock_timeout_ms: u64,
+    pub(crate) retries: u32,
+    pub(crate) pause_ms: u64,
+}
+
+impl Validate {
+    /// Parses the `key=value` arguments of the directive.
+    pub(crate) fn parse(args: &str) -> Result<Validate> {
+        let mut validate = Validate {
+            table: String::new(),
+            constraint: String::new(),
+            lock_timeout_ms: 5000,
+            retries: 0,
+            pause_ms: 1000,
+        };
+        for arg in args.split_whitespace() {
+            let invalid = || anyhow::anyhow!("invalid {} argument \"{}\"", DIRECTIVE, arg);
+            match arg.split_once('=') {
+                Some(("table", v)) => validate.table = v.to_owned(),
+                Some(("constraint", v)) => validate.constraint = v.to_owned(),
+                Some(("lock_timeout_ms", v)) => {
+                    validate.lock_timeout_ms = v.parse().map_err(|_| invalid())?
+                }
+                Some(("retries", v)) => validate.retries = v.parse().map_err(|_| invalid())?,
+                Some(("pause_ms", v)) => validate.pause_ms = v.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+        if validate.table.is_empty() || validate.constraint.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} needs table and constraint, e.g. table=orders constraint=orders_user_id_fkey",
+                DIRECTIVE
+            ));
+        }
+        Ok(validate)
+    }
+
+    fn statement(&self) -> String {
+        format!(
+            "ALTER TABLE {} VALIDATE CONSTRAINT {}",
+            self.table, self.constraint
+        )
+    }
+}
+
+/// The `args` of the directive of `sql`, None if it isn't marked.
+pub(crate) fn directive(sql: &str) -> Option<String> {
+    crate::directives::parse(sql)
+        .into_iter()
+        .find(|v| v.name == DIRECTIVE)
+        .map(|v| v.args)
+}
+
+/// A foreign key from `table.column` to `references`, `table(column)`.
+pub(crate) struct ForeignKey {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    pub(crate) references: String,
+    /// Name of the constraint, `<table>_<column>_fkey` if empty
+    pub(crate) name: String,
+    /// `ON DELETE` action, e.g. `CASCADE`
+    pub(crate) on_delete: Option<String>,
+    pub(crate) lock_timeout_ms: u64,
+    pub(crate) retries: u32,
+    pub(crate) pause_ms: u64,
+}
+
+impl ForeignKey {
+    fn name(&self) -> String {
+        if self.name.is_empty() {
+            format!("{}_{}_fkey", self.table.replace('.', "_"), self.column)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// The named up and down sql of the migrations, in the order they have to be applied.
+    pub(crate) fn migrations(&self) -> Result<Vec<(&'static str, (String, String))>> {
+        let (ref_table, ref_column) = match self.references.split_once('(') {
+            Some((table, column)) if column.ends_with(')') => {
+                (table.trim(), column.trim_end_matches(')').trim())
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "invalid reference \"{}\", expected TABLE(COLUMN)",
+                    self.references
+                ))
+            }
+        };
+        let (table, column, name) = (&self.table, &self.column, self.name());
+        let on_delete = match &self.on_delete {
+            Some(v) => format!(" ON DELETE {}", v.to_uppercase()),
+            None => String::new(),
+        };
+        let add = (
+            format!(
+                "-- architect:{}\n\
+                -- add foreign key {name}, 1/2: add it without checking the existing rows\n\
+                ALTER TABLE {table} ADD CONSTRAINT {name} FOREIGN KEY ({column}) \
+                REFERENCES {ref_table} ({ref_column}){on_delete} NOT VALID;\n",
+                crate::directives::VERBATIM
+            ),
+            format!("ALTER TABLE {table} DROP CONSTRAINT {name};\n"),
+        );
+        let validate = (
+            format!(
+                "-- add foreign key {name}, 2/2: check the existing rows\n\
+                -- architect:{DIRECTIVE} table={table} constraint={name} lock_timeout_ms={} \
+                retries={} pause_ms={}\n",
+                self.lock_timeout_ms, self.retries, self.pause_ms
+            ),
+            format!(
+                "-- architect:{}\n-- nothing to undo, the add migration drops {name}\n",
+                crate::irreversible::DIRECTIVE
+            ),
+        );
+        Ok(vec![("add", add), ("validate", validate)])
+    }
+}
+
+impl Migrator {
+    /// Writes the migrations adding `fk`, see the module docs.
+    pub(crate) fn add_foreign_key(&mut self, fk: &ForeignKey, yes: bool) -> Result<Vec<i64>> {
+        let migrations = fk.migrations()?;
+        self.write_generated(&migrations, yes)
+    }
+
+    /// Applies a migration marked with the directive, validating the constraint and recording
+    /// the version in a transaction per attempt.
+    pub(crate) fn apply_validate_constraint(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        sql: &str,
+        args: &str,
+    ) -> Result<Vec<crate::email::StatementRun>> {
+        let validate = Validate::parse(args)?;
+        let statement = validate.statement();
+        let (faults, batch) = (self.faults.clone(), self.batch);
+        let start = std::time::Instant::now();
+        let mut attempt = 0;
+        loop {
+            let mut t = self.client.transaction()?;
+            t.batch_execute(&format!(
+                "SET LOCAL lock_timeout = {}",
+                validate.lock_timeout_ms
+            ))?;
+            let e = match t.batch_execute(&statement) {
+                Ok(_) => {
+                    crate::faults::inject(&faults, version, Point::Statement(1))?;
+                    crate::assertions::run(&mut t, sql)?;
+                    crate::faults::inject(&faults, version, Point::Record)?;
+                    t.batch_execute(&crate::record_query(version, direction, batch))?;
+                    let duration_ms = start.elapsed().as_millis();
+                    if direction == Direction::Up {
+                        t.execute(
+                            "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+                            &[&(duration_ms as i64), &version],
+                        )?;
+                    }
+                    let transaction = crate::grants::transaction_id(&mut t)?;
+                    t.commit()?;
+                    self.run_transactions.push(transaction);
+                    crate::faults::inject(&faults, version, Point::Commit)?;
+                    let run = crate::email::StatementRun {
+                        statement,
+                        duration_ms,
+                    };
+                    info!("{} {:>8}ms  {}", version, run.duration_ms, run.short());
+                    return Ok(vec![run]);
+                }
+                Err(e) => e,
+            };
+            // rolled back
+            drop(t);
+            let lock_timeout = e.code().map(|v| v.code()) == Some(LOCK_NOT_AVAILABLE);
+            if !lock_timeout || attempt >= validate.retries {
+                return Err(crate::error::ArchitectError::MigrationFailed {
+                    version,
+                    direction,
+                    statement: Some(statement),
+                    source: e.into(),
+                }
+                .into());
+            }
+            attempt += 1;
+            info!(
+                "{}: {} is locked, trying again in {}ms ({} of {})",
+                version, validate.table, validate.pause_ms, attempt, validate.retries
+            );
+            std::thread::sleep(std::time::Duration::from_millis(validate.pause_ms));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn add_foreign_key() {
+        let dir = std::path::PathBuf::from("./foreign_key");
+        let mut config = crate::tests::schema_config("__foreign_key__");
+        let mut holder = config.connect().unwrap();
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        m.client
+            .batch_execute(
+                "CREATE TABLE parent (id INT PRIMARY KEY);
+                CREATE TABLE child (id INT PRIMARY KEY, parent_id INT);
+                INSERT INTO parent VALUES (1), (2);
+                INSERT INTO child VALUES (1, 1), (2, 2);",
+            )
+            .unwrap();
+        let fk = super::ForeignKey {
+            table: "child".to_owned(),
+            column: "parent_id".to_owned(),
+            references: "parent(id)".to_owned(),
+            name: String::new(),
+            on_delete: Some("cascade".to_owned()),
+            lock_timeout_ms: 50,
+            retries: 1,
+            pause_ms: 10,
+        };
+        let versions = m.add_foreign_key(&fk, true).unwrap();
+        m.migrate_up_n(1, false).unwrap();
+        let validated = |m: &mut crate::Migrator| -> bool {
+            m.client
+                .query_one(
+                    "SELECT convalidated FROM pg_constraint WHERE conname = 'child_parent_id_fkey'",
+                    &[],
+                )
+                .unwrap()
+                .get(0)
+        };
+        let added = validated(&mut m);
+        // the app holds a lock for longer than the retries wait
+        let mut t = holder.transaction().unwrap();
+        t.batch_execute("LOCK TABLE child").unwrap();
+        let locked = m.migrate_up(false);
+        t.rollback().unwrap();
+        let locked_version = m.last_version;
+        let migrated = m.migrate_up(false);
+        let validated_after = validated(&mut m);
+        m.client
+            .batch_execute("DROP SCHEMA __foreign_key__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(versions.len(), 2);
+        assert!(!added);
+        assert!(locked.is_err());
+        assert_eq!(locked_version, versions[0]);
+        assert_eq!(migrated.unwrap(), 1);
+        assert!(validated_after);
+        assert!(super::Validate::parse("table=child").is_err());
+        assert!(super::Validate::parse("table=a constraint=b retries=x").is_err());
+        assert_eq!(
+            super::Validate::parse("table=a constraint=b retries=3")
+                .unwrap()
+                .retries,
+            3
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! Grant and ownership normalization. Objects created by the migrations of a run get the owner and
+//! the privileges of the roles configured under `[grants]` in `.architect.toml`, so every new table
+//! is usable by the app's roles without each migration repeating the grants.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use anyhow::Result;
+use postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+
+use crate::output::info;
+use crate::Migrator;
+
+/// The tables, views, sequences and functions outside the system schemas with their owner and
+/// access privileges, the defaults if none were granted.
+const OBJECTS: &str = "
+    SELECT CASE c.relkind WHEN 'S' THEN 'sequence' WHEN 'v' THEN 'view'
+        WHEN 'm' THEN 'materialized view' ELSE 'table' END AS kind,
+        c.oid::regclass::text AS name, c.xmin::text::bigint AS xmin, c.relowner AS owner,
+        coalesce(c.relacl, acldefault(CASE c.relkind WHEN 'S' THEN 's' ELSE 'r' END::\"char\",
+            c.relowner)) AS acl,
+        acldefault(CASE c.relkind WHEN 'S' THEN 's' ELSE 'r' END::\"char\", c.relowner) AS all_acl
+    FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S')
+        AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+        AND n.nspname NOT LIKE 'pg_toast%' AND n.nspname NOT LIKE 'pg_temp%'
+    UNION ALL
+    SELECT 'function', p.oid::regprocedure::text, p.xmin::text::bigint, p.proowner,
+        coalesce(p.proacl, acldefault('f', p.proowner)), acldefault('f', p.proowner)
+    FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace
+    WHERE p.prokind IN ('f', 'p')
+        AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+        AND NOT EXISTS (
+            SELECT FROM pg_depend d
+            WHERE d.objid = p.oid AND d.deptype = 'e'
+        )";
+
+/// The id of the current transaction as the `xmin` of the catalog rows it writes, to tell the
+/// objects a migration created from those of other sessions.
+pub(crate) fn transaction_id(client: &mut impl GenericClient) -> Result<i64> {
+    Ok(client
+        .query_one("SELECT txid_current() % 4294967296", &[])?
+        .get(0))
+}
+
+/// Owner and privileges by role of new objects.
+#[derive(Deserialize, Default, Debug)]
+pub(crate) struct Grants {
+    /// Role new objects are handed to
+    pub(crate) owner: Option<String>,
+    /// Privileges on tables, views and materialized views by role, e.g. `app_ro = "SELECT"`
+    #[serde(default)]
+    pub(crate) tables: BTreeMap<String, String>,
+    /// Privileges on sequences by role
+    #[serde(default)]
+    pub(crate) sequences: BTreeMap<String, String>,
+    /// Privileges on functions and procedures by role
+    #[serde(default)]
+    pub(crate) functions: BTreeMap<String, String>,
+}
+
+impl Grants {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.owner.is_none()
+            && self.tables.is_empty()
+            && self.sequences.is_empty()
+            && self.functions.is_empty()
+    }
+
+    /// The privileges by role on objects of `kind`.
+    fn privileges(&self, kind: &str) -> &BTreeMap<String, String> {
+        match kind {
+            "sequence" => &self.sequences,
+            "function" => &self.functions,
+            _ => &self.tables,
+        }
+    }
+
+    /// The statements normalizing `object`.
+    pub(crate) fn statements(&self, object: &Object) -> Vec<String> {
+        let (keyword, privileges, on) = match object.kind.as_str() {
+            "sequence" => ("SEQUENCE", &self.sequences, "SEQUENCE"),
+            "function" => ("ROUTINE", &self.functions, "ROUTINE"),
+            "view" => ("VIEW", &self.tables, "TABLE"),
+            "materialized view" => ("MATERIALIZED VIEW", &self.tables, "TABLE"),
+            _ => ("TABLE", &self.tables, "TABLE"),
+        };
+        let mut result = Vec::<String>::new();
+        if let Some(owner) = &self.owner {
+            result.push(format!(
+                "ALTER {} {} OWNER TO {}",
+                keyword, object.name, owner
+            ));
+        }
+        for (role, privileges) in privileges.iter() {
+            result.push(format!(
+                "GRANT {} ON {} {} TO {}",
+                privileges, on, object.name, role
+            ));
+        }
+        result
+    }
+}
+
+/// A table, view, sequence or function in the database.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Object {
+    pub(crate) kind: String,
+    pub(crate) name: String,
+}
+
+/// An object whose owner or privileges of a role differ from the `[grants]` spec.
+#[derive(Serialize, Debug)]
+pub(crate) struct Drift {
+    pub(crate) kind: String,
+    pub(crate) name: String,
+    /// Role whose privileges differ, None if the owner does
+    pub(crate) role: Option<String>,
+    pub(crate) expected: String,
+    pub(crate) actual: String,
+}
+
+/// The privileges of a spec like `SELECT, UPDATE`, `all` if it grants all privileges.
+fn parse_privileges(spec: &str, all: &BTreeSet<String>) -> BTreeSet<String> {
+    let spec = spec.trim().to_uppercase();
+    if spec == "ALL" || spec == "ALL PRIVILEGES" {
+        return all.clone();
+    }
+    spec.split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn join(privileges: &BTreeSet<String>) -> String {
+    if privileges.is_empty() {
+        "no privileges".to_owned()
+    } else {
+        privileges
+            .iter()
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+impl Migrator {
+    /// Compares the owner and the privileges of the roles named under `[grants]` on every object
+    /// with the spec, roles only having the privileges listed for the kind of the object.
+    pub(crate) fn verify_grants(&mut self) -> Result<Vec<Drift>> {
+        let grants = self.project()?.grants;
+        if grants.is_empty() {
+            return Err(anyhow::anyhow!(
+                "no grants configured in {:?}",
+                self.dir
+                    .parent()
+                    .unwrap_or(&self.dir)
+                    .join(crate::project::PROJECT_FILE)
+            ));
+        }
+        let roles: BTreeSet<&String> = grants
+            .tables
+            .keys()
+            .chain(grants.sequences.keys())
+            .chain(grants.functions.keys())
+            .collect();
+        let rows = self.client.query(
+            format!(
+                "SELECT o.kind, o.name, pg_get_userbyid(o.owner),
+                    ARRAY(SELECT CASE a.grantee WHEN 0 THEN 'PUBLIC'
+                            ELSE pg_get_userbyid(a.grantee) END || ' ' || a.privilege_type
+                        FROM aclexplode(o.acl) a),
+                    ARRAY(SELECT a.privilege_type FROM aclexplode(o.all_acl) a
+                        WHERE a.grantee = o.owner)
+                FROM ({}) o ORDER BY o.kind, o.name",
+                OBJECTS
+            )
+            .as_str(),
+            &[],
+        )?;
+        let mut result = Vec::<Drift>::new();
+        for row in rows.iter() {
+            let (kind, name, owner): (String, String, String) =
+                (row.get(0), row.get(1), row.get(2));
+            if crate::schema::OWN_TABLES.contains(&name.as_str()) {
+                continue;
+            }
+            if let Some(expected) = grants.owner.as_ref().filter(|v| **v != owner) {
+                result.push(Drift {
+                    kind: kind.clone(),
+                    name: name.clone(),
+                    role: None,
+                    expected: expected.clone(),
+                    actual: owner.clone(),
+                });
+            }
+            let acl: Vec<String> = row.get(3);
+            let all: BTreeSet<String> = row.get::<_, Vec<String>>(4).into_iter().collect();
+            for role in roles.iter() {
+                let expected = grants
+                    .privileges(&kind)
+                    .get(*role)
+                    .map(|v| parse_privileges(v, &all))
+                    .unwrap_or_default();
+                let prefix = format!("{} ", role);
+                let actual: BTreeSet<String> = acl
+                    .iter()
+                    .filter_map(|v| v.strip_prefix(&prefix).map(str::to_owned))
+                    .collect();
+                if expected != actual {
+                    result.push(Drift {
+                        kind: kind.clone(),
+                        name: name.clone(),
+                        role: Some(role.to_string()),
+                        expected: join(&expected),
+                        actual: join(&actual),
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// The tables, views, sequences and functions outside the system schemas, except architect's
+    /// own tables.
+    pub(crate) fn objects(&mut self) -> Result<HashSet<Object>> {
+        let rows = self.client.query(
+            format!("SELECT kind, name FROM ({}) o", OBJECTS).as_str(),
+            &[],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| Object {
+                kind: row.get(0),
+                name: row.get(1),
+            })
+            .filter(|o| !crate::schema::OWN_TABLES.contains(&o.name.as_str()))
+            .collect())
+    }
+
+    /// Remembers the objects existing before the first migration of the run, if the project
+    /// normalizes grants.
+    pub(crate) fn snapshot_objects(&mut self) -> Result<()> {
+        if self.objects_before.is_none() && !self.project()?.grants.is_empty() {
+            self.objects_before = Some(self.objects()?);
+        }
+        Ok(())
+    }
+
+    /// Applies the project's owner and grants to the objects created since the snapshot by the
+    /// migrations of the run, in one transaction. Objects other sessions created meanwhile are left
+    /// alone. Returns the statements run.
+    pub(crate) fn normalize_grants(&mut self) -> Result<Vec<String>> {
+        let before = match self.objects_before.take() {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+        let grants = self.project()?.grants;
+        let rows = self.client.query(
+            format!(
+                "SELECT kind, name FROM ({}) o WHERE xmin = ANY($1)",
+                OBJECTS
+            )
+            .as_str(),
+            &[&self.run_transactions],
+        )?;
+        let mut created: Vec<Object> = rows
+            .iter()
+            .map(|row| Object {
+                kind: row.get(0),
+                name: row.get(1),
+            })
+            .filter(|o| {
+                !before.contains(o) && !crate::schema::OWN_TABLES.contains(&o.name.as_str())
+            })
+            .collect();
+        created.sort_by(|a, b| (&a.kind, &a.name).cmp(&(&b.kind, &b.name)));
+        let statements: Vec<String> = created.iter().flat_map(|o| grants.statements(o)).collect();
+        let mut t = self.client.transaction()?;
+        for s in statements.iter() {
+            t.batch_execute(s)?;
+            info!("{}", s);
+        }
+        t.commit()?;
+        Ok(statements)
+    }
+}
+
+pub(crate) fn print(drift: &[Drift]) {
+    for d in drift {
+        match &d.role {
+            Some(role) => println!(
+                "{} {}: {} has {}, expected {}",
+                d.kind, d.name, role, d.actual, d.expected
+            ),
+            None => println!(
+                "{} {}: owned by {}, expected {}",
+                d.kind, d.name, d.actual, d.expected
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn normalize_grants() {
+        let mut config = crate::tests::schema_config("__grants__");
+        let mut m =
+            crate::Migrator::new(config.clone(), std::path::PathBuf::from("./grants")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP ROLE IF EXISTS __grants_ro__;
+                CREATE ROLE __grants_ro__;
+                CREATE TABLE old (id INT);",
+            )
+            .unwrap();
+        std::fs::write(
+            "./grants/.architect.toml",
+            "[grants.tables]\n__grants_ro__ = \"SELECT\"\n\
+            [grants.sequences]\n__grants_ro__ = \"USAGE\"\n",
+        )
+        .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE new (id SERIAL);").unwrap();
+        let migrated = m.migrate_up(false);
+        // created by another session during the run
+        config
+            .connect()
+            .unwrap()
+            .batch_execute("CREATE TABLE other (id INT)")
+            .unwrap();
+        let statements = m.normalize_grants();
+        let privileges: (bool, bool, bool, bool) = m
+            .client
+            .query_one(
+                "SELECT has_table_privilege('__grants_ro__', 'new', 'SELECT'),
+                    has_sequence_privilege('__grants_ro__', 'new_id_seq', 'USAGE'),
+                    has_table_privilege('__grants_ro__', 'old', 'SELECT'),
+                    has_table_privilege('__grants_ro__', 'other', 'SELECT')",
+                &[],
+            )
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+            .unwrap();
+        m.client
+            .batch_execute(
+                "DROP SCHEMA __grants__ CASCADE;
+                DROP ROLE __grants_ro__;",
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./grants");
+
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(
+            statements.unwrap(),
+            vec![
+                "GRANT USAGE ON SEQUENCE new_id_seq TO __grants_ro__",
+                "GRANT SELECT ON TABLE new TO __grants_ro__",
+            ]
+        );
+        assert_eq!(privileges, (true, true, false, false));
+    }
+
+    #[test]
+    fn verify_grants() {
+        let config = crate::tests::schema_config("__verify_grants__");
+        let mut m =
+            crate::Migrator::new(config, std::path::PathBuf::from("./verify_grants")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP ROLE IF EXISTS __verify_grants_ro__;
+                CREATE ROLE __verify_grants_ro__;
+                CREATE TABLE a (id INT);
+                CREATE TABLE b (id INT);
+                GRANT SELECT ON a TO __verify_grants_ro__;
+                GRANT SELECT, UPDATE ON b TO __verify_grants_ro__;",
+            )
+            .unwrap();
+        std::fs::write(
+            "./verify_grants/.architect.toml",
+            "[grants.tables]\n__verify_grants_ro__ = \"select\"\n",
+        )
+        .unwrap();
+
+        let drift = m.verify_grants();
+
+        m.client
+            .batch_execute(
+                "DROP SCHEMA __verify_grants__ CASCADE;
+                DROP ROLE __verify_grants_ro__;",
+            )
+            .unwrap();
+        let _ = std::fs::remove_dir_all("./verify_grants");
+
+        // objects of other schemas are checked too
+        let drift: Vec<(String, String)> = drift
+            .unwrap()
+            .into_iter()
+            .filter(|d| d.name == "a" || d.name == "b")
+            .map(|d| (d.name, d.actual))
+            .collect();
+        assert_eq!(drift, vec![("b".to_owned(), "SELECT, UPDATE".to_owned())]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Heartbeat of long migrations. A statement waiting for a lock or rewriting a big table looks
+//! the same as a hung connection from the outside, so with `heartbeat_seconds` set a second
+//! connection checks the backend running the migration in `pg_stat_activity` at that interval and
+//! reports what it's doing: still running, waiting on the lock of a table held by another session,
+//! or gone, in which case the connection is dead. The advisory lock of a run belongs to the
+//! backend running it, so it needs no renewal while the backend lives.
+
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+
+use anyhow::Result;
+
+use crate::output::info;
+use crate::Migrator;
+
+/// The state of the backend `$1` and the sessions blocking it.
+const QUERY: &str = "SELECT a.state, coalesce(a.wait_event_type, ''), coalesce(a.wait_event, ''),
+        extract(epoch FROM now() - a.query_start)::float8,
+        (SELECT string_agg(l.relation::regclass::text || ' (' || l.mode || ')', ', ')
+        FROM pg_locks l WHERE l.pid = a.pid AND NOT l.granted AND l.relation IS NOT NULL),
+        (SELECT string_agg(b.pid || ' (' || coalesce(b.application_name, '') || '): '
+            || left(b.query, 60), ', ')
+        FROM pg_stat_activity b WHERE b.pid = ANY(pg_blocking_pids(a.pid)))
+    FROM pg_stat_activity a WHERE a.pid = $1";
+
+/// A running heartbeat, stopped when dropped.
+pub(crate) struct Heartbeat {
+    stop: Option<Sender<()>>,
+    thread: Option<std::thread::JoinHandle<Vec<String>>>,
+}
+
+/// What the backend is doing, described by a row of `QUERY`, None if it's idle.
+fn describe(version: i64, row: &postgres::Row) -> Option<String> {
+    let state: Option<String> = row.get(0);
+    let (wait_type, wait_event): (String, String) = (row.get(1), row.get(2));
+    let seconds: Option<f64> = row.get(3);
+    let (relations, blockers): (Option<String>, Option<String>) = (row.get(4), row.get(5));
+    if state.as_deref() != Some("active") {
+        return None;
+    }
+    let running = format!(
+        "{} still running after {:.0}s",
+        version,
+        seconds.unwrap_or_default()
+    );
+    Some(match (wait_type.as_str(), relations, blockers) {
+        ("Lock", Some(relations), Some(blockers)) => format!(
+            "{}, waiting on the lock of {} held by {}",
+            running, relations, blockers
+        ),
+        ("Lock", _, blockers) => format!(
+            "{}, waiting on a {} lock held by {}",
+            running,
+            wait_event,
+            blockers.unwrap_or_else(|| "another session".to_owned())
+        ),
+        ("", _, _) => running,
+        _ => format!("{} ({}: {})", running, wait_type, wait_event),
+    })
+}
+
+impl Heartbeat {
+    /// Starts checking the backend `pid` running `version` every `interval` over a connection
+    /// of `config`.
+    pub(crate) fn start(
+        mut config: crate::Config,
+        pid: i32,
+        version: i64,
+        interval: std::time::Duration,
+    ) -> Result<Heartbeat> {
+        let mut client = config.connect()?;
+        let statement = client.prepare(QUERY)?;
+        let (stop, stopped) = channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let mut reports = Vec::<String>::new();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let report = match client.query_opt(&statement, &[&pid]) {
+                    Ok(Some(row)) => match describe(version, &row) {
+                        Some(v) => v,
+                        None => continue,
+                    },
+                    Ok(None) => format!(
+                        "{}: backend {} running the migration is gone, the connection is dead",
+                        version, pid
+                    ),
+                    Err(e) => format!("{}: heartbeat failed: {}", version, e),
+                };
+                info!("{}", report);
+                let done = !report.contains("still running");
+                reports.push(report);
+                if done {
+                    break;
+                }
+            }
+            reports
+        });
+        Ok(Heartbeat {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the heartbeat, returning what it reported.
+    pub(crate) fn stop(mut self) -> Vec<String> {
+        self.stop.take();
+        match self.thread.take().map(|v| v.join()) {
+            Some(Ok(v)) => v,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Migrator {
+    /// Starts the heartbeat of the migration `version` if `heartbeat_seconds` is set. A
+    /// heartbeat failing to start doesn't stop the migration.
+    pub(crate) fn start_heartbeat(&mut self, version: i64) -> Option<Heartbeat> {
+        if self.config.heartbeat_seconds == 0 {
+            return None;
+        }
+        if self.pooled() {
+            info!("{}: no heartbeat through a transaction pooler", version);
+            return None;
+        }
+        let started = self
+            .client
+            .query_one("SELECT pg_backend_pid()", &[])
+            .map_err(anyhow::Error::from)
+            .and_then(|row| {
+                Heartbeat::start(
+                    self.config.clone(),
+                    row.get(0),
+                    version,
+                    std::time::Duration::from_secs(self.config.heartbeat_seconds),
+                )
+            });
+        match started {
+            Ok(v) => Some(v),
+            Err(e) => {
+                info!("{}: starting the heartbeat failed: {}", version, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn heartbeat() {
+        let config = crate::tests::schema_config("__heartbeat__");
+        let mut holder = config.clone().connect().unwrap();
+        let mut client = config.clone().connect().unwrap();
+        client
+            .batch_execute("CREATE TABLE beating (id INT)")
+            .unwrap();
+        let pid: i32 = client
+            .query_one("SELECT pg_backend_pid()", &[])
+            .unwrap()
+            .get(0);
+        let (locked, unlock) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let mut t = holder.transaction().unwrap();
+            t.batch_execute("LOCK TABLE beating").unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(1500));
+            t.commit().unwrap();
+        });
+        unlock.recv().unwrap();
+        let heartbeat = super::Heartbeat::start(
+            config.clone(),
+            pid,
+            1,
+            std::time::Duration::from_millis(500),
+        )
+        .unwrap();
+        client
+            .batch_execute("SELECT count(*) FROM beating")
+            .unwrap();
+        let reports = heartbeat.stop();
+        thread.join().unwrap();
+        client
+            .batch_execute("DROP SCHEMA __heartbeat__ CASCADE")
+            .unwrap();
+
+        assert!(!reports.is_empty());
+        assert!(
+            reports[0].contains("waiting on the lock of beating (AccessShareLock) held by"),
+            "{}",
+            reports[0]
+        );
+    }
+}
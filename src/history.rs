@@ -0,0 +1,106 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+pub(crate) struct HistoryEntry {
+    pub(crate) version: i64,
+    pub(crate) applied_at: Option<DateTime<Utc>>,
+    pub(crate) applied_by: Option<String>,
+    pub(crate) duration_ms: Option<i64>,
+    pub(crate) description: String,
+    pub(crate) author: String,
+}
+
+impl Migrator {
+    /// Applied migrations in the order they were applied. Versions applied before `applied_at`
+    /// was tracked come first, in version order.
+    pub(crate) fn history(&mut self) -> Result<Vec<HistoryEntry>> {
+        let mut result = Vec::<HistoryEntry>::new();
+        for row in self.client.query(
+            "SELECT version, applied_at, applied_by, duration_ms FROM schema_migrations
+            ORDER BY applied_at ASC NULLS FIRST, version ASC",
+            &[],
+        )? {
+            let version: i64 = row.get(0);
+            let up = self.migration_path(version, Direction::Up);
+            let (description, author) = if up.exists() {
+                (crate::list::description(&up)?, crate::author::read(&up)?)
+            } else {
+                (String::new(), String::new())
+            };
+            result.push(HistoryEntry {
+                version,
+                applied_at: row.get(1),
+                applied_by: row.get(2),
+                duration_ms: row.get(3),
+                description,
+                author,
+            });
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn print(entries: &[HistoryEntry]) {
+    println!(
+        "{:<23} {:<15} {:<16} {:>10} {:<24}  DESCRIPTION",
+        "APPLIED AT", "VERSION", "APPLIED BY", "DURATION", "AUTHOR"
+    );
+    for e in entries {
+        let applied_at = match e.applied_at {
+            Some(v) => v.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            None => "-".to_owned(),
+        };
+        let duration = match e.duration_ms {
+            Some(v) => format!("{}ms", v),
+            None => "-".to_owned(),
+        };
+        println!(
+            "{:<23} {:<15} {:<16} {:>10} {:<24}  {}",
+            applied_at,
+            e.version,
+            e.applied_by.as_deref().unwrap_or("-"),
+            duration,
+            if e.author.is_empty() { "-" } else { &e.author },
+            e.description
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::direction::Direction;
+    use crate::tests::test_config;
+
+    #[test]
+    fn history_records_applies() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./history")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"-- history test\nCREATE TABLE IF NOT EXISTS __history__(id INT PRIMARY KEY);",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_down.sql", version)),
+            b"DROP TABLE IF EXISTS __history__;",
+        )
+        .unwrap();
+
+        m.run_migration(version, Direction::Up).unwrap();
+        let entries = m.history().unwrap();
+        m.run_migration(version, Direction::Down).unwrap();
+
+        let _ = std::fs::remove_dir_all("./history");
+
+        let entry = entries.iter().find(|v| v.version == version).unwrap();
+        assert!(entry.applied_at.is_some());
+        assert!(entry.applied_by.is_some());
+        assert!(entry.duration_ms.is_some());
+        assert_eq!(entry.description, "history test");
+    }
+}
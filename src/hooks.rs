@@ -0,0 +1,157 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+/// Shell commands run around migrations, configured in the `[hooks]` table of the config.
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Hooks {
+    /// Runs once before the first migration of a run. A failure aborts the run.
+    #[serde(default)]
+    pub(crate) before_all: String,
+    /// Runs after every successfully committed migration
+    #[serde(default)]
+    pub(crate) after_each: String,
+    /// Runs when a migration fails, with the error in `ARCHITECT_ERROR`
+    #[serde(default)]
+    pub(crate) on_failure: String,
+}
+
+/// A command line run by the system's shell, `sh -c` or `cmd /C` on Windows.
+pub(crate) fn shell(command: &str) -> std::process::Command {
+    let (program, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut result = std::process::Command::new(program);
+    result.arg(flag).arg(command);
+    result
+}
+
+impl Migrator {
+    /// Runs a hook command with the system's shell, describing the migration in environment
+    /// variables. Its output goes to stderr. Empty commands are skipped.
+    pub(crate) fn run_hook(
+        &self,
+        name: &str,
+        command: &str,
+        version: i64,
+        direction: Direction,
+        error: Option<&str>,
+    ) -> Result<()> {
+        if command.is_empty() {
+            return Ok(());
+        }
+        let status = shell(command)
+            .env("ARCHITECT_HOOK", name)
+            .env("ARCHITECT_APP", &self.config.app)
+            .env("ARCHITECT_DBNAME", &self.config.dbname)
+            .env("ARCHITECT_DIR", &self.dir)
+            .env("ARCHITECT_VERSION", version.to_string())
+            .env("ARCHITECT_DIRECTION", direction.as_str())
+            .env("ARCHITECT_ERROR", error.unwrap_or_default())
+            // stdout carries the output of --json and rpc
+            .stdout(std::io::stderr())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!("{} hook failed: {}", name, status));
+        }
+        Ok(())
+    }
+
+    /// Statements of `_hooks/<name>.sql` in the migration directory, empty if there's no such
+    /// file. They run in the same transaction as every migration.
+    pub(crate) fn sql_hook(&self, name: &str) -> Result<Vec<String>> {
+        let path = self.dir.join("_hooks").join(format!("{}.sql", name));
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        crate::parse_statements(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn hooks_run_around_migrations() {
+        let mut config = test_config().unwrap();
+        let log = "./hooks/log";
+        config.hooks.before_all = format!("echo before_all >> {log}");
+        config.hooks.after_each =
+            format!("echo $ARCHITECT_HOOK $ARCHITECT_VERSION $ARCHITECT_DIRECTION >> {log}");
+        config.hooks.on_failure = format!("echo $ARCHITECT_HOOK $ARCHITECT_VERSION >> {log}");
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./hooks")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", second)),
+            b"SELECT * FROM __no_such_table__;",
+        )
+        .unwrap();
+        m.last_version = 0;
+
+        let result = m.migrate_up(false);
+        let _ = m.migrate_down(false);
+        let log = std::fs::read_to_string(log).unwrap();
+
+        let _ = std::fs::remove_dir_all("./hooks");
+
+        assert!(result.is_err());
+        assert_eq!(
+            log,
+            format!(
+                "before_all\nafter_each {first} up\non_failure {second}\nafter_each {first} down\n"
+            )
+        );
+    }
+
+    #[test]
+    fn sql_hooks_run_in_migration_transaction() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./sql_hooks")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::create_dir_all(m.dir.join("_hooks")).unwrap();
+        std::fs::write(
+            m.dir.join("_hooks/before_each.sql"),
+            b"CREATE TABLE IF NOT EXISTS __hook_audit__ (version TEXT, direction TEXT, n INT);",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join("_hooks/after_each.sql"),
+            b"INSERT INTO __hook_audit__ SELECT current_setting('architect.version'),
+                current_setting('architect.direction'), count(*) FROM __hook_t__;",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE __hook_t__ (id INT); INSERT INTO __hook_t__ VALUES (1);",
+        )
+        .unwrap();
+        m.last_version = 0;
+
+        m.migrate_up(false).unwrap();
+        let rows = m
+            .client
+            .query("SELECT version, direction, n FROM __hook_audit__", &[])
+            .unwrap();
+        // down doesn't drop __hook_t__, so the after_each hook still finds it
+        m.migrate_down(false).unwrap();
+        m.client
+            .batch_execute("DROP TABLE __hook_audit__; DROP TABLE __hook_t__;")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./sql_hooks");
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<_, String>(0), version.to_string());
+        assert_eq!(rows[0].get::<_, String>(1), "up");
+        assert_eq!(rows[0].get::<_, i32>(2), 1);
+    }
+}
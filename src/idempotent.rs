@@ -0,0 +1,111 @@
+//! Idempotent DDL, for projects re-running migrations against partially restored databases.
+//! With `idempotent = true` in `.architect.toml` statements with an idempotent form have to use
+//! it, `IF NOT EXISTS`, `IF EXISTS` or `OR REPLACE`, and `fmt` rewrites them to it.
+
+use sqlparser::ast::{AlterTableOperation, Statement};
+
+use crate::lint::{Finding, Severity};
+
+/// Rewrites `s` to its idempotent form, returning the clauses that were added. Statements without
+/// an idempotent form postgres accepts, like materialized views, are left as they are.
+pub(crate) fn rewrite(s: &mut Statement) -> Vec<&'static str> {
+    let mut added = Vec::<&'static str>::new();
+    let mut set = |flag: &mut bool, clause: &'static str| {
+        if !*flag {
+            *flag = true;
+            added.push(clause);
+        }
+    };
+    match s {
+        Statement::CreateTable {
+            if_not_exists,
+            or_replace: false,
+            ..
+        }
+        | Statement::CreateIndex { if_not_exists, .. }
+        | Statement::CreateSchema { if_not_exists, .. }
+        | Statement::CreateSequence { if_not_exists, .. } => set(if_not_exists, "IF NOT EXISTS"),
+        Statement::CreateView {
+            or_replace,
+            materialized: false,
+            ..
+        }
+        | Statement::CreateFunction { or_replace, .. } => set(or_replace, "OR REPLACE"),
+        Statement::Drop { if_exists, .. } | Statement::DropFunction { if_exists, .. } => {
+            set(if_exists, "IF EXISTS")
+        }
+        Statement::AlterTable { operation, .. } => match operation {
+            AlterTableOperation::AddColumn { if_not_exists, .. } => {
+                set(if_not_exists, "IF NOT EXISTS")
+            }
+            AlterTableOperation::DropColumn { if_exists, .. }
+            | AlterTableOperation::DropConstraint { if_exists, .. } => set(if_exists, "IF EXISTS"),
+            _ => {}
+        },
+        _ => {}
+    }
+    added
+}
+
+/// Reports the statements that aren't in their idempotent form.
+pub(crate) fn check(file: &str, statements: &[Statement]) -> Vec<Finding> {
+    let mut result = Vec::<Finding>::new();
+    for s in statements.iter() {
+        let added = rewrite(&mut s.clone());
+        if !added.is_empty() {
+            result.push(Finding::new(
+                file,
+                "idempotent",
+                Severity::Error,
+                format!("\"{}\" isn't idempotent, use {}", s, added.join(" and ")),
+            ));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn idempotent() {
+        let statements = crate::parse_ast(
+            "CREATE TABLE a (id INT);
+            CREATE INDEX a_id ON a (id);
+            CREATE VIEW v AS SELECT id FROM a;
+            CREATE MATERIALIZED VIEW m AS SELECT id FROM a;
+            ALTER TABLE a ADD COLUMN b TEXT;
+            ALTER TABLE a DROP COLUMN IF EXISTS c;
+            DROP TABLE a;
+            INSERT INTO a VALUES (1);",
+        )
+        .unwrap();
+
+        let findings = super::check("1_up.sql", &statements);
+        let rewritten: Vec<String> = statements
+            .into_iter()
+            .map(|mut s| {
+                super::rewrite(&mut s);
+                s.to_string()
+            })
+            .collect();
+
+        assert_eq!(findings.len(), 5);
+        assert_eq!(
+            findings[0].message,
+            "\"CREATE TABLE a (id INT)\" isn't idempotent, use IF NOT EXISTS"
+        );
+        assert_eq!(
+            rewritten,
+            vec![
+                "CREATE TABLE IF NOT EXISTS a (id INT)",
+                "CREATE INDEX IF NOT EXISTS a_id ON a(id)",
+                "CREATE OR REPLACE VIEW v AS SELECT id FROM a",
+                "CREATE MATERIALIZED VIEW m AS SELECT id FROM a",
+                "ALTER TABLE a ADD COLUMN IF NOT EXISTS b TEXT",
+                "ALTER TABLE a DROP COLUMN IF EXISTS c",
+                "DROP TABLE IF EXISTS a",
+                "INSERT INTO a VALUES (1)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Index of the versions in a migration directory, so commands don't rescan directories with
+//! thousands of files. The versions only change when files are added, removed or renamed, which
+//! changes the modification time of the directory, so the versions of the last scan are reused
+//! while it stays the same. Indexes are kept in the parse cache, see `cache`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    /// Modification time of the directory when scanned, in nanoseconds since the epoch
+    modified: u128,
+    up: Vec<i64>,
+    down: Vec<i64>,
+}
+
+/// Directories modified this recently, in nanoseconds, aren't indexed, as a change in the same
+/// tick of a coarse file system clock would go unnoticed.
+const RACY: u128 = 2_000_000_000;
+
+fn nanos(time: std::time::SystemTime) -> u128 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_nanos())
+        .unwrap_or(0)
+}
+
+/// The up and the down versions in `dir`, using the index in `cache_dir`.
+fn versions_in(cache_dir: &std::path::Path, dir: &std::path::Path) -> Result<(Vec<i64>, Vec<i64>)> {
+    let enabled = crate::cache::enabled();
+    let modified = nanos(std::fs::metadata(dir)?.modified()?);
+    let name = std::fs::canonicalize(dir)?.to_string_lossy().to_string();
+    let path = cache_dir.join(format!("index-{}.json", crate::cache::checksum(&[&name])));
+    if enabled {
+        if let Some(index) = std::fs::read(&path)
+            .ok()
+            .and_then(|v| serde_json::from_slice::<Index>(&v).ok())
+        {
+            if index.modified == modified {
+                return Ok((index.up, index.down));
+            }
+        }
+    }
+    let (up, down) = crate::scan_versions(dir)?;
+    if enabled && nanos(std::time::SystemTime::now()).saturating_sub(modified) > RACY {
+        let index = Index {
+            modified,
+            up: up.clone(),
+            down: down.clone(),
+        };
+        if let Err(e) = crate::cache::write(&path, &index) {
+            crate::output::info!("indexing {:?} failed: {}", dir, e);
+        }
+    }
+    Ok((up, down))
+}
+
+/// The up and the down versions in `dir`, like `scan_versions`.
+pub(crate) fn versions(dir: &std::path::Path) -> Result<(Vec<i64>, Vec<i64>)> {
+    versions_in(std::path::Path::new(crate::cache::DIR), dir)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn index() {
+        let cache = std::path::Path::new("./index_cache");
+        let dir = std::path::Path::new("./index_migrations");
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("1_up.sql"), "").unwrap();
+        std::fs::write(dir.join("1_down.sql"), "").unwrap();
+        let recent = super::versions_in(cache, dir).unwrap();
+        let indexed_recent = cache.exists();
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::File::open(dir).unwrap().set_modified(old).unwrap();
+        let scanned = super::versions_in(cache, dir).unwrap();
+        // a tampered index shows it's read
+        let index = std::fs::read_dir(cache)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut tampered: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&index).unwrap()).unwrap();
+        tampered["up"] = serde_json::json!([7]);
+        std::fs::write(&index, tampered.to_string()).unwrap();
+        let indexed = super::versions_in(cache, dir).unwrap();
+        std::fs::write(dir.join("2_up.sql"), "").unwrap();
+        let changed = super::versions_in(cache, dir).unwrap();
+
+        let _ = std::fs::remove_dir_all(cache);
+        let _ = std::fs::remove_dir_all(dir);
+
+        assert_eq!(recent, (vec![1], vec![1]));
+        assert!(!indexed_recent);
+        assert_eq!(scanned, (vec![1], vec![1]));
+        assert_eq!(indexed.0, vec![7]);
+        assert_eq!(changed, (vec![1, 2], vec![1]));
+    }
+}
@@ -0,0 +1,154 @@
+//! Migrations without a way back. A down migration without statements is only accepted when the
+//! migration is marked `-- architect:irreversible`, so a rollback isn't found to be impossible
+//! during an incident.
+
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::lint::{Finding, Severity};
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "irreversible";
+
+fn marked(sql: &str) -> bool {
+    crate::directives::has(sql, DIRECTIVE)
+}
+
+/// Versions in `versions` whose down migration has no statements and that aren't marked
+/// irreversible in either file. Scripts, verbatim files and files that don't parse aren't
+/// considered empty.
+pub(crate) fn empty_downs(dir: &std::path::Path, versions: &[i64]) -> Result<Vec<i64>> {
+    let mut result = Vec::<i64>::new();
+    for v in versions.iter() {
+        let up = crate::migration_file(dir, *v, Direction::Up);
+        let down = crate::migration_file(dir, *v, Direction::Down);
+        if !down.exists() || down.extension().is_some_and(|e| e != "sql") {
+            continue;
+        }
+        let sql = std::fs::read_to_string(&down)?;
+        let verbatim = crate::directives::has(&sql, crate::directives::VERBATIM);
+        if verbatim || !crate::parse_ast(&sql).is_ok_and(|s| s.is_empty()) || marked(&sql) {
+            continue;
+        }
+        if up.extension().is_some_and(|e| e == "sql")
+            && up.exists()
+            && marked(&std::fs::read_to_string(&up)?)
+        {
+            continue;
+        }
+        result.push(*v);
+    }
+    Ok(result)
+}
+
+/// Versions in `versions` that can't be migrated down: marked irreversible, without a down
+/// migration or with an empty one.
+pub(crate) fn irreversible(dir: &std::path::Path, versions: &[i64]) -> Result<Vec<i64>> {
+    let empty = empty_downs(dir, versions)?;
+    let mut result = Vec::<i64>::new();
+    for v in versions.iter() {
+        let down = crate::migration_file(dir, *v, Direction::Down);
+        let is_marked = |direction: Direction| -> Result<bool> {
+            let path = crate::migration_file(dir, *v, direction);
+            Ok(path.extension().is_some_and(|e| e == "sql")
+                && path.exists()
+                && marked(&std::fs::read_to_string(&path)?))
+        };
+        if !down.exists()
+            || empty.contains(v)
+            || is_marked(Direction::Up)?
+            || is_marked(Direction::Down)?
+        {
+            result.push(*v);
+        }
+    }
+    Ok(result)
+}
+
+/// Reports the empty down migrations of `versions`, errors if the project requires down
+/// migrations, warnings otherwise.
+pub(crate) fn check(
+    dir: &std::path::Path,
+    versions: &[i64],
+    required: bool,
+) -> Result<Vec<Finding>> {
+    let severity = if required {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    Ok(empty_downs(dir, versions)?
+        .into_iter()
+        .map(|v| {
+            Finding::new(
+                &format!("{}_down.sql", v),
+                "empty-down",
+                severity,
+                format!(
+                    "down migration has no statements, mark the migration \
+                    \"-- architect:{}\" if it can't be reversed",
+                    DIRECTIVE
+                ),
+            )
+        })
+        .collect())
+}
+
+impl Migrator {
+    /// Fails if the project requires down migrations and a pending migration has an empty one.
+    pub(crate) fn require_downs(&self) -> Result<()> {
+        if !self.project()?.require_down {
+            return Ok(());
+        }
+        let pending: Vec<i64> = self
+            .versions_up
+            .iter()
+            .filter(|v| **v > self.last_version)
+            .copied()
+            .collect();
+        let empty = empty_downs(&self.dir, &pending)?;
+        if !empty.is_empty() {
+            return Err(anyhow::anyhow!(
+                "pending migrations {:?} have empty down migrations and aren't marked \
+                \"-- architect:{}\"",
+                empty,
+                DIRECTIVE
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn empty_downs() {
+        let config = test_config().unwrap();
+        let mut m =
+            crate::Migrator::new(config, std::path::PathBuf::from("./irreversible")).unwrap();
+        let (_, empty) = m.new_migration_by(Some("Jane".to_owned())).unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        let (_, down) = m.new_migration().unwrap();
+        std::fs::write(&up, "-- architect:irreversible\nDROP TABLE a;").unwrap();
+        std::fs::write(&down, "CREATE TABLE a (id INT);").unwrap();
+        let versions = m.versions_up.clone();
+        std::fs::write("./irreversible/.architect.toml", b"require_down = true").unwrap();
+        m.last_version = 0;
+
+        let empty_downs = super::empty_downs(&m.dir, &versions).unwrap();
+        let findings = super::check(&m.dir, &versions, true).unwrap();
+        let required = m.require_downs();
+
+        let _ = std::fs::remove_dir_all("./irreversible");
+
+        assert_eq!(empty_downs, vec![versions[0]]);
+        assert_eq!(
+            findings[0].file,
+            empty.file_name().unwrap().to_string_lossy()
+        );
+        assert_eq!(findings[0].severity, crate::lint::Severity::Error);
+        assert!(required.is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use sqlparser::ast::Statement;
+
+use crate::direction::Direction;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct Finding {
+    /// Migration file name the finding is about
+    pub(crate) file: String,
+    pub(crate) rule: String,
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+}
+
+impl Finding {
+    pub(crate) fn new(file: &str, rule: &str, severity: Severity, message: String) -> Self {
+        Finding {
+            file: file.to_owned(),
+            rule: rule.to_owned(),
+            severity,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "{}[{}] {}: {}",
+            severity, self.rule, self.file, self.message
+        )
+    }
+}
+
+/// Runs all lint rules against the parsed statements of one migration file.
+pub(crate) fn lint(file: &str, direction: Direction, statements: &[Statement]) -> Vec<Finding> {
+    let mut result = Vec::<Finding>::new();
+    if statements.is_empty() && direction == Direction::Up {
+        result.push(Finding::new(
+            file,
+            "empty-migration",
+            Severity::Warning,
+            "up migration has no statements".to_owned(),
+        ));
+    }
+    for s in statements.iter() {
+        match s {
+            Statement::StartTransaction { .. }
+            | Statement::Commit { .. }
+            | Statement::Rollback { .. } => {
+                result.push(Finding::new(
+                    file,
+                    "transaction-control",
+                    Severity::Error,
+                    format!(
+                        "\"{}\" conflicts with the transaction every migration runs in",
+                        s
+                    ),
+                ));
+            }
+            _ => {}
+        }
+    }
+    for c in crate::rename::candidates(statements) {
+        result.push(Finding::new(
+            file,
+            "likely-rename",
+            Severity::Warning,
+            format!(
+                "dropping {table}.{from} and adding {to} looks like a rename that loses the data \
+                in {from}, `rename-column {table} {from} {to}` writes a safe rename instead",
+                table = c.table,
+                from = c.from,
+                to = c.to
+            ),
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Severity;
+    use crate::direction::Direction;
+
+    #[test]
+    fn lint_rules() {
+        let statements = crate::parse_ast("BEGIN; CREATE TABLE a (id INT); COMMIT;").unwrap();
+        let findings = super::lint("1_up.sql", Direction::Up, &statements);
+        let empty_up = super::lint("1_up.sql", Direction::Up, &[]);
+        let empty_down = super::lint("1_down.sql", Direction::Down, &[]);
+
+        assert_eq!(findings.len(), 2);
+        assert!(findings
+            .iter()
+            .all(|f| f.rule == "transaction-control" && f.severity == Severity::Error));
+        assert_eq!(empty_up.len(), 1);
+        assert_eq!(empty_up[0].severity, Severity::Warning);
+        assert!(empty_down.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+/// A migration version as found on disk, joined with its state in the database.
+pub(crate) struct MigrationInfo {
+    pub(crate) version: i64,
+    pub(crate) description: String,
+    pub(crate) author: String,
+    pub(crate) up_size: u64,
+    pub(crate) down_size: u64,
+    pub(crate) applied: bool,
+    /// Why the version is skipped, see `skip`
+    pub(crate) skipped: Option<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct ListFilter {
+    pub(crate) pending: bool,
+    pub(crate) applied: bool,
+    /// Only versions created at or after this unix timestamp in milliseconds
+    pub(crate) since: Option<i64>,
+    pub(crate) pattern: Option<glob::Pattern>,
+    pub(crate) reverse: bool,
+}
+
+impl ListFilter {
+    fn matches(&self, info: &MigrationInfo) -> bool {
+        if self.pending && (info.applied || info.skipped.is_some()) {
+            return false;
+        }
+        if self.applied && !info.applied {
+            return false;
+        }
+        if let Some(since) = self.since {
+            if info.version < since {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            let file_name = format!("{}_up.sql", info.version);
+            if !pattern.matches(&file_name) && !pattern.matches(&info.description) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses a `--since` argument given as `YYYY-MM-DD` into a unix timestamp in milliseconds, which
+/// is what generated versions are.
+pub(crate) fn parse_since(s: &str) -> Result<i64> {
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| anyhow::anyhow!("invalid date \"{}\": {}. Expected YYYY-MM-DD", s, e))?;
+    match date.and_hms_opt(0, 0, 0) {
+        Some(v) => Ok(v.and_utc().timestamp_millis()),
+        None => Err(anyhow::anyhow!("invalid date \"{}\"", s)),
+    }
+}
+
+/// Parses a timestamp given as `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS]` in UTC or RFC 3339 into a
+/// unix timestamp in milliseconds, comparable to generated versions.
+pub(crate) fn parse_timestamp(s: &str) -> Result<i64> {
+    if let Ok(v) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(v.timestamp_millis());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(v) = chrono::NaiveDateTime::parse_from_str(s, format) {
+            return Ok(v.and_utc().timestamp_millis());
+        }
+    }
+    parse_since(s).map_err(|_| {
+        anyhow::anyhow!(
+            "invalid timestamp \"{}\". Expected YYYY-MM-DD, YYYY-MM-DD HH:MM[:SS] or RFC 3339",
+            s
+        )
+    })
+}
+
+/// The first comment line of a migration file that isn't a directive, used as its description.
+pub(crate) fn description(path: &std::path::Path) -> Result<String> {
+    if path.extension().is_some_and(|v| v == "wasm") {
+        return Ok(String::new());
+    }
+    let s = std::fs::read_to_string(path)?;
+    for line in s.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(v) = line.strip_prefix("--") {
+            let v = v.trim();
+            if v.is_empty() || v.starts_with("architect:") {
+                continue;
+            }
+            return Ok(v.to_owned());
+        }
+        break;
+    }
+    Ok(String::new())
+}
+
+/// Formats a version as the time it was created at, assuming it is a generated millisecond
+/// timestamp.
+pub(crate) fn created_at(version: i64) -> String {
+    match chrono::DateTime::from_timestamp_millis(version) {
+        Some(v) => v.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+impl Migrator {
+    pub(crate) fn migrations(&mut self) -> Result<Vec<MigrationInfo>> {
+        let applied = self.applied_versions()?;
+        let skipped = self.skipped_versions()?;
+        let mut result = Vec::<MigrationInfo>::new();
+        for v in self.versions_up.iter() {
+            let up = self.migration_path(*v, Direction::Up);
+            let down = self.migration_path(*v, Direction::Down);
+            result.push(MigrationInfo {
+                version: *v,
+                description: description(&up)?,
+                author: crate::author::read(&up)?,
+                up_size: std::fs::metadata(&up)?.len(),
+                down_size: std::fs::metadata(&down)?.len(),
+                applied: applied.contains(v),
+                skipped: skipped.get(v).cloned(),
+            });
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn list(&mut self, filter: &ListFilter) -> Result<Vec<MigrationInfo>> {
+        let mut result: Vec<MigrationInfo> = self
+            .migrations()?
+            .into_iter()
+            .filter(|v| filter.matches(v))
+            .collect();
+        if filter.reverse {
+            result.reverse();
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn print(migrations: &[MigrationInfo]) {
+    println!(
+        "{:<15} {:<19} {:<8} {:>8} {:>8} {:<24}  DESCRIPTION",
+        "VERSION", "CREATED", "STATE", "UP", "DOWN", "AUTHOR"
+    );
+    for m in migrations {
+        println!(
+            "{:<15} {:<19} {:<8} {:>8} {:>8} {:<24}  {}",
+            m.version,
+            created_at(m.version),
+            match (m.applied, &m.skipped) {
+                (true, _) => "applied",
+                (false, Some(_)) => "skipped",
+                (false, None) => "pending",
+            },
+            m.up_size,
+            m.down_size,
+            if m.author.is_empty() { "-" } else { &m.author },
+            m.description
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn list_filters() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./list")).unwrap();
+        const N: usize = 4;
+        for _ in 0..N {
+            m.new_migration().unwrap();
+        }
+        let first = *m.versions_up.first().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", first)),
+            b"-- create the users table\nCREATE TABLE __list_users__(id INT PRIMARY KEY);",
+        )
+        .unwrap();
+
+        let all = m.list(&super::ListFilter::default()).unwrap();
+        let matched = m
+            .list(&super::ListFilter {
+                pattern: Some(glob::Pattern::new("*users*").unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        let future = m
+            .list(&super::ListFilter {
+                since: Some(super::parse_since("2999-01-01").unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./list");
+
+        assert_eq!(all.len(), N);
+        assert!(all.iter().all(|v| !v.applied));
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].description, "create the users table");
+        assert_eq!(future.len(), 0);
+    }
+}
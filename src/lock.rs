@@ -0,0 +1,281 @@
+//! `architect.lock` records a SHA-256 checksum of every migration's up and down file. It lives in
+//! the app's migration directory and is meant to be committed, so silent edits to migrations
+//! that were merged already are caught before they're applied.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::direction::Direction;
+
+pub(crate) const LOCK_FILE: &str = "architect.lock";
+
+const HEADER: &str = "# generated by `architect lock`. do not edit.";
+
+/// Checksum of a migration, covering its up and its down file.
+pub(crate) fn checksum(dir: &std::path::Path, version: i64) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for direction in [Direction::Up, Direction::Down] {
+        hasher.update(std::fs::read(crate::migration_file(
+            dir, version, direction,
+        ))?);
+        // keeps moving bytes between up and down from resulting in the same checksum
+        hasher.update([0]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect())
+}
+
+/// Checksums of all migrations in `dir`.
+pub(crate) fn generate(dir: &std::path::Path) -> Result<BTreeMap<i64, String>> {
+    let (up, _) = crate::scan_versions(dir)?;
+    let mut result = BTreeMap::new();
+    for v in up.iter() {
+        result.insert(*v, checksum(dir, *v)?);
+    }
+    Ok(result)
+}
+
+/// Writes the lock file for `dir`, returning the number of migrations locked.
+pub(crate) fn write(dir: &std::path::Path) -> Result<usize> {
+    let checksums = generate(dir)?;
+    let mut content = format!("{}\n", HEADER);
+    for (version, checksum) in checksums.iter() {
+        content.push_str(&format!("{} {}\n", version, checksum));
+    }
+    std::fs::write(dir.join(LOCK_FILE), content)?;
+    Ok(checksums.len())
+}
+
+/// Reads the lock file of `dir`, `None` if there is none.
+pub(crate) fn read(dir: &std::path::Path) -> Result<Option<BTreeMap<i64, String>>> {
+    let path = dir.join(LOCK_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut result = BTreeMap::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some((version, checksum)) => {
+                result.insert(version.parse()?, checksum.trim().to_owned());
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                    "{} line {}: expected \"<version> <sha256>\"",
+                    LOCK_FILE,
+                    i + 1
+                ))
+            }
+        }
+    }
+    Ok(Some(result))
+}
+
+/// Compares the migrations in `dir` with its lock file, describing every difference. Without a
+/// lock file there's nothing to compare.
+pub(crate) fn verify(dir: &std::path::Path) -> Result<Vec<String>> {
+    let mut result = Vec::<String>::new();
+    let locked = match read(dir)? {
+        Some(v) => v,
+        None => return Ok(result),
+    };
+    let current = generate(dir)?;
+    for (version, checksum) in current.iter() {
+        match locked.get(version) {
+            Some(v) if v == checksum => {}
+            Some(_) => result.push(format!("{} was changed after it was locked", version)),
+            None => result.push(format!("{} is not in {}", version, LOCK_FILE)),
+        }
+    }
+    for version in locked.keys() {
+        if !current.contains_key(version) {
+            result.push(format!("{} is locked but its files are missing", version));
+        }
+    }
+    Ok(result)
+}
+
+/// Versions whose files were changed after they were locked.
+pub(crate) fn changed(dir: &std::path::Path) -> Result<Vec<i64>> {
+    let locked = match read(dir)? {
+        Some(v) => v,
+        None => return Ok(Vec::new()),
+    };
+    Ok(generate(dir)?
+        .into_iter()
+        .filter(|(version, checksum)| locked.get(version).is_some_and(|v| v != checksum))
+        .map(|(version, _)| version)
+        .collect())
+}
+
+/// The git revision that last committed the lock file of `dir`.
+fn locked_revision(dir: &std::path::Path) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["log", "-1", "--format=%H", "--", LOCK_FILE])
+        .output()
+        .ok()?;
+    let rev = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    if !output.status.success() || rev.is_empty() {
+        return None;
+    }
+    Some(rev)
+}
+
+/// Explains how a changed migration differs from the revision that committed the lock file: a
+/// statement diff per file, or that only formatting and comments changed.
+pub(crate) fn explain(dir: &std::path::Path, version: i64) -> Result<Vec<String>> {
+    let rev = match locked_revision(dir) {
+        Some(v) => v,
+        None => return Ok(vec![format!("{} isn't committed, no diff", LOCK_FILE)]),
+    };
+    let mut result = Vec::<String>::new();
+    for direction in [Direction::Up, Direction::Down] {
+        let path = crate::migration_file(dir, version, direction);
+        let name = path
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !name.ends_with(".sql") {
+            result.push(format!("{}: scripts can't be diffed", name));
+            continue;
+        }
+        let old = match crate::diff::file_at_revision(dir, &name, &rev) {
+            Ok(v) => v,
+            Err(_) => {
+                result.push(format!(
+                    "{}: not in the revision that committed the lock",
+                    name
+                ));
+                continue;
+            }
+        };
+        let changes = match (
+            crate::parse_statements(&old),
+            crate::parse_statements(&std::fs::read_to_string(&path)?),
+        ) {
+            (Ok(old), Ok(new)) => crate::diff::semantic(&old, &new),
+            _ => {
+                result.push(format!("{}: doesn't parse, no diff", name));
+                continue;
+            }
+        };
+        let summary = crate::diff::summary(&changes);
+        if summary.is_empty() {
+            result.push(format!("{}: only formatting or comments changed", name));
+            continue;
+        }
+        result.push(format!("{}: {}", name, summary));
+        for line in crate::diff::lines(&changes, ";") {
+            result.push(format!("    {}", line));
+        }
+    }
+    Ok(result)
+}
+
+/// Fails if the migrations in `dir` don't match its lock file.
+pub(crate) fn check(dir: &std::path::Path) -> Result<()> {
+    let differences = verify(dir)?;
+    if differences.is_empty() {
+        return Ok(());
+    }
+    for d in differences.iter() {
+        eprintln!("{}", d);
+    }
+    for version in changed(dir)? {
+        for line in explain(dir, version)? {
+            eprintln!("{}", line);
+        }
+    }
+    Err(crate::error::ArchitectError::LockMismatch(format!(
+        "migrations don't match {}. run `architect lock` if the changes are intended",
+        LOCK_FILE
+    ))
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn lock_and_verify() {
+        let dir = std::path::PathBuf::from("./lock");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1_up.sql"), b"CREATE TABLE a (id INT);").unwrap();
+        std::fs::write(dir.join("1_down.sql"), b"DROP TABLE a;").unwrap();
+
+        let unlocked = super::verify(&dir).unwrap();
+        let locked = super::write(&dir).unwrap();
+        let clean = super::verify(&dir).unwrap();
+        std::fs::write(dir.join("1_down.sql"), b"DROP TABLE IF EXISTS a;").unwrap();
+        std::fs::write(dir.join("2_up.sql"), b"").unwrap();
+        std::fs::write(dir.join("2_down.sql"), b"").unwrap();
+        let changed = super::verify(&dir).unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(unlocked.is_empty());
+        assert_eq!(locked, 1);
+        assert!(clean.is_empty());
+        assert_eq!(
+            changed,
+            vec![
+                "1 was changed after it was locked".to_owned(),
+                "2 is not in architect.lock".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn explain_changes() {
+        let dir = std::path::PathBuf::from("./lock_explain");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1_up.sql"), b"CREATE TABLE a (id INT);").unwrap();
+        std::fs::write(dir.join("1_down.sql"), b"DROP TABLE a;").unwrap();
+        super::write(&dir).unwrap();
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "lock"]);
+        std::fs::write(
+            dir.join("1_up.sql"),
+            b"-- wider ids\nCREATE TABLE a (id BIGINT);",
+        )
+        .unwrap();
+        std::fs::write(dir.join("1_down.sql"), b"drop table a;").unwrap();
+
+        let changed = super::changed(&dir).unwrap();
+        let explained = super::explain(&dir, 1).unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(changed, vec![1]);
+        assert_eq!(
+            explained,
+            vec![
+                "1_up.sql: 1 modified",
+                "    ~ CREATE TABLE a (id INT);",
+                "      => CREATE TABLE a (id BIGINT);",
+                "1_down.sql: only formatting or comments changed",
+            ]
+        );
+    }
+}
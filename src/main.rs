@@ -1,36 +1,209 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use native_tls::{Certificate, TlsConnector};
 use postgres::{Client, NoTls};
 use postgres_native_tls::MakeTlsConnector;
 use serde::Deserialize;
 
-#[derive(Deserialize, Default)]
+use direction::Direction;
+use error::ArchitectError;
+use output::info;
+
+mod approval;
+mod apps;
+mod assertions;
+mod author;
+mod batch_update;
+mod blue_green;
+mod bundle;
+mod cache;
+mod catalog;
+mod conditions;
+mod credentials;
+mod cutover;
+mod data;
+mod diff;
+mod direction;
+mod directives;
+mod disk;
+mod docs;
+mod dryrun;
+mod editor;
+mod email;
+mod env;
+mod erd;
+mod error;
+mod expect;
+mod faults;
+mod fleet;
+mod fmt;
+mod foreign_key;
+mod grants;
+mod heartbeat;
+mod history;
+mod hooks;
+mod idempotent;
+mod index;
+mod irreversible;
+mod lint;
+mod list;
+mod lock;
+mod merge;
+mod naming;
+mod notify;
+mod online;
+mod output;
+mod owners;
+mod parallel;
+mod partitions;
+mod paths;
+mod pgbouncer;
+mod pgpass;
+mod plan;
+mod plugins;
+mod policy;
+mod post;
+mod project;
+mod protect;
+mod proxy;
+mod reconcile;
+mod reconnect;
+mod refresh;
+mod rename;
+mod report;
+mod requires;
+mod reversibility;
+mod rls;
+mod rollback;
+mod rollout;
+mod rpc;
+mod safety;
+mod sandbox;
+mod schema;
+mod script;
+mod secrets;
+mod sequences;
+mod shadow;
+mod skip;
+mod state;
+mod status;
+mod stream;
+mod tags;
+mod testdb;
+mod validate;
+mod views;
+mod wasm;
+
+#[derive(Deserialize, Default, Clone)]
 struct Config {
     app: String,
     host: String,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "env::port")]
     port: u16,
     dbname: String,
     user: String,
     #[serde(default)]
     password: String,
+    /// Credentials of the commands that only read, see credentials.rs
+    #[serde(default)]
+    verifier: credentials::Credentials,
     #[serde(default)]
     connect_timeout_seconds: u16,
     #[serde(default)]
     ssl: bool,
     #[serde(default)]
     sslrootcert: String,
+    /// Reported to the server as application_name. Defaults to `app`, or "architect" without one.
+    #[serde(default)]
+    application_name: String,
+    /// Failover list of `host` or `host:port` entries tried in turn, used instead of host and port
+    #[serde(default)]
+    hosts: Vec<String>,
+    /// Passed through to the connection string
+    #[serde(default)]
+    options: String,
+    #[serde(default)]
+    target_session_attrs: String,
+    #[serde(default)]
+    channel_binding: String,
+    /// SOCKS5 or HTTP CONNECT proxy the connection is tunneled through, see proxy.rs
+    #[serde(default)]
+    proxy: String,
+    /// "transaction" or "detect" to adapt to a transaction pooling PgBouncer, see pgbouncer.rs
+    #[serde(default)]
+    pgbouncer: String,
+    /// Whether the connection goes through a transaction pooler, once known
+    #[serde(skip)]
+    pooled: Option<bool>,
+    /// Whether a dirty last version is accepted rather than refused, see status.rs
+    #[serde(skip)]
+    dirty_ok: bool,
+    /// Database on the same server that is kept in sync with this one by replaying migrations
+    #[serde(default)]
+    shadow_dbname: String,
+    /// Database on the same server kept at the last applied version and cloned by `test --template`
+    #[serde(default)]
+    template_dbname: String,
+    #[serde(default)]
+    hooks: hooks::Hooks,
+    /// Commands speaking the JSON plugin protocol, see plugins.rs
+    #[serde(default)]
+    plugins: Vec<String>,
+    #[serde(default)]
+    email: email::Email,
+    /// Destructive actions need confirmation, e.g. for production
+    #[serde(default)]
+    protected: bool,
+    /// Name of the environment, selecting the policy from `.architect.toml`
+    #[serde(default)]
+    environment: String,
+    /// Only apply migrations through approved plans, see `plan --out`, `approve` and `apply`
+    #[serde(default)]
+    require_approval: bool,
+    /// Secret shared by the operators to sign and verify plans
+    #[serde(default)]
+    approval_key: String,
+    /// Times a run reconnects and resumes after losing the connection, 0 to fail at once
+    #[serde(default)]
+    reconnect_attempts: u32,
+    /// Migration files larger than this are streamed instead of read whole, see stream.rs
+    #[serde(default)]
+    stream_threshold_mb: u64,
+    /// Versions intentionally not applied on this database, see skip.rs
+    #[serde(default)]
+    skip_versions: Vec<i64>,
+    /// Why `skip_versions` are skipped, recorded in `schema_skipped_versions`
+    #[serde(default)]
+    skip_reason: String,
+    /// Checks of long transactions, replication lag and vacuums before migrating, see safety.rs
+    #[serde(default)]
+    safety: safety::Safety,
+    /// Seconds between the reports of a running migration, 0 for none, see heartbeat.rs
+    #[serde(default)]
+    heartbeat_seconds: u64,
+    /// NOTIFY listeners when migrations start, succeed and fail, see notify.rs
+    #[serde(default)]
+    notify: bool,
+    /// Settings of every session, e.g. `maintenance_work_mem` for index builds or `role`
+    #[serde(default)]
+    session: std::collections::BTreeMap<String, toml::Value>,
+    /// Query returning the free bytes on the server's disk, see disk.rs
+    #[serde(default)]
+    disk_free_query: String,
+    /// Versioned schemas of views the app is switched between, see blue_green.rs
+    #[serde(default)]
+    blue_green: blue_green::BlueGreen,
 }
 
 impl Config {
     fn defaults(&mut self) -> Result<()> {
-        let home_dir = match home::home_dir() {
-            Some(v) => v,
-            None => {
-                return Err(anyhow::anyhow!("couldn't read users home directory"));
-            }
-        };
+        if self.port == 0 {
+            self.port = 5432;
+        }
+
+        if self.stream_threshold_mb == 0 {
+            self.stream_threshold_mb = stream::THRESHOLD_MB;
+        }
 
         if self.password.is_empty() {
             if let Ok(v) = std::env::var("PGPASSWORD") {
@@ -38,6 +211,27 @@ impl Config {
             }
         }
 
+        if self.password.is_empty() {
+            for (host, port) in self.host_ports() {
+                if let Some(v) = pgpass::password(host, port, &self.dbname, &self.user)? {
+                    self.password = v;
+                    break;
+                }
+            }
+        }
+
+        if self.password.is_empty() && !self.plugins.is_empty() {
+            let event = plugins::Event::Password {
+                app: &self.app,
+                host: &self.host,
+                dbname: &self.dbname,
+                user: &self.user,
+            };
+            if let Some(v) = plugins::password(&self.plugins, &event)? {
+                self.password = v;
+            }
+        }
+
         if self.sslrootcert.is_empty() {
             let sslrootcert = if let Ok(v) = std::env::var("PGSSLROOTCERT") {
                 v
@@ -45,77 +239,183 @@ impl Config {
                 "".to_owned()
             };
             if sslrootcert.is_empty() {
-                self.sslrootcert = format!("{:?}/.postgresql/root.crt", &home_dir);
+                if let Some(v) = paths::default_sslrootcert() {
+                    self.sslrootcert = v.to_string_lossy().to_string();
+                }
             } else {
                 self.sslrootcert = sslrootcert;
             }
         }
+        Ok(())
+    }
 
-        if self.port == 0 {
-            self.port = 5432;
+    /// The configured hosts with their ports, `host` and `port` unless `hosts` is set.
+    fn host_ports(&self) -> Vec<(&str, u16)> {
+        if self.hosts.is_empty() {
+            return vec![(&self.host, self.port)];
+        }
+        self.hosts
+            .iter()
+            .map(|h| match h.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().unwrap_or(self.port)),
+                None => (h.as_str(), self.port),
+            })
+            .collect()
+    }
+
+    fn application_name(&self) -> &str {
+        if !self.application_name.is_empty() {
+            &self.application_name
+        } else if !self.app.is_empty() {
+            &self.app
+        } else {
+            "architect"
         }
-        Ok(())
     }
 
     fn assert(&self) -> Result<()> {
-        if self.host.is_empty() {
-            return Err(anyhow::anyhow!("host cannot be empty"));
+        if self.host.is_empty() && self.hosts.is_empty() {
+            return Err(ArchitectError::Config("host cannot be empty".to_owned()).into());
         }
         if self.dbname.is_empty() {
-            return Err(anyhow::anyhow!("dbname cannot be empty"));
+            return Err(ArchitectError::Config("dbname cannot be empty".to_owned()).into());
         }
         Ok(())
     }
 
+    /// Connects and applies the `[session]` settings, unless they're applied per transaction
+    /// because of a transaction pooler.
     fn connect(&mut self) -> Result<Client> {
+        let mut client = self.open()?;
+        if !self.pooled(&mut client)? {
+            self.apply_session(&mut client, false)?;
+        }
+        Ok(client)
+    }
+
+    fn open(&mut self) -> Result<Client> {
         self.defaults()?;
         let mut params = Vec::<String>::new();
-        params.push(format!("host={}", &self.host));
-        params.push(format!("port={}", &self.port));
-        params.push(format!("dbname={}", &self.dbname));
-        params.push("application_name=rust_migrator".to_string());
+        let forwarded = if self.proxy.is_empty() {
+            None
+        } else if !self.hosts.is_empty() {
+            return Err(ArchitectError::Config(
+                "a proxy can't be used with a failover list of hosts".to_owned(),
+            )
+            .into());
+        } else {
+            let proxy = proxy::Proxy::parse(&self.proxy)?;
+            let timeout = std::time::Duration::from_secs(self.connect_timeout_seconds.into());
+            let port = proxy::forward(&proxy, &self.host, self.port, timeout)
+                .map_err(|e| ArchitectError::Connection(e.to_string()))?;
+            Some(port)
+        };
+        if let Some(port) = forwarded {
+            params.push(param("host", "127.0.0.1"));
+            params.push(param("port", &port.to_string()));
+        } else if self.hosts.is_empty() {
+            params.push(param("host", &self.host));
+            params.push(param("port", &self.port.to_string()));
+        } else {
+            let (hosts, ports): (Vec<&str>, Vec<String>) = self
+                .host_ports()
+                .into_iter()
+                .map(|(host, port)| (host, port.to_string()))
+                .unzip();
+            params.push(param("host", &hosts.join(",")));
+            params.push(param("port", &ports.join(",")));
+        }
+        params.push(param("dbname", &self.dbname));
+        params.push(param("application_name", self.application_name()));
         params.push(format!("connect_timeout={}", &self.connect_timeout_seconds));
         if !self.user.is_empty() {
-            params.push(format!("user={}", &self.user));
+            params.push(param("user", &self.user));
         }
         if !self.password.is_empty() {
-            params.push(format!("password={}", &self.password));
+            params.push(param("password", &self.password));
+        }
+        if !self.options.is_empty() {
+            params.push(param("options", &self.options));
+        }
+        if !self.target_session_attrs.is_empty() {
+            params.push(param("target_session_attrs", &self.target_session_attrs));
+        } else if self.hosts.len() > 1 {
+            // skip replicas in a failover list
+            params.push("target_session_attrs=read-write".to_owned());
+        }
+        if !self.channel_binding.is_empty() {
+            params.push(param("channel_binding", &self.channel_binding));
         }
 
         if self.ssl {
-            eprintln!("ssl with cert: {}", &self.sslrootcert);
+            info!("ssl with cert: {}", &self.sslrootcert);
             params.push("sslmode=require".to_string());
             let mut connector = TlsConnector::builder();
             let connector = if std::path::PathBuf::from(&self.sslrootcert).exists() {
-                eprintln!("using provided root certificate");
+                info!("using provided root certificate");
                 // params.push(format!("sslrootcert={}", &self.sslrootcert));
                 let cert = std::fs::read(&self.sslrootcert)?;
                 let cert = Certificate::from_pem(&cert)?;
                 connector.add_root_certificate(cert).build()?
             } else {
-                eprintln!("using system certificate");
+                info!("using system certificate");
                 connector.build()?
             };
 
+            info!("Connection String: {}", masked(&params));
+            if forwarded.is_some() {
+                let tls = proxy::Tls {
+                    connector,
+                    domain: self.host.clone(),
+                };
+                return postgres::Client::connect(&params.join(" "), tls)
+                    .map_err(|e| connection_error(e.to_string(), &self.user).into());
+            }
             let connector = MakeTlsConnector::new(connector);
-            eprintln!("Connection String: {}", &params.join(" "));
-            return Ok(postgres::Client::connect(&params.join(" "), connector)?);
+            return postgres::Client::connect(&params.join(" "), connector)
+                .map_err(|e| connection_error(e.to_string(), &self.user).into());
         }
-        Ok(postgres::Client::connect(&params.join(" "), NoTls)?)
+        postgres::Client::connect(&params.join(" "), NoTls)
+            .map_err(|e| connection_error(e.to_string(), &self.user).into())
     }
 
-    fn init(&mut self) -> Result<(Client, i64)> {
+    /// Connects and reads the last version. With `read_only` nothing is written, so a read only
+    /// role or a replica works, but the tracking tables have to exist.
+    fn init(&mut self, read_only: bool) -> Result<(Client, i64)> {
         self.assert()?;
         let mut client = self.connect()?;
-        client.execute(
-            "
-            CREATE TABLE IF NOT EXISTS schema_migrations (
-                version BIGINT PRIMARY KEY,
-                dirty BOOLEAN DEFAULT FALSE
-            )
-        ",
+        let row = client.query_one(
+            "SELECT pg_is_in_recovery(), current_setting('transaction_read_only')",
             &[],
         )?;
+        let (in_recovery, session_read_only): (bool, String) = (row.get(0), row.get(1));
+        if !read_only && (in_recovery || session_read_only == "on") {
+            return Err(anyhow::anyhow!(
+                "{} on {} is read only{}. migrations have to run against the primary. with \
+                multiple hosts set target_session_attrs = \"read-write\"",
+                self.dbname,
+                if self.hosts.is_empty() {
+                    self.host.clone()
+                } else {
+                    self.hosts.join(",")
+                },
+                if in_recovery {
+                    " (a replica in recovery)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        if !tracking_tables_exist(&mut client)? {
+            if read_only {
+                return Err(anyhow::anyhow!(
+                    "the tracking tables of {} don't exist or are out of date. run a command \
+                    that migrates with a role allowed to create them first",
+                    self.dbname
+                ));
+            }
+            create_tracking_tables(&mut client)?;
+        }
         let mut last_version: i64 = 0;
         if let Some(row) = (client.query(
             "SELECT version, dirty FROM schema_migrations ORDER BY version DESC LIMIT 1",
@@ -126,10 +426,8 @@ impl Config {
         {
             let version: i64 = row.get(0);
             let dirty: bool = row.get(1);
-            if dirty {
-                return Err(anyhow::anyhow!(
-                    "last version is dirty. migration had failed previously"
-                ));
+            if dirty && !self.dirty_ok {
+                return Err(ArchitectError::DirtyState { version }.into());
             }
             last_version = version;
         }
@@ -148,28 +446,224 @@ impl Config {
     }
 }
 
+/// Columns of the tracking tables, those added after the first release included.
+const TRACKING_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "schema_migrations",
+        &[
+            "version",
+            "dirty",
+            "applied_at",
+            "applied_by",
+            "duration_ms",
+            "planned_by",
+            "approved_by",
+            "batch",
+        ],
+    ),
+    (
+        "schema_migration_runs",
+        &[
+            "version",
+            "direction",
+            "finished_at",
+            "run_by",
+            "duration_ms",
+            "succeeded",
+        ],
+    ),
+    ("schema_tags", &["tag", "version"]),
+    (
+        "schema_skipped_versions",
+        &["version", "reason", "skipped_at", "skipped_by"],
+    ),
+];
+
+/// Whether the tracking tables exist with all their columns, so nothing needs to be created.
+fn tracking_tables_exist(client: &mut Client) -> Result<bool> {
+    for (table, columns) in TRACKING_COLUMNS {
+        let existing: Vec<String> = client
+            .query(
+                "SELECT attname::text FROM pg_attribute
+                WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped",
+                &[table],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if columns.iter().any(|c| !existing.iter().any(|v| v == c)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn create_tracking_tables(client: &mut Client) -> Result<()> {
+    client.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            dirty BOOLEAN DEFAULT FALSE
+        )
+    ",
+        &[],
+    )?;
+    // columns added after the first release. existing tables are upgraded in place.
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS applied_at TIMESTAMPTZ",
+        &[],
+    )?;
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS applied_by VARCHAR(255)",
+        &[],
+    )?;
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS duration_ms BIGINT",
+        &[],
+    )?;
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS planned_by VARCHAR(255)",
+        &[],
+    )?;
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS approved_by VARCHAR(255)",
+        &[],
+    )?;
+    client.execute(
+        "ALTER TABLE schema_migrations ADD COLUMN IF NOT EXISTS batch BIGINT",
+        &[],
+    )?;
+    client.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_migration_runs (
+            version BIGINT NOT NULL,
+            direction VARCHAR(4) NOT NULL,
+            finished_at TIMESTAMPTZ NOT NULL,
+            run_by VARCHAR(255),
+            duration_ms BIGINT NOT NULL,
+            succeeded BOOLEAN NOT NULL
+        )
+    ",
+        &[],
+    )?;
+    client.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_tags (
+            tag VARCHAR(255) PRIMARY KEY,
+            version BIGINT NOT NULL
+        )
+    ",
+        &[],
+    )?;
+    client.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_skipped_versions (
+            version BIGINT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            skipped_at TIMESTAMPTZ NOT NULL,
+            skipped_by VARCHAR(255)
+        )
+    ",
+        &[],
+    )?;
+    Ok(())
+}
+
+/// The error of a failed connection. The postgres driver doesn't speak Kerberos, GSSAPI or
+/// SSPI, so a server asking for one of them gets a hint instead of the driver's terse message.
+fn connection_error(message: String, user: &str) -> ArchitectError {
+    if !message.ends_with("unsupported authentication method") {
+        return ArchitectError::Connection(message);
+    }
+    ArchitectError::Connection(format!(
+        "{}. the server asks {} for a Kerberos (GSSAPI or SSPI) login, which isn't supported. \
+        have pg_hba.conf accept scram-sha-256, md5 or cert logins for this user",
+        message, user
+    ))
+}
+
+/// The connection string of `params` for logging, without the password.
+fn masked(params: &[String]) -> String {
+    params
+        .iter()
+        .map(|v| match v.starts_with("password=") {
+            true => "password=********",
+            false => v.as_str(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A `key=value` pair of a connection string, quoting the value if needed.
+fn param(key: &str, value: &str) -> String {
+    if !value.is_empty() && !value.contains([' ', '\'', '\\']) {
+        return format!("{}={}", key, value);
+    }
+    format!(
+        "{}='{}'",
+        key,
+        value.replace('\\', "\\\\").replace('\'', "\\'")
+    )
+}
+
 struct Migrator {
-    // config: Config,
+    config: Config,
     dir: std::path::PathBuf,
     last_version: i64,
     client: Client,
     versions_up: Vec<i64>,
     versions_down: Vec<i64>,
     initialized: bool,
+    /// Whether the before_all hook ran already
+    before_all_ran: bool,
+    /// Migrations run by this process, for the summary email
+    runs: Vec<email::Run>,
+    /// Objects before the first migration of the run, see `grants`
+    objects_before: Option<std::collections::HashSet<grants::Object>>,
+    /// Transactions of the migrations of the run, see `grants`
+    run_transactions: Vec<i64>,
+    /// Failures to inject, see `faults`
+    faults: Vec<faults::Fault>,
+    /// Advisory lock held by the session, taken again after reconnecting, see `reconnect`
+    held_lock: Option<String>,
+    /// Batch of the versions migrated up by this run, see `rollback`
+    batch: Option<i64>,
+    /// Versions left out with their reason, see `skip`
+    skipped: std::collections::BTreeMap<i64, String>,
 }
 
 impl Migrator {
-    fn new(mut config: Config, dir: std::path::PathBuf) -> Result<Self> {
+    fn new(config: Config, dir: std::path::PathBuf) -> Result<Self> {
+        Migrator::open(config, dir, false)
+    }
+
+    /// A migrator for commands that only read, working with a read only role or on a replica,
+    /// see `Config::init`. Connects as the `[verifier]` if configured.
+    fn read_only(mut config: Config, dir: std::path::PathBuf) -> Result<Self> {
+        config.as_verifier();
+        Migrator::open(config, dir, true)
+    }
+
+    fn open(mut config: Config, dir: std::path::PathBuf, read_only: bool) -> Result<Self> {
         let dir = config.dir(&dir)?;
-        let (client, last_version) = config.init()?;
+        let (client, last_version) = config.init(read_only)?;
+        let skipped = skip::from_config(&config);
         let mut m = Migrator {
-            // config,
+            config,
             dir,
             last_version,
             client,
             versions_up: Vec::<i64>::new(),
             versions_down: Vec::<i64>::new(),
             initialized: false,
+            before_all_ran: false,
+            batch: None,
+            skipped,
+            runs: Vec::new(),
+            objects_before: None,
+            run_transactions: Vec::new(),
+            faults: faults::from_env()?,
+            held_lock: None,
         };
         m.initialized = true;
         m.available_versions()?;
@@ -198,49 +692,7 @@ version has a corresponding `down` version in your migration directory",
             return Err(anyhow::anyhow!("Migrator not initialized"));
         }
 
-        let reg = regex::Regex::new(r"^([1-9][0-9]*)_(up|down)\.sql$")?;
-        let mut vup = Vec::<i64>::new();
-        let mut vdown = Vec::<i64>::new();
-        for f in std::fs::read_dir(&self.dir)? {
-            let f = f?;
-            let f = if let Some(v) = f.file_name().to_str() {
-                String::from(v)
-            } else {
-                eprintln!("osstring to str failed");
-                continue;
-            };
-            let caps = if let Some(v) = reg.captures(&f) {
-                v
-            } else {
-                continue;
-            };
-
-            let version = match caps.get(1) {
-                Some(v) => String::from(v.as_str()),
-                None => {
-                    continue;
-                }
-            };
-            let direction = match caps.get(2) {
-                Some(v) => String::from(v.as_str()),
-                None => {
-                    continue;
-                }
-            };
-
-            match version.parse::<i64>() {
-                Ok(v) => {
-                    if &direction == "up" {
-                        vup.push(v);
-                    } else if &direction == "down" {
-                        vdown.push(v);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
-            }
-        }
+        let (vup, vdown) = index::versions(&self.dir)?;
         self.versions_up = vup;
         self.versions_down = vdown;
         self.versions_up.sort();
@@ -249,35 +701,76 @@ version has a corresponding `down` version in your migration directory",
         Ok(())
     }
 
-    fn new_migration(&mut self) -> Result<()> {
+    fn applied_versions(&mut self) -> Result<Vec<i64>> {
+        let mut versions = Vec::<i64>::new();
+        for row in self.client.query(
+            "SELECT version FROM schema_migrations ORDER BY version",
+            &[],
+        )? {
+            versions.push(row.get(0));
+        }
+        Ok(versions)
+    }
+
+    /// Creates the empty up and down files of a new version, returning their paths.
+    fn new_migration(&mut self) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
         let ts = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis();
-        let up = self.dir.join(format!("{ts}_up.sql"));
-        let down = self.dir.join(format!("{ts}_down.sql"));
-        if up.exists() {
-            return Err(anyhow::anyhow!(format!("file {:?} exists", &up)));
-        }
-        let _ = std::fs::File::create(&up)?;
-        if down.exists() {
-            return Err(anyhow::anyhow!(format!("file {:?} exists", &down)));
-        }
-        let _ = std::fs::File::create(&down)?;
+        let (up, down) = self.allocate_version(ts as i64)?;
         self.available_versions()?;
-        eprintln!("new migration files created:");
-        eprintln!("{:?}", up);
-        eprintln!("{:?}", down);
-        Ok(())
+        output::result(
+            &format!("new migration files created:\n{:?}\n{:?}", up, down),
+            serde_json::json!({ "up": up, "down": down }),
+        );
+        Ok((up, down))
+    }
+
+    /// Creates the files of the first free version from `version` on. A version is taken if it
+    /// has a file of any kind or is in `schema_migrations`. Files are created exclusively, so
+    /// concurrent processes, e.g. parallel CI jobs, bump past each other instead of sharing one.
+    fn allocate_version(
+        &mut self,
+        mut version: i64,
+    ) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+        let applied = self.applied_versions()?;
+        loop {
+            let taken = applied.contains(&version)
+                || migration_file(&self.dir, version, Direction::Up).exists()
+                || migration_file(&self.dir, version, Direction::Down).exists();
+            if !taken {
+                let up = self.dir.join(format!("{version}_up.sql"));
+                let down = self.dir.join(format!("{version}_down.sql"));
+                if create_new(&up)? {
+                    if create_new(&down)? {
+                        return Ok((up, down));
+                    }
+                    std::fs::remove_file(&up)?;
+                }
+            }
+            version += 1;
+        }
+    }
+
+    /// Path of a migration file, the script if the migration is one.
+    fn migration_path(&self, version: i64, direction: Direction) -> std::path::PathBuf {
+        migration_file(&self.dir, version, direction)
     }
 
-    fn get_queries(&self, version: i64, direction: &str) -> Result<Vec<String>> {
+    fn get_queries(&self, version: i64, direction: Direction) -> Result<Vec<String>> {
         let mut result = Vec::<String>::new();
 
+        if let Some(path) = self.script_path(version, direction) {
+            return Err(anyhow::anyhow!(
+                "migration: {:?} is a script and has no sql statements",
+                path
+            ));
+        }
         let filename = self.dir.join(format!("{}_{}.sql", &version, &direction));
         if !filename.exists() {
             return Err(anyhow::anyhow!(format!(
                 "migration: \"{}_{}.sql\" does not exist",
-                &version, &direction
+                &version, direction
             )));
         }
 
@@ -289,33 +782,194 @@ version has a corresponding `down` version in your migration directory",
         };
 
         let s = std::fs::read_to_string(&f)?;
-        let dialect = sqlparser::dialect::PostgreSqlDialect {};
-        let ast = sqlparser::parser::Parser::parse_sql(&dialect, &s)?;
-        for v in ast.iter() {
-            result.push(v.to_string());
-        }
-        if direction == "up" {
-            result.push(format!(
-                "INSERT INTO schema_migrations(version) VALUES ({version})",
-            ));
-        } else if direction == "down" {
-            result.push(format!(
-                "DELETE FROM schema_migrations WHERE version = {version}",
-            ))
-        }
+        result.append(&mut parse_statements(&s)?);
+        result.push(record_query(version, direction, self.batch));
 
         Ok(result)
     }
 
-    fn run_migration(&mut self, version: i64, direction: String) -> Result<()> {
+    fn run_migration(&mut self, version: i64, direction: Direction) -> Result<()> {
         // eprintln!("run_migration called");
-        let queries = self.get_queries(version, &direction)?;
+        self.before_all(version, direction)?;
+        self.notify_listeners("started", version, direction, None, None);
+        let start = std::time::Instant::now();
+        let heartbeat = self.start_heartbeat(version);
+        let result = self.apply_migration(version, direction);
+        if let Some(v) = heartbeat {
+            v.stop();
+        }
+        let duration_ms = start.elapsed().as_millis();
+        self.finish_run(version, direction, duration_ms, result)?;
+        self.run_post_steps(version, direction)
+    }
+
+    /// Runs the before_all hook before the first migration of the run.
+    fn before_all(&mut self, version: i64, direction: Direction) -> Result<()> {
+        if !self.before_all_ran {
+            self.snapshot_objects()?;
+            let hooks = self.config.hooks.clone();
+            self.run_hook("before_all", &hooks.before_all, version, direction, None)?;
+            self.before_all_ran = true;
+        }
+        if self.batch.is_none() && direction == Direction::Up {
+            self.batch = Some(rollback::next_batch(&mut self.client)?);
+        }
+        Ok(())
+    }
+
+    /// Records the run of a migration that finished with `result`, notifies the hooks and
+    /// plugins and returns the error of a failed run.
+    fn finish_run(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        duration_ms: u128,
+        result: Result<Vec<email::StatementRun>>,
+    ) -> Result<()> {
+        let hooks = self.config.hooks.clone();
+        if let Err(e) = self.record_run(version, direction, duration_ms, result.is_ok()) {
+            eprintln!("recording the run failed: {}", e);
+        }
+        let (statements, result) = match result {
+            Ok(v) => (v, Ok(())),
+            Err(e) => (Vec::new(), Err(e)),
+        };
+        self.runs.push(email::Run {
+            version,
+            direction,
+            duration_ms,
+            error: result.as_ref().err().map(|e| e.to_string()),
+            statements,
+            post: Vec::new(),
+        });
+        if let Err(e) = result {
+            let error = e.to_string();
+            let hook = self.run_hook(
+                "on_failure",
+                &hooks.on_failure,
+                version,
+                direction,
+                Some(&error),
+            );
+            if let Err(e) = hook {
+                eprintln!("{}", e);
+            }
+            let event = plugins::Event::MigrationFailed {
+                app: &self.config.app,
+                dbname: &self.config.dbname,
+                version,
+                direction: direction.as_str(),
+                error: &error,
+            };
+            if let Err(e) = plugins::notify(&self.config.plugins, &event) {
+                eprintln!("{}", e);
+            }
+            self.notify_listeners("failed", version, direction, None, Some(&error));
+            return Err(error::migration_failed(version, direction, e));
+        }
+        let event = plugins::Event::MigrationApplied {
+            app: &self.config.app,
+            dbname: &self.config.dbname,
+            version,
+            direction: direction.as_str(),
+        };
+        if let Err(e) = plugins::notify(&self.config.plugins, &event) {
+            eprintln!("{}", e);
+        }
+        self.notify_listeners("succeeded", version, direction, Some(duration_ms), None);
+        self.run_hook("after_each", &hooks.after_each, version, direction, None)
+    }
+
+    /// Applies a migration, returning the timings of its statements. Scripts aren't timed per
+    /// statement.
+    fn apply_migration(
+        &mut self,
+        version: i64,
+        direction: Direction,
+    ) -> Result<Vec<email::StatementRun>> {
+        if let Some(path) = self.script_path(version, direction) {
+            self.run_script(version, direction, &path)?;
+            return Ok(Vec::new());
+        }
+        if let Some(path) = self.streamed(version, direction) {
+            return self.apply_streamed(version, direction, &path);
+        }
+        let sql = self.sql(version, direction)?;
+        if let Some(args) = foreign_key::directive(&sql) {
+            return self.apply_validate_constraint(version, direction, &sql, &args);
+        }
+        if let Some(args) = batch_update::directive(&sql) {
+            return self.apply_batch_update(version, direction, &sql, &args);
+        }
+        let mut queries = self.get_queries(version, direction)?;
+        // the last query records the version
+        let record = queries.pop().unwrap_or_default();
+        let mut blocks = conditions::Blocks::parse(&sql)?;
+        let before_each = self.sql_hook("before_each")?;
+        let after_each = self.sql_hook("after_each")?;
+        let faults = self.faults.clone();
+        let pooled = self.pooled();
+        let start = std::time::Instant::now();
         let mut t = self.client.transaction()?;
-        for query in queries {
-            t.batch_execute(&query)?;
+        if pooled {
+            self.config.apply_session(&mut t, true)?;
+        }
+        if !before_each.is_empty() || !after_each.is_empty() {
+            t.execute(
+                "SELECT set_config('architect.version', $1, true), \
+                set_config('architect.direction', $2, true)",
+                &[&version.to_string(), &direction.as_str()],
+            )?;
+        }
+        for query in before_each.iter() {
+            t.batch_execute(query)?;
+        }
+        let mut statements = Vec::<email::StatementRun>::new();
+        for (i, query) in queries.iter().enumerate() {
+            if !blocks.runs(&mut t, i)? {
+                let skipped = email::StatementRun {
+                    statement: query.clone(),
+                    duration_ms: 0,
+                };
+                info!("{} {:>10}  {}", version, "skipped", skipped.short());
+                continue;
+            }
+            let start = std::time::Instant::now();
+            if let Err(e) = views::execute(&mut t, query) {
+                return Err(ArchitectError::MigrationFailed {
+                    version,
+                    direction: direction.to_owned(),
+                    statement: Some(query.clone()),
+                    source: e,
+                }
+                .into());
+            }
+            faults::inject(&faults, version, faults::Point::Statement(i + 1))?;
+            let run = email::StatementRun {
+                statement: query.clone(),
+                duration_ms: start.elapsed().as_millis(),
+            };
+            info!("{} {:>8}ms  {}", version, run.duration_ms, run.short());
+            statements.push(run);
+        }
+        assertions::run(&mut t, &sql)?;
+        faults::inject(&faults, version, faults::Point::Record)?;
+        t.batch_execute(&record)?;
+        for query in after_each.iter() {
+            t.batch_execute(query)?;
         }
+        if direction == Direction::Up {
+            let duration_ms = start.elapsed().as_millis() as i64;
+            t.execute(
+                "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+                &[&duration_ms, &version],
+            )?;
+        }
+        let transaction = grants::transaction_id(&mut t)?;
         t.commit()?;
-        Ok(())
+        self.run_transactions.push(transaction);
+        faults::inject(&faults, version, faults::Point::Commit)?;
+        Ok(statements)
     }
 
     fn migrate_up_n(&mut self, n: usize, test: bool) -> Result<usize> {
@@ -327,34 +981,33 @@ version has a corresponding `down` version in your migration directory",
             return Err(anyhow::anyhow!("migrating {} steps makes no sense!", n));
         }
 
-        let mut version = self.last_version;
-        let mut versions = Vec::<i64>::new();
-        let mut count = 0;
-        for v in self.versions_up.iter() {
-            if *v > version && count < n {
-                versions.push(*v);
-                version = *v;
-                count += 1;
-            }
-        }
+        let mut steps = self.steps(self.last_version, i64::MAX);
+        steps.truncate(n);
+        self.run_steps(&steps, test)
+    }
 
-        for v in versions.iter() {
-            if !test {
-                match self.run_migration(*v, "up".to_owned()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(anyhow::anyhow!(format!(
-                            "error running migration {}_up.sql",
-                            *v
-                        )));
-                    }
-                }
-            }
-            self.last_version = *v;
-        }
+    /// Versions migrated up by this process, in the order they were applied.
+    fn applied_in_run(&self) -> Vec<i64> {
+        self.runs
+            .iter()
+            .filter(|r| r.direction == Direction::Up && r.error.is_none())
+            .map(|r| r.version)
+            .collect()
+    }
 
-        Ok(versions.len())
+    /// Whether this process migrated any version, up or down.
+    fn migrated_in_run(&self) -> bool {
+        self.runs.iter().any(|r| r.error.is_none())
+    }
+
+    /// The steps following the migrations of a run: resetting sequences, normalizing grants of
+    /// new objects, building the next blue/green schema and refreshing materialized views.
+    /// Returns the refreshes.
+    fn after_run(&mut self) -> Result<Vec<refresh::RefreshRun>> {
+        self.fix_sequences_after_run()?;
+        self.normalize_grants()?;
+        self.build_next_schema_after_run()?;
+        self.refresh_views()
     }
 
     fn migrate_up(&mut self, test: bool) -> Result<usize> {
@@ -362,31 +1015,8 @@ version has a corresponding `down` version in your migration directory",
             return Err(anyhow::anyhow!("no migrations found"));
         }
 
-        let mut version = self.last_version;
-        let mut versions = Vec::<i64>::new();
-        for v in self.versions_up.iter() {
-            if *v > version {
-                versions.push(*v);
-                version = *v;
-            }
-        }
-
-        for v in versions.iter() {
-            if !test {
-                match self.run_migration(*v, "up".to_owned()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(anyhow::anyhow!(format!(
-                            "error running migration {}_up.sql",
-                            *v
-                        )));
-                    }
-                }
-            }
-            self.last_version = *v;
-        }
-        Ok(versions.len())
+        let steps = self.steps(self.last_version, i64::MAX);
+        self.run_steps(&steps, test)
     }
 
     fn migrate_down_n(&mut self, n: usize, test: bool) -> Result<usize> {
@@ -398,44 +1028,9 @@ version has a corresponding `down` version in your migration directory",
             return Err(anyhow::anyhow!("migrating {} steps down makes no sense", n));
         }
 
-        let mut version = self.last_version;
-        let mut versions = Vec::<i64>::new();
-        let mut count = 0;
-        let mut index = 0;
-        for (i, v) in self.versions_down.iter().rev().enumerate() {
-            if *v <= version && count < n {
-                versions.push(*v);
-                version = *v;
-                count += 1;
-                index = self.versions_down.len() - 1 - i;
-            }
-        }
-        eprintln!("index: {}", &index);
-
-        for v in versions.iter() {
-            self.last_version = *v;
-            if !test {
-                match self.run_migration(*v, "down".to_owned()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(anyhow::anyhow!(format!(
-                            "error running migration {}_up.sql",
-                            *v
-                        )));
-                    }
-                }
-            }
-        }
-        if index > 0 {
-            if let Some(v) = self.versions_down.get(index - 1) {
-                self.last_version = *v;
-            }
-        } else {
-            self.last_version = 0;
-        }
-
-        Ok(versions.len())
+        let mut steps = self.steps(self.last_version, 0);
+        steps.truncate(n);
+        self.run_steps(&steps, test)
     }
 
     fn migrate_down(&mut self, test: bool) -> Result<usize> {
@@ -443,36 +1038,151 @@ version has a corresponding `down` version in your migration directory",
             return Err(anyhow::anyhow!("no migrations found"));
         }
 
-        let mut version = self.last_version;
-        let mut versions = Vec::<i64>::new();
-        for v in self.versions_down.iter().rev() {
-            if *v <= version {
-                versions.push(*v);
-                version = *v;
-            }
-        }
-
-        for v in versions.iter() {
-            self.last_version = *v;
-            if !test {
-                match self.run_migration(*v, "down".to_owned()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        return Err(anyhow::anyhow!(format!(
-                            "error running migration {}_up.sql",
-                            *v
-                        )));
-                    }
-                }
-            }
-        }
+        let steps = self.steps(self.last_version, 0);
+        let count = self.run_steps(&steps, test)?;
         self.last_version = 0;
+        Ok(count)
+    }
+
+    fn goto(&mut self, target: i64, test: bool) -> Result<usize> {
+        if target != 0 && !self.versions_up.contains(&target) {
+            return Err(anyhow::anyhow!("version {} does not exist", target));
+        }
 
-        Ok(versions.len())
+        let steps = self.steps(self.last_version, target);
+        let count = self.run_steps(&steps, test)?;
+        self.last_version = target;
+        Ok(count)
     }
 }
 
+/// Naming convention of migration files: `<version>_(up|down).sql`, or `.rhai` for scripts and
+/// `.wasm` for WASM modules
+const MIGRATION_FILE: &str = r"^([1-9][0-9]*)_(up|down)\.(sql|rhai|wasm)$";
+
+/// Path of a migration file in `dir`, the script or WASM module if the migration is one.
+fn migration_file(dir: &std::path::Path, version: i64, direction: Direction) -> std::path::PathBuf {
+    for extension in ["rhai", "wasm"] {
+        let path = dir.join(format!("{}_{}.{}", version, direction, extension));
+        if path.exists() {
+            return path;
+        }
+    }
+    dir.join(format!("{}_{}.sql", version, direction))
+}
+
+/// Creates an empty file, false if it exists already.
+fn create_new(path: &std::path::Path) -> Result<bool> {
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(anyhow::anyhow!("can't create {:?}: {}", path, e)),
+    }
+}
+
+/// Statement recording that a migration was applied in `batch` or reverted.
+fn record_query(version: i64, direction: Direction, batch: Option<i64>) -> String {
+    match (direction, batch) {
+        (Direction::Up, Some(batch)) => format!(
+            "INSERT INTO schema_migrations(version, applied_at, applied_by, batch) \
+            VALUES ({version}, now(), current_user, {batch})"
+        ),
+        (Direction::Up, None) => format!(
+            "INSERT INTO schema_migrations(version, applied_at, applied_by) \
+            VALUES ({version}, now(), current_user)"
+        ),
+        (Direction::Down, _) => format!("DELETE FROM schema_migrations WHERE version = {version}"),
+    }
+}
+
+/// Directories with more entries than this are matched on several threads.
+const PARALLEL_SCAN: usize = 2000;
+
+/// The version and direction of the migration file `name`, None for other files.
+fn file_version(reg: &regex::Regex, name: &str) -> Option<(i64, Direction)> {
+    let caps = reg.captures(name)?;
+    let version = match caps.get(1)?.as_str().parse::<i64>() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+    Some((version, Direction::parse(caps.get(2)?.as_str())?))
+}
+
+/// Scans `dir` for migration files, returning the up and the down versions found.
+fn scan_versions(dir: &std::path::Path) -> Result<(Vec<i64>, Vec<i64>)> {
+    let reg = regex::Regex::new(MIGRATION_FILE)?;
+    let mut names = Vec::<String>::new();
+    for f in std::fs::read_dir(dir)? {
+        let f = f?;
+        match f.file_name().to_str() {
+            Some(v) => names.push(String::from(v)),
+            None => eprintln!("osstring to str failed"),
+        }
+    }
+    let threads = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let found: Vec<(i64, Direction)> = if names.len() > PARALLEL_SCAN && threads > 1 {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .chunks(names.len().div_ceil(threads))
+                .map(|chunk| {
+                    let reg = &reg;
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|v| file_version(reg, v))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap_or_default())
+                .collect()
+        })
+    } else {
+        names.iter().filter_map(|v| file_version(&reg, v)).collect()
+    };
+    let mut vup = Vec::<i64>::new();
+    let mut vdown = Vec::<i64>::new();
+    for (version, direction) in found {
+        match direction {
+            Direction::Up => vup.push(version),
+            Direction::Down => vdown.push(version),
+        }
+    }
+    // a version may have both a sql and a script file, validate reports that
+    vup.sort();
+    vup.dedup();
+    vdown.sort();
+    vdown.dedup();
+    Ok((vup, vdown))
+}
+
+/// Parses sql into its statements. Verbatim sql has none architect can see.
+fn parse_ast(sql: &str) -> Result<Vec<sqlparser::ast::Statement>> {
+    if directives::has(sql, directives::VERBATIM) {
+        return Ok(Vec::new());
+    }
+    let dialect = sqlparser::dialect::PostgreSqlDialect {};
+    Ok(sqlparser::parser::Parser::parse_sql(&dialect, sql)?)
+}
+
+/// Splits sql into its statements, each normalized by printing it back from the parsed AST.
+/// Verbatim sql is kept whole.
+fn parse_statements(sql: &str) -> Result<Vec<String>> {
+    if directives::has(sql, directives::VERBATIM) {
+        return Ok(vec![sql.to_owned()]);
+    }
+    Ok(parse_ast(sql)?.iter().map(|v| v.to_string()).collect())
+}
+
 #[derive(Debug, Parser)]
 #[command(author,version,about,long_about=None)]
 struct Args {
@@ -480,36 +1190,633 @@ struct Args {
     /// defined by configs passed. These subdirectories contain the actual migration files.
     #[arg(short, long, default_value = "./migrations")]
     migdir: String,
-    /// Path to the config file that contains the app name and database connection parameters
+    /// Path to the config file that contains the app name and database connection parameters.
+    /// Defaults to the first of ./architect.toml, $XDG_CONFIG_HOME/architect/config.toml and
+    /// ~/.architect.toml that exists. Can be repeated to merge override files into a base config
     #[arg(short, long)]
-    config: String,
-    /// Migrate upwards n steps from last recorded version
-    #[arg(long, default_value = "0")]
-    upn: usize,
-    /// Migrate up all existing versions from the last recorded version
-    #[arg(long)]
-    up: bool,
-    // Migrate downwards n steps from last recorded version
-    #[arg(long, default_value = "0")]
-    downn: usize,
-    /// Migrate downwards all version from last recorded version. Be careful!
+    config: Vec<String>,
+    /// Env file loaded before reading the config. Defaults to ./.env if it exists
     #[arg(long)]
-    down: bool,
-    /// Create a new migration version. <timestamp>_up.sql and <timestamp>_down.sql files are
-    /// created on running this.
-    #[arg(short, long)]
-    new: bool,
+    env_file: Option<std::path::PathBuf>,
     /// Invoke the wizard for a guided migration experience.
     #[arg(short, long)]
     wizard: bool,
+    /// Skip confirming destructive actions on a database configured as protected
+    #[arg(long = "i-know-what-im-doing", global = true)]
+    force: bool,
+    /// Suppress informational output. Commands print their result as a single line of JSON
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a new migration version, <timestamp>_up.sql and <timestamp>_down.sql
+    New {
+        /// Open the new files in $VISUAL or $EDITOR
+        #[arg(long)]
+        edit: bool,
+        /// Author recorded in the files. Defaults to git's user.name and user.email
+        #[arg(long)]
+        author: Option<String>,
+    },
+    /// Migrate up all versions after the last applied version
+    Up {
+        /// Run the migrations in a transaction that is rolled back at the end, reporting the
+        /// statements that would have failed
+        #[arg(long)]
+        sandbox: bool,
+        /// Print the runs with the timings of their statements as JSON
+        #[arg(long, conflicts_with = "sandbox")]
+        json: bool,
+        /// Apply every pending migration's up, down and up again in a transaction that is rolled
+        /// back, like test-reversibility, and refuse to migrate if a down migration is broken
+        #[arg(long, conflicts_with = "sandbox")]
+        verify_down: bool,
+        /// Run verify-data after migrating and migrate the applied migrations down again if a
+        /// check fails, unless one of them can't be reversed
+        #[arg(long, conflicts_with = "sandbox")]
+        verify_then_rollback_on_failure: bool,
+        /// Load the pending migrations, resolving their directives and splitting their statements,
+        /// and report each file without executing anything
+        #[arg(long, conflicts_with_all = ["sandbox", "verify_down", "verify_then_rollback_on_failure"])]
+        dry_run: bool,
+        /// Apply independent migrations over this many connections at once, see
+        /// `-- architect:independent`
+        #[arg(long, value_name = "N", conflicts_with_all = ["sandbox", "dry_run"])]
+        parallel: Option<usize>,
+        /// Migrate every app listed under `apps` in `.architect.toml` one after the other, once
+        /// all of them passed their preflight checks
+        #[arg(long, conflicts_with_all = ["sandbox", "dry_run", "parallel", "json", "verify_down", "verify_then_rollback_on_failure"])]
+        all_apps: bool,
+        /// Apps checked at once with --all-apps
+        #[arg(long, default_value_t = apps::JOBS, requires = "all_apps")]
+        jobs: usize,
+        /// Only migrate up versions created before this time, YYYY-MM-DD, YYYY-MM-DD HH:MM[:SS]
+        /// in UTC or RFC 3339
+        #[arg(long, value_name = "TIMESTAMP", conflicts_with_all = ["sandbox", "dry_run", "parallel", "all_apps"])]
+        to_date: Option<String>,
+        /// Only migrate up this many versions
+        #[arg(long, value_name = "N", conflicts_with_all = ["sandbox", "dry_run", "parallel", "all_apps", "to_date"])]
+        steps: Option<usize>,
+        /// Leave out this version (or tag), in addition to `skip_versions` of the config
+        #[arg(
+            long,
+            value_name = "VERSION",
+            requires = "skip_reason",
+            conflicts_with = "all_apps"
+        )]
+        skip: Vec<String>,
+        /// Why the versions of --skip are skipped, recorded once the run passes them
+        #[arg(long, value_name = "TEXT", requires = "skip")]
+        skip_reason: Option<String>,
+    },
+    /// Show the pending migrations with their statements, lint findings and policy violations
+    /// for the configured environment. Exits with an error if there are errors.
+    Plan {
+        /// Write the plan signed with the approval key to a file, to be approved and applied
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+        /// Identity of the planner. Defaults to ARCHITECT_OPERATOR, then USER
+        #[arg(long)]
+        operator: Option<String>,
+        /// Plan every app listed under `apps` in `.architect.toml`, concurrently
+        #[arg(long, conflicts_with = "out")]
+        all_apps: bool,
+        /// Apps planned at once with --all-apps
+        #[arg(long, default_value_t = apps::JOBS, requires = "all_apps")]
+        jobs: usize,
+    },
+    /// Approve a plan file written by `plan --out` as a second operator
+    Approve {
+        plan: std::path::PathBuf,
+        /// Identity of the approver. Defaults to ARCHITECT_OPERATOR, then USER
+        #[arg(long)]
+        operator: Option<String>,
+    },
+    /// Apply the migrations of an approved plan file
+    Apply { plan: std::path::PathBuf },
+    /// Tag a migration version with a name, e.g. a release name. Tags are accepted anywhere a
+    /// version is expected.
+    Tag {
+        /// Name of the tag
+        name: String,
+        /// The version (or an existing tag) to point the tag at
+        #[arg(long)]
+        version: String,
+    },
+    /// Migrate up or down until the given version is the last applied one. Use 0 to migrate
+    /// down all versions.
+    Goto {
+        /// The version or tag to migrate to
+        #[arg(long)]
+        version: String,
+    },
+    /// Migrate down the last versions applied, all of them, or those created at or after a time
+    #[command(group(clap::ArgGroup::new("versions").required(true)))]
+    Down {
+        /// Migrate down this many versions
+        #[arg(long, value_name = "N", group = "versions")]
+        steps: Option<usize>,
+        /// Migrate down all applied versions. Be very sure this is what you want
+        #[arg(long, group = "versions")]
+        all: bool,
+        /// Migrate down the versions created at or after this time, so only older versions stay
+        /// applied. YYYY-MM-DD, YYYY-MM-DD HH:MM[:SS] in UTC or RFC 3339
+        #[arg(long, value_name = "TIMESTAMP", group = "versions")]
+        before: Option<String>,
+    },
+    /// Migrate down the versions applied by the last run migrating up
+    Rollback {
+        /// Revert the versions of the last batch, recorded for every run
+        #[arg(long, required = true)]
+        last_batch: bool,
+    },
+    /// List migration versions with their description, file sizes and state
+    List {
+        /// Only list versions that have not been applied yet
+        #[arg(long, conflicts_with = "applied")]
+        pending: bool,
+        /// Only list versions that have been applied
+        #[arg(long)]
+        applied: bool,
+        /// Only list versions created on or after this date (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only list versions whose up file name or description matches this glob
+        #[arg(long = "match")]
+        pattern: Option<String>,
+        /// List the newest versions first
+        #[arg(long)]
+        reverse: bool,
+    },
+    /// Show every migration with whether and when it was applied, and whether the last version
+    /// is dirty
+    Status,
+    /// Show applied migrations in the order they were applied, with who applied them and how
+    /// long each took
+    History {
+        /// Only show the last n applied migrations
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Print the statements of a migration exactly as they would be executed
+    Show {
+        /// The version or tag to show
+        version: String,
+        /// Show the down migration instead of the up migration
+        #[arg(long)]
+        down: bool,
+    },
+    /// Diff the statements of two migrations, or of one migration against its version at a git
+    /// revision. Formatting and comment changes are ignored.
+    DiffMigrations {
+        /// The version or tag to diff from
+        v1: String,
+        /// The version or tag to diff to
+        #[arg(required_unless_present = "against", conflicts_with = "against")]
+        v2: Option<String>,
+        /// Diff `v1` as of this git revision against the file in the working tree
+        #[arg(long)]
+        against: Option<String>,
+        /// Diff the down migrations instead of the up migrations
+        #[arg(long)]
+        down: bool,
+    },
+    /// Write an expand and a contract migration renaming a column without losing its data,
+    /// instead of dropping it and adding another
+    RenameColumn {
+        table: String,
+        from: String,
+        to: String,
+        /// Write the migrations without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write the migrations changing a column's type or name online: adding a new column kept
+    /// in sync by a trigger, backfilling it in batches, swapping it in and dropping the old one
+    ChangeColumn {
+        table: String,
+        column: String,
+        /// The new type of the column, unchanged if not given
+        #[arg(long = "type")]
+        data_type: Option<String>,
+        /// The new name of the column, unchanged if not given
+        #[arg(long)]
+        to: Option<String>,
+        /// Expression converting the old column's values, a cast to the new type by default
+        #[arg(long)]
+        using: Option<String>,
+        /// Integer key of the table the backfill batches by
+        #[arg(long, default_value = "id")]
+        key: String,
+        /// Rows per backfill batch
+        #[arg(long, default_value_t = 1000)]
+        size: i64,
+        /// Write the migrations without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write the migrations adding a foreign key without blocking writes: adding it NOT VALID
+    /// and validating the existing rows in a separate migration
+    AddForeignKey {
+        table: String,
+        column: String,
+        /// The referenced column, as TABLE(COLUMN)
+        #[arg(long)]
+        references: String,
+        /// Name of the constraint, TABLE_COLUMN_fkey by default
+        #[arg(long, default_value = "")]
+        name: String,
+        /// Action on deleting a referenced row, e.g. CASCADE
+        #[arg(long)]
+        on_delete: Option<String>,
+        /// Milliseconds the validation waits for its lock before giving up
+        #[arg(long, default_value_t = 5000)]
+        lock_timeout_ms: u64,
+        /// Times the validation is tried again when it timed out waiting for its lock
+        #[arg(long, default_value_t = 10)]
+        retries: u32,
+        /// Milliseconds to pause before trying again
+        #[arg(long, default_value_t = 5000)]
+        pause_ms: u64,
+        /// Write the migrations without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Run the data expectations in the app's expect directory, failing if any isn't met
+    VerifyData,
+    /// Compare the owner and privileges of every object with the [grants] spec, failing on drift
+    VerifyGrants,
+    /// Reset the sequences of serial and identity columns to continue after the largest value in
+    /// their column
+    FixSequences {
+        /// The tables, those of `fix_sequences` in `.architect.toml` if none are given
+        tables: Vec<String>,
+    },
+    /// Print the schema as DDL, either as it is in the database or as it was at a version
+    Schema {
+        /// Apply migrations till this version or tag to an empty throw away schema and print
+        /// the result instead
+        #[arg(long)]
+        at: Option<String>,
+    },
+    /// Write docs of the schema, a page per table with its columns, constraints, indexes and
+    /// comments, into _docs in the app's migration directory
+    Docs {
+        /// Document the schema resulting from applying migrations till this version or tag to an
+        /// empty throw away schema instead
+        #[arg(long)]
+        at: Option<String>,
+        /// markdown or html
+        #[arg(long, default_value = "markdown")]
+        format: String,
+        /// Directory to write the docs to instead
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+    },
+    /// Export the schema catalog, its tables with columns, constraints, indexes and comments and
+    /// its views, for data catalogs and other downstream tooling
+    Catalog {
+        /// Export the schema resulting from applying migrations till this version or tag to an
+        /// empty throw away schema instead
+        #[arg(long)]
+        at: Option<String>,
+        /// json or csv
+        #[arg(long, default_value = "json")]
+        format: String,
+        /// Write the catalog to this file instead of stdout
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+    },
+    /// Print an entity-relationship diagram of the schema with its tables, columns and foreign
+    /// keys
+    Erd {
+        /// Diagram the schema resulting from applying migrations till this version or tag to an
+        /// empty throw away schema instead
+        #[arg(long)]
+        at: Option<String>,
+        /// mermaid or dot
+        #[arg(long, default_value = "mermaid")]
+        format: String,
+    },
+    /// Check migration files without connecting to the database: naming, up and down pairs,
+    /// parsing, directives and lints, and show the teams owning migrations. Exits with an error if
+    /// any check fails.
+    #[command(alias = "lint")]
+    Validate,
+    /// Format sql migrations canonically, a statement per line printed back from its parsed AST.
+    /// Comments are kept
+    Fmt {
+        /// Only list the files that aren't formatted, failing if there are any
+        #[arg(long)]
+        check: bool,
+    },
+    /// Package the binary, the migrations, a config template and an install.sh applying them into
+    /// a directory, for installations without access to the repository
+    Bundle {
+        /// Directory the bundle is written to. It has to be empty or not exist
+        #[arg(long)]
+        out: std::path::PathBuf,
+    },
+    /// Write architect.lock with a checksum of every migration. `up` and `goto` refuse to run
+    /// when the migration files don't match it.
+    Lock,
+    /// Fail if this branch adds migrations with versions older than the newest migration on the
+    /// base git revision. They would be skipped wherever the base's migrations are applied.
+    MergeCheck {
+        /// The git ref the branch is merged into, e.g. origin/main
+        #[arg(long)]
+        base: String,
+    },
+    /// Apply each pending migration's up, down and up again in a throw away schema and check that
+    /// down reverts up
+    TestReversibility,
+    /// Manage the shadow database configured with `shadow_dbname`
+    Shadow {
+        #[command(subcommand)]
+        command: ShadowCommand,
+    },
+    /// Summarize the apply times of migrations recorded in `schema_migration_runs`
+    Report {
+        /// Only show the slowest migrations, by their longest run
+        #[arg(long)]
+        slowest: bool,
+        /// Number of migrations shown with --slowest
+        #[arg(long, default_value = "10")]
+        limit: usize,
+        /// Configs of further databases to include, e.g. other environments or shards
+        #[arg(long = "with")]
+        with: Vec<std::path::PathBuf>,
+    },
+    /// Summarize the versions of a fleet of databases sharing these migrations, e.g. shards
+    Fleet {
+        /// Connection configs of the databases
+        #[arg(required = true)]
+        configs: Vec<std::path::PathBuf>,
+        /// Databases checked at once
+        #[arg(long, default_value_t = apps::JOBS)]
+        jobs: usize,
+        /// List every database, not only those that aren't up to date
+        #[arg(long)]
+        all: bool,
+        /// Check again every SECONDS, reusing the connections
+        #[arg(long, value_name = "SECONDS")]
+        watch: Option<u64>,
+    },
+    /// Create, run and list data migrations, batched backfills tracked apart from schema
+    /// migrations
+    Data {
+        #[command(subcommand)]
+        command: DataCommand,
+    },
+    /// Enable row-level security on tables and check the schemas mandating it, see `[rls]` in
+    /// `.architect.toml`
+    Rls {
+        #[command(subcommand)]
+        command: RlsCommand,
+    },
+    /// Advance the rollout of the latest migration across the clusters of `[[rollout]]` in
+    /// `.architect.toml`, one after the other as each passes its gates
+    Rollout {
+        /// Approve the stage with this name
+        #[arg(long)]
+        approve: Option<String>,
+        /// Who approves, defaults to $ARCHITECT_OPERATOR, then $USER
+        #[arg(long, requires = "approve")]
+        operator: Option<String>,
+    },
+    /// Answer JSON requests (plan, apply, status, lock) read line by line from stdin on stdout
+    Rpc,
+    /// Watch a git repository and apply the migrations merged into it, as `up` does
+    Reconcile {
+        /// URL or path of the repository
+        #[arg(long)]
+        repo: String,
+        /// Migration directory in the repository
+        #[arg(long, default_value = "migrations")]
+        path: std::path::PathBuf,
+        /// Branch to follow, the default branch if not given
+        #[arg(long)]
+        branch: Option<String>,
+        /// Where the repository is checked out
+        #[arg(long, default_value = reconcile::CHECKOUT)]
+        checkout: std::path::PathBuf,
+        /// How often to check for new commits, e.g. 30s, 1m or 2h
+        #[arg(long, default_value = "1m", value_parser = reconcile::parse_interval)]
+        interval: u64,
+        /// Reconcile once instead of watching
+        #[arg(long)]
+        once: bool,
+    },
+    /// Maintain the partitions of tables partitioned by date, see `[[partitions]]` in
+    /// `.architect.toml`
+    Partitions {
+        #[command(subcommand)]
+        command: PartitionCommand,
+    },
+    /// Move a table to a rebuilt copy kept in sync by a trigger, for changes too big for a
+    /// migration, resumable phase by phase
+    Cutover {
+        #[command(subcommand)]
+        command: CutoverCommand,
+    },
+    /// Switch the app between the versioned schemas built by up, see `[blue_green]` in the
+    /// config
+    BlueGreen {
+        #[command(subcommand)]
+        command: BlueGreenCommand,
+    },
+    /// Export or import the bookkeeping tables as JSON
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// Create an ephemeral database, run all migrations from zero against it and drop it again.
+    /// By default a temporary database is created on the configured server.
+    Test {
+        /// Run against a throw away postgres container instead. Requires docker.
+        #[arg(long)]
+        docker: bool,
+        /// The docker image to use with --docker
+        #[arg(long, default_value = "postgres:15")]
+        image: String,
+        /// Clone the database configured with `template_dbname`, which is kept at the last
+        /// applied version, so only pending migrations are run
+        #[arg(long, conflicts_with = "docker")]
+        template: bool,
+    },
 }
 
-fn read_config_toml(p: &std::path::PathBuf) -> Result<Config> {
-    let cs = std::fs::read_to_string(p)?;
-    Ok(toml::from_str(&cs)?)
+#[derive(Debug, Subcommand)]
+enum ShadowCommand {
+    /// Drop and recreate the shadow database, replaying migrations till the last applied version
+    Reset,
+    /// Migrate the shadow database up or down to the last applied version
+    Sync,
 }
 
-fn wizard(mut m: Migrator) -> Result<()> {
+#[derive(Debug, Subcommand)]
+enum CutoverCommand {
+    /// Create TABLE_new with the changes, sync writes to it and copy the existing rows
+    Start {
+        name: String,
+        #[arg(long)]
+        table: String,
+        /// Statement changing the new table, which is named {table} in it, e.g.
+        /// "ALTER TABLE {table} ALTER COLUMN id TYPE bigint"
+        #[arg(long)]
+        alter: Vec<String>,
+        /// Unique integer key of the table the rows are copied by
+        #[arg(long, default_value = "id")]
+        key: String,
+        /// Rows copied per batch
+        #[arg(long, default_value_t = 1000)]
+        size: i64,
+    },
+    /// Continue an interrupted cutover
+    Run {
+        name: String,
+        /// Swap the tables once the rows are copied
+        #[arg(long)]
+        swap: bool,
+    },
+    /// List the cutovers with their phase and the rows missing in their new tables
+    Status,
+    /// Drop the old table of a swapped cutover
+    Finish { name: String },
+    /// Drop the new table of a cutover that isn't swapped yet and forget it
+    Abort { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum BlueGreenCommand {
+    /// Build the next versioned schema from the tables as they are now
+    Build,
+    /// Switch the search_path of the app's role to a versioned schema
+    Cutover {
+        /// Version of the schema, the last one built by default
+        #[arg(long)]
+        to: Option<i32>,
+    },
+    /// Switch back to the schema before the active one
+    Rollback,
+    /// List the versioned schemas
+    Status,
+}
+
+#[derive(Debug, Subcommand)]
+enum DataCommand {
+    /// Create an empty data migration in the app's data directory
+    New,
+    /// Run the unfinished data migrations in order, resuming interrupted ones
+    Run,
+    /// List the data migrations with their progress
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum RlsCommand {
+    /// Write a migration enabling row-level security on TABLE with a standard policy
+    Enable {
+        table: String,
+        #[arg(long, value_enum, default_value = "tenant")]
+        policy: rls::Policy,
+        /// The column the policy compares, the project's `column` by default
+        #[arg(long)]
+        column: Option<String>,
+        /// Write the migration without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// List the tables of the schemas mandating row-level security that lack it, failing if any
+    Check,
+}
+
+#[derive(Debug, Subcommand)]
+enum PartitionCommand {
+    /// Create the missing future partitions, meant to run on a schedule
+    Maintain,
+    /// Show how far the partitions reach, failing if writes already fall outside them
+    Check,
+    /// Write a migration attaching TABLE as the partition of PARENT from FROM to TO
+    Attach {
+        parent: String,
+        table: String,
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+        /// Write the migration without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write a migration detaching the partition TABLE from PARENT
+    Detach {
+        parent: String,
+        table: String,
+        /// Write the migration without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// Write the applied versions and tags as JSON to stdout or a file
+    Export {
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+    },
+    /// Import applied versions and tags from a JSON file written by `state export`
+    Import {
+        file: std::path::PathBuf,
+        /// Replace existing bookkeeping instead of requiring empty tables
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+/// Reads the config from one or more files, merging each file into the ones before it.
+fn read_config_toml(paths: &[std::path::PathBuf]) -> Result<Config> {
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    for p in paths.iter() {
+        let cs = secrets::read_config_file(p)?;
+        match toml::from_str(&cs) {
+            Ok(v) => merge_toml(&mut merged, v),
+            Err(e) => {
+                return Err(ArchitectError::Config(format!("invalid config {:?}: {}", p, e)).into())
+            }
+        }
+    }
+    env::interpolate_config(&mut merged)?;
+    secrets::decrypt_config(&mut merged)?;
+    merged
+        .try_into()
+        .map_err(|e| ArchitectError::Config(format!("invalid config: {}", e)).into())
+}
+
+/// Deep merges `overlay` into `base`. Tables are merged key by key, any other value, arrays
+/// included, is replaced.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(v) => merge_toml(v, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn wizard(m: &mut Migrator, force: bool) -> Result<()> {
     const HELP: &str = r##"
 Choose an action from the following:
 1. Create a new migration
@@ -528,32 +1835,38 @@ Choose an action from the following:
         (std::io::stdin()).read_line(&mut user_input)?;
         // eprintln!("This is {user_input} yo!");
         if &user_input == "1\n" {
-            m.new_migration()?;
+            m.new_migration_by(None)?;
             continue;
         } else if &user_input == "2\n" {
-            eprintln!("Migrated up {} versions!", m.migrate_up(false)?);
+            m.check_approval_mode()?;
+            println!("Migrated up {} versions!", m.migrate_up(false)?);
             continue;
         } else if &user_input == "3\n" {
             let mut ns = String::new();
             (std::io::stdin()).read_line(&mut ns)?;
             ns = String::from(ns.trim_matches('\n'));
             let n: usize = ns.parse()?;
-            eprintln!("Migrated up {} versions!", m.migrate_up_n(n, false)?);
+            m.check_approval_mode()?;
+            println!("Migrated up {} versions!", m.migrate_up_n(n, false)?);
             continue;
         } else if user_input == "4\n" {
-            eprintln!("Migrated down {} versions!", m.migrate_down(false)?);
+            m.confirm_destructive("migrate down", force)?;
+            println!("Migrated down {} versions!", m.migrate_down(false)?);
             continue;
         } else if &user_input == "5\n" {
             let mut ns = String::new();
             (std::io::stdin()).read_line(&mut ns)?;
             ns = String::from(ns.trim_matches('\n'));
             let n: usize = ns.parse()?;
-            eprintln!("Migrated down {} versions!", m.migrate_down_n(n, false)?);
+            m.confirm_destructive("migrate down", force)?;
+            println!("Migrated down {} versions!", m.migrate_down_n(n, false)?);
             continue;
         } else if &user_input == "6\n" {
-            eprintln!("Migrating down");
+            m.confirm_destructive("reapply the last version", force)?;
+            m.check_approval_mode()?;
+            info!("Migrating down");
             m.migrate_down_n(1, false)?;
-            eprintln!("Migrating up");
+            info!("Migrating up");
             m.migrate_up_n(1, false)?;
             continue;
         } else if &user_input == "7\n" {
@@ -563,18 +1876,829 @@ Choose an action from the following:
     Ok(())
 }
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        if let Some(ArchitectError::MigrationFailed {
+            statement: Some(statement),
+            ..
+        }) = e.downcast_ref::<ArchitectError>()
+        {
+            eprintln!("failed statement: {}", statement);
+        }
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+fn run() -> Result<()> {
     let args = Args::parse();
-    let cp = std::path::PathBuf::from(&args.config);
-    if !cp.exists() {
-        return Err(anyhow::anyhow!("config path does not exist"));
+    output::set_quiet(args.quiet);
+    match &args.env_file {
+        Some(v) => env::load_dotenv(v, true)?,
+        None => env::load_dotenv(std::path::Path::new(".env"), false)?,
+    }
+    let paths = if args.config.is_empty() {
+        vec![paths::discover_config()?]
+    } else {
+        args.config.iter().map(std::path::PathBuf::from).collect()
+    };
+    if let Some(p) = paths.iter().find(|v| !v.exists()) {
+        return Err(ArchitectError::Config(format!("config path {:?} does not exist", p)).into());
     }
-    let config: Config = read_config_toml(&cp)?;
+    let config: Config = read_config_toml(&paths)?;
     let dir = std::path::PathBuf::from(&args.migdir);
 
-    let m = Migrator::new(config, dir)?;
+    if let Some(command) = args.command {
+        let result = run_command(config, dir, command, args.force);
+        if let Err(e) = &result {
+            output::failure(e);
+        }
+        return result;
+    }
+    let mut m = Migrator::new(config, dir)?;
     if args.wizard {
-        return wizard(m);
+        let result = wizard(&mut m, args.force);
+        m.send_summary(&result);
+        return result;
+    }
+    Ok(())
+}
+
+fn run_command(
+    mut config: Config,
+    dir: std::path::PathBuf,
+    command: Command,
+    force: bool,
+) -> Result<()> {
+    let mut m = match command {
+        // commands that don't need a connection to the configured database
+        Command::Validate => return validate::run(&config.dir(&dir)?, &config.plugins),
+        Command::MergeCheck { base } => return merge::run(&config.dir(&dir)?, &base),
+        Command::Fmt { check } => {
+            let files = fmt::run(&config.dir(&dir)?, check)?;
+            if check {
+                for f in files.iter() {
+                    println!("{}", f);
+                }
+                if !files.is_empty() {
+                    return Err(anyhow::anyhow!("{} files aren't formatted", files.len()));
+                }
+                return Ok(());
+            }
+            output::result(
+                &format!("Formatted {} files", files.len()),
+                serde_json::json!({ "formatted": files }),
+            );
+            return Ok(());
+        }
+        Command::Bundle { out } => {
+            let files = bundle::run(&config.app, &config.dir(&dir)?, &config.plugins, &out)?;
+            output::result(
+                &format!("Bundled {} migration files into {}", files, out.display()),
+                serde_json::json!({ "out": out, "files": files }),
+            );
+            return Ok(());
+        }
+        Command::Lock => {
+            let dir = config.dir(&dir)?;
+            let count = lock::write(&dir)?;
+            output::result(
+                &format!(
+                    "Locked {} migrations in {:?}",
+                    count,
+                    dir.join(lock::LOCK_FILE)
+                ),
+                serde_json::json!({ "locked": count }),
+            );
+            return Ok(());
+        }
+        Command::Test {
+            docker,
+            image,
+            template,
+        } => return testdb::run(config, dir, docker, &image, template),
+        Command::Approve { plan, operator } => {
+            return approval::approve(&plan, &config.approval_key, &approval::operator(operator)?)
+        }
+        Command::Rollout { approve, operator } => {
+            let operator = match &approve {
+                Some(_) => approval::operator(operator)?,
+                None => String::new(),
+            };
+            let approve = approve.as_deref().map(|v| (v, operator.as_str()));
+            let progress = rollout::run(&dir, approve)?;
+            if output::quiet() {
+                output::result("", serde_json::json!({ "stages": progress }));
+            } else {
+                rollout::print(&progress);
+            }
+            return Ok(());
+        }
+        Command::Plan {
+            all_apps: true,
+            jobs,
+            ..
+        } => {
+            return apps::print(&apps::preflight(&dir, jobs)?);
+        }
+        Command::Up {
+            all_apps: true,
+            jobs,
+            ..
+        } => {
+            let migrated = apps::up(&dir, jobs)?;
+            let total: usize = migrated.iter().map(|(_, v)| v).sum();
+            let fields: serde_json::Map<String, serde_json::Value> = migrated
+                .iter()
+                .map(|(app, count)| (app.clone(), serde_json::json!(count)))
+                .collect();
+            output::result(
+                &format!("Migrated up {} versions of {} apps!", total, migrated.len()),
+                serde_json::json!({ "migrated": fields }),
+            );
+            return Ok(());
+        }
+        Command::Fleet {
+            configs,
+            jobs,
+            all,
+            watch,
+        } => return fleet::run(&config.dir(&dir)?, &configs, jobs, all, watch),
+        Command::Reconcile {
+            repo,
+            path,
+            branch,
+            checkout,
+            interval,
+            once,
+        } => {
+            return reconcile::run(
+                config,
+                &repo,
+                &path,
+                branch.as_deref(),
+                &checkout,
+                interval,
+                once,
+            )
+        }
+        Command::Status => {
+            config.dirty_ok = true;
+            Migrator::read_only(config, dir)?
+        }
+        Command::List { .. }
+        | Command::History { .. }
+        | Command::Show { .. }
+        | Command::Plan { .. }
+        | Command::VerifyData
+        | Command::VerifyGrants
+        | Command::Report { .. }
+        | Command::Schema { at: None }
+        | Command::Docs { at: None, .. }
+        | Command::Catalog { at: None, .. }
+        | Command::Erd { at: None, .. } => Migrator::read_only(config, dir)?,
+        _ => Migrator::new(config, dir)?,
+    };
+    let result = dispatch(&mut m, command, force);
+    m.send_summary(&result);
+    result
+}
+
+fn dispatch(m: &mut Migrator, command: Command, force: bool) -> Result<()> {
+    match command {
+        // run by run_command without a migrator
+        command @ (Command::Validate
+        | Command::Fmt { .. }
+        | Command::Bundle { .. }
+        | Command::Lock
+        | Command::MergeCheck { .. }
+        | Command::Test { .. }
+        | Command::Approve { .. }
+        | Command::Rollout { .. }
+        | Command::Fleet { .. }
+        | Command::Reconcile { .. }) => {
+            return Err(anyhow::anyhow!(
+                "{:?} doesn't run on a migrator, it has to be handled before connecting",
+                command
+            ))
+        }
+        Command::New { edit, author } => {
+            let (up, down) = m.new_migration_by(author)?;
+            if edit {
+                editor::open(&[&up, &down])?;
+            }
+        }
+        Command::Up {
+            sandbox,
+            json,
+            verify_down,
+            verify_then_rollback_on_failure,
+            dry_run,
+            parallel,
+            to_date,
+            steps,
+            skip,
+            skip_reason,
+            ..
+        } => {
+            for v in skip.iter() {
+                let version = m.resolve_version(v)?;
+                m.skip(version, skip_reason.as_deref().unwrap_or_default());
+            }
+            skip::warn(&m.skipped_versions()?);
+            if sandbox {
+                let (count, failures) = m.sandbox_up()?;
+                sandbox::print(count, &failures)?;
+            } else if dry_run {
+                let steps = m.steps(m.last_version, i64::MAX);
+                m.check_steps(&steps)?;
+                let count = steps.len();
+                let mut fields = serde_json::json!({ "dry_run": true, "checked": count });
+                if json {
+                    fields["files"] = serde_json::to_value(m.dry_run(&steps))?;
+                }
+                output::result(
+                    &format!("Loaded {} migrations, nothing was executed", count),
+                    fields,
+                );
+            } else {
+                m.check_approval_mode()?;
+                lock::check(&m.dir)?;
+                let plan = m.plan()?;
+                disk::warn(&plan);
+                pgbouncer::warn(&plan);
+                plan::enforce_policy(&plan)?;
+                m.require_downs()?;
+                m.verify_downs(verify_down)?;
+                let result = match (parallel, to_date, steps) {
+                    (_, Some(v), _) => list::parse_timestamp(&v)
+                        .and_then(|timestamp| m.migrate_up_before(timestamp, false)),
+                    (_, None, Some(n)) => m.migrate_up_n(n, false),
+                    (Some(n), None, None) if n > 1 => m.migrate_up_parallel(n),
+                    _ => m.migrate_up(false),
+                };
+                let refreshes = match result {
+                    Ok(_) => {
+                        let refreshes = m.after_run()?;
+                        if verify_then_rollback_on_failure {
+                            m.verify_or_roll_back()?;
+                        }
+                        refreshes
+                    }
+                    Err(_) => Vec::new(),
+                };
+                if json && !output::quiet() {
+                    // keeps stdout valid JSON
+                    println!("{}", serde_json::to_string_pretty(&m.runs)?);
+                    info!("Migrated up {} versions!", result?);
+                } else {
+                    let count = result?;
+                    let mut fields =
+                        serde_json::json!({ "migrated": count, "version": m.last_version });
+                    if json {
+                        fields["runs"] = serde_json::to_value(&m.runs)?;
+                        fields["refreshes"] = serde_json::to_value(&refreshes)?;
+                    }
+                    output::result(&format!("Migrated up {} versions!", count), fields);
+                }
+            }
+        }
+        Command::Plan { out, operator, .. } => {
+            skip::warn(&m.skipped_versions()?);
+            plan::print(&m.plan()?)?;
+            if let Some(out) = out {
+                lock::check(&m.dir)?;
+                m.signed_plan(&approval::operator(operator)?)?.write(&out)?;
+                output::result(
+                    &format!("Wrote the signed plan to {:?}", out),
+                    serde_json::json!({ "plan": out }),
+                );
+            }
+        }
+        Command::Apply { plan } => {
+            lock::check(&m.dir)?;
+            let plan = approval::SignedPlan::read(&plan)?;
+            plan::enforce_policy(&m.plan()?)?;
+            m.require_downs()?;
+            m.verify_downs(false)?;
+            let count = m.apply(&plan)?;
+            m.after_run()?;
+            output::result(
+                &format!("Migrated up {} versions!", count),
+                serde_json::json!({ "migrated": count, "version": m.last_version }),
+            );
+        }
+        Command::Tag { name, version } => {
+            let version = m.resolve_version(&version)?;
+            m.tag(&name, version)?;
+            output::result(
+                &format!("Tagged version {} as {}", version, name),
+                serde_json::json!({ "tag": name, "version": version }),
+            );
+        }
+        Command::Goto { version } => {
+            let version = m.resolve_version(&version)?;
+            lock::check(&m.dir)?;
+            if version < m.last_version {
+                m.confirm_destructive("migrate down", force)?;
+            } else {
+                m.check_approval_mode()?;
+                m.require_downs()?;
+                m.verify_downs(false)?;
+            }
+            let count = m.goto(version, false)?;
+            m.after_run()?;
+            output::result(
+                &format!("Migrated {} versions!", count),
+                serde_json::json!({ "migrated": count, "version": m.last_version }),
+            );
+        }
+        Command::Down { steps, all, before } => {
+            let timestamp = before.map(|v| list::parse_timestamp(&v)).transpose()?;
+            lock::check(&m.dir)?;
+            m.confirm_destructive("migrate down", force)?;
+            let count = match (steps, all, timestamp) {
+                (Some(n), _, _) => m.migrate_down_n(n, false)?,
+                (_, true, _) => m.migrate_down(false)?,
+                (_, _, Some(timestamp)) => m.migrate_down_from(timestamp, false)?,
+                _ => 0,
+            };
+            m.after_run()?;
+            output::result(
+                &format!("Migrated down {} versions!", count),
+                serde_json::json!({ "migrated": count, "version": m.last_version }),
+            );
+        }
+        Command::Rollback { .. } => {
+            lock::check(&m.dir)?;
+            m.confirm_destructive("roll back the last batch", force)?;
+            let (batch, count) = m.rollback_last_batch(false)?;
+            m.after_run()?;
+            output::result(
+                &format!("Rolled back {} versions of batch {}!", count, batch),
+                serde_json::json!({
+                    "batch": batch,
+                    "migrated": count,
+                    "version": m.last_version
+                }),
+            );
+        }
+        Command::List {
+            pending,
+            applied,
+            since,
+            pattern,
+            reverse,
+        } => {
+            let filter = list::ListFilter {
+                pending,
+                applied,
+                since: since.map(|v| list::parse_since(&v)).transpose()?,
+                pattern: pattern.map(|v| glob::Pattern::new(&v)).transpose()?,
+                reverse,
+            };
+            list::print(&m.list(&filter)?);
+            skip::warn(&m.skipped_versions()?);
+        }
+        Command::Status => {
+            let status = m.status()?;
+            if output::quiet() {
+                output::result("", serde_json::json!(status));
+            } else {
+                status::print(&status);
+            }
+        }
+        Command::History { limit } => {
+            let mut entries = m.history()?;
+            if let Some(n) = limit {
+                entries.drain(..entries.len().saturating_sub(n));
+            }
+            history::print(&entries);
+        }
+        Command::Show { version, down } => {
+            let version = m.resolve_version(&version)?;
+            let direction = if down { Direction::Down } else { Direction::Up };
+            if m.script_path(version, direction).is_some() {
+                print!("{}", m.sql(version, direction)?);
+                return Ok(());
+            }
+            for query in m.get_queries(version, direction)? {
+                println!("{};", query);
+            }
+        }
+        Command::DiffMigrations {
+            v1,
+            v2,
+            against,
+            down,
+        } => {
+            let direction = if down { Direction::Down } else { Direction::Up };
+            let v1 = m.resolve_version(&v1)?;
+            let old = match &against {
+                Some(rev) => m.sql_at_revision(v1, direction, rev)?,
+                None => m.sql(v1, direction)?,
+            };
+            let new = match v2 {
+                Some(v) => {
+                    let v2 = m.resolve_version(&v)?;
+                    m.sql(v2, direction)?
+                }
+                None => m.sql(v1, direction)?,
+            };
+            let changes = diff::semantic(&parse_statements(&old)?, &parse_statements(&new)?);
+            diff::print(&changes);
+        }
+        Command::RenameColumn {
+            table,
+            from,
+            to,
+            yes,
+        } => {
+            let versions = m.rename_column(&table, &from, &to, yes)?;
+            output::result(
+                &format!(
+                    "Wrote migrations {:?} renaming {}.{} to {}",
+                    versions, table, from, to
+                ),
+                serde_json::json!({ "versions": versions }),
+            );
+        }
+        Command::ChangeColumn {
+            table,
+            column,
+            data_type,
+            to,
+            using,
+            key,
+            size,
+            yes,
+        } => {
+            let change = online::Change {
+                name: to.unwrap_or_else(|| column.clone()),
+                table: table.clone(),
+                column: column.clone(),
+                old_type: String::new(),
+                data_type: data_type.unwrap_or_default(),
+                using: using.unwrap_or_default(),
+                key,
+                size,
+            };
+            let versions = m.change_column(change, yes)?;
+            output::result(
+                &format!(
+                    "Wrote migrations {:?} changing {}.{}, apply them in separate deploys",
+                    versions, table, column
+                ),
+                serde_json::json!({ "versions": versions }),
+            );
+        }
+        Command::AddForeignKey {
+            table,
+            column,
+            references,
+            name,
+            on_delete,
+            lock_timeout_ms,
+            retries,
+            pause_ms,
+            yes,
+        } => {
+            let fk = foreign_key::ForeignKey {
+                table: table.clone(),
+                column: column.clone(),
+                references: references.clone(),
+                name,
+                on_delete,
+                lock_timeout_ms,
+                retries,
+                pause_ms,
+            };
+            let versions = m.add_foreign_key(&fk, yes)?;
+            output::result(
+                &format!(
+                    "Wrote migrations {:?} adding a foreign key from {}.{} to {}, apply them in \
+                    separate deploys",
+                    versions, table, column, references
+                ),
+                serde_json::json!({ "versions": versions }),
+            );
+        }
+        Command::VerifyData => {
+            let outcomes = m.verify_data()?;
+            let failed = outcomes.iter().filter(|o| o.error.is_some()).count();
+            if output::quiet() {
+                output::result("", serde_json::json!({ "checks": outcomes }));
+            } else {
+                expect::print(&outcomes);
+            }
+            if failed > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} of {} checks failed",
+                    failed,
+                    outcomes.len()
+                ));
+            }
+        }
+        Command::Rpc => {
+            let stdin = std::io::stdin();
+            rpc::serve(m, stdin.lock(), std::io::stdout())?;
+        }
+        Command::VerifyGrants => {
+            let drift = m.verify_grants()?;
+            if output::quiet() {
+                output::result("", serde_json::json!({ "drift": drift }));
+            } else {
+                grants::print(&drift);
+            }
+            if !drift.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} objects drifted from the grants spec",
+                    drift.len()
+                ));
+            }
+        }
+        Command::FixSequences { tables } => {
+            let tables = if tables.is_empty() {
+                m.project()?.fix_sequences
+            } else {
+                tables
+            };
+            let resets = m.fix_sequences(&tables)?;
+            output::result(
+                &format!("Reset {} sequences", resets.len()),
+                serde_json::json!({ "resets": resets }),
+            );
+        }
+        Command::Schema { at } => {
+            let schema = match at {
+                Some(v) => {
+                    let version = m.resolve_version(&v)?;
+                    m.schema_at(version)?
+                }
+                None => m.current_schema()?,
+            };
+            println!("{}", schema.ddl());
+        }
+        Command::Catalog { at, format, out } => {
+            let (schema, version) = match at {
+                Some(v) => {
+                    let version = m.resolve_version(&v)?;
+                    (m.schema_at(version)?, version)
+                }
+                None => (m.current_schema()?, m.last_version),
+            };
+            let catalog = catalog::Catalog::new(&schema, &m.config.app, &m.config.dbname, version);
+            let content = match format.as_str() {
+                "json" => catalog.json()?,
+                "csv" => catalog.csv(),
+                v => {
+                    return Err(anyhow::anyhow!(
+                        "unknown catalog format \"{}\". Expected json or csv",
+                        v
+                    ))
+                }
+            };
+            match out {
+                Some(out) => std::fs::write(out, content)?,
+                None => print!("{}", content),
+            }
+        }
+        Command::Erd { at, format } => {
+            let schema = match at {
+                Some(v) => {
+                    let version = m.resolve_version(&v)?;
+                    m.schema_at(version)?
+                }
+                None => m.current_schema()?,
+            };
+            println!("{}", erd::render(&schema, &format)?);
+        }
+        Command::Docs { at, format, out } => {
+            let format = docs::Format::parse(&format)?;
+            let (schema, title) = match at {
+                Some(v) => {
+                    let version = m.resolve_version(&v)?;
+                    let title = format!("{} at version {}", m.config.app, version);
+                    (m.schema_at(version)?, title)
+                }
+                None => (m.current_schema()?, m.config.dbname.clone()),
+            };
+            let dir = out.unwrap_or_else(|| m.dir.join(docs::DOCS_DIR));
+            let count = docs::write(&schema, &title, &dir, format)?;
+            output::result(
+                &format!("Wrote docs of {} tables to {:?}", count, dir),
+                serde_json::json!({ "tables": count, "dir": dir }),
+            );
+        }
+        Command::Shadow { command } => {
+            let shadow = match command {
+                ShadowCommand::Reset => m.shadow_reset()?,
+                ShadowCommand::Sync => m.shadow()?,
+            };
+            output::result(
+                &format!(
+                    "shadow database {} is at version {}",
+                    shadow.config.dbname, shadow.last_version
+                ),
+                serde_json::json!({
+                    "dbname": shadow.config.dbname,
+                    "version": shadow.last_version
+                }),
+            );
+        }
+        Command::Report {
+            slowest,
+            limit,
+            with,
+        } => report::print(report::collect(m, &with)?, slowest, limit),
+        Command::Cutover { command } => {
+            let c = match command {
+                CutoverCommand::Start {
+                    name,
+                    table,
+                    alter,
+                    key,
+                    size,
+                } => m.start_cutover(&name, &table, &alter, &key, size)?,
+                CutoverCommand::Run { name, swap } => m.run_cutover(&name, swap)?,
+                CutoverCommand::Finish { name } => m.finish_cutover(&name)?,
+                CutoverCommand::Abort { name } => {
+                    m.abort_cutover(&name)?;
+                    output::result(
+                        &format!("Aborted cutover {}", name),
+                        serde_json::json!({ "name": name }),
+                    );
+                    return Ok(());
+                }
+                CutoverCommand::Status => {
+                    let cutovers = m.cutover_status()?;
+                    if output::quiet() {
+                        let cutovers: Vec<serde_json::Value> = cutovers
+                            .iter()
+                            .map(|(c, missing)| {
+                                serde_json::json!({ "cutover": c, "missing": missing })
+                            })
+                            .collect();
+                        output::result("", serde_json::json!({ "cutovers": cutovers }));
+                    } else {
+                        cutover::print(&cutovers);
+                    }
+                    return Ok(());
+                }
+            };
+            output::result(
+                &format!("Cutover {} of {} is {}", c.name, c.table, c.phase),
+                serde_json::json!({ "cutover": c }),
+            );
+        }
+        Command::BlueGreen { command } => {
+            let schema = match command {
+                BlueGreenCommand::Build => {
+                    let version = m.build_next_schema()?;
+                    let schema = m.config.blue_green.versioned(version);
+                    output::result(
+                        &format!("Built {}", schema),
+                        serde_json::json!({ "schema": schema }),
+                    );
+                    return Ok(());
+                }
+                BlueGreenCommand::Cutover { to } => m.blue_green_cutover(to)?,
+                BlueGreenCommand::Rollback => m.blue_green_rollback()?,
+                BlueGreenCommand::Status => {
+                    let versions = m.blue_green_versions()?;
+                    if output::quiet() {
+                        output::result("", serde_json::json!({ "schemas": versions }));
+                    } else {
+                        blue_green::print(&versions);
+                    }
+                    return Ok(());
+                }
+            };
+            output::result(
+                &format!("Switched to {}", schema),
+                serde_json::json!({ "schema": schema }),
+            );
+        }
+        Command::State { command } => match command {
+            StateCommand::Export { out } => {
+                let json = serde_json::to_string_pretty(&m.export_state()?)? + "\n";
+                match out {
+                    Some(out) => std::fs::write(out, json)?,
+                    None => print!("{}", json),
+                }
+            }
+            StateCommand::Import { file, replace } => {
+                let state = state::read(&file)?;
+                if replace {
+                    m.confirm_destructive("replace the bookkeeping", force)?;
+                }
+                m.import_state(&state, replace)?;
+                output::result(
+                    &format!(
+                        "Imported {} versions and {} tags. last version is {}",
+                        state.migrations.len(),
+                        state.tags.len(),
+                        m.last_version
+                    ),
+                    serde_json::json!({
+                        "migrations": state.migrations.len(),
+                        "tags": state.tags.len(),
+                        "version": m.last_version
+                    }),
+                );
+            }
+        },
+        Command::TestReversibility => reversibility::print(&m.test_reversibility()?)?,
+        Command::Data { command } => match command {
+            DataCommand::New => {
+                let path = m.new_data_migration()?;
+                output::result(
+                    &format!("new data migration created:\n{:?}", path),
+                    serde_json::json!({ "path": path }),
+                );
+            }
+            DataCommand::Run => {
+                let count = m.run_data_migrations()?;
+                output::result(
+                    &format!("Finished {} data migrations", count),
+                    serde_json::json!({ "finished": count }),
+                );
+            }
+            DataCommand::List => data::print(&m.data_migrations()?),
+        },
+        Command::Rls { command } => match command {
+            RlsCommand::Enable {
+                table,
+                policy,
+                column,
+                yes,
+            } => {
+                let versions = m.enable_rls(&table, policy, column, yes)?;
+                output::result(
+                    &format!(
+                        "Wrote migration {:?} enabling row-level security on {}",
+                        versions, table
+                    ),
+                    serde_json::json!({ "versions": versions }),
+                );
+            }
+            RlsCommand::Check => {
+                let missing = m.check_rls()?;
+                rls::print(&missing);
+                if !missing.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "{} tables lack row-level security",
+                        missing.len()
+                    ));
+                }
+            }
+        },
+        Command::Partitions { command } => {
+            let today = chrono::Utc::now().date_naive();
+            match command {
+                PartitionCommand::Maintain => {
+                    let created = m.maintain_partitions(today)?;
+                    output::result(
+                        &format!("Created {} partitions", created.len()),
+                        serde_json::json!({ "created": created }),
+                    );
+                }
+                PartitionCommand::Check => {
+                    let coverage = m.check_partitions(today)?;
+                    partitions::print(&coverage, today);
+                    let exceeded = coverage
+                        .iter()
+                        .filter(|c| c.state(today) == "exceeded")
+                        .count();
+                    if exceeded > 0 {
+                        return Err(anyhow::anyhow!(
+                            "writes to {} tables fall outside their partitions",
+                            exceeded
+                        ));
+                    }
+                }
+                PartitionCommand::Attach {
+                    parent,
+                    table,
+                    from,
+                    to,
+                    yes,
+                } => {
+                    let versions = m.attach_partition(&parent, &table, &from, &to, yes)?;
+                    output::result(
+                        &format!("Wrote migration {:?} attaching {}", versions, table),
+                        serde_json::json!({ "versions": versions }),
+                    );
+                }
+                PartitionCommand::Detach { parent, table, yes } => {
+                    let versions = m.detach_partition(&parent, &table, yes)?;
+                    output::result(
+                        &format!("Wrote migration {:?} detaching {}", versions, table),
+                        serde_json::json!({ "versions": versions }),
+                    );
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -591,13 +2715,98 @@ mod tests {
         });
     }
 
-    fn test_config() -> Result<crate::Config> {
-        let p = std::env::var("ARCHITECT_TEST_CONFIG")?;
+    /// The config file of the test database: `ARCHITECT_TEST_CONFIG`, or without it one
+    /// connecting to a postgres container started once for the test run, which is removed when
+    /// the tests exit. That one requires docker.
+    pub(crate) fn test_config_path() -> Result<std::path::PathBuf> {
+        static CONTAINER: std::sync::OnceLock<Result<std::path::PathBuf, String>> =
+            std::sync::OnceLock::new();
+        if let Ok(v) = std::env::var("ARCHITECT_TEST_CONFIG") {
+            return Ok(v.into());
+        }
+        CONTAINER
+            .get_or_init(|| container_config().map_err(|e| e.to_string()))
+            .clone()
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "ARCHITECT_TEST_CONFIG isn't set and starting a test container failed: {}",
+                    e
+                )
+            })
+    }
+
+    fn container_config() -> Result<std::path::PathBuf> {
+        let config = crate::Config {
+            app: "test".to_owned(),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("architect_test_{}.toml", std::process::id()));
+        let c =
+            crate::testdb::Ephemeral::docker(&config, "postgres:15")?.keep_until_exit(&[&path])?;
+        std::fs::write(
+            &path,
+            format!(
+                "app = {:?}\nhost = {:?}\nport = {}\ndbname = {:?}\nuser = {:?}\n\
+                password = {:?}\nconnect_timeout_seconds = {}\n",
+                c.app, c.host, c.port, c.dbname, c.user, c.password, c.connect_timeout_seconds
+            ),
+        )?;
+        Ok(path)
+    }
+
+    pub(crate) fn test_config() -> Result<crate::Config> {
+        let p = test_config_path()?;
         let s = std::fs::read_to_string(p)?;
         let c: crate::Config = toml::from_str(&s)?;
         Ok(c)
     }
 
+    /// The test config with the tracking tables in a new `schema` of their own, so the versions
+    /// of tests running at the same time don't interfere. Drop the schema when done.
+    pub(crate) fn schema_config(schema: &str) -> crate::Config {
+        let mut config = test_config().unwrap();
+        config
+            .connect()
+            .unwrap()
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}"
+            ))
+            .unwrap();
+        config.options = format!("-c search_path={}", schema);
+        config
+    }
+
+    #[test]
+    fn merge_config() {
+        let mut base: toml::Value = toml::from_str(
+            "app = \"app\"
+            host = \"localhost\"
+            dbname = \"app\"
+            user = \"app\"
+            plugins = [\"a\", \"b\"]
+            [hooks]
+            before_all = \"echo base\"
+            on_failure = \"echo failed\"",
+        )
+        .unwrap();
+        let overlay: toml::Value = toml::from_str(
+            "host = \"db.internal\"
+            plugins = [\"c\"]
+            [hooks]
+            before_all = \"echo overlay\"",
+        )
+        .unwrap();
+
+        crate::merge_toml(&mut base, overlay);
+        let config: crate::Config = base.try_into().unwrap();
+
+        assert_eq!(config.app, "app");
+        assert_eq!(config.host, "db.internal");
+        assert_eq!(config.plugins, vec!["c"]);
+        assert_eq!(config.hooks.before_all, "echo overlay");
+        assert_eq!(config.hooks.on_failure, "echo failed");
+    }
+
     #[test]
     fn new_migration() {
         init();
@@ -626,6 +2835,190 @@ mod tests {
         assert!(down_exists);
     }
 
+    #[test]
+    fn dispatch_without_migrator() {
+        let mut m = crate::Migrator::new(
+            test_config().unwrap(),
+            std::path::PathBuf::from("./dispatch_without_migrator"),
+        )
+        .unwrap();
+        let result = crate::dispatch(&mut m, crate::Command::Validate, false);
+        let _ = std::fs::remove_dir_all("./dispatch_without_migrator");
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Validate doesn't run on a migrator"));
+    }
+
+    #[test]
+    fn application_name() {
+        init();
+        let mut config = test_config().unwrap();
+        let default = config
+            .clone()
+            .connect()
+            .unwrap()
+            .query_one("SHOW application_name", &[])
+            .unwrap()
+            .get::<_, String>(0);
+        config.application_name = "architect_test".to_owned();
+        let configured = config
+            .connect()
+            .unwrap()
+            .query_one("SHOW application_name", &[])
+            .unwrap()
+            .get::<_, String>(0);
+        assert_eq!(default, config.app);
+        assert_eq!(configured, "architect_test");
+    }
+
+    #[test]
+    fn connection_options() {
+        init();
+        let mut config = test_config().unwrap();
+        // the first host refuses connections, the second is the configured server
+        config.hosts = vec![
+            "127.0.0.1:1".to_owned(),
+            format!("{}:{}", config.host, config.port),
+        ];
+        config.options = "-c statement_timeout=1234".to_owned();
+        config.target_session_attrs = "read-write".to_owned();
+        let timeout = config
+            .connect()
+            .unwrap()
+            .query_one("SHOW statement_timeout", &[])
+            .unwrap()
+            .get::<_, String>(0);
+        assert_eq!(timeout, "1234ms");
+        config.session = toml::from_str(
+            "maintenance_work_mem = \"256MB\"\nlock_timeout = 5000\nsynchronous_commit = false",
+        )
+        .unwrap();
+        let row = config
+            .connect()
+            .unwrap()
+            .query_one(
+                "SELECT current_setting('maintenance_work_mem'), current_setting('lock_timeout'), \
+                current_setting('synchronous_commit')",
+                &[],
+            )
+            .unwrap();
+        let session: (String, String, String) = (row.get(0), row.get(1), row.get(2));
+        assert_eq!(
+            session,
+            ("256MB".to_owned(), "5s".to_owned(), "off".to_owned())
+        );
+        config.session = toml::from_str("no_such_setting = 1").unwrap();
+        assert!(config.connect().is_err());
+        assert_eq!(crate::param("user", "a"), "user=a");
+        assert_eq!(crate::param("password", "it's a"), r"password='it\'s a'");
+        assert_eq!(crate::param("options", ""), "options=''");
+        let params = [
+            crate::param("user", "a"),
+            crate::param("password", "it's a"),
+        ];
+        assert_eq!(crate::masked(&params), "user=a password=********");
+    }
+
+    #[test]
+    fn kerberos_hint() {
+        let gss = crate::connection_error(
+            "authentication error: unsupported authentication method".to_owned(),
+            "migrator",
+        );
+        assert!(gss.to_string().contains("Kerberos"));
+        let refused = crate::connection_error("connection refused".to_owned(), "migrator");
+        assert_eq!(refused.to_string(), "connection refused");
+    }
+
+    #[test]
+    fn refuses_read_only() {
+        init();
+        let mut config = test_config().unwrap();
+        config.options = "-c default_transaction_read_only=on".to_owned();
+        let result = crate::Migrator::new(config, std::path::PathBuf::from("./read_only"));
+        let _ = std::fs::remove_dir_all("./read_only");
+        assert!(result.err().unwrap().to_string().contains("is read only"));
+    }
+
+    #[test]
+    fn subcommands() {
+        let parse = |args: &[&str]| {
+            <crate::Args as clap::Parser>::try_parse_from([&["architect"], args].concat())
+        };
+        let down = parse(&["down", "--steps", "2"]).unwrap();
+        assert!(matches!(
+            down.command,
+            Some(crate::Command::Down {
+                steps: Some(2),
+                all: false,
+                before: None
+            })
+        ));
+        assert!(parse(&["down"]).is_err());
+        assert!(parse(&["down", "--all", "--steps", "1"]).is_err());
+        assert!(parse(&["up", "--steps", "1", "--parallel", "2"]).is_err());
+        assert!(parse(&["--upn", "1"]).is_err());
+    }
+
+    #[test]
+    fn read_only_commands() {
+        init();
+        // creates the tracking tables
+        crate::Migrator::new(
+            test_config().unwrap(),
+            std::path::PathBuf::from("./read_only_cmds"),
+        )
+        .unwrap();
+        let mut config = test_config().unwrap();
+        config.options = "-c default_transaction_read_only=on".to_owned();
+        let m = crate::Migrator::read_only(config, std::path::PathBuf::from("./read_only_cmds"));
+        let history = m.and_then(|mut m| m.history());
+        let _ = std::fs::remove_dir_all("./read_only_cmds");
+        history.unwrap();
+    }
+
+    #[test]
+    fn verifier() {
+        init();
+        let dir = std::path::PathBuf::from("./verifier");
+        let mut m = crate::Migrator::new(test_config().unwrap(), dir.clone()).unwrap();
+        m.client
+            .batch_execute(
+                "DROP ROLE IF EXISTS __verifier__;
+                CREATE ROLE __verifier__ LOGIN PASSWORD 'verify';
+                GRANT SELECT ON schema_migrations TO __verifier__;",
+            )
+            .unwrap();
+        let mut config = test_config().unwrap();
+        config.verifier = toml::from_str("user = \"__verifier__\"\npassword = \"verify\"").unwrap();
+        let current_user = |m: &mut crate::Migrator| -> String {
+            m.client
+                .query_one("SELECT current_user::text", &[])
+                .unwrap()
+                .get(0)
+        };
+        let mut migrator = crate::Migrator::new(config.clone(), dir.clone()).unwrap();
+        let mut verifier = crate::Migrator::read_only(config.clone(), dir.clone()).unwrap();
+        let users = (current_user(&mut migrator), current_user(&mut verifier));
+        let history = verifier.history();
+        let write = verifier
+            .client
+            .batch_execute("DELETE FROM schema_migrations WHERE version = -1");
+        drop(verifier);
+        m.client
+            .batch_execute(
+                "REVOKE ALL ON schema_migrations FROM __verifier__; DROP ROLE __verifier__;",
+            )
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(users, (config.user, "__verifier__".to_owned()));
+        history.unwrap();
+        assert!(write.is_err());
+    }
+
     #[test]
     fn available_versions() {
         init();
@@ -640,6 +3033,23 @@ mod tests {
         assert_eq!(m.versions_down.len(), N);
     }
 
+    #[test]
+    fn allocate_version() {
+        init();
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./allocate")).unwrap();
+        std::fs::write(m.dir.join("5_up.sql"), b"").unwrap();
+        std::fs::write(m.dir.join("6_down.rhai"), b"").unwrap();
+
+        let (up, down) = m.allocate_version(5).unwrap();
+        let (next, _) = m.allocate_version(5).unwrap();
+
+        let _ = std::fs::remove_dir_all("./allocate");
+        assert!(up.ends_with("7_up.sql"));
+        assert!(down.ends_with("7_down.sql"));
+        assert!(next.ends_with("8_up.sql"));
+    }
+
     #[test]
     fn run_mig() {
         init();
@@ -672,7 +3082,7 @@ DROP TABLE IF EXISTS __another__;",
         )
         .unwrap();
 
-        m.run_migration(version, "up".to_owned()).unwrap();
+        m.run_migration(version, crate::Direction::Up).unwrap();
 
         let vrows = m
             .client
@@ -695,7 +3105,7 @@ DROP TABLE IF EXISTS __another__;",
         assert_eq!(mver, version);
         assert_eq!(count, 2);
 
-        m.run_migration(version, "down".to_owned()).unwrap();
+        m.run_migration(version, crate::Direction::Down).unwrap();
         let vrows = m
             .client
             .query(
@@ -848,4 +3258,25 @@ DROP TABLE IF EXISTS __another__;",
         assert_eq!(m.last_version, 0);
         assert_eq!(n, 12);
     }
+    #[test]
+    fn goto_up_and_down() {
+        init();
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./goto")).unwrap();
+        const N: usize = 10;
+        for _ in 0..N {
+            m.new_migration().unwrap();
+        }
+        m.last_version = 0;
+        let target = *m.versions_up.get(6).unwrap();
+        let up = m.goto(target, true).unwrap(); // call with test true to not run migrations
+        let back = *m.versions_up.get(2).unwrap();
+        let down = m.goto(back, true).unwrap();
+
+        let _ = std::fs::remove_dir_all("./goto");
+
+        assert_eq!(up, 7);
+        assert_eq!(down, 4);
+        assert_eq!(m.last_version, back);
+    }
 }
@@ -0,0 +1,107 @@
+use anyhow::Result;
+
+/// Migration versions in `dir` as of the git revision `rev`.
+pub(crate) fn versions_at_revision(dir: &std::path::Path, rev: &str) -> Result<Vec<i64>> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["ls-tree", "--name-only", rev, "./"])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git ls-tree failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let reg = regex::Regex::new(crate::MIGRATION_FILE)?;
+    let mut result = Vec::<i64>::new();
+    for line in String::from_utf8(output.stdout)?.lines() {
+        let name = line.rsplit('/').next().unwrap_or(line);
+        if let Some(caps) = reg.captures(name) {
+            if &caps[2] == "up" {
+                result.push(caps[1].parse()?);
+            }
+        }
+    }
+    result.sort();
+    result.dedup();
+    Ok(result)
+}
+
+/// Versions in `dir` that aren't on `base` but are older than the newest version on `base`.
+/// Once merged they'd be skipped by `up` wherever base's migrations are applied already.
+pub(crate) fn merge_check(dir: &std::path::Path, base: &str) -> Result<Vec<i64>> {
+    let base_versions = versions_at_revision(dir, base)?;
+    let newest = match base_versions.last() {
+        Some(v) => *v,
+        None => return Ok(Vec::new()),
+    };
+    let (current, _) = crate::scan_versions(dir)?;
+    Ok(current
+        .into_iter()
+        .filter(|v| *v < newest && !base_versions.contains(v))
+        .collect())
+}
+
+/// Reports the out of order versions, failing if there are any.
+pub(crate) fn run(dir: &std::path::Path, base: &str) -> Result<()> {
+    let versions = merge_check(dir, base)?;
+    for v in versions.iter() {
+        println!(
+            "{}: older than the newest migration on {}. create it again with a new version",
+            v, base
+        );
+    }
+    if !versions.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} migrations would be applied out of order after merging into {}",
+            versions.len(),
+            base
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn merge_check() {
+        let root = std::path::PathBuf::from("./merge_check");
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        let touch = |name: &str| std::fs::write(dir.join(name), b"").unwrap();
+        git(&["init", "-q"]);
+        touch("1_up.sql");
+        touch("1_down.sql");
+        touch("3_up.sql");
+        touch("3_down.sql");
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "base"]);
+        git(&["tag", "base"]);
+        // added on a branch created before 3 was merged
+        touch("2_up.sql");
+        touch("2_down.sql");
+        touch("4_up.sql");
+        touch("4_down.sql");
+
+        let base = super::versions_at_revision(&dir, "base").unwrap();
+        let out_of_order = super::merge_check(&dir, "base").unwrap();
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(base, vec![1, 3]);
+        assert_eq!(out_of_order, vec![2]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Naming conventions for the objects migrations create or rename, configured as regexes per kind
+//! of object in the `[naming]` table of `.architect.toml`.
+
+use anyhow::Result;
+use serde::Deserialize;
+use sqlparser::ast::{
+    AlterIndexOperation, AlterTableOperation, ColumnDef, ColumnOption, Ident, ObjectName,
+    Statement, TableConstraint,
+};
+
+use crate::lint::{Finding, Severity};
+
+/// Keywords postgres reserves, which can only be used as names when quoted.
+const RESERVED: &str = "\
+    all analyse analyze and any array as asc asymmetric authorization binary both case cast check \
+    collate collation column concurrently constraint create cross current_catalog current_date \
+    current_role current_schema current_time current_timestamp current_user default deferrable \
+    desc distinct do else end except false fetch for foreign freeze from full grant group having \
+    ilike in initially inner intersect into is isnull join lateral leading left like limit \
+    localtime localtimestamp natural not notnull null offset on only or order outer overlaps \
+    placing primary references returning right select session_user similar some symmetric \
+    system_user table tablesample then to trailing true union unique user using variadic verbose \
+    when where window with";
+
+/// Patterns names have to match, by kind of object. `{table}` in the patterns of indexes and
+/// constraints is replaced by the name of their table, `{references}` in the pattern of foreign
+/// keys by the name of the table they reference.
+#[derive(Deserialize, Default)]
+pub(crate) struct Naming {
+    pub(crate) table: Option<String>,
+    pub(crate) column: Option<String>,
+    pub(crate) view: Option<String>,
+    pub(crate) index: Option<String>,
+    pub(crate) foreign_key: Option<String>,
+    /// Primary key, unique and check constraints
+    pub(crate) constraint: Option<String>,
+    /// Disallow reserved keywords as names
+    #[serde(default)]
+    pub(crate) reserved: bool,
+}
+
+/// A name a statement gives to an object.
+struct Name {
+    kind: &'static str,
+    name: String,
+    table: String,
+    references: String,
+}
+
+impl Name {
+    fn new(kind: &'static str, name: &Ident, table: &str) -> Self {
+        Name {
+            kind,
+            name: ident(name),
+            table: table.to_owned(),
+            references: String::new(),
+        }
+    }
+}
+
+/// `i` as postgres sees it: unquoted identifiers are lower case.
+pub(crate) fn ident(i: &Ident) -> String {
+    match i.quote_style {
+        Some(_) => i.value.clone(),
+        None => i.value.to_lowercase(),
+    }
+}
+
+/// The name of an object without its schema.
+fn last(name: &ObjectName) -> String {
+    name.0.last().map(ident).unwrap_or_default()
+}
+
+fn column_names(result: &mut Vec<Name>, c: &ColumnDef, table: &str) {
+    result.push(Name::new("column", &c.name, table));
+    for o in c.options.iter() {
+        if let Some(name) = &o.name {
+            let mut n = Name::new("constraint", name, table);
+            if let ColumnOption::ForeignKey { foreign_table, .. } = &o.option {
+                n.kind = "foreign_key";
+                n.references = last(foreign_table);
+            }
+            result.push(n);
+        }
+    }
+}
+
+fn constraint_name(result: &mut Vec<Name>, c: &TableConstraint, table: &str) {
+    match c {
+        TableConstraint::Unique {
+            name: Some(name), ..
+        }
+        | TableConstraint::Check {
+            name: Some(name), ..
+        } => result.push(Name::new("constraint", name, table)),
+        TableConstraint::ForeignKey {
+            name: Some(name),
+            foreign_table,
+            ..
+        } => result.push(Name {
+            references: last(foreign_table),
+            ..Name::new("foreign_key", name, table)
+        }),
+        _ => {}
+    }
+}
+
+/// Names the statements give to objects, in the order they appear.
+fn names(statements: &[Statement]) -> Vec<Name> {
+    let mut result = Vec::<Name>::new();
+    for s in statements.iter() {
+        match s {
+            Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                ..
+            } => {
+                let table = last(name);
+                if let Some(i) = name.0.last() {
+                    result.push(Name::new("table", i, &table));
+                }
+                for c in columns.iter() {
+                    column_names(&mut result, c, &table);
+                }
+                for c in constraints.iter() {
+                    constraint_name(&mut result, c, &table);
+                }
+            }
+            Statement::AlterTable { name, operation } => {
+                let table = last(name);
+                match operation {
+                    AlterTableOperation::AddColumn { column_def, .. } => {
+                        column_names(&mut result, column_def, &table)
+                    }
+                    AlterTableOperation::AddConstraint(c) => {
+                        constraint_name(&mut result, c, &table)
+                    }
+                    AlterTableOperation::RenameColumn {
+                        new_column_name, ..
+                    } => result.push(Name::new("column", new_column_name, &table)),
+                    AlterTableOperation::RenameTable { table_name } => {
+                        if let Some(i) = table_name.0.last() {
+                            result.push(Name::new("table", i, &last(table_name)));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Statement::CreateIndex {
+                name, table_name, ..
+            } => {
+                if let Some(i) = name.0.last() {
+                    result.push(Name::new("index", i, &last(table_name)));
+                }
+            }
+            Statement::AlterIndex {
+                operation: AlterIndexOperation::RenameIndex { index_name },
+                ..
+            } => {
+                // the table isn't known, patterns using it can't match
+                if let Some(i) = index_name.0.last() {
+                    result.push(Name::new("index", i, ""));
+                }
+            }
+            Statement::CreateView { name, .. } => {
+                if let Some(i) = name.0.last() {
+                    result.push(Name::new("view", i, ""));
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+impl Naming {
+    fn pattern(&self, kind: &str) -> Option<&String> {
+        match kind {
+            "table" => self.table.as_ref(),
+            "column" => self.column.as_ref(),
+            "view" => self.view.as_ref(),
+            "index" => self.index.as_ref(),
+            "foreign_key" => self.foreign_key.as_ref(),
+            _ => self.constraint.as_ref(),
+        }
+    }
+
+    /// Reports the names in `statements` that don't follow the conventions.
+    pub(crate) fn check(&self, file: &str, statements: &[Statement]) -> Result<Vec<Finding>> {
+        let mut result = Vec::<Finding>::new();
+        for n in names(statements) {
+            let kind = n.kind.replace('_', " ");
+            if self.reserved
+                && RESERVED
+                    .split_whitespace()
+                    .any(|v| v == n.name.to_lowercase())
+            {
+                result.push(Finding::new(
+                    file,
+                    "reserved-word",
+                    Severity::Error,
+                    format!("{} \"{}\" is a reserved keyword", kind, n.name),
+                ));
+            }
+            let pattern = match self.pattern(n.kind) {
+                Some(v) => v
+                    .replace("{table}", &regex::escape(&n.table))
+                    .replace("{references}", &regex::escape(&n.references)),
+                None => continue,
+            };
+            let reg = match regex::Regex::new(&pattern) {
+                Ok(v) => v,
+                Err(e) => return Err(anyhow::anyhow!("invalid {} naming pattern: {}", n.kind, e)),
+            };
+            if !reg.is_match(&n.name) {
+                result.push(Finding::new(
+                    file,
+                    "naming-convention",
+                    Severity::Error,
+                    format!("{} \"{}\" doesn't match {}", kind, n.name, pattern),
+                ));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn naming() {
+        let naming = super::Naming {
+            table: Some("^[a-z][a-z0-9_]*$".to_owned()),
+            column: Some("^[a-z][a-z0-9_]*$".to_owned()),
+            index: Some("^{table}_.+_idx$".to_owned()),
+            foreign_key: Some("^{table}_{references}_fk$".to_owned()),
+            reserved: true,
+            ..Default::default()
+        };
+        let statements = crate::parse_ast(
+            "CREATE TABLE teams (id INT PRIMARY KEY, \"Name\" TEXT);
+            CREATE TABLE users (id INT, team_id INT,
+                CONSTRAINT users_teams_fk FOREIGN KEY (team_id) REFERENCES teams (id));
+            ALTER TABLE users ADD COLUMN \"user\" TEXT;
+            ALTER TABLE users ADD CONSTRAINT team_fk FOREIGN KEY (team_id) REFERENCES teams (id);
+            CREATE INDEX users_team_id_idx ON users (team_id);
+            CREATE INDEX team_id ON Users (team_id);",
+        )
+        .unwrap();
+
+        let messages: Vec<String> = naming
+            .check("1_up.sql", &statements)
+            .unwrap()
+            .into_iter()
+            .map(|f| format!("{} {}", f.rule, f.message))
+            .collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "naming-convention column \"Name\" doesn't match ^[a-z][a-z0-9_]*$",
+                "reserved-word column \"user\" is a reserved keyword",
+                "naming-convention foreign key \"team_fk\" doesn't match ^users_teams_fk$",
+                "naming-convention index \"team_id\" doesn't match ^users_.+_idx$",
+            ]
+        );
+        assert!(super::Naming {
+            table: Some("(".to_owned()),
+            ..Default::default()
+        }
+        .check("1_up.sql", &statements)
+        .is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Notifications of migrations to the database's listeners. With `notify` set, every migration
+//! sends `NOTIFY architect_migrations` with a JSON payload when it starts, succeeds and fails, so
+//! services LISTENing on the database can react, e.g. refresh caches or pause consumers, without
+//! any messaging infrastructure. The notifications are sent outside of the migrations'
+//! transactions, so they're delivered at once.
+
+use serde::Serialize;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+pub(crate) const CHANNEL: &str = "architect_migrations";
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    /// `started`, `succeeded` or `failed`
+    event: &'a str,
+    app: &'a str,
+    dbname: &'a str,
+    version: i64,
+    direction: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl Migrator {
+    /// Notifies the listeners of `event` of the migration if `notify` is set. A failing
+    /// notification doesn't stop the run.
+    pub(crate) fn notify_listeners(
+        &mut self,
+        event: &str,
+        version: i64,
+        direction: Direction,
+        duration_ms: Option<u128>,
+        error: Option<&str>,
+    ) {
+        if !self.config.notify {
+            return;
+        }
+        let payload = Payload {
+            event,
+            app: &self.config.app,
+            dbname: &self.config.dbname,
+            version,
+            direction: direction.as_str(),
+            duration_ms,
+            error,
+        };
+        let payload = match serde_json::to_string(&payload) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("notifying {} failed: {}", CHANNEL, e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .client
+            .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+        {
+            eprintln!("notifying {} failed: {}", CHANNEL, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres::fallible_iterator::FallibleIterator;
+
+    #[test]
+    fn notify() {
+        let dir = std::path::PathBuf::from("./notify");
+        let mut config = crate::tests::schema_config("__notify__");
+        config.notify = true;
+        let mut listener = config.clone().connect().unwrap();
+        listener
+            .batch_execute(&format!("LISTEN {}", super::CHANNEL))
+            .unwrap();
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE notified (id INT);").unwrap();
+        let version = *m.versions_up.last().unwrap();
+        m.migrate_up(false).unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE notified (id INT);").unwrap();
+        let failed = m.migrate_up(false);
+        let mut events = Vec::<serde_json::Value>::new();
+        let mut notifications = listener.notifications();
+        let mut iter = notifications.timeout_iter(std::time::Duration::from_millis(500));
+        while let Ok(Some(n)) = iter.next() {
+            let payload: serde_json::Value = serde_json::from_str(n.payload()).unwrap();
+            if payload["version"].as_i64().unwrap_or(0) >= version {
+                events.push(payload);
+            }
+        }
+        m.client
+            .batch_execute("DROP SCHEMA __notify__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(failed.is_err());
+        let names: Vec<&str> = events
+            .iter()
+            .map(|v| v["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["started", "succeeded", "started", "failed"]);
+        assert_eq!(events[0]["version"], version);
+        assert_eq!(events[1]["direction"], "up");
+        assert!(events[1]["duration_ms"].is_number());
+        assert!(events[3]["error"]
+            .as_str()
+            .unwrap()
+            .contains("already exists"));
+    }
+}
@@ -0,0 +1,244 @@
+//! Online column changes. `change-column` writes the sequence changing a column's type or name
+//! without locking the table for a rewrite, as migrations applied in order, each in a deploy of
+//! its own:
+//!
+//! 1. expand: add the new column and a trigger keeping it in sync with writes to the old one
+//! 2. backfill: copy the existing rows in batches, see `batch_update`
+//! 3. swap: check the backfill is complete, drop the trigger and give the new column the name
+//! 4. drop: drop the old column once nothing uses it
+//!
+//! The trigger needs sql architect can't parse, so those migrations are verbatim.
+
+use anyhow::Result;
+
+use crate::Migrator;
+
+/// A change of `table.column` to `data_type` named `name`.
+pub(crate) struct Change {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    /// Type of the column before the change
+    pub(crate) old_type: String,
+    pub(crate) data_type: String,
+    pub(crate) name: String,
+    /// Expression converting the old column to the new type
+    pub(crate) using: String,
+    /// Integer key of the table the backfill batches by
+    pub(crate) key: String,
+    pub(crate) size: i64,
+}
+
+impl Change {
+    /// Name of the new column until the swap.
+    fn new_column(&self) -> String {
+        if self.name == self.column {
+            format!("{}_new", self.column)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// Name of the old column after the swap.
+    fn old_column(&self) -> String {
+        if self.name == self.column {
+            format!("{}_old", self.column)
+        } else {
+            self.column.clone()
+        }
+    }
+
+    fn trigger(&self) -> String {
+        format!("{}_{}_sync", self.table.replace('.', "_"), self.column)
+    }
+
+    /// The conversion expression on the row the trigger fires for.
+    fn row_using(&self) -> String {
+        let column = regex::Regex::new(&format!(r"\b{}\b", regex::escape(&self.column))).unwrap();
+        column
+            .replace_all(&self.using, format!("NEW.{}", self.column).as_str())
+            .into_owned()
+    }
+
+    fn create_trigger(&self) -> String {
+        format!(
+            "CREATE FUNCTION {trigger}() RETURNS trigger LANGUAGE plpgsql AS $$\n\
+            BEGIN\n    NEW.{new} := {using};\n    RETURN NEW;\nEND\n$$;\n\
+            CREATE TRIGGER {trigger} BEFORE INSERT OR UPDATE ON {table}\n    \
+            FOR EACH ROW EXECUTE FUNCTION {trigger}();\n",
+            trigger = self.trigger(),
+            new = self.new_column(),
+            using = self.row_using(),
+            table = self.table
+        )
+    }
+
+    fn drop_trigger(&self) -> String {
+        format!(
+            "DROP TRIGGER {trigger} ON {table};\nDROP FUNCTION {trigger}();\n",
+            trigger = self.trigger(),
+            table = self.table
+        )
+    }
+
+    /// The named up and down sql of the migrations, in the order they have to be applied.
+    pub(crate) fn migrations(&self) -> Vec<(&'static str, (String, String))> {
+        let (table, column) = (&self.table, &self.column);
+        let (new, old) = (self.new_column(), self.old_column());
+        let verbatim = format!("-- architect:{}\n", crate::directives::VERBATIM);
+        let expand = (
+            format!(
+                "{verbatim}-- change {table}.{column}, 1/4: add {new}, kept in sync by a trigger\n\
+                ALTER TABLE {table} ADD COLUMN {new} {data_type};\n{trigger}",
+                data_type = self.data_type,
+                trigger = self.create_trigger()
+            ),
+            format!(
+                "{verbatim}{}ALTER TABLE {table} DROP COLUMN {new};\n",
+                self.drop_trigger()
+            ),
+        );
+        let backfill = (
+            format!(
+                "-- change {table}.{column}, 2/4: backfill {new}\n\
+                -- architect:{} key={} size={}\n\
+                UPDATE {table} SET {new} = {using} WHERE {new} IS DISTINCT FROM {using};\n",
+                crate::batch_update::DIRECTIVE,
+                self.key,
+                self.size,
+                using = self.using
+            ),
+            format!(
+                "-- architect:{}\n-- nothing to undo, the expand migration drops {new}\n",
+                crate::irreversible::DIRECTIVE
+            ),
+        );
+        let mut swap_up = format!(
+            "{verbatim}-- change {table}.{column}, 3/4: swap in {new} once the backfill is done\n\
+            LOCK TABLE {table} IN SHARE ROW EXCLUSIVE MODE;\n\
+            DO $$\nBEGIN\n    \
+            IF EXISTS (SELECT FROM {table} WHERE {new} IS DISTINCT FROM {using}) THEN\n        \
+            RAISE EXCEPTION '{table}.{new} isn''t backfilled, apply the backfill migration first';\n    \
+            END IF;\nEND\n$$;\n{}",
+            self.drop_trigger(),
+            using = self.using
+        );
+        let mut swap_down = format!("{verbatim}{}", self.create_trigger());
+        if new != self.name {
+            swap_up.push_str(&format!(
+                "ALTER TABLE {table} RENAME COLUMN {column} TO {old};\n\
+                ALTER TABLE {table} RENAME COLUMN {new} TO {column};\n"
+            ));
+            swap_down = format!(
+                "{verbatim}ALTER TABLE {table} RENAME COLUMN {column} TO {new};\n\
+                ALTER TABLE {table} RENAME COLUMN {old} TO {column};\n{}",
+                self.create_trigger()
+            );
+        }
+        let drop = (
+            format!(
+                "-- change {table}.{column}, 4/4: drop {old} once nothing uses it\n\
+                ALTER TABLE {table} DROP COLUMN {old};\n"
+            ),
+            format!(
+                "ALTER TABLE {table} ADD COLUMN {old} {old_type};\n\
+                UPDATE {table} SET {old} = {name}::{old_type};\n",
+                old_type = self.old_type,
+                name = self.name
+            ),
+        );
+        vec![
+            ("expand", expand),
+            ("backfill", backfill),
+            ("swap", (swap_up, swap_down)),
+            ("drop", drop),
+        ]
+    }
+}
+
+impl Migrator {
+    /// Writes the migrations changing `change.column` online, see the module docs. The old type is
+    /// read from the database.
+    pub(crate) fn change_column(&mut self, mut change: Change, yes: bool) -> Result<Vec<i64>> {
+        change.old_type = self.column_type(&change.table, &change.column)?;
+        if change.data_type.is_empty() {
+            change.data_type = change.old_type.clone();
+        }
+        if change.data_type == change.old_type && change.name == change.column {
+            return Err(anyhow::anyhow!(
+                "nothing to change, give a new type or a new name"
+            ));
+        }
+        if change.using.is_empty() {
+            change.using = format!("{}::{}", change.column, change.data_type);
+        }
+        let migrations = change.migrations();
+        self.write_generated(&migrations, yes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn change_column() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./online")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __online__;
+                CREATE TABLE __online__ (id INT PRIMARY KEY, amount INT);
+                INSERT INTO __online__ SELECT i, i * 10 FROM generate_series(1, 25) i;",
+            )
+            .unwrap();
+        let change = super::Change {
+            table: "__online__".to_owned(),
+            column: "amount".to_owned(),
+            old_type: String::new(),
+            data_type: "bigint".to_owned(),
+            name: "amount".to_owned(),
+            using: String::new(),
+            key: "id".to_owned(),
+            size: 10,
+        };
+        let versions = m.change_column(change, true).unwrap();
+        // the app writes while the change is in progress
+        m.migrate_up_n(1, false).unwrap();
+        m.client
+            .batch_execute("INSERT INTO __online__ VALUES (26, 260)")
+            .unwrap();
+        let migrated = m.migrate_up(false);
+        let row = m
+            .client
+            .query_one(
+                "SELECT sum(amount)::bigint, pg_typeof(amount)::text FROM __online__ GROUP BY 2",
+                &[],
+            )
+            .unwrap();
+        let (sum, data_type): (i64, String) = (row.get(0), row.get(1));
+        let down = m.migrate_down_n(4, false);
+        let restored: String = m
+            .client
+            .query_one(
+                "SELECT pg_typeof(amount)::text FROM __online__ LIMIT 1",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        m.client.batch_execute("DROP TABLE __online__").unwrap();
+        for v in versions.iter() {
+            m.client
+                .execute("DELETE FROM schema_migrations WHERE version = $1", &[v])
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all("./online");
+
+        assert_eq!(versions.len(), 4);
+        assert_eq!(migrated.unwrap(), 3);
+        assert_eq!(sum, 3510);
+        assert_eq!(data_type, "bigint");
+        assert_eq!(down.unwrap(), 4);
+        assert_eq!(restored, "integer");
+    }
+}
@@ -0,0 +1,69 @@
+//! Informational output and command results. Results, like tables, plans, JSON or what a command
+//! did, go to stdout. Progress and diagnostics go to stderr. With `--quiet` informational output
+//! is suppressed and commands report their result as a single line of JSON instead.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub(crate) fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `eprintln!` for informational output, suppressed with `--quiet`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::output::quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+/// The single line reporting a command's result with `--quiet`. Keys are sorted.
+fn line(ok: bool, mut fields: serde_json::Value) -> String {
+    if let Some(v) = fields.as_object_mut() {
+        v.insert("ok".to_owned(), serde_json::Value::Bool(ok));
+    }
+    fields.to_string()
+}
+
+/// Reports the result of a command on stdout: `human`, or `fields` as a line of JSON with
+/// `--quiet`.
+pub(crate) fn result(human: &str, fields: serde_json::Value) {
+    if quiet() {
+        println!("{}", line(true, fields));
+    } else {
+        println!("{}", human);
+    }
+}
+
+/// Reports a failed command as a line of JSON on stdout with `--quiet`. The error itself is
+/// still returned to be printed on stderr.
+pub(crate) fn failure(error: &anyhow::Error) {
+    if quiet() {
+        println!(
+            "{}",
+            line(false, serde_json::json!({ "error": error.to_string() }))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn result_line() {
+        assert_eq!(
+            super::line(true, serde_json::json!({ "version": 2, "migrated": 1 })),
+            r#"{"migrated":1,"ok":true,"version":2}"#
+        );
+        assert_eq!(
+            super::line(false, serde_json::json!({ "error": "a\nb" })),
+            r#"{"error":"a\nb","ok":false}"#
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Teams owning migrations, so review can be routed to the owners of a schema. Owners are
+//! configured in the `[[owners]]` array of `.architect.toml` and match migrations by path or by
+//! the tables their statements touch.
+
+use anyhow::Result;
+use serde::Deserialize;
+use sqlparser::ast::{CommentObject, ObjectName, ObjectType, Statement, TableFactor};
+
+use crate::direction::Direction;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Owner {
+    pub(crate) team: String,
+    /// Globs matched against `<app>/<file>`, the path relative to the parent migration directory
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+    /// Globs matched against the tables a migration touches, with and without their schema
+    #[serde(default)]
+    pub(crate) tables: Vec<String>,
+}
+
+/// `name` as postgres sees it: unquoted identifiers are lower case.
+pub(crate) fn object_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(crate::naming::ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Tables the statements create, change or write to.
+pub(crate) fn tables(statements: &[Statement]) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    for s in statements.iter() {
+        let names: Vec<&ObjectName> = match s {
+            Statement::CreateTable { name, .. } | Statement::AlterTable { name, .. } => vec![name],
+            Statement::Drop {
+                object_type: ObjectType::Table,
+                names,
+                ..
+            } => names.iter().collect(),
+            Statement::CreateIndex { table_name, .. }
+            | Statement::Insert { table_name, .. }
+            | Statement::Truncate { table_name, .. } => vec![table_name],
+            Statement::Update { table, .. } => match &table.relation {
+                TableFactor::Table { name, .. } => vec![name],
+                _ => vec![],
+            },
+            Statement::Delete {
+                table_name: TableFactor::Table { name, .. },
+                ..
+            } => vec![name],
+            Statement::Comment {
+                object_type: CommentObject::Table,
+                object_name,
+                ..
+            } => vec![object_name],
+            _ => vec![],
+        };
+        for name in names {
+            let name = object_name(name);
+            if !result.contains(&name) {
+                result.push(name);
+            }
+        }
+    }
+    result
+}
+
+/// Teams owning a migration at `path`, `<app>/<file>`, in the order they're configured.
+pub(crate) fn owners(
+    owners: &[Owner],
+    path: &str,
+    statements: &[Statement],
+) -> Result<Vec<String>> {
+    let tables = tables(statements);
+    let mut result = Vec::<String>::new();
+    for owner in owners.iter() {
+        let mut matches = false;
+        for p in owner.paths.iter() {
+            matches |= glob::Pattern::new(p)?.matches(path);
+        }
+        for p in owner.tables.iter() {
+            let pattern = glob::Pattern::new(p)?;
+            matches |= tables
+                .iter()
+                .any(|t| pattern.matches(t) || pattern.matches(t.rsplit('.').next().unwrap_or(t)));
+        }
+        if matches && !result.contains(&owner.team) {
+            result.push(owner.team.clone());
+        }
+    }
+    Ok(result)
+}
+
+/// Owners of the up migrations in the app directory `dir`, leaving out migrations without any.
+pub(crate) fn of_dir(dir: &std::path::Path) -> Result<Vec<(String, Vec<String>)>> {
+    let project = match dir.parent() {
+        Some(v) => crate::project::Project::read(v)?,
+        None => return Ok(Vec::new()),
+    };
+    let app = dir
+        .file_name()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut result = Vec::<(String, Vec<String>)>::new();
+    if project.owners.is_empty() {
+        return Ok(result);
+    }
+    let (up, _) = crate::scan_versions(dir)?;
+    for v in up {
+        let path = crate::migration_file(dir, v, Direction::Up);
+        let file = path
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // files that don't parse are reported by validate, they're matched by path only
+        let statements = match path.extension() {
+            Some(e) if e == "sql" => {
+                crate::parse_ast(&std::fs::read_to_string(&path)?).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        let teams = owners(&project.owners, &format!("{}/{}", app, file), &statements)?;
+        if !teams.is_empty() {
+            result.push((file, teams));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn owners() {
+        let owners = vec![
+            super::Owner {
+                team: "@payments".to_owned(),
+                paths: vec![],
+                tables: vec!["payment*".to_owned()],
+            },
+            super::Owner {
+                team: "@billing".to_owned(),
+                paths: vec!["billing/*".to_owned()],
+                tables: vec!["billing.*".to_owned()],
+            },
+        ];
+        let statements = crate::parse_ast(
+            "CREATE INDEX idx ON Payments (id);
+            UPDATE billing.invoices SET paid = true;",
+        )
+        .unwrap();
+
+        assert_eq!(
+            super::tables(&statements),
+            vec!["payments".to_owned(), "billing.invoices".to_owned()]
+        );
+        assert_eq!(
+            super::owners(&owners, "app/1_up.sql", &statements).unwrap(),
+            vec!["@payments".to_owned(), "@billing".to_owned()]
+        );
+        assert_eq!(
+            super::owners(&owners, "billing/1_up.sql", &[]).unwrap(),
+            vec!["@billing".to_owned()]
+        );
+        assert!(super::owners(&owners, "app/1_up.sql", &[])
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+//! Parallel application of independent migrations, for bootstrapping fresh databases with many
+//! migrations. `up --parallel N` applies consecutive pending migrations that don't depend on each
+//! other in waves of up to N over N connections. A migration is independent if it has the
+//! `-- architect:independent` directive, or if it only creates tables with their indexes and
+//! comments. The migrations of a wave touch disjoint tables and the wave only commits once all of
+//! them succeeded, otherwise all of them are rolled back, so the applied versions stay contiguous.
+//! Scripts, batch updates, migrations with `if` blocks and other migrations run one at a time as
+//! usual.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Barrier;
+
+use anyhow::Result;
+use postgres::{Client, Transaction};
+use sqlparser::ast::{ColumnOption, CommentObject, Statement, TableConstraint};
+
+use crate::direction::{Direction, Step};
+use crate::email::StatementRun;
+use crate::error::ArchitectError;
+use crate::output::info;
+use crate::Migrator;
+
+/// Marks a migration that can run concurrently with the migrations next to it.
+pub(crate) const DIRECTIVE: &str = "independent";
+
+/// Error of the migrations rolled back because another one of their wave failed.
+const ROLLED_BACK: &str = "rolled back as another migration of the wave failed";
+
+/// Tables a migration touches if it's independent of the migrations around it, None if it
+/// isn't.
+pub(crate) fn independent(sql: &str) -> Result<Option<Vec<String>>> {
+    let annotated = crate::directives::has(sql, DIRECTIVE);
+    if crate::directives::has(sql, crate::directives::VERBATIM) {
+        return Ok(if annotated { Some(Vec::new()) } else { None });
+    }
+    let statements = crate::parse_ast(sql)?;
+    let mut created = Vec::<String>::new();
+    let mut referenced = Vec::<String>::new();
+    for s in statements.iter() {
+        match s {
+            Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                query: None,
+                like: None,
+                clone: None,
+                ..
+            } => {
+                created.push(crate::owners::object_name(name));
+                for c in constraints.iter() {
+                    if let TableConstraint::ForeignKey { foreign_table, .. } = c {
+                        referenced.push(crate::owners::object_name(foreign_table));
+                    }
+                }
+                for o in columns.iter().flat_map(|c| c.options.iter()) {
+                    if let ColumnOption::ForeignKey { foreign_table, .. } = &o.option {
+                        referenced.push(crate::owners::object_name(foreign_table));
+                    }
+                }
+            }
+            Statement::CreateIndex { .. }
+            | Statement::Comment {
+                object_type: CommentObject::Table,
+                ..
+            } => {}
+            _ if annotated => {}
+            _ => return Ok(None),
+        }
+    }
+    let mut tables = crate::owners::tables(&statements);
+    // indexes and comments only on the tables the migration creates
+    if !annotated && tables.iter().any(|t| !created.contains(t)) {
+        return Ok(None);
+    }
+    for t in referenced {
+        if !tables.contains(&t) {
+            tables.push(t);
+        }
+    }
+    Ok(Some(tables))
+}
+
+/// A migration of a wave.
+struct Job {
+    version: i64,
+    sql: String,
+    /// Its statements, the last one recording the version
+    queries: Vec<String>,
+}
+
+/// The sql hooks run around every migration.
+struct SqlHooks {
+    before_each: Vec<String>,
+    after_each: Vec<String>,
+}
+
+/// Runs the statements of `job` in `t`, like `Migrator::apply_migration`.
+fn execute(t: &mut Transaction, job: &Job, hooks: &SqlHooks) -> Result<Vec<StatementRun>> {
+    let start = std::time::Instant::now();
+    let (record, queries) = match job.queries.split_last() {
+        Some(v) => v,
+        None => return Err(anyhow::anyhow!("no statement records {}", job.version)),
+    };
+    if !hooks.before_each.is_empty() || !hooks.after_each.is_empty() {
+        t.execute(
+            "SELECT set_config('architect.version', $1, true), \
+            set_config('architect.direction', $2, true)",
+            &[&job.version.to_string(), &Direction::Up.as_str()],
+        )?;
+    }
+    for query in hooks.before_each.iter() {
+        t.batch_execute(query)?;
+    }
+    let mut statements = Vec::<StatementRun>::new();
+    for query in queries.iter() {
+        let start = std::time::Instant::now();
+        if let Err(e) = crate::views::execute(t, query) {
+            return Err(ArchitectError::MigrationFailed {
+                version: job.version,
+                direction: Direction::Up,
+                statement: Some(query.clone()),
+                source: e,
+            }
+            .into());
+        }
+        let run = StatementRun {
+            statement: query.clone(),
+            duration_ms: start.elapsed().as_millis(),
+        };
+        info!("{} {:>8}ms  {}", job.version, run.duration_ms, run.short());
+        statements.push(run);
+    }
+    crate::assertions::run(t, &job.sql)?;
+    t.batch_execute(record)?;
+    for query in hooks.after_each.iter() {
+        t.batch_execute(query)?;
+    }
+    let duration_ms = start.elapsed().as_millis() as i64;
+    t.execute(
+        "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+        &[&duration_ms, &job.version],
+    )?;
+    Ok(statements)
+}
+
+/// Runs `job` on `client` and commits once every migration of the wave got through, waiting for
+/// the others at `barrier`. `failed` tells the wave that a migration failed.
+fn apply(
+    client: &mut Client,
+    job: &Job,
+    hooks: &SqlHooks,
+    barrier: &Barrier,
+    failed: &AtomicBool,
+) -> Result<Vec<StatementRun>> {
+    let mut t = client.transaction();
+    let result = match t.as_mut() {
+        Ok(t) => execute(t, job, hooks),
+        Err(e) => Err(anyhow::anyhow!("{}", e)),
+    };
+    if result.is_err() {
+        failed.store(true, Ordering::SeqCst);
+    }
+    barrier.wait();
+    let statements = result?;
+    if failed.load(Ordering::SeqCst) {
+        return Err(anyhow::anyhow!(ROLLED_BACK));
+    }
+    t?.commit()?;
+    Ok(statements)
+}
+
+impl Migrator {
+    /// The independent migrations at the start of `steps` forming the next wave, at most `max`.
+    /// Empty unless at least two can run together.
+    fn wave(&self, steps: &[Step], max: usize) -> Result<Vec<Job>> {
+        let mut jobs = Vec::<Job>::new();
+        let mut tables = HashSet::<String>::new();
+        for s in steps.iter().take(max) {
+            if s.direction != Direction::Up || self.script_path(s.version, s.direction).is_some() {
+                break;
+            }
+            let sql = self.sql(s.version, s.direction)?;
+            if crate::batch_update::directive(&sql).is_some()
+                || crate::foreign_key::directive(&sql).is_some()
+                || !crate::post::directives(&sql)?.is_empty()
+                || crate::directives::has(&sql, crate::conditions::DIRECTIVE)
+            {
+                break;
+            }
+            let touched = match independent(&sql)? {
+                Some(v) => v,
+                None => break,
+            };
+            if touched.iter().any(|t| tables.contains(t)) {
+                break;
+            }
+            tables.extend(touched);
+            jobs.push(Job {
+                version: s.version,
+                queries: self.get_queries(s.version, s.direction)?,
+                sql,
+            });
+        }
+        if jobs.len() < 2 {
+            jobs.clear();
+        }
+        Ok(jobs)
+    }
+
+    /// Applies `jobs` at once, one per client.
+    fn run_wave(&mut self, jobs: &[Job], clients: &mut [Client], hooks: &SqlHooks) -> Result<()> {
+        for job in jobs.iter() {
+            self.notify_listeners("started", job.version, Direction::Up, None, None);
+        }
+        let barrier = Barrier::new(jobs.len());
+        let failed = AtomicBool::new(false);
+        let results: Vec<(u128, Result<Vec<StatementRun>>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = jobs
+                .iter()
+                .zip(clients.iter_mut())
+                .map(|(job, client)| {
+                    let (barrier, failed) = (&barrier, &failed);
+                    scope.spawn(move || {
+                        let start = std::time::Instant::now();
+                        let result = apply(client, job, hooks, barrier, failed);
+                        (start.elapsed().as_millis(), result)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| match h.join() {
+                    Ok(v) => v,
+                    Err(_) => (0, Err(anyhow::anyhow!("migration thread panicked"))),
+                })
+                .collect()
+        });
+        let mut errors = Vec::<anyhow::Error>::new();
+        for (job, (duration_ms, result)) in jobs.iter().zip(results) {
+            if let Err(e) = self.finish_run(job.version, Direction::Up, duration_ms, result) {
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        // the failure that rolled back the wave
+        let cause = errors
+            .iter()
+            .position(|e| !e.to_string().contains(ROLLED_BACK))
+            .unwrap_or(0);
+        Err(errors.swap_remove(cause))
+    }
+
+    /// Migrates up like `migrate_up`, applying independent migrations over `connections`
+    /// connections at once.
+    pub(crate) fn migrate_up_parallel(&mut self, connections: usize) -> Result<usize> {
+        if self.versions_up.is_empty() {
+            return Err(anyhow::anyhow!("no migrations found"));
+        }
+        let steps = self.steps(self.last_version, i64::MAX);
+        let steps = self.check_requirements(&steps)?;
+        self.check_safety(&steps)?;
+        let hooks = SqlHooks {
+            before_each: self.sql_hook("before_each")?,
+            after_each: self.sql_hook("after_each")?,
+        };
+        let mut clients = Vec::<Client>::new();
+        let mut i = 0;
+        while i < steps.len() {
+            // before building the wave, which records the versions in the batch of the run
+            self.before_all(steps[i].version, Direction::Up)?;
+            let jobs = self.wave(&steps[i..], connections)?;
+            if jobs.is_empty() {
+                self.run_steps(&steps[i..i + 1], false)?;
+                i += 1;
+                continue;
+            }
+            while clients.len() < jobs.len() {
+                clients.push(self.config.connect()?);
+            }
+            info!("applying {} migrations at once", jobs.len());
+            self.run_wave(&jobs, &mut clients, &hooks)?;
+            i += jobs.len();
+            self.last_version = steps[i - 1].version;
+        }
+        Ok(steps.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn independent() {
+        let tables = super::independent(
+            "CREATE TABLE a (id INT PRIMARY KEY, b_id INT REFERENCES b (id));
+            CREATE INDEX a_b ON a (b_id);
+            COMMENT ON TABLE a IS 'a';",
+        )
+        .unwrap();
+        assert_eq!(tables, Some(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(
+            super::independent("ALTER TABLE a ADD COLUMN c INT;").unwrap(),
+            None
+        );
+        assert_eq!(
+            super::independent("CREATE INDEX b_id ON b (id);").unwrap(),
+            None
+        );
+        assert_eq!(
+            super::independent("-- architect:independent\nINSERT INTO c VALUES (1);").unwrap(),
+            Some(vec!["c".to_owned()])
+        );
+    }
+
+    #[test]
+    fn parallel_waves() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./parallel")).unwrap();
+        let sqls = [
+            "CREATE TABLE __parallel_a__ (id INT PRIMARY KEY);",
+            "CREATE TABLE __parallel_b__ (id INT PRIMARY KEY);",
+            "CREATE TABLE __parallel_c__ (id INT PRIMARY KEY);",
+            // depends on the ones before
+            "INSERT INTO __parallel_a__ VALUES (1);",
+            "CREATE TABLE __parallel_d__ (id INT);",
+            "CREATE TABLE __parallel_e__ (id INT REFERENCES __parallel_missing__ (id));",
+        ];
+        let mut versions = Vec::new();
+        for sql in sqls {
+            let (up, _) = m.new_migration().unwrap();
+            std::fs::write(&up, sql).unwrap();
+            versions.push(*m.versions_up.last().unwrap());
+        }
+
+        let migrated = m.migrate_up_parallel(3);
+        let applied = m.applied_versions().unwrap();
+        let last_version = m.last_version;
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __parallel_a__, __parallel_b__, __parallel_c__, \
+                __parallel_d__, __parallel_e__",
+            )
+            .unwrap();
+        for v in versions.iter() {
+            m.client
+                .execute("DELETE FROM schema_migrations WHERE version = $1", &[v])
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all("./parallel");
+
+        let e = migrated.unwrap_err();
+        assert!(e.to_string().contains("__parallel_missing__"), "{}", e);
+        assert_eq!(last_version, versions[3]);
+        assert!(versions[..4].iter().all(|v| applied.contains(v)));
+        // rolled back with the failed one of its wave
+        assert!(!applied.contains(&versions[4]));
+        assert!(!applied.contains(&versions[5]));
+    }
+}
@@ -0,0 +1,341 @@
+//! Partition maintenance of tables partitioned by range of a date or timestamp. Tables listed under
+//! `[[partitions]]` in `.architect.toml` get their future partitions created by `partitions
+//! maintain`, meant to run on a schedule, and `partitions check` warns when writes will soon fall
+//! outside the existing partitions. Attaching and detaching partitions is written as migrations
+//! waiting at most `LOCK_TIMEOUT` for their locks.
+
+use anyhow::Result;
+use chrono::{Datelike, Months, NaiveDate};
+use serde::Deserialize;
+
+use crate::output::info;
+use crate::Migrator;
+
+/// How long partition changes wait for their locks before giving up.
+pub(crate) const LOCK_TIMEOUT: &str = "5s";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Interval {
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    /// Start of the partition `date` falls into.
+    pub(crate) fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Day => date,
+            Interval::Week => {
+                date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+            }
+            Interval::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    /// `date` plus `n` intervals.
+    pub(crate) fn add(self, date: NaiveDate, n: u32) -> NaiveDate {
+        match self {
+            Interval::Day => date + chrono::Duration::days(n as i64),
+            Interval::Week => date + chrono::Duration::weeks(n as i64),
+            Interval::Month => date + Months::new(n),
+        }
+    }
+
+    /// Name of the partition of `table` starting at `start`.
+    pub(crate) fn name(self, table: &str, start: NaiveDate) -> String {
+        let suffix = match self {
+            Interval::Month => start.format("%Y%m"),
+            _ => start.format("%Y%m%d"),
+        };
+        format!("{}_p{}", table, suffix)
+    }
+}
+
+/// A table whose partitions architect maintains.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Partitioned {
+    pub(crate) table: String,
+    pub(crate) interval: Interval,
+    /// Number of partitions kept ahead of the current one
+    #[serde(default = "default_ahead")]
+    pub(crate) ahead: u32,
+}
+
+fn default_ahead() -> u32 {
+    3
+}
+
+impl Partitioned {
+    /// The date the partitions have to reach on `today`.
+    fn wanted(&self, today: NaiveDate) -> NaiveDate {
+        self.interval
+            .add(self.interval.start(today), self.ahead + 1)
+    }
+}
+
+/// How far the partitions of a table reach.
+pub(crate) struct Coverage {
+    pub(crate) table: String,
+    /// End of the last partition, None without partitions
+    pub(crate) until: Option<NaiveDate>,
+    pub(crate) wanted: NaiveDate,
+}
+
+impl Coverage {
+    pub(crate) fn state(&self, today: NaiveDate) -> &'static str {
+        match self.until {
+            Some(v) if v >= self.wanted => "ok",
+            Some(v) if v > today => "soon",
+            _ => "exceeded",
+        }
+    }
+}
+
+/// The upper bound of a `FOR VALUES FROM (..) TO (..)` partition bound, None for other bounds.
+pub(crate) fn upper_bound(bound: &str) -> Option<NaiveDate> {
+    let reg = regex::Regex::new(r"TO \('(\d{4}-\d{2}-\d{2})").unwrap();
+    let date = reg.captures(bound)?.get(1)?.as_str();
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// The column a table is partitioned by, None unless it's a single column.
+fn partition_key(definition: &str) -> Option<String> {
+    let reg = regex::Regex::new(r"^RANGE \((\w+)\)$").unwrap();
+    Some(reg.captures(definition)?.get(1)?.as_str().to_owned())
+}
+
+impl Migrator {
+    /// The partitions of `table` with their bounds.
+    fn partitions(&mut self, table: &str) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .client
+            .query(
+                "SELECT c.oid::regclass::text, pg_get_expr(c.relpartbound, c.oid)
+                FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+                WHERE i.inhparent = $1::text::regclass ORDER BY 1",
+                &[&table],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn coverage(&mut self, p: &Partitioned, today: NaiveDate) -> Result<Coverage> {
+        let until = self
+            .partitions(&p.table)?
+            .iter()
+            .filter_map(|(_, bound)| upper_bound(bound))
+            .max();
+        Ok(Coverage {
+            table: p.table.clone(),
+            until,
+            wanted: p.wanted(today),
+        })
+    }
+
+    /// How far the partitions of the project's partitioned tables reach.
+    pub(crate) fn check_partitions(&mut self, today: NaiveDate) -> Result<Vec<Coverage>> {
+        let mut result = Vec::<Coverage>::new();
+        for p in self.project()?.partitions.iter() {
+            result.push(self.coverage(p, today)?);
+        }
+        Ok(result)
+    }
+
+    /// Creates the partitions missing up to `ahead` intervals after the current one for the
+    /// project's partitioned tables, each in its own transaction. Returns the partitions created.
+    pub(crate) fn maintain_partitions(&mut self, today: NaiveDate) -> Result<Vec<String>> {
+        let mut result = Vec::<String>::new();
+        for p in self.project()?.partitions.iter() {
+            let coverage = self.coverage(p, today)?;
+            let mut start = coverage.until.unwrap_or_else(|| p.interval.start(today));
+            while start < coverage.wanted {
+                let end = p.interval.add(p.interval.start(start), 1);
+                let name = p.interval.name(&p.table, start);
+                let mut t = self.client.transaction()?;
+                t.batch_execute(&format!(
+                    "SET LOCAL lock_timeout = '{}';
+                    CREATE TABLE {} PARTITION OF {} FOR VALUES FROM ('{}') TO ('{}')",
+                    LOCK_TIMEOUT, name, p.table, start, end
+                ))?;
+                t.commit()?;
+                info!("created {} for {} till {}", name, start, end);
+                result.push(name);
+                start = end;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Writes a migration attaching `table` as the partition of `parent` from `from` to `to`. A
+    /// validated check constraint matching the bounds spares attaching a scan under lock.
+    pub(crate) fn attach_partition(
+        &mut self,
+        parent: &str,
+        table: &str,
+        from: &str,
+        to: &str,
+        yes: bool,
+    ) -> Result<Vec<i64>> {
+        let definition: Option<String> = self
+            .client
+            .query_one("SELECT pg_get_partkeydef($1::text::regclass)", &[&parent])?
+            .get(0);
+        let key = match definition {
+            Some(v) => partition_key(&v),
+            None => return Err(anyhow::anyhow!("{} isn't partitioned", parent)),
+        };
+        let verbatim = format!("-- architect:{}\n", crate::directives::VERBATIM);
+        let bound = format!("FOR VALUES FROM ('{}') TO ('{}')", from, to);
+        let constraint = format!("{}_bound", table.rsplit('.').next().unwrap_or(table));
+        let mut up = format!(
+            "{verbatim}-- attach {table} to {parent}\nSET LOCAL lock_timeout = '{LOCK_TIMEOUT}';\n"
+        );
+        if let Some(key) = &key {
+            up.push_str(&format!(
+                "ALTER TABLE {table} ADD CONSTRAINT {constraint} \
+                CHECK ({key} IS NOT NULL AND {key} >= '{from}' AND {key} < '{to}') NOT VALID;\n\
+                ALTER TABLE {table} VALIDATE CONSTRAINT {constraint};\n"
+            ));
+        }
+        up.push_str(&format!(
+            "ALTER TABLE {parent} ATTACH PARTITION {table} {bound};\n"
+        ));
+        if key.is_some() {
+            up.push_str(&format!(
+                "ALTER TABLE {table} DROP CONSTRAINT {constraint};\n"
+            ));
+        }
+        let down = format!(
+            "{verbatim}SET LOCAL lock_timeout = '{LOCK_TIMEOUT}';\n\
+            ALTER TABLE {parent} DETACH PARTITION {table};\n"
+        );
+        self.write_generated(&[("attach", (up, down))], yes)
+    }
+
+    /// Writes a migration detaching the partition `table` from `parent`, its down migration
+    /// attaching it again with its current bounds.
+    pub(crate) fn detach_partition(
+        &mut self,
+        parent: &str,
+        table: &str,
+        yes: bool,
+    ) -> Result<Vec<i64>> {
+        let table_name = table.trim_matches('"');
+        let bound = match self
+            .partitions(parent)?
+            .into_iter()
+            .find(|(name, _)| name == table || name == table_name)
+        {
+            Some((_, bound)) => bound,
+            None => return Err(anyhow::anyhow!("{} isn't a partition of {}", table, parent)),
+        };
+        let verbatim = format!("-- architect:{}\n", crate::directives::VERBATIM);
+        let up = format!(
+            "{verbatim}-- detach {table} from {parent}\n\
+            SET LOCAL lock_timeout = '{LOCK_TIMEOUT}';\n\
+            ALTER TABLE {parent} DETACH PARTITION {table};\n"
+        );
+        let down = format!(
+            "{verbatim}SET LOCAL lock_timeout = '{LOCK_TIMEOUT}';\n\
+            ALTER TABLE {parent} ATTACH PARTITION {table} {bound};\n"
+        );
+        self.write_generated(&[("detach", (up, down))], yes)
+    }
+}
+
+pub(crate) fn print(coverage: &[Coverage], today: NaiveDate) {
+    println!("{:<40} {:<10} {:<10} STATE", "TABLE", "UNTIL", "WANTED");
+    for c in coverage {
+        let until = c
+            .until
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        println!(
+            "{:<40} {:<10} {:<10} {}",
+            c.table,
+            until,
+            c.wanted,
+            c.state(today)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn maintain_partitions() {
+        let today = NaiveDate::from_ymd_opt(2024, 1, 17).unwrap();
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./partitions")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __partitions__, __partitions_old__;
+                CREATE TABLE __partitions__ (id INT, created_at DATE)
+                    PARTITION BY RANGE (created_at);
+                CREATE TABLE __partitions_old__ (id INT, created_at DATE);
+                INSERT INTO __partitions_old__ VALUES (1, '2023-12-05');",
+            )
+            .unwrap();
+        std::fs::write(
+            "./partitions/.architect.toml",
+            "[[partitions]]\ntable = \"__partitions__\"\ninterval = \"month\"\nahead = 2\n",
+        )
+        .unwrap();
+
+        let before = m.check_partitions(today).map(|v| v[0].state(today));
+        let created = m.maintain_partitions(today);
+        let after = m.check_partitions(today);
+        let again = m.maintain_partitions(today);
+        let versions = m.attach_partition(
+            "__partitions__",
+            "__partitions_old__",
+            "2023-12-01",
+            "2024-01-01",
+            true,
+        );
+        let migrated = m.migrate_up(false);
+        let attached = m.partitions("__partitions__").map(|v| v.len());
+        let down = m.migrate_down_n(1, false);
+        m.client
+            .batch_execute("DROP TABLE __partitions__, __partitions_old__")
+            .unwrap();
+        if let Ok(versions) = &versions {
+            m.client
+                .execute(
+                    "DELETE FROM schema_migrations WHERE version = $1",
+                    &[&versions[0]],
+                )
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all("./partitions");
+
+        assert_eq!(before.unwrap(), "exceeded");
+        assert_eq!(
+            created.unwrap(),
+            vec![
+                "__partitions___p202401",
+                "__partitions___p202402",
+                "__partitions___p202403"
+            ]
+        );
+        let after = after.unwrap();
+        assert_eq!(after[0].until, NaiveDate::from_ymd_opt(2024, 4, 1));
+        assert_eq!(after[0].state(today), "ok");
+        assert!(again.unwrap().is_empty());
+        assert_eq!(versions.unwrap().len(), 1);
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(attached.unwrap(), 4);
+        assert_eq!(down.unwrap(), 1);
+        assert_eq!(
+            super::upper_bound("FOR VALUES FROM ('2024-01-01') TO ('2024-02-01 00:00:00+00')"),
+            NaiveDate::from_ymd_opt(2024, 2, 1)
+        );
+    }
+}
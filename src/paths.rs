@@ -0,0 +1,128 @@
+//! Default locations of files outside the migration directory. Everything platform specific about
+//! them lives here: on Windows libpq's files are in `%APPDATA%\postgresql` instead of the home
+//! directory.
+
+use std::path::PathBuf;
+
+#[cfg(not(windows))]
+fn home() -> Option<PathBuf> {
+    home::home_dir()
+}
+
+/// `%APPDATA%` on Windows.
+#[cfg(windows)]
+fn app_data() -> Option<PathBuf> {
+    std::env::var_os("APPDATA")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Directory of libpq's per user files: `~/.postgresql`, `%APPDATA%\postgresql` on Windows.
+#[cfg(not(windows))]
+pub(crate) fn postgresql_dir() -> Option<PathBuf> {
+    home().map(|v| v.join(".postgresql"))
+}
+
+#[cfg(windows)]
+pub(crate) fn postgresql_dir() -> Option<PathBuf> {
+    app_data().map(|v| v.join("postgresql"))
+}
+
+/// Root certificate used when neither `sslrootcert` nor `PGSSLROOTCERT` is set.
+pub(crate) fn default_sslrootcert() -> Option<PathBuf> {
+    postgresql_dir().map(|v| v.join("root.crt"))
+}
+
+/// The password file: `PGPASSFILE`, else `~/.pgpass`, `%APPDATA%\postgresql\pgpass.conf` on
+/// Windows.
+pub(crate) fn pgpass() -> Option<PathBuf> {
+    if let Some(v) = std::env::var_os("PGPASSFILE").filter(|v| !v.is_empty()) {
+        return Some(PathBuf::from(v));
+    }
+    default_pgpass()
+}
+
+#[cfg(not(windows))]
+fn default_pgpass() -> Option<PathBuf> {
+    home().map(|v| v.join(".pgpass"))
+}
+
+#[cfg(windows)]
+fn default_pgpass() -> Option<PathBuf> {
+    postgresql_dir().map(|v| v.join("pgpass.conf"))
+}
+
+/// Directory for architect's own user config: `$XDG_CONFIG_HOME/architect`, else
+/// `~/.config/architect`, `%APPDATA%\architect` on Windows.
+#[cfg(not(windows))]
+fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(v) => Some(PathBuf::from(v).join("architect")),
+        None => home().map(|v| v.join(".config").join("architect")),
+    }
+}
+
+#[cfg(windows)]
+fn config_dir() -> Option<PathBuf> {
+    app_data().map(|v| v.join("architect"))
+}
+
+/// The age identity file decrypting encrypted config values: `ARCHITECT_AGE_KEY_FILE`, else
+/// `age.key` in architect's config directory.
+#[cfg(feature = "age")]
+pub(crate) fn age_key() -> Option<PathBuf> {
+    if let Some(v) = std::env::var_os("ARCHITECT_AGE_KEY_FILE").filter(|v| !v.is_empty()) {
+        return Some(PathBuf::from(v));
+    }
+    config_dir().map(|v| v.join("age.key"))
+}
+
+/// Where the config is looked for without `--config`, in order.
+pub(crate) fn config_candidates() -> Vec<PathBuf> {
+    let mut result = vec![PathBuf::from("architect.toml")];
+    if let Some(v) = config_dir() {
+        result.push(v.join("config.toml"));
+    }
+    if let Some(v) = home::home_dir() {
+        result.push(v.join(".architect.toml"));
+    }
+    result
+}
+
+/// The config to use without `--config`, the first of the candidates that exists.
+pub(crate) fn discover_config() -> anyhow::Result<PathBuf> {
+    let candidates = config_candidates();
+    match candidates.iter().find(|v| v.is_file()) {
+        Some(v) => Ok(v.clone()),
+        None => Err(anyhow::anyhow!(
+            "no config found. pass --config or create one of {}",
+            candidates
+                .iter()
+                .map(|v| v.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn default_sslrootcert() {
+        let path = super::default_sslrootcert().unwrap();
+
+        // used to be built with `{:?}`, quoting the home directory
+        assert!(!path.to_string_lossy().contains('"'));
+        assert!(path.ends_with("root.crt"));
+        assert!(path.starts_with(super::postgresql_dir().unwrap()));
+    }
+
+    #[test]
+    fn config_candidates() {
+        let candidates = super::config_candidates();
+
+        assert_eq!(candidates[0], std::path::PathBuf::from("architect.toml"));
+        assert!(candidates[1].ends_with("architect/config.toml"));
+        assert!(candidates[2].ends_with(".architect.toml"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Running through a PgBouncer in transaction pooling mode, configured with `pgbouncer`. Every
+//! transaction may run on another server connection there, so session state doesn't survive:
+//!
+//! - the `[session]` settings are set locally in the transaction of every migration instead
+//! - `reconcile` takes its lock as a row of `schema_locks` instead of an advisory lock
+//! - heartbeats, which watch the server connection of the migration, are off
+//! - `plan` and `up` warn about migrations that don't run in a single transaction and `SET`s
+//!   that would change the server connection for the pool's other clients
+//!
+//! `pgbouncer = "transaction"` assumes the pooler, `pgbouncer = "detect"` checks whether
+//! consecutive statements run on different server connections, which misses a pool that happens
+//! to have a single one.
+
+use anyhow::Result;
+use postgres::{Client, GenericClient};
+
+use crate::lint::{Finding, Severity};
+use crate::output::info;
+use crate::{Config, Migrator};
+
+pub(crate) const RULE: &str = "pgbouncer";
+
+/// Statements checking for a pooler in `detect` mode.
+const PROBES: usize = 5;
+
+/// Lock rows older than this are taken over, their holder presumably gone.
+const LOCK_EXPIRY: &str = "1 hour";
+
+impl Config {
+    /// Whether the connection goes through a transaction pooler, detecting it once if needed.
+    pub(crate) fn pooled(&mut self, client: &mut Client) -> Result<bool> {
+        if let Some(v) = self.pooled {
+            return Ok(v);
+        }
+        let pooled = match self.pgbouncer.as_str() {
+            "" | "off" => false,
+            "transaction" => true,
+            "detect" => {
+                let mut pids = Vec::<i32>::new();
+                for _ in 0..PROBES {
+                    pids.push(client.query_one("SELECT pg_backend_pid()", &[])?.get(0));
+                }
+                pids.dedup();
+                pids.len() > 1
+            }
+            v => {
+                return Err(anyhow::anyhow!(
+                    "invalid pgbouncer \"{}\", expected \"transaction\", \"detect\" or \"off\"",
+                    v
+                ))
+            }
+        };
+        if pooled && self.pgbouncer == "detect" {
+            info!("detected a transaction pooler, adapting to it");
+        }
+        self.pooled = Some(pooled);
+        Ok(pooled)
+    }
+
+    /// Applies the `[session]` settings, `local` to the current transaction.
+    pub(crate) fn apply_session(&self, client: &mut impl GenericClient, local: bool) -> Result<()> {
+        for (name, value) in self.session.iter() {
+            let value = match value {
+                toml::Value::String(v) => v.clone(),
+                v => v.to_string(),
+            };
+            client
+                .execute("SELECT set_config($1, $2, $3)", &[name, &value, &local])
+                .map_err(|e| anyhow::anyhow!("setting {} of [session] failed: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Migrator {
+    /// Whether the migrator's connection goes through a transaction pooler.
+    pub(crate) fn pooled(&self) -> bool {
+        self.config.pooled == Some(true)
+    }
+
+    fn init_locks(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_locks (
+                key TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                acquired_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    fn lock_holder() -> String {
+        format!(
+            "{}:{}",
+            std::env::var("HOSTNAME").unwrap_or_default(),
+            std::process::id()
+        )
+    }
+
+    /// Takes the lock `key` unless another process holds it: an advisory lock, or a row of
+    /// `schema_locks` through a pooler. Returns whether it was taken.
+    pub(crate) fn try_lock(&mut self, key: &str) -> Result<bool> {
+        if !self.pooled() {
+            let locked: bool = self
+                .client
+                .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&key])?
+                .get(0);
+            if locked {
+                self.held_lock = Some(key.to_owned());
+            }
+            return Ok(locked);
+        }
+        self.init_locks()?;
+        let taken = self.client.query_opt(
+            &format!(
+                "INSERT INTO schema_locks (key, holder) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET holder = EXCLUDED.holder, acquired_at = now()
+                WHERE schema_locks.acquired_at < now() - interval '{}'
+                RETURNING key",
+                LOCK_EXPIRY
+            ),
+            &[&key, &Migrator::lock_holder()],
+        )?;
+        Ok(taken.is_some())
+    }
+
+    /// Releases the lock `key` taken by `try_lock`.
+    pub(crate) fn unlock(&mut self, key: &str) -> Result<()> {
+        if !self.pooled() {
+            if self.held_lock.take().is_some() {
+                self.client
+                    .query_one("SELECT pg_advisory_unlock(hashtext($1))", &[&key])?;
+            }
+            return Ok(());
+        }
+        self.client.execute(
+            "DELETE FROM schema_locks WHERE key = $1 AND holder = $2",
+            &[&key, &Migrator::lock_holder()],
+        )?;
+        Ok(())
+    }
+}
+
+/// Whether `statement` changes a setting of the session rather than of its transaction.
+fn session_set(statement: &str) -> bool {
+    let words: Vec<String> = statement
+        .split_whitespace()
+        .take(2)
+        .map(|v| v.to_uppercase())
+        .collect();
+    match words.first().map(|v| v.as_str()) {
+        Some("SET") => !matches!(
+            words.get(1).map(|v| v.as_str()),
+            Some("LOCAL" | "TRANSACTION" | "CONSTRAINTS")
+        ),
+        Some("RESET") => true,
+        _ => false,
+    }
+}
+
+/// Warnings about the migration `file` with `sql` when running through a transaction pooler.
+pub(crate) fn findings(file: &str, sql: &str) -> Vec<Finding> {
+    let mut result = Vec::<Finding>::new();
+    let warning = |message: String| Finding::new(file, RULE, Severity::Warning, message);
+    if crate::batch_update::directive(sql).is_some() {
+        result.push(warning(
+            "batch-update commits every batch on its own, don't rely on session state between \
+            them"
+                .to_owned(),
+        ));
+    }
+    if crate::post::directives(sql).is_ok_and(|v| !v.is_empty()) {
+        result.push(warning(
+            "post steps run outside of the migration's transaction, possibly on another server \
+            connection"
+                .to_owned(),
+        ));
+    }
+    // statements without the comments, good enough for SETs
+    let code: String = sql
+        .lines()
+        .filter(|v| !v.trim_start().starts_with("--"))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    for statement in code.split(';').map(|v| v.trim()).filter(|v| session_set(v)) {
+        result.push(warning(format!(
+            "\"{}\" changes the server connection for the pool's other clients, use SET LOCAL",
+            statement.lines().next().unwrap_or_default()
+        )));
+    }
+    result
+}
+
+/// Prints the pooler warnings of `plan`.
+pub(crate) fn warn(plan: &crate::plan::Plan) {
+    for f in plan.findings.iter().filter(|f| f.rule == RULE) {
+        info!("warning: {}", f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn pgbouncer() {
+        let findings = super::findings(
+            "1_up.sql",
+            "-- architect:post analyze a\n-- SET x = 1;\nSET search_path = a;\n\
+            SET LOCAL work_mem = '1GB';\nset transaction isolation level serializable;\n\
+            UPDATE a SET b = 1;",
+        );
+        let messages: Vec<&str> = findings.iter().map(|f| f.message.as_str()).collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("post steps"));
+        assert!(messages[1].starts_with("\"SET search_path = a\""));
+
+        let mut config = crate::tests::test_config().unwrap();
+        config.pgbouncer = "detect".to_owned();
+        config.session = toml::from_str("work_mem = \"7MB\"").unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./pgbouncer")).unwrap();
+        let work_mem: String = m.client.query_one("SHOW work_mem", &[]).unwrap().get(0);
+        let direct = m.pooled();
+        // assumed, as if connected through the pooler
+        m.config.pgbouncer = "transaction".to_owned();
+        m.config.pooled = None;
+        let mut client = m.config.connect().unwrap();
+        let pooled = m.config.pooled(&mut client).unwrap();
+        let pooled_work_mem: String = client.query_one("SHOW work_mem", &[]).unwrap().get(0);
+        let key = "architect pgbouncer test";
+        let first = m.try_lock(key).unwrap();
+        let second = m.try_lock(key).unwrap();
+        m.unlock(key).unwrap();
+        let again = m.try_lock(key).unwrap();
+        m.unlock(key).unwrap();
+        let _ = std::fs::remove_dir_all("./pgbouncer");
+
+        assert_eq!(work_mem, "7MB");
+        assert!(!direct);
+        assert!(pooled);
+        assert_ne!(pooled_work_mem, "7MB");
+        assert_eq!((first, second, again), (true, false, true));
+        assert!(m.held_lock.is_none());
+    }
+}
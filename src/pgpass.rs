@@ -0,0 +1,94 @@
+//! Passwords from libpq's password file, see `paths::pgpass`. Lines are
+//! `hostname:port:database:username:password`, the first matching line wins, `*` matches
+//! anything and `\` escapes `:` and `\`.
+
+use anyhow::Result;
+
+/// Splits a line into its fields, resolving escapes.
+fn fields(line: &str) -> Vec<String> {
+    let mut result = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(v) = chars.next() {
+                    result.last_mut().unwrap().push(v);
+                }
+            }
+            ':' if result.len() < 5 => result.push(String::new()),
+            _ => result.last_mut().unwrap().push(c),
+        }
+    }
+    result
+}
+
+/// The password for the connection from the content of a password file.
+pub(crate) fn find(
+    content: &str,
+    host: &str,
+    port: u16,
+    dbname: &str,
+    user: &str,
+) -> Option<String> {
+    let port = port.to_string();
+    for line in content.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = fields(line);
+        if fields.len() != 5 {
+            continue;
+        }
+        let matches = [host, &port, dbname, user]
+            .iter()
+            .zip(fields.iter())
+            .all(|(v, pattern)| pattern == "*" || pattern == v);
+        if matches {
+            return Some(fields[4].clone());
+        }
+    }
+    None
+}
+
+/// Looks up the password in the password file, if there is one.
+pub(crate) fn password(host: &str, port: u16, dbname: &str, user: &str) -> Result<Option<String>> {
+    let path = match crate::paths::pgpass() {
+        Some(v) if v.is_file() => v,
+        _ => return Ok(None),
+    };
+    Ok(find(
+        &std::fs::read_to_string(path)?,
+        host,
+        port,
+        dbname,
+        user,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn find() {
+        let content = "# comment
+            db.internal:5432:app:admin:first
+            *:5432:*:admin:any\\:db
+            invalid line";
+        let content: String = content
+            .lines()
+            .map(|v| v.trim().to_owned() + "\n")
+            .collect();
+
+        assert_eq!(
+            super::find(&content, "db.internal", 5432, "app", "admin").as_deref(),
+            Some("first")
+        );
+        assert_eq!(
+            super::find(&content, "localhost", 5432, "other", "admin").as_deref(),
+            Some("any:db")
+        );
+        assert_eq!(
+            super::find(&content, "localhost", 5433, "app", "admin"),
+            None
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::Result;
+
+use crate::lint::{Finding, Severity};
+use crate::project::Project;
+use crate::Migrator;
+
+/// A pending migration as it would be applied.
+#[derive(serde::Serialize)]
+pub(crate) struct Step {
+    pub(crate) file: String,
+    /// Statements of sql migrations. Scripts aren't analysed.
+    pub(crate) statements: Vec<String>,
+    /// Teams owning the migration, see `owners::owners`
+    pub(crate) owners: Vec<String>,
+}
+
+/// What `up` would do, with the lint and policy findings for it.
+#[derive(serde::Serialize)]
+pub(crate) struct Plan {
+    pub(crate) dbname: String,
+    pub(crate) environment: String,
+    pub(crate) from: i64,
+    pub(crate) steps: Vec<Step>,
+    pub(crate) findings: Vec<Finding>,
+}
+
+impl Migrator {
+    /// Project settings from `.architect.toml` in the parent migration directory.
+    pub(crate) fn project(&self) -> Result<Project> {
+        match self.dir.parent() {
+            Some(v) => Project::read(v),
+            None => Ok(Project::default()),
+        }
+    }
+
+    pub(crate) fn plan(&mut self) -> Result<Plan> {
+        let project = self.project()?;
+        let environment = self.config.environment.clone();
+        let policy = project.policy.get(&environment);
+        let mut plan = Plan {
+            dbname: self.config.dbname.clone(),
+            environment: environment.clone(),
+            from: self.last_version,
+            steps: Vec::new(),
+            findings: Vec::new(),
+        };
+        let mut needs = Vec::<(String, Vec<crate::disk::Need>)>::new();
+        for step in self.steps(self.last_version, i64::MAX) {
+            let v = &step.version;
+            let path = self.migration_path(*v, step.direction);
+            let file = path
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if self.script_path(*v, step.direction).is_some() {
+                if policy.is_some() {
+                    plan.findings.push(Finding::new(
+                        &file,
+                        "policy",
+                        Severity::Warning,
+                        "scripts can't be checked against policies".to_owned(),
+                    ));
+                }
+                let path = format!("{}/{}", self.config.app, file);
+                plan.steps.push(Step {
+                    owners: crate::owners::owners(&project.owners, &path, &[])?,
+                    file,
+                    statements: Vec::new(),
+                });
+                continue;
+            }
+            let sql = std::fs::read_to_string(&path)?;
+            if self.pooled() {
+                plan.findings
+                    .append(&mut crate::pgbouncer::findings(&file, &sql));
+            }
+            if crate::directives::has(&sql, crate::directives::VERBATIM) {
+                if policy.is_some() {
+                    plan.findings.push(Finding::new(
+                        &file,
+                        "policy",
+                        Severity::Warning,
+                        "verbatim sql can't be checked against policies".to_owned(),
+                    ));
+                }
+                let path = format!("{}/{}", self.config.app, file);
+                plan.steps.push(Step {
+                    owners: crate::owners::owners(&project.owners, &path, &[])?,
+                    file,
+                    statements: vec![sql.trim().trim_end_matches(';').to_owned()],
+                });
+                continue;
+            }
+            let mut parsed = crate::cache::parse(&file, step.direction, &sql)?;
+            let statements = parsed.statements;
+            plan.findings.append(&mut parsed.lint);
+            if project.idempotent {
+                plan.findings
+                    .append(&mut crate::idempotent::check(&file, &statements));
+            }
+            plan.findings
+                .append(&mut project.naming.check(&file, &statements)?);
+            if let Some(policy) = policy {
+                plan.findings
+                    .append(&mut policy.check(&environment, &file, &statements));
+            }
+            needs.push((file.clone(), crate::disk::needs(&statements)));
+            let path = format!("{}/{}", self.config.app, file);
+            plan.steps.push(Step {
+                owners: crate::owners::owners(&project.owners, &path, &statements)?,
+                file,
+                statements: statements.iter().map(|s| s.to_string()).collect(),
+            });
+        }
+        plan.findings.append(&mut self.disk_findings(&needs)?);
+        let steps = self.steps(self.last_version, i64::MAX);
+        plan.findings
+            .append(&mut self.requirement_findings(&steps)?);
+        for (file, reason) in self.manual_migrations()?.unwrap_or_default() {
+            plan.findings.push(Finding::new(
+                &file,
+                "policy",
+                Severity::Warning,
+                format!("needs approval in {}: {}", environment, reason),
+            ));
+        }
+        let pending: Vec<i64> = self
+            .versions_up
+            .iter()
+            .filter(|v| **v > self.last_version)
+            .copied()
+            .collect();
+        plan.findings.append(&mut crate::irreversible::check(
+            &self.dir,
+            &pending,
+            project.require_down,
+        )?);
+        Ok(plan)
+    }
+}
+
+impl Plan {
+    pub(crate) fn errors(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .count()
+    }
+}
+
+/// Prints the plan, failing if it has errors.
+pub(crate) fn print(plan: &Plan) -> Result<()> {
+    println!(
+        "{} pending migrations for {}{} after version {}",
+        plan.steps.len(),
+        plan.dbname,
+        if plan.environment.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", plan.environment)
+        },
+        plan.from
+    );
+    for s in plan.steps.iter() {
+        if s.owners.is_empty() {
+            println!("\n{}", s.file);
+        } else {
+            println!("\n{} (owners: {})", s.file, s.owners.join(", "));
+        }
+        if s.statements.is_empty() {
+            println!("    (script)");
+        }
+        for statement in s.statements.iter() {
+            println!("    {};", statement);
+        }
+    }
+    if !plan.findings.is_empty() {
+        println!();
+    }
+    for f in plan.findings.iter() {
+        println!("{}", f);
+    }
+    if plan.errors() > 0 {
+        return Err(anyhow::anyhow!("plan failed with {} errors", plan.errors()));
+    }
+    Ok(())
+}
+
+/// Fails with the policy violations of a plan, if there are any.
+pub(crate) fn enforce_policy(plan: &Plan) -> Result<()> {
+    let violations: Vec<&Finding> = plan
+        .findings
+        .iter()
+        .filter(|f| f.rule == "policy" && f.severity == Severity::Error)
+        .collect();
+    for f in violations.iter() {
+        eprintln!("{}", f);
+    }
+    if !violations.is_empty() {
+        return Err(crate::error::ArchitectError::PolicyViolation(format!(
+            "{} statements violate the policy for {}",
+            violations.len(),
+            plan.environment
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn plan_with_policy() {
+        let mut config = test_config().unwrap();
+        config.environment = "prod".to_owned();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./plan")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        m.last_version = 0;
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", first)),
+            b"CREATE TABLE a (id INT);",
+        )
+        .unwrap();
+        std::fs::write(m.dir.join(format!("{}_up.sql", second)), b"DROP TABLE a;").unwrap();
+        std::fs::write(
+            "./plan/.architect.toml",
+            b"[policy.prod]\ndeny = [\"drop-table\"]\n\n[[owners]]\nteam = \"@core\"\ntables = [\"a\"]\n",
+        )
+        .unwrap();
+
+        let plan = m.plan().unwrap();
+        let enforced = super::enforce_policy(&plan);
+
+        let _ = std::fs::remove_dir_all("./plan");
+
+        assert_eq!(plan.steps.len(), 2);
+        assert_eq!(plan.steps[0].statements, vec!["CREATE TABLE a (id INT)"]);
+        assert_eq!(plan.steps[1].owners, vec!["@core"]);
+        assert_eq!(plan.errors(), 1);
+        assert_eq!(plan.findings[0].file, format!("{}_up.sql", second));
+        assert!(enforced.is_err());
+    }
+}
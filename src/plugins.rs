@@ -0,0 +1,177 @@
+//! Plugins are external commands configured with `plugins = [...]`. For every lifecycle event
+//! architect runs each plugin with `sh -c`, or `cmd /C` on Windows, writes one JSON object
+//! describing the event to its stdin and reads one JSON object back from its stdout. Plugins not
+//! interested in an event exit successfully without printing anything.
+
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::lint::{Finding, Severity};
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    /// Asks for the database password when none is configured
+    Password {
+        app: &'a str,
+        host: &'a str,
+        dbname: &'a str,
+        user: &'a str,
+    },
+    /// Asks for additional lint findings for a migration file
+    Lint {
+        file: &'a str,
+        direction: &'a str,
+        sql: &'a str,
+    },
+    /// A migration was committed
+    MigrationApplied {
+        app: &'a str,
+        dbname: &'a str,
+        version: i64,
+        direction: &'a str,
+    },
+    /// A migration failed and was rolled back
+    MigrationFailed {
+        app: &'a str,
+        dbname: &'a str,
+        version: i64,
+        direction: &'a str,
+        error: &'a str,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct Response {
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    findings: Vec<PluginFinding>,
+}
+
+#[derive(Deserialize)]
+struct PluginFinding {
+    rule: String,
+    #[serde(default)]
+    error: bool,
+    message: String,
+}
+
+/// Sends `event` to `plugin` and reads its response.
+fn call(plugin: &str, event: &Event) -> Result<Response> {
+    let mut child = crate::hooks::shell(plugin)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // plugins not interested in the event may exit without reading it
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let _ = stdin.write_all(&line);
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "plugin {} failed: {}",
+            plugin,
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    if stdout.trim().is_empty() {
+        return Ok(Response::default());
+    }
+    match serde_json::from_str(&stdout) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(anyhow::anyhow!(
+            "invalid response from plugin {}: {}",
+            plugin,
+            e
+        )),
+    }
+}
+
+/// Sends a notification to every plugin, ignoring responses.
+pub(crate) fn notify(plugins: &[String], event: &Event) -> Result<()> {
+    for p in plugins.iter() {
+        call(p, event)?;
+    }
+    Ok(())
+}
+
+/// Returns the password provided by the first plugin answering with one.
+pub(crate) fn password(plugins: &[String], event: &Event) -> Result<Option<String>> {
+    for p in plugins.iter() {
+        if let Some(v) = call(p, event)?.password {
+            return Ok(Some(v));
+        }
+    }
+    Ok(None)
+}
+
+/// Collects the findings of all plugins for one migration file.
+pub(crate) fn lint(
+    plugins: &[String],
+    file: &str,
+    direction: &str,
+    sql: &str,
+) -> Result<Vec<Finding>> {
+    let mut result = Vec::<Finding>::new();
+    let event = Event::Lint {
+        file,
+        direction,
+        sql,
+    };
+    for p in plugins.iter() {
+        for f in call(p, &event)?.findings {
+            let severity = if f.error {
+                Severity::Error
+            } else {
+                Severity::Warning
+            };
+            result.push(Finding::new(file, &f.rule, severity, f.message));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+
+    #[test]
+    fn plugin_protocol() {
+        let plugins = vec![
+            // not interested in anything
+            "cat > /dev/null".to_owned(),
+            r#"grep -q '"event":"password"' && echo '{"password": "s3cret"}' || echo '{"findings": [{"rule": "no-drop", "error": true, "message": "drops"}]}'"#.to_owned(),
+        ];
+        let password = super::password(
+            &plugins,
+            &Event::Password {
+                app: "test",
+                host: "localhost",
+                dbname: "test",
+                user: "test",
+            },
+        )
+        .unwrap();
+        let findings = super::lint(&plugins, "1_down.sql", "down", "DROP TABLE a;").unwrap();
+        let failing = super::notify(
+            &["exit 1".to_owned()],
+            &Event::MigrationApplied {
+                app: "test",
+                dbname: "test",
+                version: 1,
+                direction: "up",
+            },
+        );
+
+        assert_eq!(password.as_deref(), Some("s3cret"));
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].rule, "no-drop");
+        assert!(failing.is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::Result;
+use serde::Deserialize;
+use sqlparser::ast::{AlterTableOperation, ObjectType, Statement};
+
+use crate::lint::{Finding, Severity};
+use crate::Migrator;
+
+/// Statement kinds that only add to the schema or data.
+const ADDITIVE: &[&str] = &[
+    "create-table",
+    "create-index",
+    "create-view",
+    "create-schema",
+    "create-sequence",
+    "create-other",
+    "add-column",
+    "add-constraint",
+    "insert",
+    "grant",
+    "comment",
+];
+
+/// Restrictions on the statements migrations may run in an environment, configured in the
+/// `[policy.<environment>]` tables of `.architect.toml`.
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Policy {
+    /// Statement kinds that aren't allowed, e.g. `drop-table`
+    #[serde(default)]
+    pub(crate) deny: Vec<String>,
+    /// Only allow statements that add to the schema or data
+    #[serde(default)]
+    pub(crate) additive_only: bool,
+    /// Statement kinds applied by `up` without approval, `additive` standing for all additive
+    /// kinds. Migrations with other statements need an approved plan.
+    #[serde(default)]
+    pub(crate) auto_apply: Vec<String>,
+    /// "skip" to skip migrations requiring another server version instead of failing, see
+    /// requires.rs
+    #[serde(default)]
+    pub(crate) unmet_requirements: String,
+}
+
+/// The kind of a statement policies refer to, e.g. `drop-table` or `add-column`.
+pub(crate) fn kind(s: &Statement) -> &'static str {
+    match s {
+        Statement::CreateTable { .. } => "create-table",
+        Statement::CreateIndex { .. } => "create-index",
+        Statement::CreateView { .. } => "create-view",
+        Statement::CreateSchema { .. } => "create-schema",
+        Statement::CreateSequence { .. } => "create-sequence",
+        Statement::CreateDatabase { .. }
+        | Statement::CreateFunction { .. }
+        | Statement::CreateRole { .. }
+        | Statement::CreateVirtualTable { .. } => "create-other",
+        Statement::Drop { object_type, .. } => match object_type {
+            ObjectType::Table => "drop-table",
+            ObjectType::View => "drop-view",
+            ObjectType::Index => "drop-index",
+            ObjectType::Schema => "drop-schema",
+            ObjectType::Sequence => "drop-sequence",
+            ObjectType::Role => "drop-role",
+        },
+        Statement::DropFunction { .. } => "drop-function",
+        Statement::AlterTable { operation, .. } => {
+            match operation {
+                AlterTableOperation::AddColumn { .. } => "add-column",
+                AlterTableOperation::DropColumn { .. } => "drop-column",
+                AlterTableOperation::RenameColumn { .. }
+                | AlterTableOperation::ChangeColumn { .. } => "rename-column",
+                AlterTableOperation::RenameTable { .. } => "rename-table",
+                AlterTableOperation::AlterColumn { .. } => "alter-column",
+                AlterTableOperation::AddConstraint(_) => "add-constraint",
+                AlterTableOperation::DropConstraint { .. }
+                | AlterTableOperation::DropPrimaryKey => "drop-constraint",
+                _ => "alter-table",
+            }
+        }
+        Statement::AlterIndex { .. } => "alter-index",
+        Statement::Truncate { .. } => "truncate",
+        Statement::Insert { .. } => "insert",
+        Statement::Update { .. } => "update",
+        Statement::Delete { .. } => "delete",
+        Statement::Grant { .. } => "grant",
+        Statement::Revoke { .. } => "revoke",
+        Statement::Comment { .. } => "comment",
+        _ => "other",
+    }
+}
+
+impl Policy {
+    /// Checks the statements of one migration file against the policy of `environment`.
+    pub(crate) fn check(
+        &self,
+        environment: &str,
+        file: &str,
+        statements: &[Statement],
+    ) -> Vec<Finding> {
+        let mut result = Vec::<Finding>::new();
+        for s in statements.iter() {
+            let kind = kind(s);
+            let reason = if self.deny.iter().any(|v| v == kind) {
+                "denied"
+            } else if self.additive_only && !ADDITIVE.contains(&kind) {
+                "not additive"
+            } else {
+                continue;
+            };
+            result.push(Finding::new(
+                file,
+                "policy",
+                Severity::Error,
+                format!("{} \"{}\" is {} in {}", kind, s, reason, environment),
+            ));
+        }
+        result
+    }
+
+    /// The first statement kind that keeps a migration with `statements` from being applied
+    /// without approval, None if it's auto-applied.
+    pub(crate) fn manual_kind(&self, statements: &[Statement]) -> Option<&'static str> {
+        let additive = self.auto_apply.iter().any(|v| v == "additive");
+        statements.iter().map(kind).find(|kind| {
+            let allowed =
+                self.auto_apply.iter().any(|v| v == kind) || additive && ADDITIVE.contains(kind);
+            !allowed
+        })
+    }
+}
+
+impl Migrator {
+    /// The pending migrations that need approval under the `auto_apply` policy of the
+    /// environment, with the reason, or None if it has none.
+    pub(crate) fn manual_migrations(&self) -> Result<Option<Vec<(String, String)>>> {
+        let policy = match self.project()?.policy.remove(&self.config.environment) {
+            Some(v) if !v.auto_apply.is_empty() => v,
+            _ => return Ok(None),
+        };
+        let mut result = Vec::<(String, String)>::new();
+        for step in self.steps(self.last_version, i64::MAX) {
+            let path = self.migration_path(step.version, step.direction);
+            let file = path
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if self.script_path(step.version, step.direction).is_some() {
+                result.push((file, "scripts can't be classified".to_owned()));
+                continue;
+            }
+            let sql = std::fs::read_to_string(&path)?;
+            if crate::directives::has(&sql, crate::directives::VERBATIM) {
+                result.push((file, "verbatim sql can't be classified".to_owned()));
+                continue;
+            }
+            let parsed = crate::cache::parse(&file, step.direction, &sql)?;
+            if let Some(kind) = policy.manual_kind(&parsed.statements) {
+                result.push((file, format!("{} isn't auto-applied", kind)));
+            }
+        }
+        Ok(Some(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+
+    #[test]
+    fn policy_check() {
+        let statements = crate::parse_ast(
+            "CREATE TABLE a (id INT);
+            ALTER TABLE a ADD COLUMN b TEXT;
+            ALTER TABLE a DROP COLUMN b;
+            DROP TABLE a;",
+        )
+        .unwrap();
+        let kinds: Vec<&str> = statements.iter().map(super::kind).collect();
+        let deny = Policy {
+            deny: vec!["drop-table".to_owned()],
+            ..Default::default()
+        };
+        let additive = Policy {
+            additive_only: true,
+            ..Default::default()
+        };
+        let auto_apply = Policy {
+            auto_apply: vec!["additive".to_owned(), "drop-column".to_owned()],
+            ..Default::default()
+        };
+
+        let denied = deny.check("prod", "1_up.sql", &statements);
+        let not_additive = additive.check("staging", "1_up.sql", &statements);
+
+        assert_eq!(
+            kinds,
+            vec!["create-table", "add-column", "drop-column", "drop-table"]
+        );
+        assert_eq!(denied.len(), 1);
+        assert!(denied[0].message.starts_with("drop-table"));
+        assert!(denied[0].message.ends_with("is denied in prod"));
+        assert_eq!(not_additive.len(), 2);
+        assert_eq!(auto_apply.manual_kind(&statements[..3]), None);
+        assert_eq!(auto_apply.manual_kind(&statements), Some("drop-table"));
+    }
+}
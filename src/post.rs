@@ -0,0 +1,162 @@
+//! Post steps of migrations. A migration marked `-- architect:post analyze TABLE`, `post vacuum
+//! TABLE` or `post vacuum analyze TABLE` runs the step right after it's committed, outside of its
+//! transaction, so the statistics of tables it changed are refreshed before the next migration
+//! and VACUUM, which can't run in a transaction, is possible. The steps' durations are reported
+//! with the migration's run.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::direction::Direction;
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "post";
+
+/// A step run after a migration committed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Post {
+    Analyze(String),
+    Vacuum(String),
+    VacuumAnalyze(String),
+}
+
+impl Post {
+    fn query(&self) -> String {
+        match self {
+            Post::Analyze(table) => format!("ANALYZE {}", table),
+            Post::Vacuum(table) => format!("VACUUM {}", table),
+            Post::VacuumAnalyze(table) => format!("VACUUM (ANALYZE) {}", table),
+        }
+    }
+}
+
+/// A post step run by this process.
+#[derive(Serialize)]
+pub(crate) struct PostRun {
+    pub(crate) statement: String,
+    pub(crate) duration_ms: u128,
+    pub(crate) error: Option<String>,
+}
+
+/// The post steps the directives of `sql` ask for, in the order they're declared.
+pub(crate) fn directives(sql: &str) -> Result<Vec<Post>> {
+    let mut result = Vec::<Post>::new();
+    for d in crate::directives::parse(sql) {
+        if d.name != DIRECTIVE {
+            continue;
+        }
+        let post = match d.args.split_whitespace().collect::<Vec<_>>()[..] {
+            ["analyze", table] => Post::Analyze(table.to_owned()),
+            ["vacuum", table] => Post::Vacuum(table.to_owned()),
+            ["vacuum", "analyze", table] => Post::VacuumAnalyze(table.to_owned()),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{} on line {} needs analyze, vacuum or vacuum analyze and a table, e.g. \
+                    -- architect:{} analyze orders",
+                    DIRECTIVE,
+                    d.line,
+                    DIRECTIVE
+                ))
+            }
+        };
+        result.push(post);
+    }
+    Ok(result)
+}
+
+impl Migrator {
+    /// Runs the post steps of the migration just committed, adding them to its run. Fails after
+    /// the first step failing; the migration stays applied.
+    pub(crate) fn run_post_steps(&mut self, version: i64, direction: Direction) -> Result<()> {
+        if self.script_path(version, direction).is_some()
+            || self.streamed(version, direction).is_some()
+        {
+            return Ok(());
+        }
+        for post in directives(&self.sql(version, direction)?)? {
+            let statement = post.query();
+            let start = std::time::Instant::now();
+            let result = self.client.batch_execute(&statement);
+            let run = PostRun {
+                statement,
+                duration_ms: start.elapsed().as_millis(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            };
+            info!(
+                "{} {:>8}ms  post: {}",
+                version, run.duration_ms, run.statement
+            );
+            let failed = run.error.clone();
+            let statement = run.statement.clone();
+            if let Some(r) = self.runs.last_mut() {
+                r.post.push(run);
+            }
+            if let Some(e) = failed {
+                return Err(anyhow::anyhow!(
+                    "{} after {} failed, the migration is applied: {}",
+                    statement,
+                    version,
+                    e
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Post;
+
+    #[test]
+    fn post_steps() {
+        let dir = std::path::PathBuf::from("./post_steps");
+        let mut m =
+            crate::Migrator::new(crate::tests::schema_config("__post__"), dir.clone()).unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:post vacuum analyze posts\n\
+            -- architect:post analyze posts\n\
+            CREATE TABLE posts (id INT);\n\
+            INSERT INTO posts SELECT generate_series(1, 100);",
+        )
+        .unwrap();
+        std::fs::write(&down, "DROP TABLE posts;").unwrap();
+        let migrated = m.migrate_up(false);
+        let post: Vec<(String, bool)> = m.runs[0]
+            .post
+            .iter()
+            .map(|p| (p.statement.clone(), p.error.is_none()))
+            .collect();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(&up, "-- architect:post analyze missing\nSELECT 1;").unwrap();
+        let failed = m.migrate_up(false);
+        let applied = m.applied_versions().unwrap().len();
+        m.client
+            .batch_execute("DROP SCHEMA __post__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(
+            post,
+            vec![
+                ("VACUUM (ANALYZE) posts".to_owned(), true),
+                ("ANALYZE posts".to_owned(), true)
+            ]
+        );
+        assert!(failed
+            .unwrap_err()
+            .to_string()
+            .contains("migration is applied"));
+        assert_eq!(applied, 2);
+        assert_eq!(
+            super::directives("-- architect:post vacuum t\n").unwrap(),
+            vec![Post::Vacuum("t".to_owned())]
+        );
+        assert!(super::directives("-- architect:post reindex t\n").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! Project settings shared by all apps, read from `.architect.toml` in the parent migration
+//! directory. Unlike the connection config they're meant to be committed with the migrations.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::grants::Grants;
+use crate::naming::Naming;
+use crate::owners::Owner;
+use crate::partitions::Partitioned;
+use crate::policy::Policy;
+use crate::refresh::Refresh;
+use crate::rls::Rls;
+use crate::rollout::Stage;
+
+pub(crate) const PROJECT_FILE: &str = ".architect.toml";
+
+#[derive(Deserialize, Default)]
+pub(crate) struct Project {
+    /// Statement policies by environment, see `environment` in the connection config
+    #[serde(default)]
+    pub(crate) policy: HashMap<String, Policy>,
+    /// Teams owning migrations, see `owners::owners`
+    #[serde(default)]
+    pub(crate) owners: Vec<Owner>,
+    /// Require DDL in its idempotent form, see `idempotent::check`
+    #[serde(default)]
+    pub(crate) idempotent: bool,
+    /// Fail on empty down migrations not marked irreversible, see `irreversible::check`
+    #[serde(default)]
+    pub(crate) require_down: bool,
+    /// Check pending migrations are reversible before applying them, see `Migrator::verify_downs`
+    #[serde(default)]
+    pub(crate) verify_down: bool,
+    /// Naming conventions, see `naming::Naming`
+    #[serde(default)]
+    pub(crate) naming: Naming,
+    /// Materialized views to refresh after every run applying migrations, see `refresh`
+    #[serde(default)]
+    pub(crate) refresh: Vec<Refresh>,
+    /// Tables whose partitions architect maintains, see `partitions`
+    #[serde(default)]
+    pub(crate) partitions: Vec<Partitioned>,
+    /// Tables whose sequences are reset after every run applying migrations, see `sequences`
+    #[serde(default)]
+    pub(crate) fix_sequences: Vec<String>,
+    /// Owner and privileges of objects created by migrations, see `grants`
+    #[serde(default)]
+    pub(crate) grants: Grants,
+    /// Schemas mandating row-level security and the defaults of its policies, see `rls`
+    #[serde(default)]
+    pub(crate) rls: Rls,
+    /// Clusters migrated one after the other, see `rollout`
+    #[serde(default)]
+    pub(crate) rollout: Vec<Stage>,
+    /// Connection configs of the apps handled by `--all-apps`, relative to the parent migration
+    /// directory, see `apps`
+    #[serde(default)]
+    pub(crate) apps: Vec<std::path::PathBuf>,
+}
+
+impl Project {
+    /// Reads the settings from `migdir`, defaults if there's no settings file.
+    pub(crate) fn read(migdir: &std::path::Path) -> Result<Project> {
+        let path = migdir.join(PROJECT_FILE);
+        if !path.exists() {
+            return Ok(Project::default());
+        }
+        match toml::from_str(&std::fs::read_to_string(&path)?) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(anyhow::anyhow!("invalid {:?}: {}", path, e)),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+
+use crate::Migrator;
+
+/// Whether `answer` confirms a destructive action on `dbname`.
+fn confirmed(answer: &str, dbname: &str) -> bool {
+    answer.trim() == dbname
+}
+
+impl Migrator {
+    /// Guards destructive actions on databases configured with `protected = true`, asking to type
+    /// the database name unless `force` is given with --i-know-what-im-doing.
+    pub(crate) fn confirm_destructive(&self, action: &str, force: bool) -> Result<()> {
+        if !self.config.protected || force {
+            return Ok(());
+        }
+        eprint!(
+            "{} is protected. type the database name to {}: ",
+            self.config.dbname, action
+        );
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !confirmed(&answer, &self.config.dbname) {
+            return Err(anyhow::anyhow!(
+                "not confirmed. {} of protected database {} aborted",
+                action,
+                self.config.dbname
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn confirmation() {
+        assert!(super::confirmed("prod\n", "prod"));
+        assert!(!super::confirmed("y\n", "prod"));
+        assert!(!super::confirmed("\n", "prod"));
+    }
+}
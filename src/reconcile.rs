@@ -0,0 +1,215 @@
+//! GitOps reconciliation. `reconcile` keeps a checkout of a git repository in sync and applies the
+//! migrations merged into it as `up` would, checking the lock file and the policies and sending the
+//! summary email. An advisory lock, or a lock row through a pooler, keeps reconcilers of the same
+//! app from migrating at once.
+
+use anyhow::Result;
+
+use crate::output::info;
+use crate::{Config, Migrator};
+
+/// Where the repository is checked out unless given.
+pub(crate) const CHECKOUT: &str = ".architect/reconcile";
+
+/// Seconds of an interval like `30s`, `1m` or `2h`, plain numbers being seconds.
+pub(crate) fn parse_interval(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("invalid interval {:?}, use e.g. 30s, 1m or 2h", s)),
+    };
+    match number.parse::<u64>() {
+        Ok(v) if v > 0 => Ok(v * factor),
+        _ => Err(format!("invalid interval {:?}, use e.g. 30s, 1m or 2h", s)),
+    }
+}
+
+fn git(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Clones `repo` into `checkout` or resets the checkout to the latest commit of `branch`, the
+/// default branch if None. Returns the commit checked out.
+pub(crate) fn sync(repo: &str, branch: Option<&str>, checkout: &std::path::Path) -> Result<String> {
+    let path = checkout.to_string_lossy();
+    if checkout.join(".git").exists() {
+        git(&[
+            "-C",
+            &path,
+            "fetch",
+            "--quiet",
+            "origin",
+            branch.unwrap_or("HEAD"),
+        ])?;
+        git(&["-C", &path, "reset", "--quiet", "--hard", "FETCH_HEAD"])?;
+    } else {
+        let mut args = vec!["clone", "--quiet"];
+        if let Some(branch) = branch {
+            args.extend(["--branch", branch]);
+        }
+        args.extend([repo, &path]);
+        git(&args)?;
+    }
+    git(&["-C", &path, "rev-parse", "HEAD"])
+}
+
+impl Migrator {
+    fn lock_key(&self) -> String {
+        format!("architect reconcile {}", self.config.app)
+    }
+
+    /// Migrates up as `up` does, unless another reconciler of the app holds the lock. Returns the
+    /// versions migrated, None if the lock was held.
+    fn reconcile_up(&mut self) -> Result<Option<usize>> {
+        let key = self.lock_key();
+        if !self.try_lock(&key)? {
+            return Ok(None);
+        }
+        let result = (|| {
+            self.check_approval_mode()?;
+            crate::lock::check(&self.dir)?;
+            crate::plan::enforce_policy(&self.plan()?)?;
+            self.require_downs()?;
+            let count = self.migrate_up(false)?;
+            self.after_run()?;
+            Ok(count)
+        })();
+        self.unlock(&key)?;
+        result.map(Some)
+    }
+}
+
+/// Syncs the checkout and applies its new migrations every `interval` seconds, only once if
+/// `once`. Failures are reported and retried once the commit changes, unless `once`.
+pub(crate) fn run(
+    config: Config,
+    repo: &str,
+    path: &std::path::Path,
+    branch: Option<&str>,
+    checkout: &std::path::Path,
+    interval: u64,
+    once: bool,
+) -> Result<()> {
+    let mut failed: Option<String> = None;
+    loop {
+        let result = sync(repo, branch, checkout).and_then(|commit| {
+            if failed.as_ref() == Some(&commit) {
+                return Ok(());
+            }
+            let mut m = Migrator::new(config.clone(), checkout.join(path))?;
+            let result = m.reconcile_up();
+            let summary = match &result {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow::anyhow!("{}", e)),
+            };
+            m.send_summary(&summary);
+            match result {
+                Ok(Some(count)) => {
+                    info!("{}: migrated up {} versions", &commit[..7], count);
+                    failed = None;
+                    Ok(())
+                }
+                Ok(None) => {
+                    info!("another reconcile of {} holds the lock", m.config.app);
+                    Ok(())
+                }
+                Err(e) => {
+                    failed = Some(commit);
+                    Err(e)
+                }
+            }
+        });
+        if once {
+            return result;
+        }
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn reconcile() {
+        assert_eq!(super::parse_interval("1m"), Ok(60));
+        assert_eq!(super::parse_interval("45"), Ok(45));
+        assert!(super::parse_interval("1d").is_err());
+
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(
+            config.clone(),
+            std::path::PathBuf::from("./reconcile_repo/migrations"),
+        )
+        .unwrap();
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __reconcile__")
+            .unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE __reconcile__ (id INT);").unwrap();
+        std::fs::write(&down, "DROP TABLE __reconcile__;").unwrap();
+        let version = *m.versions_up.last().unwrap();
+        for args in [
+            vec!["init", "--quiet"],
+            vec!["add", "."],
+            vec![
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "migration",
+            ],
+        ] {
+            super::git(&[&["-C", "./reconcile_repo"], args.as_slice()].concat()).unwrap();
+        }
+
+        let result = super::run(
+            config,
+            "./reconcile_repo",
+            std::path::Path::new("migrations"),
+            None,
+            std::path::Path::new("./reconcile_checkout"),
+            60,
+            true,
+        );
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('__reconcile__') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __reconcile__")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./reconcile_repo");
+        let _ = std::fs::remove_dir_all("./reconcile_checkout");
+
+        result.unwrap();
+        assert!(exists);
+    }
+}
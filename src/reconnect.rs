@@ -0,0 +1,160 @@
+//! Resuming runs after the connection is lost. With `reconnect_attempts` set, a migration failing
+//! because the connection dropped doesn't abort the run: architect reconnects with backoff, checks
+//! `schema_migrations` as on start, re-acquires the advisory lock it held and continues with the
+//! first step not applied yet. A migration that committed before the connection dropped isn't run
+//! again.
+
+use anyhow::Result;
+
+use crate::direction::{Direction, Step};
+use crate::error::ArchitectError;
+use crate::output::info;
+use crate::Migrator;
+
+/// Longest wait between reconnection attempts, in seconds.
+const MAX_DELAY: u64 = 30;
+
+impl Migrator {
+    /// Whether the connection to the database is gone.
+    fn connection_lost(&mut self) -> bool {
+        self.client.is_closed()
+            || self
+                .client
+                .is_valid(std::time::Duration::from_secs(5))
+                .is_err()
+    }
+
+    /// Reconnects, waiting 1s, 2s, 4s and so on up to `MAX_DELAY` between the attempts, and
+    /// re-reads the last version.
+    fn reconnect(&mut self) -> Result<()> {
+        let attempts = self.config.reconnect_attempts;
+        let mut delay = 1;
+        for attempt in 1..=attempts {
+            info!(
+                "connection lost, reconnecting in {}s ({} of {})",
+                delay, attempt, attempts
+            );
+            std::thread::sleep(std::time::Duration::from_secs(delay));
+            match self.config.init(false) {
+                Ok((client, last_version)) => {
+                    self.client = client;
+                    self.last_version = last_version;
+                    break;
+                }
+                Err(e) => match e.downcast_ref::<ArchitectError>() {
+                    Some(ArchitectError::Connection(_)) if attempt < attempts => {
+                        info!("{}", e)
+                    }
+                    _ => return Err(e),
+                },
+            }
+            delay = (delay * 2).min(MAX_DELAY);
+        }
+        if let Some(key) = self.held_lock.clone() {
+            let locked: bool = self
+                .client
+                .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&key])?
+                .get(0);
+            if !locked {
+                self.held_lock = None;
+                return Err(anyhow::anyhow!(
+                    "lock {:?} was taken by another session while reconnecting",
+                    key
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `step` is in effect already, e.g. because its commit went through just before the
+    /// connection was lost.
+    fn step_done(&mut self, step: &Step) -> Result<bool> {
+        let applied = self.applied_versions()?.contains(&step.version);
+        Ok(match step.direction {
+            Direction::Up => applied,
+            Direction::Down => !applied,
+        })
+    }
+
+    /// Runs `step`, reconnecting and running it again if the connection was lost, at most
+    /// `reconnect_attempts` times.
+    pub(crate) fn run_step(&mut self, step: &Step) -> Result<()> {
+        let mut resumed = 0;
+        loop {
+            let e = match self.run_migration(step.version, step.direction) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if resumed >= self.config.reconnect_attempts || !self.connection_lost() {
+                return Err(e);
+            }
+            info!("{}", e);
+            self.reconnect()?;
+            resumed += 1;
+            if self.step_done(step)? {
+                info!(
+                    "{}_{} was applied before the connection was lost",
+                    step.version, step.direction
+                );
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn reconnect() {
+        let mut config = test_config().unwrap();
+        config.reconnect_attempts = 2;
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./reconnect")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __reconnect__;
+                DROP SEQUENCE IF EXISTS __reconnect_seq__;
+                CREATE SEQUENCE __reconnect_seq__;",
+            )
+            .unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        // the first run loses the connection, the second succeeds
+        std::fs::write(
+            &up,
+            "SELECT CASE WHEN nextval('__reconnect_seq__') = 1 \
+            THEN pg_terminate_backend(pg_backend_pid()) END;\n\
+            CREATE TABLE __reconnect__ (id INT);",
+        )
+        .unwrap();
+        std::fs::write(&down, "DROP TABLE __reconnect__;").unwrap();
+        let version = *m.versions_up.last().unwrap();
+
+        let migrated = m.migrate_up(false);
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('__reconnect__') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __reconnect__;
+                DROP SEQUENCE IF EXISTS __reconnect_seq__;",
+            )
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./reconnect");
+
+        assert_eq!(migrated.unwrap(), 1);
+        assert!(exists);
+        assert_eq!(m.last_version, version);
+        assert_eq!(m.runs.len(), 2);
+        assert!(m.runs[0].error.is_some());
+    }
+}
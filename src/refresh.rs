@@ -0,0 +1,223 @@
+//! Materialized view refreshes after migrations. An up migration marked `-- architect:refresh
+//! VIEW [concurrently]` refreshes the view once the migrations of the run are applied, as do the
+//! views listed under `[[refresh]]` in `.architect.toml` after every run applying migrations.
+//! Refreshes are recorded in `schema_refreshes`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::direction::Direction;
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "refresh";
+
+/// A materialized view to refresh.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Refresh {
+    pub(crate) view: String,
+    /// Refresh without locking out reads, needs a unique index on the view
+    #[serde(default)]
+    pub(crate) concurrently: bool,
+}
+
+impl Refresh {
+    fn query(&self) -> String {
+        let concurrently = if self.concurrently {
+            " CONCURRENTLY"
+        } else {
+            ""
+        };
+        format!("REFRESH MATERIALIZED VIEW{} {}", concurrently, self.view)
+    }
+}
+
+/// A refresh run by this process, `version` being the migration asking for it if any.
+#[derive(Serialize)]
+pub(crate) struct RefreshRun {
+    pub(crate) view: String,
+    pub(crate) version: Option<i64>,
+    pub(crate) duration_ms: u128,
+    pub(crate) error: Option<String>,
+}
+
+/// The refreshes the directives of `sql` ask for.
+pub(crate) fn directives(sql: &str) -> Result<Vec<Refresh>> {
+    let mut result = Vec::<Refresh>::new();
+    for d in crate::directives::parse(sql) {
+        if d.name != DIRECTIVE {
+            continue;
+        }
+        let refresh = match d.args.split_whitespace().collect::<Vec<_>>()[..] {
+            [view] => Refresh {
+                view: view.to_owned(),
+                concurrently: false,
+            },
+            [view, "concurrently"] => Refresh {
+                view: view.to_owned(),
+                concurrently: true,
+            },
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{} on line {} needs a view and optionally \"concurrently\", e.g. \
+                    -- architect:{} daily_totals concurrently",
+                    DIRECTIVE,
+                    d.line,
+                    DIRECTIVE
+                ))
+            }
+        };
+        result.push(refresh);
+    }
+    Ok(result)
+}
+
+impl Migrator {
+    fn init_refreshes(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_refreshes (
+                view VARCHAR(255) NOT NULL,
+                version BIGINT,
+                finished_at TIMESTAMPTZ NOT NULL,
+                run_by VARCHAR(255),
+                duration_ms BIGINT NOT NULL,
+                succeeded BOOLEAN NOT NULL
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    /// The refreshes due after the migrations of this run, those of the up migrations' directives
+    /// in version order followed by the project's, each view once.
+    pub(crate) fn pending_refreshes(&self) -> Result<Vec<(Refresh, Option<i64>)>> {
+        let mut result = Vec::<(Refresh, Option<i64>)>::new();
+        let applied = self.applied_in_run();
+        if !self.migrated_in_run() {
+            return Ok(result);
+        }
+        for v in applied.iter() {
+            if self.script_path(*v, Direction::Up).is_some() {
+                continue;
+            }
+            for r in directives(&self.sql(*v, Direction::Up)?)? {
+                result.push((r, Some(*v)));
+            }
+        }
+        for r in self.project()?.refresh {
+            result.push((r, None));
+        }
+        let mut seen = std::collections::HashSet::<String>::new();
+        result.retain(|(r, _)| seen.insert(r.view.clone()));
+        Ok(result)
+    }
+
+    /// Refreshes the views due after the migrations of this run, recording each refresh. Fails
+    /// after the first refresh failing; the migrations stay applied.
+    pub(crate) fn refresh_views(&mut self) -> Result<Vec<RefreshRun>> {
+        let pending = self.pending_refreshes()?;
+        let mut result = Vec::<RefreshRun>::new();
+        if pending.is_empty() {
+            return Ok(result);
+        }
+        self.init_refreshes()?;
+        for (r, version) in pending {
+            let start = std::time::Instant::now();
+            let refreshed = self.client.batch_execute(&r.query());
+            let duration_ms = start.elapsed().as_millis();
+            self.client.execute(
+                "INSERT INTO schema_refreshes
+                (view, version, finished_at, run_by, duration_ms, succeeded)
+                VALUES ($1, $2, now(), current_user, $3, $4)",
+                &[&r.view, &version, &(duration_ms as i64), &refreshed.is_ok()],
+            )?;
+            info!("refresh {:>8}ms  {}", duration_ms, r.view);
+            result.push(RefreshRun {
+                view: r.view.clone(),
+                version,
+                duration_ms,
+                error: refreshed.as_ref().err().map(|e| e.to_string()),
+            });
+            if let Err(e) = refreshed {
+                return Err(anyhow::anyhow!(
+                    "refreshing {} failed, the migrations are applied: {}",
+                    r.view,
+                    e
+                ));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn refresh_views() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./refresh")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __refresh__ CASCADE;
+                CREATE TABLE __refresh__ (id INT PRIMARY KEY);
+                CREATE MATERIALIZED VIEW __refresh_count__ AS SELECT count(*) AS n FROM __refresh__;
+                CREATE UNIQUE INDEX ON __refresh_count__ (n);
+                CREATE MATERIALIZED VIEW __refresh_ids__ AS SELECT id FROM __refresh__;",
+            )
+            .unwrap();
+        std::fs::write(
+            "./refresh/.architect.toml",
+            "[[refresh]]\nview = \"__refresh_ids__\"\n",
+        )
+        .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:refresh __refresh_count__ concurrently\n\
+            INSERT INTO __refresh__ VALUES (1), (2);",
+        )
+        .unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let migrated = m.migrate_up(false);
+        let refreshed = m.refresh_views();
+        let counts: (i64, i64) = m
+            .client
+            .query_one(
+                "SELECT (SELECT n FROM __refresh_count__), (SELECT count(*) FROM __refresh_ids__)",
+                &[],
+            )
+            .map(|row| (row.get(0), row.get(1)))
+            .unwrap();
+        let recorded: i64 = m
+            .client
+            .query_one(
+                "SELECT count(*) FROM schema_refreshes WHERE version = $1 AND succeeded",
+                &[&version],
+            )
+            .unwrap()
+            .get(0);
+        m.client
+            .batch_execute("DROP TABLE __refresh__ CASCADE")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./refresh");
+
+        assert_eq!(migrated.unwrap(), 1);
+        let refreshed = refreshed.unwrap();
+        assert_eq!(refreshed.len(), 2);
+        assert_eq!(refreshed[0].version, Some(version));
+        assert_eq!(refreshed[1].view, "__refresh_ids__");
+        assert_eq!(counts, (2, 2));
+        assert_eq!(recorded, 1);
+        assert!(super::directives("-- architect:refresh a b c").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Column renames. Dropping a column and adding another to the same table in one migration is
+//! likely a rename that loses the column's data. `rename-column` writes a safe rename instead, an
+//! expand migration adding and backfilling the new column and a contract migration dropping the
+//! old one behind a compatibility view.
+
+use anyhow::Result;
+use sqlparser::ast::{AlterTableOperation, Statement};
+
+use crate::direction::Direction;
+use crate::output::info;
+use crate::Migrator;
+
+/// A column dropped and another one added to the same table.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Candidate {
+    pub(crate) table: String,
+    pub(crate) from: String,
+    pub(crate) to: String,
+    pub(crate) data_type: String,
+}
+
+/// Tables the statements drop exactly one column from and add exactly one column to.
+pub(crate) fn candidates(statements: &[Statement]) -> Vec<Candidate> {
+    let mut dropped = Vec::<(String, String)>::new();
+    let mut added = Vec::<(String, String, String)>::new();
+    for s in statements.iter() {
+        if let Statement::AlterTable { name, operation } = s {
+            match operation {
+                AlterTableOperation::DropColumn { column_name, .. } => {
+                    dropped.push((name.to_string(), column_name.to_string()))
+                }
+                AlterTableOperation::AddColumn { column_def, .. } => added.push((
+                    name.to_string(),
+                    column_def.name.to_string(),
+                    column_def.data_type.to_string(),
+                )),
+                _ => {}
+            }
+        }
+    }
+    let mut result = Vec::<Candidate>::new();
+    for (table, from) in dropped.iter() {
+        let mut adds = added.iter().filter(|v| &v.0 == table);
+        let mut drops = dropped.iter().filter(|v| &v.0 == table);
+        if let (Some((_, to, data_type)), None, Some(_), None) =
+            (adds.next(), adds.next(), drops.next(), drops.next())
+        {
+            result.push(Candidate {
+                table: table.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                data_type: data_type.clone(),
+            });
+        }
+    }
+    result
+}
+
+/// The up and down sql of the expand and the contract migration renaming a column.
+pub(crate) fn migrations(c: &Candidate) -> [(String, String); 2] {
+    let expand = (
+        format!(
+            "-- rename {table}.{from} to {to}: add and backfill {to}, switch the app over to it\n\
+            ALTER TABLE {table} ADD COLUMN {to} {data_type};\n\
+            UPDATE {table} SET {to} = {from};\n",
+            table = c.table,
+            from = c.from,
+            to = c.to,
+            data_type = c.data_type
+        ),
+        format!("ALTER TABLE {} DROP COLUMN {};\n", c.table, c.to),
+    );
+    let contract = (
+        format!(
+            "-- rename {table}.{from} to {to}: drop {from} once nothing uses it, readers still \
+            using it can switch to {table}_compat\n\
+            UPDATE {table} SET {to} = {from} WHERE {to} IS NULL;\n\
+            ALTER TABLE {table} DROP COLUMN {from};\n\
+            CREATE VIEW {table}_compat AS SELECT *, {to} AS {from} FROM {table};\n",
+            table = c.table,
+            from = c.from,
+            to = c.to
+        ),
+        format!(
+            "DROP VIEW {table}_compat;\n\
+            ALTER TABLE {table} ADD COLUMN {from} {data_type};\n\
+            UPDATE {table} SET {from} = {to};\n",
+            table = c.table,
+            from = c.from,
+            to = c.to,
+            data_type = c.data_type
+        ),
+    );
+    [expand, contract]
+}
+
+impl Migrator {
+    /// The rename of `table.from` to `to`. The type of the column is taken from a pending
+    /// migration dropping `from` and adding `to`, else from the database.
+    fn rename_candidate(&mut self, table: &str, from: &str, to: &str) -> Result<Candidate> {
+        for v in self.versions_up.clone() {
+            if v <= self.last_version || self.script_path(v, Direction::Up).is_some() {
+                continue;
+            }
+            let statements = crate::parse_ast(&self.sql(v, Direction::Up)?)?;
+            if let Some(c) = candidates(&statements)
+                .into_iter()
+                .find(|c| c.table == table && c.from == from && c.to == to)
+            {
+                info!(
+                    "{}_up.sql drops {}.{} and adds {}, remove those statements from it",
+                    v, table, from, to
+                );
+                return Ok(c);
+            }
+        }
+        Ok(Candidate {
+            table: table.to_owned(),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            data_type: self.column_type(table, from)?,
+        })
+    }
+
+    /// The type of `table.column` in the database.
+    pub(crate) fn column_type(&mut self, table: &str, column: &str) -> Result<String> {
+        let found = self.client.query_opt(
+            "SELECT format_type(a.atttypid, a.atttypmod) FROM pg_attribute a
+            WHERE a.attrelid = to_regclass($1) AND a.attname = $2 AND a.attnum > 0
+                AND NOT a.attisdropped",
+            &[&table, &column.trim_matches('"')],
+        )?;
+        match found {
+            Some(row) => Ok(row.get(0)),
+            None => Err(anyhow::anyhow!(
+                "column {}.{} doesn't exist in {}",
+                table,
+                column,
+                self.config.dbname
+            )),
+        }
+    }
+
+    /// Writes the expand and contract migrations renaming `table.from` to `to` after showing
+    /// them, unless the author doesn't confirm. Returns the versions written.
+    pub(crate) fn rename_column(
+        &mut self,
+        table: &str,
+        from: &str,
+        to: &str,
+        yes: bool,
+    ) -> Result<Vec<i64>> {
+        let c = self.rename_candidate(table, from, to)?;
+        let [expand, contract] = migrations(&c);
+        self.write_generated(&[("expand", expand), ("contract", contract)], yes)
+    }
+
+    /// Shows the named up and down sql of generated migrations and writes them as new migrations
+    /// in order once the author confirms, right away with `yes`. Returns the versions written.
+    pub(crate) fn write_generated(
+        &mut self,
+        migrations: &[(&str, (String, String))],
+        yes: bool,
+    ) -> Result<Vec<i64>> {
+        for (name, (up, down)) in migrations.iter() {
+            eprintln!("{} up:\n{}\n{} down:\n{}", name, up, name, down);
+        }
+        if !yes {
+            eprint!("write these migrations? [y/N] ");
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                return Err(anyhow::anyhow!("not confirmed, no migrations written"));
+            }
+        }
+        let mut result = Vec::<i64>::new();
+        for (_, (up, down)) in migrations.iter() {
+            let (up_path, down_path) = self.new_migration_by(None)?;
+            for (path, sql) in [(up_path, up), (down_path, down)] {
+                let header = std::fs::read_to_string(&path)?;
+                std::fs::write(&path, header + sql.as_str())?;
+            }
+            if let Some(v) = self.versions_up.last() {
+                result.push(*v);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn rename_column() {
+        let statements = crate::parse_ast(
+            "ALTER TABLE users DROP COLUMN name;
+            ALTER TABLE users ADD COLUMN full_name VARCHAR(20);
+            ALTER TABLE teams DROP COLUMN a;
+            ALTER TABLE teams DROP COLUMN b;
+            ALTER TABLE teams ADD COLUMN c INT;",
+        )
+        .unwrap();
+        let candidates = super::candidates(&statements);
+
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./rename")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        m.last_version = 0;
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        let create = b"CREATE TABLE users (id INT, name TEXT); INSERT INTO users VALUES (1, 'a');";
+        std::fs::write(m.dir.join(format!("{}_up.sql", first)), create).unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", second)),
+            b"ALTER TABLE users DROP COLUMN name; ALTER TABLE users ADD COLUMN full_name TEXT;",
+        )
+        .unwrap();
+        let versions = m.rename_column("users", "name", "full_name", true).unwrap();
+        // the author removes the destructive statements
+        std::fs::write(m.dir.join(format!("{}_up.sql", second)), b"").unwrap();
+        let schema = m.schema_at(*versions.last().unwrap());
+
+        let _ = std::fs::remove_dir_all("./rename");
+
+        assert_eq!(
+            candidates,
+            vec![super::Candidate {
+                table: "users".to_owned(),
+                from: "name".to_owned(),
+                to: "full_name".to_owned(),
+                data_type: "VARCHAR(20)".to_owned(),
+            }]
+        );
+        assert_eq!(versions.len(), 2);
+        let schema = schema.unwrap();
+        let users = schema.tables.iter().find(|t| t.name == "users").unwrap();
+        assert_eq!(users.columns[1].name, "full_name");
+        assert_eq!(users.columns[1].data_type, "text");
+        assert!(schema.views.iter().any(|v| v.name == "users_compat"));
+    }
+}
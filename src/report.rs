@@ -0,0 +1,145 @@
+use anyhow::Result;
+use postgres::Client;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+/// Apply times of one version in one environment, from `schema_migration_runs`.
+pub(crate) struct DurationStats {
+    pub(crate) environment: String,
+    pub(crate) version: i64,
+    pub(crate) runs: i64,
+    pub(crate) avg_ms: i64,
+    pub(crate) max_ms: i64,
+    pub(crate) last_ms: i64,
+}
+
+impl Migrator {
+    /// Records a run in `schema_migration_runs`. Unlike `schema_migrations` it keeps every run,
+    /// including failed ones and versions that were migrated down again.
+    pub(crate) fn record_run(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        duration_ms: u128,
+        succeeded: bool,
+    ) -> Result<()> {
+        self.client.execute(
+            "INSERT INTO schema_migration_runs
+            (version, direction, finished_at, run_by, duration_ms, succeeded)
+            VALUES ($1, $2, now(), current_user, $3, $4)",
+            &[
+                &version,
+                &direction.as_str(),
+                &(duration_ms as i64),
+                &succeeded,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Label of a config in reports: its environment, else its database.
+pub(crate) fn environment(config: &crate::Config) -> String {
+    if config.environment.is_empty() {
+        config.dbname.clone()
+    } else {
+        config.environment.clone()
+    }
+}
+
+/// Statistics of the successful up runs per version.
+pub(crate) fn durations(client: &mut Client, environment: &str) -> Result<Vec<DurationStats>> {
+    let mut result = Vec::<DurationStats>::new();
+    for row in client.query(
+        "SELECT version, count(*), avg(duration_ms)::BIGINT, max(duration_ms),
+            (array_agg(duration_ms ORDER BY finished_at DESC))[1]
+        FROM schema_migration_runs WHERE direction = 'up' AND succeeded
+        GROUP BY version ORDER BY version",
+        &[],
+    )? {
+        result.push(DurationStats {
+            environment: environment.to_owned(),
+            version: row.get(0),
+            runs: row.get(1),
+            avg_ms: row.get(2),
+            max_ms: row.get(3),
+            last_ms: row.get(4),
+        });
+    }
+    Ok(result)
+}
+
+/// Duration statistics of this database and the databases of `others`, e.g. of other
+/// environments or shards. The other databases are only read from.
+pub(crate) fn collect(
+    m: &mut Migrator,
+    others: &[std::path::PathBuf],
+) -> Result<Vec<DurationStats>> {
+    let mut result = durations(&mut m.client, &environment(&m.config))?;
+    for path in others.iter() {
+        let mut config = crate::read_config_toml(std::slice::from_ref(path))?;
+        let label = environment(&config);
+        let mut client = config.connect()?;
+        match durations(&mut client, &label) {
+            Ok(mut v) => result.append(&mut v),
+            Err(e) => eprintln!("skipping {}: {}", label, e),
+        }
+    }
+    Ok(result)
+}
+
+/// Prints the statistics, the `limit` slowest by their maximum first with `slowest`.
+pub(crate) fn print(mut stats: Vec<DurationStats>, slowest: bool, limit: usize) {
+    if slowest {
+        stats.sort_by_key(|v| std::cmp::Reverse(v.max_ms));
+        stats.truncate(limit);
+    }
+    println!(
+        "{:<15} {:<16} {:>5} {:>10} {:>10} {:>10}",
+        "VERSION", "ENVIRONMENT", "RUNS", "AVG", "MAX", "LAST"
+    );
+    for s in stats.iter() {
+        println!(
+            "{:<15} {:<16} {:>5} {:>10} {:>10} {:>10}",
+            s.version,
+            s.environment,
+            s.runs,
+            format!("{}ms", s.avg_ms),
+            format!("{}ms", s.max_ms),
+            format!("{}ms", s.last_ms)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::direction::Direction;
+    use crate::tests::test_config;
+
+    #[test]
+    fn durations_are_recorded() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./report")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"SELECT pg_sleep(0.05);",
+        )
+        .unwrap();
+
+        m.run_migration(version, Direction::Up).unwrap();
+        m.run_migration(version, Direction::Down).unwrap();
+        m.run_migration(version, Direction::Up).unwrap();
+        m.run_migration(version, Direction::Down).unwrap();
+        let stats = super::durations(&mut m.client, "test").unwrap();
+
+        let _ = std::fs::remove_dir_all("./report");
+
+        let s = stats.iter().find(|v| v.version == version).unwrap();
+        assert_eq!(s.runs, 2);
+        assert!(s.max_ms >= 50);
+        assert!(s.avg_ms >= 50);
+    }
+}
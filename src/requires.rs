@@ -0,0 +1,316 @@
+//! Migrations depending on the server version. A migration marked `-- architect:requires pg>=14`
+//! is only applied by servers meeting the requirement, so one migration directory serves
+//! installations running different major versions. Requirements compare with `>=`, `>`, `<=`,
+//! `<`, `=` or `!=` against a major version, or a minor one like `9.6` or `14.2`, and a directive
+//! may list several, all of which have to be met.
+//!
+//! Migrating up to a migration the server doesn't meet fails, unless the policy of the
+//! environment sets `unmet_requirements = "skip"`, which skips it like `up --skip` with the
+//! requirement as its reason.
+
+use anyhow::Result;
+
+use crate::direction::{Direction, Step};
+use crate::lint::{Finding, Severity};
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "requires";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Op {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Eq,
+    Ne,
+}
+
+/// A requirement on the server version, like `pg>=14`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Requirement {
+    op: Op,
+    /// In the format of `server_version_num`
+    version_num: i32,
+    /// The server version is truncated to a multiple of this before comparing, so `14` covers
+    /// all minor versions of 14
+    unit: i32,
+    text: String,
+}
+
+impl Requirement {
+    pub(crate) fn parse(arg: &str) -> Result<Requirement> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "invalid {} requirement \"{}\", expected e.g. pg>=14 or pg<9.6",
+                DIRECTIVE,
+                arg
+            )
+        };
+        let rest = arg.strip_prefix("pg").ok_or_else(invalid)?;
+        let (op, version) = [
+            (">=", Op::Ge),
+            ("<=", Op::Le),
+            ("!=", Op::Ne),
+            (">", Op::Gt),
+            ("<", Op::Lt),
+            ("=", Op::Eq),
+        ]
+        .iter()
+        .find_map(|(prefix, op)| rest.strip_prefix(prefix).map(|v| (*op, v)))
+        .ok_or_else(invalid)?;
+        let (version_num, unit) = version_num(version).ok_or_else(invalid)?;
+        Ok(Requirement {
+            op,
+            version_num,
+            unit,
+            text: arg.to_owned(),
+        })
+    }
+
+    /// Whether the server of `server_version_num` meets the requirement.
+    fn met(&self, server: i32) -> bool {
+        let server = server - server % self.unit;
+        match self.op {
+            Op::Ge => server >= self.version_num,
+            Op::Gt => server > self.version_num,
+            Op::Le => server <= self.version_num,
+            Op::Lt => server < self.version_num,
+            Op::Eq => server == self.version_num,
+            Op::Ne => server != self.version_num,
+        }
+    }
+}
+
+/// `14`, `14.2` or `9.6` in the format of `server_version_num`, with the unit the server
+/// version is compared in. Before 10 major versions have two parts, like `9.6`.
+fn version_num(version: &str) -> Option<(i32, i32)> {
+    let mut parts = version.split('.');
+    let first: i32 = parts.next()?.parse().ok()?;
+    let second: Option<i32> = match parts.next() {
+        Some(v) => Some(v.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    match (first, second) {
+        (_, None) => Some((first * 10000, 10000)),
+        (10.., Some(minor)) => Some((first * 10000 + minor, 1)),
+        (_, Some(v)) => Some((first * 10000 + v * 100, 100)),
+    }
+}
+
+/// `server_version_num` for humans, e.g. `14.2` or `9.6.24`.
+fn pretty(version_num: i32) -> String {
+    if version_num >= 100_000 {
+        format!("{}.{}", version_num / 10000, version_num % 10000)
+    } else {
+        format!(
+            "{}.{}.{}",
+            version_num / 10000,
+            version_num / 100 % 100,
+            version_num % 100
+        )
+    }
+}
+
+/// The requirements of all directives in `sql`.
+pub(crate) fn requirements(sql: &str) -> Result<Vec<Requirement>> {
+    let mut result = Vec::<Requirement>::new();
+    for d in crate::directives::parse(sql) {
+        if d.name != DIRECTIVE {
+            continue;
+        }
+        if d.args.trim().is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} needs a requirement, e.g. pg>=14",
+                DIRECTIVE
+            ));
+        }
+        for arg in d.args.split_whitespace() {
+            result.push(Requirement::parse(arg)?);
+        }
+    }
+    Ok(result)
+}
+
+impl Migrator {
+    fn server_version_num(&mut self) -> Result<i32> {
+        let version: String = self
+            .client
+            .query_one("SHOW server_version_num", &[])?
+            .get(0);
+        Ok(version.parse()?)
+    }
+
+    /// Whether the policy of the environment skips migrations the server doesn't meet.
+    fn skips_unmet(&self) -> Result<bool> {
+        let project = self.project()?;
+        let policy = project.policy.get(&self.config.environment);
+        match policy.map(|v| v.unmet_requirements.as_str()) {
+            None | Some("" | "fail") => Ok(false),
+            Some("skip") => Ok(true),
+            Some(v) => Err(anyhow::anyhow!(
+                "invalid unmet_requirements \"{}\" in the policy for {}, expected \"fail\" or \
+                \"skip\"",
+                v,
+                self.config.environment
+            )),
+        }
+    }
+
+    /// The requirements of the up migration `version` the server doesn't meet, described.
+    fn unmet_requirements(&mut self, version: i64, server: &mut Option<i32>) -> Result<String> {
+        if self.script_path(version, Direction::Up).is_some()
+            || self.streamed(version, Direction::Up).is_some()
+        {
+            return Ok(String::new());
+        }
+        let requirements = requirements(&self.sql(version, Direction::Up)?)?;
+        if requirements.is_empty() {
+            return Ok(String::new());
+        }
+        let server = match server {
+            Some(v) => *v,
+            None => *server.insert(self.server_version_num()?),
+        };
+        let unmet: Vec<&str> = requirements
+            .iter()
+            .filter(|r| !r.met(server))
+            .map(|r| r.text.as_str())
+            .collect();
+        if unmet.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(format!(
+            "requires {}, the server runs {}",
+            unmet.join(" "),
+            pretty(server)
+        ))
+    }
+
+    /// The steps whose requirements the server meets. The up migrations it doesn't meet are
+    /// skipped, or fail the run, per the policy of the environment.
+    pub(crate) fn check_requirements(&mut self, steps: &[Step]) -> Result<Vec<Step>> {
+        let mut result = Vec::<Step>::new();
+        let mut server = None;
+        for s in steps.iter() {
+            if s.direction == Direction::Down {
+                result.push(*s);
+                continue;
+            }
+            let unmet = self.unmet_requirements(s.version, &mut server)?;
+            if unmet.is_empty() {
+                result.push(*s);
+                continue;
+            }
+            if !self.skips_unmet()? {
+                return Err(anyhow::anyhow!(
+                    "{} {}. set unmet_requirements = \"skip\" in the policy to skip it",
+                    s.version,
+                    unmet
+                ));
+            }
+            info!("{}: skipped, {}", s.version, unmet);
+            self.skip(s.version, &unmet);
+        }
+        Ok(result)
+    }
+
+    /// The findings of the plan about pending migrations the server doesn't meet.
+    pub(crate) fn requirement_findings(&mut self, steps: &[Step]) -> Result<Vec<Finding>> {
+        let mut result = Vec::<Finding>::new();
+        let mut server = None;
+        for s in steps.iter().filter(|s| s.direction == Direction::Up) {
+            let unmet = self.unmet_requirements(s.version, &mut server)?;
+            if unmet.is_empty() {
+                continue;
+            }
+            let file = self
+                .migration_path(s.version, s.direction)
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let (severity, outcome) = if self.skips_unmet()? {
+                (Severity::Warning, "skipped")
+            } else {
+                (Severity::Error, "fails")
+            };
+            result.push(Finding::new(
+                &file,
+                DIRECTIVE,
+                severity,
+                format!("{}, {}", unmet, outcome),
+            ));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Requirement;
+
+    #[test]
+    fn requires() {
+        let met = |r: &str, server: i32| Requirement::parse(r).unwrap().met(server);
+        assert!(met("pg>=14", 140005));
+        assert!(!met("pg>=14", 130010));
+        assert!(met("pg<=14", 140005));
+        assert!(met("pg=14", 140005));
+        assert!(!met("pg<14", 140005));
+        assert!(met("pg>=14.2", 140005));
+        assert!(!met("pg>=14.6", 140005));
+        assert!(met("pg>9.6", 100000));
+        assert!(met("pg=9.6", 90624));
+        assert!(Requirement::parse("postgres>=14").is_err());
+        assert!(Requirement::parse("pg~14").is_err());
+        assert!(super::requirements("-- architect:requires\n").is_err());
+        assert_eq!(super::pretty(140005), "14.5");
+        assert_eq!(super::pretty(90624), "9.6.24");
+
+        let dir = std::path::PathBuf::from("./requires");
+        let mut m =
+            crate::Migrator::new(crate::tests::schema_config("__requires__"), dir.clone()).unwrap();
+        for (v, sql) in [
+            (1, "-- architect:requires pg>=10\nCREATE TABLE a (id INT);"),
+            (2, "-- architect:requires pg<10\nCREATE TABLE b (id INT);"),
+            (3, "CREATE TABLE c (id INT);"),
+        ] {
+            std::fs::write(m.dir.join(format!("{}_up.sql", v)), sql).unwrap();
+            std::fs::write(m.dir.join(format!("{}_down.sql", v)), "SELECT 1;").unwrap();
+        }
+        m.available_versions().unwrap();
+        let plan = m.plan().unwrap();
+        let failed = m.migrate_up(false);
+        let failed_at = m.last_version;
+        std::fs::write(
+            dir.join(crate::project::PROJECT_FILE),
+            "[policy.\"\"]\nunmet_requirements = \"skip\"\n",
+        )
+        .unwrap();
+        let migrated = m.migrate_up(false);
+        let applied = m.applied_versions().unwrap();
+        let skipped = m.skipped_versions().unwrap();
+        m.client
+            .batch_execute("DROP SCHEMA __requires__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let findings: Vec<&crate::lint::Finding> = plan
+            .findings
+            .iter()
+            .filter(|f| f.rule == "requires")
+            .collect();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].file, "2_up.sql");
+        assert!(failed.unwrap_err().to_string().contains("requires pg<10"));
+        assert_eq!(failed_at, 0);
+        assert_eq!(migrated.unwrap(), 2);
+        assert_eq!(applied, vec![1, 3]);
+        assert!(skipped.get(&2).unwrap().starts_with("requires pg<10"));
+    }
+}
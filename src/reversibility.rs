@@ -0,0 +1,246 @@
+use anyhow::Result;
+use postgres::{Client, Transaction};
+
+use crate::diff::Change;
+use crate::direction::Direction;
+use crate::output::info;
+use crate::schema::Schema;
+use crate::Migrator;
+
+pub(crate) enum Outcome {
+    Reversible,
+    /// The schema after up and down differs from the schema before up. Checking stops.
+    DownDiffers(Vec<Change>),
+    /// Applying up again after down results in a different schema than applying it the first time
+    ReapplyDiffers(Vec<Change>),
+    /// A statement failed. The shadow schema is unusable after this so checking stops.
+    Failed(String),
+}
+
+pub(crate) struct Check {
+    pub(crate) version: i64,
+    pub(crate) outcome: Outcome,
+}
+
+fn execute(t: &mut Transaction, statements: &[String]) -> Result<()> {
+    for s in statements.iter() {
+        t.batch_execute(s)?;
+    }
+    Ok(())
+}
+
+fn schema_diff(a: &Schema, b: &Schema) -> Vec<Change> {
+    let lines = |s: &Schema| -> Vec<String> { s.ddl().lines().map(String::from).collect() };
+    crate::diff::diff(&lines(a), &lines(b))
+        .into_iter()
+        .filter(|c| !matches!(c, Change::Unchanged(_)))
+        .collect()
+}
+
+/// Runs up, down and up again for every pending migration, comparing the schema after each step.
+/// With `scratch` the applied migrations are first replayed into a throw away schema, otherwise
+/// the client's database is expected to be at the last applied version already. Everything runs
+/// in a transaction that is rolled back.
+fn check(
+    client: &mut Client,
+    applied: &[(i64, Vec<String>)],
+    pending: &[(i64, Vec<String>, Vec<String>)],
+    scratch: bool,
+) -> Result<Vec<Check>> {
+    let mut t = client.transaction()?;
+    let schema = if scratch {
+        let shadow = crate::schema::create_shadow(&mut t)?;
+        for (version, up) in applied.iter() {
+            if let Err(e) = execute(&mut t, up) {
+                return Err(anyhow::anyhow!(
+                    "error running migration {}_up.sql in shadow schema: {}",
+                    version,
+                    e
+                ));
+            }
+        }
+        shadow
+    } else {
+        t.query_one("SELECT current_schema()", &[])?.get(0)
+    };
+
+    let mut result = Vec::<Check>::new();
+    for (version, up, down) in pending.iter() {
+        let before = Schema::introspect(&mut t, &schema)?;
+        if let Err(e) = execute(&mut t, up) {
+            result.push(Check {
+                version: *version,
+                outcome: Outcome::Failed(format!("{}_up.sql: {}", version, e)),
+            });
+            break;
+        }
+        let after_up = Schema::introspect(&mut t, &schema)?;
+        if let Err(e) = execute(&mut t, down) {
+            result.push(Check {
+                version: *version,
+                outcome: Outcome::Failed(format!("{}_down.sql: {}", version, e)),
+            });
+            break;
+        }
+        let after_down = Schema::introspect(&mut t, &schema)?;
+        if after_down != before {
+            // later migrations would run against the wrong schema, so checking stops
+            result.push(Check {
+                version: *version,
+                outcome: Outcome::DownDiffers(schema_diff(&before, &after_down)),
+            });
+            break;
+        }
+        if let Err(e) = execute(&mut t, up) {
+            result.push(Check {
+                version: *version,
+                outcome: Outcome::Failed(format!("{}_up.sql after down: {}", version, e)),
+            });
+            break;
+        }
+        let after_reapply = Schema::introspect(&mut t, &schema)?;
+        let outcome = if after_reapply != after_up {
+            Outcome::ReapplyDiffers(schema_diff(&after_up, &after_reapply))
+        } else {
+            Outcome::Reversible
+        };
+        result.push(Check {
+            version: *version,
+            outcome,
+        });
+    }
+    t.rollback()?;
+    Ok(result)
+}
+
+impl Migrator {
+    /// Applies every pending migration's up, down and up again, comparing the schema after each
+    /// step. The checks run in the shadow database when one is configured. Otherwise applied
+    /// migrations are replayed into a throw away schema first so pending ones run against the
+    /// schema they'll meet.
+    pub(crate) fn test_reversibility(&mut self) -> Result<Vec<Check>> {
+        let mut applied = Vec::<(i64, Vec<String>)>::new();
+        let mut pending = Vec::<(i64, Vec<String>, Vec<String>)>::new();
+        for v in self.versions_up.iter() {
+            if *v <= self.last_version {
+                applied.push((*v, crate::parse_statements(&self.sql(*v, Direction::Up)?)?));
+            } else {
+                let up = crate::parse_statements(&self.sql(*v, Direction::Up)?)?;
+                let down = crate::parse_statements(&self.sql(*v, Direction::Down)?)?;
+                pending.push((*v, up, down));
+            }
+        }
+
+        if self.config.shadow_dbname.is_empty() {
+            return check(&mut self.client, &applied, &pending, true);
+        }
+        let mut shadow = self.shadow()?;
+        check(&mut shadow.client, &applied, &pending, false)
+    }
+}
+
+/// Lines describing the checks and the number of migrations that aren't reversible.
+fn report(checks: &[Check]) -> (Vec<String>, usize) {
+    let mut lines = Vec::<String>::new();
+    let mut failed = 0;
+    for c in checks.iter() {
+        match &c.outcome {
+            Outcome::Reversible => lines.push(format!("{}: ok", c.version)),
+            Outcome::DownDiffers(changes) => {
+                failed += 1;
+                lines.push(format!(
+                    "{}: down does not revert up. schema before up vs after down:",
+                    c.version
+                ));
+                lines.append(&mut crate::diff::lines(changes, ""));
+            }
+            Outcome::ReapplyDiffers(changes) => {
+                failed += 1;
+                lines.push(format!(
+                    "{}: up after down results in a different schema. first up vs second up:",
+                    c.version
+                ));
+                lines.append(&mut crate::diff::lines(changes, ""));
+            }
+            Outcome::Failed(e) => {
+                failed += 1;
+                lines.push(format!("{}: failed: {}", c.version, e));
+            }
+        }
+    }
+    (lines, failed)
+}
+
+/// Prints the checks, failing if any migration isn't reversible.
+pub(crate) fn print(checks: &[Check]) -> Result<()> {
+    let (lines, failed) = report(checks);
+    for line in lines {
+        println!("{}", line);
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} migrations are not reversible", failed));
+    }
+    Ok(())
+}
+
+impl Migrator {
+    /// Checks the pending migrations are reversible before applying them when `requested` or the
+    /// project sets `verify_down`, failing with the migrations that aren't.
+    pub(crate) fn verify_downs(&mut self, requested: bool) -> Result<()> {
+        if !requested && !self.project()?.verify_down {
+            return Ok(());
+        }
+        let checks = self.test_reversibility()?;
+        let (lines, failed) = report(&checks);
+        if failed > 0 {
+            for line in lines {
+                eprintln!("{}", line);
+            }
+            return Err(anyhow::anyhow!(
+                "{} pending migrations are not reversible, refusing to apply them",
+                failed
+            ));
+        }
+        info!(
+            "Verified the down migrations of {} pending versions",
+            checks.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outcome;
+    use crate::tests::test_config;
+
+    #[test]
+    fn reversibility() {
+        let config = test_config().unwrap();
+        let mut m =
+            crate::Migrator::new(config, std::path::PathBuf::from("./reversibility")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        m.last_version = 0;
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        let write = |v: i64, direction: &str, sql: &[u8]| {
+            std::fs::write(m.dir.join(format!("{}_{}.sql", v, direction)), sql).unwrap();
+        };
+        write(first, "up", b"CREATE TABLE users (id INT PRIMARY KEY);");
+        write(first, "down", b"DROP TABLE users;");
+        write(second, "up", b"ALTER TABLE users ADD COLUMN email TEXT;");
+        // forgets to drop the column
+        write(second, "down", b"SELECT 1;");
+
+        let checks = m.test_reversibility().unwrap();
+        let verified = m.verify_downs(true);
+
+        let _ = std::fs::remove_dir_all("./reversibility");
+
+        assert_eq!(checks.len(), 2);
+        assert!(matches!(checks[0].outcome, Outcome::Reversible));
+        assert!(matches!(checks[1].outcome, Outcome::DownDiffers(_)));
+        assert!(verified.is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Row-level security. `rls enable` writes a migration enabling RLS on a table together with a
+//! standard policy, and `rls check` reports the tables without it in the schemas `[rls]` in
+//! `.architect.toml` mandates it for.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::Migrator;
+
+/// Where RLS is mandated and the defaults of the standard policies.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct Rls {
+    /// Schemas whose tables must have RLS enabled with at least one policy
+    pub(crate) schemas: Vec<String>,
+    /// Column the standard policies compare
+    pub(crate) column: String,
+    /// Setting holding the current tenant for the `tenant` policy
+    pub(crate) setting: String,
+}
+
+impl Default for Rls {
+    fn default() -> Self {
+        Rls {
+            schemas: Vec::new(),
+            column: "tenant_id".to_owned(),
+            setting: "app.tenant_id".to_owned(),
+        }
+    }
+}
+
+/// The standard policies.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Policy {
+    /// Rows whose column equals the current tenant setting
+    Tenant,
+    /// Rows whose column equals the current database user
+    Owner,
+}
+
+/// The up and down sql enabling RLS on `table` with `policy` on `column` of `data_type`.
+pub(crate) fn migration(
+    table: &str,
+    policy: Policy,
+    column: &str,
+    data_type: &str,
+    setting: &str,
+) -> (String, String) {
+    let (name, condition) = match policy {
+        Policy::Tenant => (
+            "tenant_isolation",
+            format!(
+                "{} = current_setting('{}', true)::{}",
+                column, setting, data_type
+            ),
+        ),
+        Policy::Owner => ("owner_only", format!("{} = current_user", column)),
+    };
+    let policy = format!("{}_{}", table.rsplit('.').next().unwrap_or(table), name);
+    let verbatim = format!("-- architect:{}\n", crate::directives::VERBATIM);
+    let up = format!(
+        "{verbatim}-- enable row-level security on {table}\n\
+        ALTER TABLE {table} ENABLE ROW LEVEL SECURITY;\n\
+        ALTER TABLE {table} FORCE ROW LEVEL SECURITY;\n\
+        CREATE POLICY {policy} ON {table}\n    \
+        USING ({condition})\n    \
+        WITH CHECK ({condition});\n"
+    );
+    let down = format!(
+        "{verbatim}DROP POLICY {policy} ON {table};\n\
+        ALTER TABLE {table} NO FORCE ROW LEVEL SECURITY;\n\
+        ALTER TABLE {table} DISABLE ROW LEVEL SECURITY;\n"
+    );
+    (up, down)
+}
+
+/// A table in a schema mandating RLS, with what it lacks.
+pub(crate) struct Missing {
+    pub(crate) table: String,
+    pub(crate) enabled: bool,
+    pub(crate) policies: i64,
+}
+
+impl Migrator {
+    /// Writes the migration enabling RLS on `table` with the standard `policy`, on the project's
+    /// column unless `column` is given.
+    pub(crate) fn enable_rls(
+        &mut self,
+        table: &str,
+        policy: Policy,
+        column: Option<String>,
+        yes: bool,
+    ) -> Result<Vec<i64>> {
+        let rls = self.project()?.rls;
+        let column = column.unwrap_or(rls.column);
+        let data_type = self.column_type(table, &column)?;
+        let migration = migration(table, policy, &column, &data_type, &rls.setting);
+        self.write_generated(&[("rls", migration)], yes)
+    }
+
+    /// The tables of the schemas mandating RLS that don't enable it or have no policy.
+    pub(crate) fn check_rls(&mut self) -> Result<Vec<Missing>> {
+        let schemas = self.project()?.rls.schemas;
+        Ok(self
+            .client
+            .query(
+                "SELECT c.oid::regclass::text, c.relrowsecurity,
+                    (SELECT count(*) FROM pg_policy p WHERE p.polrelid = c.oid)
+                FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.relkind IN ('r', 'p') AND n.nspname = ANY($1) AND NOT c.relispartition
+                ORDER BY 1",
+                &[&schemas],
+            )?
+            .iter()
+            .map(|row| Missing {
+                table: row.get(0),
+                enabled: row.get(1),
+                policies: row.get(2),
+            })
+            .filter(|m| !m.enabled || m.policies == 0)
+            .collect())
+    }
+}
+
+pub(crate) fn print(missing: &[Missing]) {
+    for m in missing {
+        let problem = if m.enabled {
+            "has no policy"
+        } else {
+            "doesn't enable row-level security"
+        };
+        println!("{} {}", m.table, problem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn enable_rls() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./rls")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP SCHEMA IF EXISTS __rls__ CASCADE;
+                CREATE SCHEMA __rls__;
+                CREATE TABLE __rls__.notes (id INT, tenant_id BIGINT);
+                CREATE TABLE __rls__.other (id INT);",
+            )
+            .unwrap();
+        std::fs::write("./rls/.architect.toml", "[rls]\nschemas = [\"__rls__\"]\n").unwrap();
+
+        let before = m.check_rls().map(|v| v.len());
+        let versions = m.enable_rls("__rls__.notes", super::Policy::Tenant, None, true);
+        let migrated = m.migrate_up(false);
+        let after = m.check_rls();
+        m.client
+            .batch_execute("DROP SCHEMA __rls__ CASCADE")
+            .unwrap();
+        if let Ok(versions) = &versions {
+            m.client
+                .execute(
+                    "DELETE FROM schema_migrations WHERE version = $1",
+                    &[&versions[0]],
+                )
+                .unwrap();
+        }
+
+        let _ = std::fs::remove_dir_all("./rls");
+
+        assert_eq!(before.unwrap(), 2);
+        assert_eq!(versions.unwrap().len(), 1);
+        assert_eq!(migrated.unwrap(), 1);
+        let after = after.unwrap();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].table, "__rls__.other");
+        let (up, _) = super::migration("notes", super::Policy::Owner, "owner", "text", "");
+        assert!(up.contains("CREATE POLICY notes_owner_only ON notes"));
+    }
+}
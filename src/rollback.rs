@@ -0,0 +1,101 @@
+//! Rolling back the last run. Every run migrating up records its versions in
+//! `schema_migrations` with the next batch number, so `rollback --last-batch` reverts exactly the
+//! versions the latest deploy applied, without counting them. Versions applied before batches
+//! were recorded have none.
+
+use anyhow::Result;
+use postgres::Client;
+
+use crate::Migrator;
+
+/// The batch number of a new run.
+pub(crate) fn next_batch(client: &mut Client) -> Result<i64> {
+    let row = client.query_one(
+        "SELECT coalesce(max(batch), 0) + 1 FROM schema_migrations",
+        &[],
+    )?;
+    Ok(row.get(0))
+}
+
+impl Migrator {
+    /// The last batch with its versions, None if no version has one.
+    pub(crate) fn last_batch(&mut self) -> Result<Option<(i64, Vec<i64>)>> {
+        let row = self.client.query_one(
+            "SELECT max(batch), coalesce(array_agg(version ORDER BY version)
+                FILTER (WHERE batch = (SELECT max(batch) FROM schema_migrations)), '{}')
+            FROM schema_migrations",
+            &[],
+        )?;
+        Ok(row
+            .get::<_, Option<i64>>(0)
+            .map(|batch| (batch, row.get(1))))
+    }
+
+    /// Migrates down the versions of the last batch. Returns the batch and the number of
+    /// versions migrated down.
+    pub(crate) fn rollback_last_batch(&mut self, test: bool) -> Result<(i64, usize)> {
+        let (batch, versions) = match self.last_batch()? {
+            Some(v) => v,
+            None => return Err(anyhow::anyhow!("no batch has been applied")),
+        };
+        let first = versions.first().copied().unwrap_or(0);
+        let applied = self.applied_versions()?;
+        let later: Vec<i64> = applied
+            .iter()
+            .filter(|v| **v > first && !versions.contains(v))
+            .copied()
+            .collect();
+        if !later.is_empty() {
+            return Err(anyhow::anyhow!(
+                "versions {:?} were applied after batch {} but aren't part of it, migrate them \
+                down first",
+                later,
+                batch
+            ));
+        }
+        let target = applied.iter().rfind(|v| **v < first).copied().unwrap_or(0);
+        Ok((batch, self.goto(target, test)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rollback_last_batch() {
+        let mut m = crate::Migrator::new(
+            crate::tests::schema_config("__rollback__"),
+            std::path::PathBuf::from("./rollback"),
+        )
+        .unwrap();
+        let mut versions = Vec::new();
+        for table in ["__rollback_a__", "__rollback_b__", "__rollback_c__"] {
+            let (up, down) = m.new_migration().unwrap();
+            std::fs::write(&up, format!("CREATE TABLE {} (id INT);", table)).unwrap();
+            std::fs::write(&down, format!("DROP TABLE {};", table)).unwrap();
+            versions.push(*m.versions_up.last().unwrap());
+        }
+        m.migrate_up_n(1, false).unwrap();
+        // a deploy of its own
+        m.batch = None;
+        m.migrate_up(false).unwrap();
+        let (batch, last) = m.last_batch().unwrap().unwrap();
+        let rolled_back = m.rollback_last_batch(false);
+        let applied = m.applied_versions().unwrap();
+        let exists: bool = m
+            .client
+            .query_one("SELECT to_regclass('__rollback_b__') IS NOT NULL", &[])
+            .unwrap()
+            .get(0);
+        m.client
+            .batch_execute("DROP SCHEMA __rollback__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./rollback");
+
+        assert_eq!(last, versions[1..].to_vec());
+        assert_eq!(rolled_back.unwrap(), (batch, 2));
+        assert!(applied.contains(&versions[0]));
+        assert!(!applied.contains(&versions[1]));
+        assert!(!exists);
+    }
+}
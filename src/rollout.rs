@@ -0,0 +1,236 @@
+//! Rollouts across clusters. The stages listed under `[[rollout]]` in `.architect.toml` are
+//! migrated in order, each only once the previous one passed its gates: a wait after its
+//! migrations, a verification query returning true and a manual approval. The progress of a
+//! release, the latest migration, is recorded in `schema_rollouts` of each stage's database, so
+//! `rollout` picks up where it stopped when run again.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::output::info;
+use crate::Migrator;
+
+/// A cluster of the rollout and the gates it has to pass before the next one is migrated.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Stage {
+    pub(crate) name: String,
+    /// Connection config of the cluster, relative to the parent migration directory
+    pub(crate) config: std::path::PathBuf,
+    /// Seconds to wait after the cluster is migrated
+    #[serde(default)]
+    pub(crate) wait_seconds: i64,
+    /// Query on the cluster that has to return true
+    pub(crate) verify: Option<String>,
+    /// Whether an operator has to approve the stage with `rollout --approve NAME`
+    #[serde(default)]
+    pub(crate) approval: bool,
+}
+
+/// Where a stage of the release stands.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum State {
+    Passed,
+    /// Migrated, waiting till the given time
+    Waiting(String),
+    NeedsApproval,
+    Pending,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Progress {
+    pub(crate) stage: String,
+    pub(crate) state: State,
+}
+
+impl Migrator {
+    fn init_rollouts(&mut self) -> Result<()> {
+        self.client.batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS schema_rollouts (
+                release BIGINT NOT NULL,
+                stage VARCHAR(255) NOT NULL,
+                migrated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                passed_at TIMESTAMPTZ,
+                approved_by VARCHAR(255),
+                PRIMARY KEY (release, stage)
+            )
+        ",
+        )?;
+        Ok(())
+    }
+
+    /// Migrates the stage's cluster up to `release` unless it passed already, then checks its
+    /// gates, recording the progress.
+    fn advance(&mut self, stage: &Stage, release: i64, approve: Option<&str>) -> Result<State> {
+        self.init_rollouts()?;
+        let recorded = self.client.query_opt(
+            "SELECT passed_at IS NOT NULL, \
+            to_char(migrated_at + make_interval(secs => $3), 'YYYY-MM-DD HH24:MI:SS TZ'), \
+            now() >= migrated_at + make_interval(secs => $3) \
+            FROM schema_rollouts WHERE release = $1 AND stage = $2",
+            &[&release, &stage.name, &(stage.wait_seconds as f64)],
+        )?;
+        if recorded.as_ref().is_some_and(|row| row.get(0)) {
+            return Ok(State::Passed);
+        }
+        if recorded.is_none() {
+            self.check_approval_mode()?;
+            crate::lock::check(&self.dir)?;
+            crate::plan::enforce_policy(&self.plan()?)?;
+            self.require_downs()?;
+            let count = self.migrate_up(false)?;
+            self.after_run()?;
+            info!("{}: migrated up {} versions", stage.name, count);
+            self.client.execute(
+                "INSERT INTO schema_rollouts (release, stage) VALUES ($1, $2)",
+                &[&release, &stage.name],
+            )?;
+            return self.advance(stage, release, approve);
+        }
+        let row = recorded.unwrap();
+        if !row.get::<_, bool>(2) {
+            return Ok(State::Waiting(row.get(1)));
+        }
+        if let Some(query) = &stage.verify {
+            let verified: bool = self.client.query_one(query.as_str(), &[])?.get(0);
+            if !verified {
+                return Err(anyhow::anyhow!(
+                    "verification of {} failed: {}",
+                    stage.name,
+                    query
+                ));
+            }
+        }
+        let approved_by = match (stage.approval, approve) {
+            (false, _) => None,
+            (true, Some(operator)) => Some(operator.to_owned()),
+            (true, None) => return Ok(State::NeedsApproval),
+        };
+        self.client.execute(
+            "UPDATE schema_rollouts SET passed_at = now(), approved_by = $3 \
+            WHERE release = $1 AND stage = $2",
+            &[&release, &stage.name, &approved_by],
+        )?;
+        Ok(State::Passed)
+    }
+}
+
+/// Advances the rollout of the latest migration in `migdir` stage by stage, stopping at the first
+/// stage that hasn't passed its gates. `approve` names the stage the operator approves.
+pub(crate) fn run(
+    migdir: &std::path::Path,
+    approve: Option<(&str, &str)>,
+) -> Result<Vec<Progress>> {
+    let stages = crate::project::Project::read(migdir)?.rollout;
+    if stages.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no rollout stages configured in {:?}",
+            migdir.join(crate::project::PROJECT_FILE)
+        ));
+    }
+    let mut result = Vec::<Progress>::new();
+    let mut blocked = false;
+    for stage in stages.iter() {
+        if blocked {
+            result.push(Progress {
+                stage: stage.name.clone(),
+                state: State::Pending,
+            });
+            continue;
+        }
+        let config = crate::read_config_toml(&[migdir.join(&stage.config)])?;
+        let mut m = Migrator::new(config, migdir.to_owned())?;
+        let release = match m.versions_up.last() {
+            Some(v) => *v,
+            None => return Err(anyhow::anyhow!("no migrations found")),
+        };
+        let operator = approve
+            .filter(|(name, _)| *name == stage.name)
+            .map(|(_, operator)| operator);
+        let state = m.advance(stage, release, operator);
+        let summary = match &state {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+        m.send_summary(&summary);
+        let state = state?;
+        blocked = state != State::Passed;
+        result.push(Progress {
+            stage: stage.name.clone(),
+            state,
+        });
+    }
+    Ok(result)
+}
+
+pub(crate) fn print(progress: &[Progress]) {
+    for p in progress {
+        let state = match &p.state {
+            State::Passed => "passed".to_owned(),
+            State::Waiting(until) => format!("waiting till {}", until),
+            State::NeedsApproval => format!("needs approval, run rollout --approve {}", p.stage),
+            State::Pending => "pending".to_owned(),
+        };
+        println!("{:<20} {}", p.stage, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rollout() {
+        let config = crate::tests::test_config_path().unwrap();
+        let mut m = crate::Migrator::new(
+            crate::tests::test_config().unwrap(),
+            std::path::PathBuf::from("./rollout"),
+        )
+        .unwrap();
+        std::fs::write(
+            "./rollout/.architect.toml",
+            format!(
+                "[[rollout]]\nname = \"__rollout_a__\"\nconfig = {:?}\n\
+                verify = \"SELECT true\"\napproval = true\n\n\
+                [[rollout]]\nname = \"__rollout_b__\"\nconfig = {:?}\nwait_seconds = 3600\n",
+                config, config
+            ),
+        )
+        .unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let dir = m.dir.parent().unwrap().to_owned();
+
+        let first = super::run(&dir, None);
+        let second = super::run(&dir, Some(("__rollout_a__", "jane")));
+        let approved_by: Option<String> = m
+            .client
+            .query_one(
+                "SELECT approved_by FROM schema_rollouts WHERE release = $1 AND stage = $2",
+                &[&version, &"__rollout_a__"],
+            )
+            .unwrap()
+            .get(0);
+        m.client
+            .execute(
+                "DELETE FROM schema_rollouts WHERE release = $1",
+                &[&version],
+            )
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./rollout");
+
+        let first = first.unwrap();
+        assert_eq!(first[0].state, super::State::NeedsApproval);
+        assert_eq!(first[1].state, super::State::Pending);
+        let second = second.unwrap();
+        assert_eq!(second[0].state, super::State::Passed);
+        assert!(matches!(second[1].state, super::State::Waiting(_)));
+        assert_eq!(approved_by.as_deref(), Some("jane"));
+    }
+}
@@ -0,0 +1,203 @@
+//! A machine protocol over stdio for tools embedding architect, like deployment controllers or
+//! editors. `rpc` reads one JSON request per line, `{"id": 1, "method": "plan", "params": {}}`,
+//! and writes one JSON response per line with the same id and either a `result` or an `error`
+//! with a `message`. Progress still goes to stderr.
+
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Migrator;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+impl Migrator {
+    /// `plan`: what `up` would do, with the lint and policy findings.
+    fn rpc_plan(&mut self) -> Result<Value> {
+        Ok(serde_json::to_value(self.plan()?)?)
+    }
+
+    /// `apply`: migrates up as `up` does, or applies the signed plan at `params.plan`.
+    fn rpc_apply(&mut self, params: &Value) -> Result<Value> {
+        crate::lock::check(&self.dir)?;
+        crate::plan::enforce_policy(&self.plan()?)?;
+        self.require_downs()?;
+        self.verify_downs(false)?;
+        self.runs.clear();
+        // every request applies a batch of its own
+        self.batch = None;
+        let result = match params.get("plan").and_then(Value::as_str) {
+            Some(path) => {
+                let plan = crate::approval::SignedPlan::read(std::path::Path::new(path))?;
+                self.apply(&plan)
+            }
+            None => self
+                .check_approval_mode()
+                .and_then(|_| self.migrate_up(false)),
+        };
+        let result = result.and_then(|count| {
+            self.after_run()?;
+            Ok(count)
+        });
+        let summary = match &result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+        self.send_summary(&summary);
+        let count = result?;
+        Ok(serde_json::json!({
+            "migrated": count,
+            "version": self.last_version,
+            "runs": self.runs,
+        }))
+    }
+
+    /// `status`: the version of the database and the pending versions.
+    fn rpc_status(&mut self) -> Result<Value> {
+        let pending: Vec<i64> = self
+            .versions_up
+            .iter()
+            .filter(|v| **v > self.last_version && !self.skipped.contains_key(v))
+            .copied()
+            .collect();
+        Ok(serde_json::json!({
+            "app": self.config.app,
+            "dbname": self.config.dbname,
+            "version": self.last_version,
+            "pending": pending,
+            "skipped": self.skipped_versions()?,
+        }))
+    }
+
+    /// `lock`: writes the lock file, or checks it with `params.check`.
+    fn rpc_lock(&mut self, params: &Value) -> Result<Value> {
+        if params.get("check").and_then(Value::as_bool) == Some(true) {
+            crate::lock::check(&self.dir)?;
+            return Ok(serde_json::json!({ "checked": true }));
+        }
+        let count = crate::lock::write(&self.dir)?;
+        Ok(serde_json::json!({ "locked": count }))
+    }
+
+    fn rpc_call(&mut self, request: &Request) -> Result<Value> {
+        // files may have changed since the last request
+        self.available_versions()?;
+        match request.method.as_str() {
+            "plan" => self.rpc_plan(),
+            "apply" => self.rpc_apply(&request.params),
+            "status" => self.rpc_status(),
+            "lock" => self.rpc_lock(&request.params),
+            v => Err(anyhow::anyhow!("unknown method {:?}", v)),
+        }
+    }
+}
+
+/// The response to the request on `line`.
+fn respond(m: &mut Migrator, line: &str) -> Value {
+    let (id, result) = match serde_json::from_str::<Request>(line) {
+        Ok(request) => (request.id.clone(), m.rpc_call(&request)),
+        Err(e) => (Value::Null, Err(anyhow::anyhow!("invalid request: {}", e))),
+    };
+    match result {
+        Ok(v) => serde_json::json!({ "id": id, "result": v }),
+        Err(e) => serde_json::json!({ "id": id, "error": { "message": e.to_string() } }),
+    }
+}
+
+/// Answers the requests of `input` on `output` till the input ends.
+pub(crate) fn serve(m: &mut Migrator, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", respond(m, &line))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn rpc() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./rpc")).unwrap();
+        let input = r#"{"id": 1, "method": "lock"}
+{"id": 2, "method": "lock", "params": {"check": true}}
+
+{"id": "a", "method": "status"}
+{"id": 3, "method": "drop"}
+not json
+"#;
+        let mut output = Vec::<u8>::new();
+
+        let served = super::serve(&mut m, input.as_bytes(), &mut output);
+
+        let _ = std::fs::remove_dir_all("./rpc");
+
+        served.unwrap();
+        let responses: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 5);
+        assert_eq!(
+            responses[0],
+            serde_json::json!({ "id": 1, "result": { "locked": 0 } })
+        );
+        assert_eq!(responses[1]["result"]["checked"], true);
+        assert_eq!(responses[2]["id"], "a");
+        assert_eq!(responses[2]["result"]["pending"], serde_json::json!([]));
+        assert_eq!(responses[3]["error"]["message"], "unknown method \"drop\"");
+        assert_eq!(responses[4]["id"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn rpc_hook_output() {
+        // serves on the real stdout in a child process, where the hooks' output must not end up
+        if std::env::var("ARCHITECT_RPC_CHILD").is_ok() {
+            let mut config = crate::tests::schema_config("__rpc_hooks__");
+            config.hooks.before_all = "echo hook output".to_owned();
+            let dir = std::path::PathBuf::from("./rpc_hooks");
+            let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+            let (up, down) = m.new_migration().unwrap();
+            std::fs::write(up, "CREATE TABLE a (id INT);").unwrap();
+            std::fs::write(down, "DROP TABLE a;").unwrap();
+            let input = r#"{"id": 1, "method": "apply"}"#;
+            let served = super::serve(&mut m, input.as_bytes(), std::io::stdout());
+            m.client
+                .batch_execute("DROP SCHEMA __rpc_hooks__ CASCADE")
+                .unwrap();
+            let _ = std::fs::remove_dir_all(&dir);
+            served.unwrap();
+            return;
+        }
+        let child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["rpc::tests::rpc_hook_output", "--exact", "--nocapture"])
+            .env("ARCHITECT_RPC_CHILD", "1")
+            .output()
+            .unwrap();
+        let (stdout, stderr) = (
+            String::from_utf8_lossy(&child.stdout),
+            String::from_utf8_lossy(&child.stderr),
+        );
+
+        assert!(child.status.success(), "{}", stderr);
+        assert!(stdout.contains(r#"{"id":1,"result":"#), "{}", stdout);
+        assert!(!stdout.contains("hook output"), "{}", stdout);
+        assert!(stderr.contains("hook output"), "{}", stderr);
+    }
+}
@@ -0,0 +1,193 @@
+//! Checks of the database before migrating, configured in the `[safety]` table of the config.
+//! DDL waits for the locks of every transaction touching its table, and everything queues behind
+//! it meanwhile, so a transaction running for hours stalls the app. Runs wait while a transaction
+//! runs longer than `max_transaction_seconds`, a replica lags behind more than
+//! `max_replication_lag_seconds` or, with `vacuum`, a table the migrations touch is vacuumed,
+//! checking again every `interval_seconds`, and give up after `wait_seconds`.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::direction::Step;
+use crate::output::info;
+use crate::Migrator;
+
+/// Default of `interval_seconds`.
+const INTERVAL_SECONDS: u64 = 10;
+
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Safety {
+    /// Longest transaction, in seconds, tolerated while migrating. 0 doesn't check.
+    #[serde(default)]
+    pub(crate) max_transaction_seconds: i64,
+    /// Largest replay lag of a replica, in seconds, tolerated while migrating. 0 doesn't check.
+    #[serde(default)]
+    pub(crate) max_replication_lag_seconds: i64,
+    /// Wait while a table the migrations touch is vacuumed
+    #[serde(default)]
+    pub(crate) vacuum: bool,
+    /// Seconds to wait for the checks to pass before giving up, 0 to give up at once
+    #[serde(default)]
+    pub(crate) wait_seconds: u64,
+    /// Seconds between checks while waiting
+    #[serde(default)]
+    pub(crate) interval_seconds: u64,
+}
+
+impl Safety {
+    fn enabled(&self) -> bool {
+        self.max_transaction_seconds > 0 || self.max_replication_lag_seconds > 0 || self.vacuum
+    }
+}
+
+impl Migrator {
+    /// The tables the sql migrations of `steps` touch, as far as they're parsed.
+    fn touched_tables(&self, steps: &[Step]) -> Vec<String> {
+        let mut result = Vec::<String>::new();
+        for s in steps.iter() {
+            if self.script_path(s.version, s.direction).is_some()
+                || self.streamed(s.version, s.direction).is_some()
+            {
+                continue;
+            }
+            let statements = match self
+                .sql(s.version, s.direction)
+                .and_then(|v| crate::parse_ast(&v))
+            {
+                Ok(v) => v,
+                // verbatim migrations aren't parsed
+                Err(_) => continue,
+            };
+            for t in crate::owners::tables(&statements) {
+                if !result.contains(&t) {
+                    result.push(t);
+                }
+            }
+        }
+        result
+    }
+
+    /// Why migrating `tables` isn't safe right now, empty if it is.
+    pub(crate) fn safety_problems(&mut self, tables: &[String]) -> Result<Vec<String>> {
+        let safety = self.config.safety.clone();
+        let mut result = Vec::<String>::new();
+        if safety.max_transaction_seconds > 0 {
+            for row in self.client.query(
+                "SELECT pid, extract(epoch FROM now() - xact_start)::bigint,
+                    coalesce(application_name, ''), left(query, 60)
+                FROM pg_stat_activity
+                WHERE datname = current_database() AND pid <> pg_backend_pid()
+                    AND xact_start < now() - $1::float8 * interval '1 second'
+                ORDER BY xact_start",
+                &[&(safety.max_transaction_seconds as f64)],
+            )? {
+                let (pid, seconds, application, query): (i32, i64, String, String) =
+                    (row.get(0), row.get(1), row.get(2), row.get(3));
+                result.push(format!(
+                    "transaction of pid {} ({}) running for {}s: {}",
+                    pid, application, seconds, query
+                ));
+            }
+        }
+        if safety.max_replication_lag_seconds > 0 {
+            for row in self.client.query(
+                "SELECT application_name, extract(epoch FROM replay_lag)::float8
+                FROM pg_stat_replication
+                WHERE replay_lag > $1::float8 * interval '1 second'",
+                &[&(safety.max_replication_lag_seconds as f64)],
+            )? {
+                let (replica, seconds): (String, f64) = (row.get(0), row.get(1));
+                result.push(format!(
+                    "replica {} lagging {:.0}s behind",
+                    replica, seconds
+                ));
+            }
+        }
+        if safety.vacuum && !tables.is_empty() {
+            for row in self.client.query(
+                "SELECT relid::regclass::text, phase FROM pg_stat_progress_vacuum
+                WHERE datid = (SELECT oid FROM pg_database WHERE datname = current_database())
+                    AND relid IN (SELECT to_regclass(t) FROM unnest($1::text[]) t)",
+                &[&tables],
+            )? {
+                let (table, phase): (String, String) = (row.get(0), row.get(1));
+                result.push(format!("{} is being vacuumed ({})", table, phase));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Waits until the `[safety]` checks pass for `steps`, failing once `wait_seconds` passed.
+    pub(crate) fn check_safety(&mut self, steps: &[Step]) -> Result<()> {
+        let safety = self.config.safety.clone();
+        if steps.is_empty() || !safety.enabled() {
+            return Ok(());
+        }
+        let tables = self.touched_tables(steps);
+        let interval = match safety.interval_seconds {
+            0 => INTERVAL_SECONDS,
+            v => v,
+        };
+        let start = std::time::Instant::now();
+        loop {
+            let problems = self.safety_problems(&tables)?;
+            if problems.is_empty() {
+                return Ok(());
+            }
+            let waited = start.elapsed().as_secs();
+            if waited >= safety.wait_seconds {
+                return Err(anyhow::anyhow!(
+                    "not migrating, the database isn't safe to migrate{}: {}",
+                    if waited > 0 {
+                        format!(" after waiting {}s", waited)
+                    } else {
+                        String::new()
+                    },
+                    problems.join("; ")
+                ));
+            }
+            info!(
+                "waiting {}s to migrate: {}",
+                interval.min(safety.wait_seconds - waited),
+                problems.join("; ")
+            );
+            std::thread::sleep(std::time::Duration::from_secs(
+                interval.min(safety.wait_seconds - waited),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn long_transaction() {
+        let dir = std::path::PathBuf::from("./safety");
+        let mut config = crate::tests::schema_config("__safety__");
+        let mut holder = config.connect().unwrap();
+        config.safety.max_transaction_seconds = 1;
+        config.safety.vacuum = true;
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        std::fs::write(&up, "CREATE TABLE safe (id INT);").unwrap();
+        std::fs::write(&down, "DROP TABLE safe;").unwrap();
+        let mut t = holder.transaction().unwrap();
+        t.batch_execute("SELECT txid_current()").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1200));
+        let refused = m.migrate_up(false);
+        let refused_version = m.last_version;
+        t.commit().unwrap();
+        let migrated = m.migrate_up(false);
+        m.client
+            .batch_execute("DROP SCHEMA __safety__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let refused = refused.unwrap_err().to_string();
+        assert!(refused.contains("isn't safe to migrate"), "{}", refused);
+        assert!(refused.contains("transaction of pid"));
+        assert_eq!(refused_version, 0);
+        assert_eq!(migrated.unwrap(), 1);
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+pub(crate) struct Failure {
+    pub(crate) version: i64,
+    pub(crate) statement: String,
+    pub(crate) error: String,
+}
+
+impl Migrator {
+    /// Runs all pending up migrations in a single transaction that is rolled back at the end.
+    /// Every statement runs in its own savepoint, so a failing statement is recorded and the run
+    /// carries on with the next one. Returns the number of statements run and the failures.
+    pub(crate) fn sandbox_up(&mut self) -> Result<(usize, Vec<Failure>)> {
+        let mut migrations = Vec::<(i64, Vec<String>)>::new();
+        for v in self.versions_up.iter() {
+            if *v > self.last_version {
+                migrations.push((*v, self.get_queries(*v, Direction::Up)?));
+            }
+        }
+
+        let mut count = 0;
+        let mut failures = Vec::<Failure>::new();
+        let mut t = self.client.transaction()?;
+        for (version, queries) in migrations.iter() {
+            for query in queries.iter() {
+                count += 1;
+                let mut sp = t.savepoint("architect_sandbox")?;
+                match sp.batch_execute(query) {
+                    Ok(_) => sp.commit()?,
+                    // dropping the savepoint rolls back to it
+                    Err(e) => failures.push(Failure {
+                        version: *version,
+                        statement: query.clone(),
+                        error: match e.as_db_error() {
+                            Some(v) => v.message().to_owned(),
+                            None => e.to_string(),
+                        },
+                    }),
+                }
+            }
+        }
+        t.rollback()?;
+        Ok((count, failures))
+    }
+}
+
+/// Prints the failures of a sandbox run, failing if there are any.
+pub(crate) fn print(count: usize, failures: &[Failure]) -> Result<()> {
+    for f in failures.iter() {
+        println!("{}_up.sql: {}\n    {};", f.version, f.error, f.statement);
+    }
+    if !failures.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} of {} statements would have failed",
+            failures.len(),
+            count
+        ));
+    }
+    crate::output::result(
+        &format!(
+            "all {} statements ran successfully and were rolled back",
+            count
+        ),
+        serde_json::json!({ "statements": count }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn sandbox_reports_failures() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./sandbox")).unwrap();
+        m.new_migration().unwrap();
+        m.last_version = 0;
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE __sandbox__ (id INT);
+            INSERT INTO __no_such_table__ VALUES (1);
+            INSERT INTO __sandbox__ VALUES (1);",
+        )
+        .unwrap();
+
+        let (count, failures) = m.sandbox_up().unwrap();
+        let rows = m
+            .client
+            .query("SELECT 1 FROM pg_class WHERE relname = '__sandbox__'", &[])
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./sandbox");
+
+        // three statements plus recording the version
+        assert_eq!(count, 4);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].statement.contains("__no_such_table__"));
+        assert!(rows.is_empty());
+    }
+}
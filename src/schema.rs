@@ -0,0 +1,330 @@
+use anyhow::Result;
+use postgres::{GenericClient, Transaction};
+use serde::Serialize;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Column {
+    pub(crate) name: String,
+    pub(crate) data_type: String,
+    pub(crate) nullable: bool,
+    pub(crate) default: Option<String>,
+    pub(crate) comment: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Constraint {
+    pub(crate) name: String,
+    /// One of `p` (primary key), `f` (foreign key), `u` (unique) or `c` (check)
+    pub(crate) kind: String,
+    pub(crate) definition: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Index {
+    pub(crate) name: String,
+    pub(crate) definition: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Table {
+    pub(crate) name: String,
+    pub(crate) comment: Option<String>,
+    pub(crate) columns: Vec<Column>,
+    pub(crate) constraints: Vec<Constraint>,
+    pub(crate) indexes: Vec<Index>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct View {
+    pub(crate) name: String,
+    pub(crate) definition: String,
+}
+
+/// The tables and views of a database schema as read from the catalogs.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Schema {
+    pub(crate) name: String,
+    pub(crate) tables: Vec<Table>,
+    pub(crate) views: Vec<View>,
+}
+
+/// architect's own tables, created by `init`.
+pub(crate) const OWN_TABLES: &[&str] = &[
+    "schema_migrations",
+    "schema_migration_runs",
+    "schema_tags",
+    "schema_data_migrations",
+    "schema_refreshes",
+    "schema_rollouts",
+    "schema_skipped_versions",
+    "schema_cutovers",
+    "schema_blue_green",
+    "schema_locks",
+];
+
+impl Schema {
+    /// The tables except architect's own.
+    pub(crate) fn app_tables(&self) -> impl Iterator<Item = &Table> {
+        self.tables
+            .iter()
+            .filter(|t| !OWN_TABLES.contains(&t.name.as_str()))
+    }
+
+    pub(crate) fn introspect<C: GenericClient>(client: &mut C, schema: &str) -> Result<Schema> {
+        let mut tables = Vec::<Table>::new();
+        for row in client.query(
+            "SELECT c.relname::text, obj_description(c.oid, 'pg_class') FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname = $1 AND c.relkind IN ('r', 'p')
+            ORDER BY c.relname",
+            &[&schema],
+        )? {
+            tables.push(Table {
+                name: row.get(0),
+                comment: row.get(1),
+                columns: Vec::new(),
+                constraints: Vec::new(),
+                indexes: Vec::new(),
+            });
+        }
+
+        for row in client.query(
+            "SELECT c.relname::text, a.attname::text, format_type(a.atttypid, a.atttypmod),
+                NOT a.attnotnull, pg_get_expr(d.adbin, d.adrelid),
+                col_description(a.attrelid, a.attnum)
+            FROM pg_attribute a
+            JOIN pg_class c ON c.oid = a.attrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+            WHERE n.nspname = $1 AND c.relkind IN ('r', 'p') AND a.attnum > 0
+                AND NOT a.attisdropped
+            ORDER BY c.relname, a.attnum",
+            &[&schema],
+        )? {
+            let table: String = row.get(0);
+            if let Some(t) = tables.iter_mut().find(|v| v.name == table) {
+                t.columns.push(Column {
+                    name: row.get(1),
+                    data_type: row.get(2),
+                    nullable: row.get(3),
+                    default: row.get(4),
+                    comment: row.get(5),
+                });
+            }
+        }
+
+        for row in client.query(
+            "SELECT c.relname::text, con.conname::text, con.contype::text,
+                pg_get_constraintdef(con.oid)
+            FROM pg_constraint con
+            JOIN pg_class c ON c.oid = con.conrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname = $1
+            ORDER BY c.relname, con.conname",
+            &[&schema],
+        )? {
+            let table: String = row.get(0);
+            if let Some(t) = tables.iter_mut().find(|v| v.name == table) {
+                t.constraints.push(Constraint {
+                    name: row.get(1),
+                    kind: row.get(2),
+                    definition: unqualify(&row.get::<_, String>(3), schema),
+                });
+            }
+        }
+
+        for row in client.query(
+            "SELECT tablename::text, indexname::text, indexdef FROM pg_indexes
+            WHERE schemaname = $1
+            ORDER BY tablename, indexname",
+            &[&schema],
+        )? {
+            let table: String = row.get(0);
+            let name: String = row.get(1);
+            if let Some(t) = tables.iter_mut().find(|v| v.name == table) {
+                // indexes backing a constraint are part of the constraint's definition
+                if t.constraints.iter().any(|c| c.name == name) {
+                    continue;
+                }
+                t.indexes.push(Index {
+                    name,
+                    definition: unqualify(&row.get::<_, String>(2), schema),
+                });
+            }
+        }
+
+        let mut views = Vec::<View>::new();
+        for row in client.query(
+            "SELECT viewname::text, definition FROM pg_views
+            WHERE schemaname = $1
+            ORDER BY viewname",
+            &[&schema],
+        )? {
+            views.push(View {
+                name: row.get(0),
+                definition: unqualify(&row.get::<_, String>(1), schema),
+            });
+        }
+
+        Ok(Schema {
+            name: schema.to_owned(),
+            tables,
+            views,
+        })
+    }
+
+    /// The schema as `CREATE` statements.
+    pub(crate) fn ddl(&self) -> String {
+        let mut result = Vec::<String>::new();
+        for t in self.tables.iter() {
+            let mut lines = Vec::<String>::new();
+            for c in t.columns.iter() {
+                let mut line = format!("    {} {}", c.name, c.data_type);
+                if let Some(d) = &c.default {
+                    line.push_str(&format!(" DEFAULT {}", d));
+                }
+                if !c.nullable {
+                    line.push_str(" NOT NULL");
+                }
+                lines.push(line);
+            }
+            for c in t.constraints.iter() {
+                lines.push(format!("    CONSTRAINT {} {}", c.name, c.definition));
+            }
+            result.push(format!(
+                "CREATE TABLE {} (\n{}\n);",
+                t.name,
+                lines.join(",\n")
+            ));
+            for i in t.indexes.iter() {
+                result.push(format!("{};", i.definition));
+            }
+        }
+        for v in self.views.iter() {
+            result.push(format!(
+                "CREATE VIEW {} AS\n{}",
+                v.name,
+                v.definition.trim_end()
+            ));
+        }
+        result.join("\n\n")
+    }
+}
+
+/// Removes `schema.` qualifiers from a catalog definition so dumps of different schemas compare
+/// equal.
+fn unqualify(definition: &str, schema: &str) -> String {
+    definition.replace(&format!("{}.", schema), "")
+}
+
+static SHADOW_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Creates an empty throw away schema in the transaction and puts it first on the search path,
+/// returning its name. Rolling back the transaction removes it.
+pub(crate) fn create_shadow(t: &mut Transaction) -> Result<String> {
+    let shadow = format!(
+        "architect_shadow_{}_{}",
+        std::process::id(),
+        SHADOW_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    );
+    t.batch_execute(&format!(
+        "CREATE SCHEMA {shadow}; SET LOCAL search_path TO {shadow}"
+    ))?;
+    Ok(shadow)
+}
+
+impl Migrator {
+    /// The schema resulting from applying all up migrations till `target`, in order, to an empty
+    /// schema. The migrations are run in a throw away schema inside a transaction that is rolled
+    /// back, so nothing is left behind. Migrations that qualify objects with a schema name escape
+    /// the throw away schema and will fail or leak into the dump.
+    pub(crate) fn schema_at(&mut self, target: i64) -> Result<Schema> {
+        if target != 0 && !self.versions_up.contains(&target) {
+            return Err(anyhow::anyhow!("version {} does not exist", target));
+        }
+
+        let mut migrations = Vec::<(i64, Vec<String>)>::new();
+        for v in self.versions_up.iter() {
+            if *v > target {
+                break;
+            }
+            migrations.push((*v, crate::parse_statements(&self.sql(*v, Direction::Up)?)?));
+        }
+
+        let mut t = self.client.transaction()?;
+        let shadow = create_shadow(&mut t)?;
+        for (version, statements) in migrations.iter() {
+            for s in statements.iter() {
+                if let Err(e) = t.batch_execute(s) {
+                    return Err(anyhow::anyhow!(
+                        "error running migration {}_up.sql in shadow schema: {}",
+                        version,
+                        e
+                    ));
+                }
+            }
+        }
+        let schema = Schema::introspect(&mut t, &shadow)?;
+        t.rollback()?;
+        Ok(schema)
+    }
+
+    /// The schema of the database as it is, read from the first schema on the search path.
+    pub(crate) fn current_schema(&mut self) -> Result<Schema> {
+        let row = self
+            .client
+            .query_one("SELECT current_schema()::text", &[])?;
+        let name: String = row.get(0);
+        Schema::introspect(&mut self.client, &name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn schema_at_version() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./schema_at")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", first)),
+            b"CREATE TABLE users (id INT PRIMARY KEY, name VARCHAR(20) NOT NULL);",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", second)),
+            b"ALTER TABLE users ADD COLUMN email TEXT; CREATE INDEX users_email ON users (email);",
+        )
+        .unwrap();
+
+        let at_first = m.schema_at(first).unwrap();
+        let at_second = m.schema_at(second).unwrap();
+        let leaked = m
+            .client
+            .query(
+                "SELECT 1 FROM pg_namespace WHERE nspname LIKE 'architect_shadow_%'",
+                &[],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./schema_at");
+
+        assert_eq!(at_first.tables.len(), 1);
+        assert_eq!(at_first.tables[0].columns.len(), 2);
+        assert!(at_first.tables[0].indexes.is_empty());
+        assert_eq!(at_second.tables[0].columns.len(), 3);
+        assert_eq!(at_second.tables[0].indexes.len(), 1);
+        assert!(at_second
+            .ddl()
+            .contains("name character varying(20) NOT NULL"));
+        assert!(leaked.is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+//! Migrations written as Rhai scripts, `<version>_(up|down).rhai`, for data transformations that
+//! need control flow beyond sql. Scripts only get a small API:
+//!
+//! - `execute(sql)` and `execute(sql, params)` run a statement and return the rows affected
+//! - `query(sql)` and `query(sql, params)` run a select and return its rows as object maps
+//! - `log(message)` prints to stderr
+//!
+//! Params are an array passed as text, so placeholders may need a cast, e.g. `$1::int`. Running
+//! them requires building with the `rhai` feature.
+//!
+//! Scripts, and WASM modules (see wasm.rs), run on their own connection in a transaction together
+//! with the sql hooks and recording the version.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::Result;
+use postgres::Client;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+impl Migrator {
+    /// Path of the script or WASM module for a migration if the migration is one.
+    pub(crate) fn script_path(
+        &self,
+        version: i64,
+        direction: Direction,
+    ) -> Option<std::path::PathBuf> {
+        let path = crate::migration_file(&self.dir, version, direction);
+        if path.extension().is_some_and(|v| v == "sql") {
+            None
+        } else {
+            Some(path)
+        }
+    }
+
+    pub(crate) fn run_script(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        let wasm = path.extension().is_some_and(|v| v == "wasm");
+        let before_each = self.sql_hook("before_each")?;
+        let after_each = self.sql_hook("after_each")?;
+
+        let client = Rc::new(RefCell::new(self.config.clone().connect()?));
+        let start = std::time::Instant::now();
+        client.borrow_mut().batch_execute("BEGIN")?;
+        let result = (|| -> Result<i64> {
+            client.borrow_mut().execute(
+                "SELECT set_config('architect.version', $1, true), \
+                set_config('architect.direction', $2, true)",
+                &[&version.to_string(), &direction.as_str()],
+            )?;
+            for query in before_each.iter() {
+                client.borrow_mut().batch_execute(query)?;
+            }
+            if wasm {
+                crate::wasm::run(client.clone(), &self.config, path)?;
+            } else {
+                run_rhai(client.clone(), path)?;
+            }
+            for query in after_each.iter() {
+                client.borrow_mut().batch_execute(query)?;
+            }
+            client
+                .borrow_mut()
+                .batch_execute(&crate::record_query(version, direction, self.batch))?;
+            if direction == Direction::Up {
+                let duration_ms = start.elapsed().as_millis() as i64;
+                client.borrow_mut().execute(
+                    "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+                    &[&duration_ms, &version],
+                )?;
+            }
+            crate::grants::transaction_id(&mut *client.borrow_mut())
+        })();
+        match result {
+            Ok(transaction) => {
+                client.borrow_mut().batch_execute("COMMIT")?;
+                self.run_transactions.push(transaction);
+            }
+            Err(e) => {
+                client.borrow_mut().batch_execute("ROLLBACK")?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "rhai"))]
+fn run_rhai(_client: Rc<RefCell<Client>>, path: &std::path::Path) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "{:?} is a script migration but architect was built without the rhai feature",
+        path
+    ))
+}
+
+#[cfg(feature = "rhai")]
+fn run_rhai(client: Rc<RefCell<Client>>, path: &std::path::Path) -> Result<()> {
+    let script = std::fs::read_to_string(path)?;
+    if let Err(e) = api::engine(client).run(&script) {
+        return Err(anyhow::anyhow!("{}", e));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "rhai")]
+mod api {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use postgres::types::{ToSql, Type};
+    use postgres::Client;
+    use rhai::{Array, Dynamic, Engine, EvalAltResult, Map};
+
+    type Shared = Rc<RefCell<Client>>;
+
+    fn text_params(params: &Array) -> Vec<Option<String>> {
+        params
+            .iter()
+            .map(|v| {
+                if v.is_unit() {
+                    None
+                } else {
+                    Some(v.to_string())
+                }
+            })
+            .collect()
+    }
+
+    fn json_to_dynamic(v: serde_json::Value) -> Dynamic {
+        match v {
+            serde_json::Value::Null => Dynamic::UNIT,
+            serde_json::Value::Bool(v) => v.into(),
+            serde_json::Value::Number(v) => match v.as_i64() {
+                Some(i) => i.into(),
+                None => v.as_f64().unwrap_or_default().into(),
+            },
+            serde_json::Value::String(v) => v.into(),
+            serde_json::Value::Array(v) => {
+                v.into_iter().map(json_to_dynamic).collect::<Array>().into()
+            }
+            serde_json::Value::Object(v) => v
+                .into_iter()
+                .map(|(k, v)| (k.into(), json_to_dynamic(v)))
+                .collect::<Map>()
+                .into(),
+        }
+    }
+
+    fn execute(client: &Shared, sql: &str, params: &Array) -> Result<i64, Box<EvalAltResult>> {
+        let params = text_params(params);
+        let params: Vec<&(dyn ToSql + Sync)> =
+            params.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+        let mut client = client.borrow_mut();
+        let result = client
+            .prepare_typed(sql, &vec![Type::TEXT; params.len()])
+            .and_then(|statement| client.execute(&statement, &params));
+        match result {
+            Ok(v) => Ok(v as i64),
+            Err(e) => Err(e.to_string().into()),
+        }
+    }
+
+    fn query(client: &Shared, sql: &str, params: &Array) -> Result<Array, Box<EvalAltResult>> {
+        let params = text_params(params);
+        let params: Vec<&(dyn ToSql + Sync)> =
+            params.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+        // rows are fetched as json so every column type maps onto a rhai value
+        let sql = format!("SELECT row_to_json(q)::text FROM ({}) q", sql);
+        let mut client = client.borrow_mut();
+        let result = client
+            .prepare_typed(&sql, &vec![Type::TEXT; params.len()])
+            .and_then(|statement| client.query(&statement, &params));
+        let rows = match result {
+            Ok(v) => v,
+            Err(e) => return Err(e.to_string().into()),
+        };
+        let mut result = Array::new();
+        for row in rows {
+            let json: String = row.get(0);
+            match serde_json::from_str(&json) {
+                Ok(v) => result.push(json_to_dynamic(v)),
+                Err(e) => return Err(e.to_string().into()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// An engine with the migration API bound to `client`.
+    pub(super) fn engine(client: Shared) -> Engine {
+        let mut engine = Engine::new();
+        engine.on_print(|v| crate::output::info!("{}", v));
+        engine.register_fn("log", |v: &str| crate::output::info!("{}", v));
+
+        let c = client.clone();
+        engine.register_fn("execute", move |sql: &str| execute(&c, sql, &Array::new()));
+        let c = client.clone();
+        engine.register_fn("execute", move |sql: &str, params: Array| {
+            execute(&c, sql, &params)
+        });
+        let c = client.clone();
+        engine.register_fn("query", move |sql: &str| query(&c, sql, &Array::new()));
+        engine.register_fn("query", move |sql: &str, params: Array| {
+            query(&client, sql, &params)
+        });
+        engine
+    }
+}
+
+#[cfg(all(test, feature = "rhai"))]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn script_migration() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./script")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::remove_file(m.dir.join(format!("{}_up.sql", version))).unwrap();
+        std::fs::remove_file(m.dir.join(format!("{}_down.sql", version))).unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.rhai", version)),
+            b"execute(\"CREATE TABLE __script__ (id INT, name TEXT)\");
+            for i in 1..=3 {
+                execute(\"INSERT INTO __script__ VALUES ($1::int, $2)\", [i, `n${i}`]);
+            }
+            let rows = query(\"SELECT * FROM __script__ WHERE id > $1::int\", [1]);
+            if rows.len() != 2 || rows[0].name != \"n2\" {
+                throw \"unexpected rows\";
+            }",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_down.rhai", version)),
+            b"execute(\"DROP TABLE __script__\");",
+        )
+        .unwrap();
+        m.available_versions().unwrap();
+        m.last_version = 0;
+
+        let up = m.migrate_up(false);
+        let count = m
+            .client
+            .query("SELECT 1 FROM __script__", &[])
+            .map(|v| v.len());
+        let down = m.migrate_down(false);
+
+        let _ = std::fs::remove_dir_all("./script");
+
+        up.unwrap();
+        down.unwrap();
+        assert_eq!(count.unwrap(), 3);
+    }
+}
@@ -0,0 +1,140 @@
+//! Encrypted configs, so credentials can be committed next to the migrations. A config named
+//! `*.age` is decrypted as a whole with age, one named `*.sops.toml` with the `sops` cli. String
+//! values that are armored age ciphertext are decrypted in place. Decrypting age requires building
+//! with the `age` feature, sops reads its keys the way it always does.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Reads a config file, decrypting it if it's encrypted as a whole.
+pub(crate) fn read_config_file(path: &Path) -> Result<String> {
+    let name = path
+        .file_name()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if name.ends_with(".sops.toml") {
+        return sops(path);
+    }
+    if name.ends_with(".age") {
+        return match decrypt(&std::fs::read(path)?) {
+            Ok(v) => Ok(String::from_utf8(v)?),
+            Err(e) => Err(anyhow::anyhow!("can't decrypt config {:?}: {}", path, e)),
+        };
+    }
+    Ok(std::fs::read_to_string(path)?)
+}
+
+fn sops(path: &Path) -> Result<String> {
+    let output = match std::process::Command::new("sops")
+        .arg("--decrypt")
+        .arg(path)
+        .output()
+    {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "can't run sops to decrypt {:?}: {}",
+                path,
+                e
+            ))
+        }
+    };
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "sops failed to decrypt {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Decrypts the string values of a config that are armored age ciphertext. A single trailing
+/// newline of the plaintext, as left by `echo secret | age -a`, is dropped.
+pub(crate) fn decrypt_config(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(v) if v.trim_start().starts_with(ARMOR_BEGIN) => {
+            let plain = match decrypt(v.trim().as_bytes()) {
+                Ok(p) => String::from_utf8(p)?,
+                Err(e) => return Err(anyhow::anyhow!("can't decrypt config value: {}", e)),
+            };
+            *v = plain.strip_suffix('\n').unwrap_or(&plain).to_owned();
+        }
+        toml::Value::Array(v) => {
+            for item in v.iter_mut() {
+                decrypt_config(item)?;
+            }
+        }
+        toml::Value::Table(v) => {
+            for (_, item) in v.iter_mut() {
+                decrypt_config(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "age"))]
+fn decrypt(_ciphertext: &[u8]) -> Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "the config is encrypted with age but architect was built without the age feature"
+    ))
+}
+
+/// Identities from `ARCHITECT_AGE_KEY`, else from the key file, see `paths::age_key`.
+#[cfg(feature = "age")]
+fn identities() -> Result<Vec<Box<dyn age::Identity>>> {
+    let content = match std::env::var("ARCHITECT_AGE_KEY") {
+        Ok(v) if !v.is_empty() => v,
+        _ => match crate::paths::age_key() {
+            Some(p) if p.is_file() => std::fs::read_to_string(p)?,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "no age key. set ARCHITECT_AGE_KEY or ARCHITECT_AGE_KEY_FILE"
+                ))
+            }
+        },
+    };
+    Ok(age::IdentityFile::from_buffer(content.as_bytes())?.into_identities()?)
+}
+
+/// Decrypts age ciphertext, armored or binary.
+#[cfg(feature = "age")]
+fn decrypt(ciphertext: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let identities = identities()?;
+    let decryptor = age::Decryptor::new_buffered(age::armor::ArmoredReader::new(ciphertext))?;
+    let mut reader =
+        decryptor.decrypt(identities.iter().map(|v| v.as_ref() as &dyn age::Identity))?;
+    let mut result = Vec::<u8>::new();
+    reader.read_to_end(&mut result)?;
+    Ok(result)
+}
+
+#[cfg(all(test, feature = "age"))]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    #[test]
+    fn decrypt_config() {
+        let identity = age::x25519::Identity::generate();
+        std::env::set_var("ARCHITECT_AGE_KEY", identity.to_string().expose_secret());
+        let password = age::encrypt_and_armor(&identity.to_public(), b"secret\n").unwrap();
+        let mut config: toml::Value = toml::from_str(&format!(
+            "user = \"admin\"\npassword = \"\"\"{}\"\"\"\n[email]\npassword = \"\"\"{}\"\"\"",
+            password, password
+        ))
+        .unwrap();
+
+        super::decrypt_config(&mut config).unwrap();
+
+        assert_eq!(config["user"].as_str(), Some("admin"));
+        assert_eq!(config["password"].as_str(), Some("secret"));
+        assert_eq!(config["email"]["password"].as_str(), Some("secret"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Sequence synchronization. Rows inserted with explicit ids, like by data imports in migrations,
+//! leave the sequences of serial and identity columns behind, so later inserts collide. The
+//! sequences of tables named by an up migration's `-- architect:fix-sequences TABLE...` or by
+//! `fix_sequences` in `.architect.toml` are reset after the migrations of a run, and `fix-sequences`
+//! resets them on demand.
+
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::output::info;
+use crate::Migrator;
+
+pub(crate) const DIRECTIVE: &str = "fix-sequences";
+
+/// A sequence reset to follow the largest value of its column.
+#[derive(serde::Serialize)]
+pub(crate) struct Reset {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    pub(crate) sequence: String,
+    /// The value the next insert gets
+    pub(crate) next: i64,
+}
+
+/// The tables the directives of `sql` name.
+pub(crate) fn directives(sql: &str) -> Vec<String> {
+    crate::directives::parse(sql)
+        .into_iter()
+        .filter(|d| d.name == DIRECTIVE)
+        .flat_map(|d| {
+            d.args
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+impl Migrator {
+    /// Resets the sequences of the serial and identity columns of `tables` to continue after
+    /// the largest value in their column.
+    pub(crate) fn fix_sequences(&mut self, tables: &[String]) -> Result<Vec<Reset>> {
+        let mut result = Vec::<Reset>::new();
+        for table in tables.iter() {
+            let columns = self.client.query(
+                "SELECT a.attname::text, pg_get_serial_sequence($1, a.attname)
+                FROM pg_attribute a
+                WHERE a.attrelid = $1::text::regclass AND a.attnum > 0 AND NOT a.attisdropped
+                    AND pg_get_serial_sequence($1, a.attname) IS NOT NULL
+                ORDER BY a.attnum",
+                &[table],
+            )?;
+            for row in columns.iter() {
+                let (column, sequence): (String, String) = (row.get(0), row.get(1));
+                let next: i64 = self
+                    .client
+                    .query_one(
+                        &format!(
+                            "SELECT setval($1::text::regclass, COALESCE(max(\"{}\"), 0) + 1, false) FROM {}",
+                            column.replace('"', "\"\""),
+                            table
+                        ),
+                        &[&sequence],
+                    )?
+                    .get(0);
+                info!("{} of {}.{} continues at {}", sequence, table, column, next);
+                result.push(Reset {
+                    table: table.clone(),
+                    column,
+                    sequence,
+                    next,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// The tables whose sequences are due after the migrations of this run, those of the up
+    /// migrations' directives followed by the project's, each once.
+    pub(crate) fn pending_sequence_tables(&self) -> Result<Vec<String>> {
+        let applied = self.applied_in_run();
+        let mut result = Vec::<String>::new();
+        if !self.migrated_in_run() {
+            return Ok(result);
+        }
+        for v in applied.iter() {
+            if self.script_path(*v, Direction::Up).is_none() {
+                result.append(&mut directives(&self.sql(*v, Direction::Up)?));
+            }
+        }
+        result.append(&mut self.project()?.fix_sequences);
+        let mut seen = std::collections::HashSet::<String>::new();
+        result.retain(|v| seen.insert(v.clone()));
+        Ok(result)
+    }
+
+    /// Resets the sequences due after the migrations of this run.
+    pub(crate) fn fix_sequences_after_run(&mut self) -> Result<Vec<Reset>> {
+        let tables = self.pending_sequence_tables()?;
+        self.fix_sequences(&tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn fix_sequences() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./sequences")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __sequences__, __sequences_identity__;
+                CREATE TABLE __sequences__ (id SERIAL PRIMARY KEY, n BIGSERIAL);
+                CREATE TABLE __sequences_identity__ (
+                    id INT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY
+                );",
+            )
+            .unwrap();
+        std::fs::write(
+            "./sequences/.architect.toml",
+            "fix_sequences = [\"__sequences_identity__\"]\n",
+        )
+        .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "-- architect:fix-sequences __sequences__\n\
+            INSERT INTO __sequences__ VALUES (41, 7);\n\
+            INSERT INTO __sequences_identity__ VALUES (5);",
+        )
+        .unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let migrated = m.migrate_up(false);
+        let resets = m.fix_sequences_after_run();
+        let inserted: (i32, i64) = m
+            .client
+            .query_one(
+                "INSERT INTO __sequences__ DEFAULT VALUES RETURNING id, n",
+                &[],
+            )
+            .map(|row| (row.get(0), row.get(1)))
+            .unwrap_or_default();
+        let identity: i32 = m
+            .client
+            .query_one(
+                "INSERT INTO __sequences_identity__ DEFAULT VALUES RETURNING id",
+                &[],
+            )
+            .map(|row| row.get(0))
+            .unwrap_or_default();
+        m.client
+            .batch_execute("DROP TABLE __sequences__, __sequences_identity__")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./sequences");
+
+        assert_eq!(migrated.unwrap(), 1);
+        let resets = resets.unwrap();
+        assert_eq!(resets.len(), 3);
+        assert_eq!(resets[0].next, 42);
+        assert_eq!(resets[1].column, "n");
+        assert_eq!(resets[1].next, 8);
+        assert_eq!(resets[2].table, "__sequences_identity__");
+        assert_eq!(resets[2].next, 6);
+        assert_eq!(inserted, (42, 8));
+        assert_eq!(identity, 6);
+    }
+}
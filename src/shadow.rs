@@ -0,0 +1,120 @@
+use anyhow::Result;
+
+use crate::output::info;
+use crate::{Config, Migrator};
+
+impl Migrator {
+    /// Config for another database on the same server as the main database.
+    fn replica_config(&self, dbname: &str, option: &str) -> Result<Config> {
+        if dbname.is_empty() {
+            return Err(anyhow::anyhow!("{} is not configured", option));
+        }
+        if dbname == self.config.dbname {
+            return Err(anyhow::anyhow!("{} cannot be the same as dbname", option));
+        }
+        let mut config = self.config.clone();
+        config.dbname = dbname.to_owned();
+        // hooks and plugins are about the main database only. the password is known already.
+        config.hooks = Default::default();
+        config.plugins = Vec::new();
+        config.email = Default::default();
+        Ok(config)
+    }
+
+    fn database_exists(&mut self, dbname: &str) -> Result<bool> {
+        let rows = self
+            .client
+            .query("SELECT 1 FROM pg_database WHERE datname = $1", &[&dbname])?;
+        Ok(!rows.is_empty())
+    }
+
+    /// Returns a migrator for another database on the same server, creating the database if it
+    /// doesn't exist and migrating it up or down to the last version applied to the main
+    /// database.
+    fn replica(&mut self, config: Config) -> Result<Migrator> {
+        if !self.database_exists(&config.dbname)? {
+            info!("creating database {}", config.dbname);
+            self.client
+                .batch_execute(&format!("CREATE DATABASE {}", config.dbname))?;
+        }
+        let parent = match self.dir.parent() {
+            Some(v) => v.to_path_buf(),
+            None => return Err(anyhow::anyhow!("invalid path: {:?}", &self.dir)),
+        };
+        let mut replica = Migrator::new(config, parent)?;
+        replica.goto(self.last_version, false)?;
+        Ok(replica)
+    }
+
+    /// Returns a migrator for the shadow database, synced to the main database.
+    pub(crate) fn shadow(&mut self) -> Result<Migrator> {
+        let config = self.replica_config(&self.config.shadow_dbname, "shadow_dbname")?;
+        self.replica(config)
+    }
+
+    /// Drops the shadow database and creates it again from the migration files.
+    pub(crate) fn shadow_reset(&mut self) -> Result<Migrator> {
+        let config = self.replica_config(&self.config.shadow_dbname, "shadow_dbname")?;
+        self.client
+            .batch_execute(&format!("DROP DATABASE IF EXISTS {}", config.dbname))?;
+        self.replica(config)
+    }
+
+    /// Syncs the template database to the main database and returns its name. Its connection is
+    /// closed again since postgres only clones templates nobody is connected to.
+    pub(crate) fn template(&mut self) -> Result<String> {
+        let config = self.replica_config(&self.config.template_dbname, "template_dbname")?;
+        let template = self.replica(config)?;
+        Ok(template.config.dbname.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn shadow_follows_last_version() {
+        let mut config = test_config().unwrap();
+        config.shadow_dbname = format!("architect_shadow_test_{}", std::process::id());
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./shadow")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        let first = *m.versions_up.first().unwrap();
+        let second = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", first)),
+            b"CREATE TABLE __shadow__ (id INT);",
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_down.sql", first)),
+            b"DROP TABLE __shadow__;",
+        )
+        .unwrap();
+        // pretend the first migration is applied to the main database
+        m.last_version = first;
+
+        let mut shadow = m.shadow().unwrap();
+        let synced = shadow.last_version;
+        let rows = shadow
+            .client
+            .query("SELECT 1 FROM pg_class WHERE relname = '__shadow__'", &[])
+            .unwrap();
+        drop(shadow);
+        let reset = m.shadow_reset().unwrap().last_version;
+        m.client
+            .batch_execute(&format!(
+                "DROP DATABASE IF EXISTS {}",
+                m.config.shadow_dbname
+            ))
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./shadow");
+
+        assert_eq!(synced, first);
+        assert_eq!(reset, first);
+        assert_ne!(synced, second);
+        assert_eq!(rows.len(), 1);
+    }
+}
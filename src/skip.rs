@@ -0,0 +1,112 @@
+//! Versions intentionally not applied on a database, e.g. when a hotfix made an environment
+//! diverge. The versions in `skip_versions` of the connection config, or given with `up --skip`,
+//! are left out when migrating up or down, and a run passing over one records it with its reason
+//! in `schema_skipped_versions`. `list` shows them as skipped and warns about them.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::output::info;
+use crate::Migrator;
+
+/// Reason of the versions in `skip_versions` without a `skip_reason`.
+pub(crate) const CONFIG_REASON: &str = "listed in skip_versions";
+
+/// The versions of `skip_versions` in `config` with their reason.
+pub(crate) fn from_config(config: &crate::Config) -> BTreeMap<i64, String> {
+    let reason = if config.skip_reason.is_empty() {
+        CONFIG_REASON
+    } else {
+        &config.skip_reason
+    };
+    config
+        .skip_versions
+        .iter()
+        .map(|v| (*v, reason.to_owned()))
+        .collect()
+}
+
+impl Migrator {
+    /// Skips `version` for this run.
+    pub(crate) fn skip(&mut self, version: i64, reason: &str) {
+        self.skipped.insert(version, reason.to_owned());
+    }
+
+    /// Records the skipped versions the database has passed, which won't be applied.
+    pub(crate) fn record_skipped(&mut self) -> Result<()> {
+        let (versions, reasons): (Vec<i64>, Vec<String>) = self
+            .skipped
+            .iter()
+            .filter(|(v, _)| **v < self.last_version)
+            .map(|(v, reason)| (*v, reason.clone()))
+            .unzip();
+        if versions.is_empty() {
+            return Ok(());
+        }
+        let recorded = self.client.execute(
+            "INSERT INTO schema_skipped_versions (version, reason, skipped_at, skipped_by)
+            SELECT version, reason, now(), current_user FROM unnest($1::BIGINT[], $2::TEXT[])
+                AS s (version, reason)
+            WHERE version NOT IN (SELECT version FROM schema_migrations)
+            ON CONFLICT (version) DO NOTHING",
+            &[&versions, &reasons],
+        )?;
+        if recorded > 0 {
+            info!("recorded {} skipped versions", recorded);
+        }
+        Ok(())
+    }
+
+    /// The skipped versions with their reason, the recorded ones and those skipped by this run.
+    pub(crate) fn skipped_versions(&mut self) -> Result<BTreeMap<i64, String>> {
+        let mut result = self.skipped.clone();
+        for row in self
+            .client
+            .query("SELECT version, reason FROM schema_skipped_versions", &[])?
+        {
+            result.entry(row.get(0)).or_insert_with(|| row.get(1));
+        }
+        Ok(result)
+    }
+}
+
+/// Warns about the skipped versions on stderr.
+pub(crate) fn warn(skipped: &BTreeMap<i64, String>) {
+    for (version, reason) in skipped.iter() {
+        info!("warning: version {} is skipped: {}", version, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn skip_versions() {
+        let mut config = crate::tests::schema_config("__skip__");
+        let dir = std::path::PathBuf::from("./skip");
+        config.skip_versions = vec![2];
+        let mut m = crate::Migrator::new(config, dir.clone()).unwrap();
+        for v in 1..=4 {
+            std::fs::write(m.dir.join(format!("{}_up.sql", v)), "SELECT 1;").unwrap();
+            std::fs::write(m.dir.join(format!("{}_down.sql", v)), "SELECT 1;").unwrap();
+        }
+        m.available_versions().unwrap();
+        m.skip(3, "broken on staging");
+
+        let up = m.migrate_up(false);
+        let applied = m.applied_versions().unwrap();
+        let skipped = m.skipped_versions().unwrap();
+        let down = m.migrate_down(false);
+        m.client
+            .batch_execute("DROP SCHEMA __skip__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(up.unwrap(), 2);
+        assert_eq!(applied, vec![1, 4]);
+        assert_eq!(skipped.get(&2).unwrap(), super::CONFIG_REASON);
+        assert_eq!(skipped.get(&3).unwrap(), "broken on staging");
+        assert_eq!(down.unwrap(), 2);
+    }
+}
@@ -0,0 +1,194 @@
+//! Export and import of the bookkeeping tables, `schema_migrations` and `schema_tags`, as JSON.
+//! Useful for moving the bookkeeping between clusters, seeding a database restored from a backup
+//! or looking into support cases.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Migrator;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct MigrationState {
+    pub(crate) version: i64,
+    pub(crate) dirty: bool,
+    /// RFC 3339
+    pub(crate) applied_at: Option<String>,
+    pub(crate) applied_by: Option<String>,
+    pub(crate) duration_ms: Option<i64>,
+    pub(crate) planned_by: Option<String>,
+    pub(crate) approved_by: Option<String>,
+    /// The run that applied it, see `rollback`
+    #[serde(default)]
+    pub(crate) batch: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct TagState {
+    pub(crate) tag: String,
+    pub(crate) version: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct State {
+    pub(crate) app: String,
+    pub(crate) dbname: String,
+    pub(crate) exported_at: String,
+    pub(crate) migrations: Vec<MigrationState>,
+    pub(crate) tags: Vec<TagState>,
+}
+
+impl Migrator {
+    pub(crate) fn export_state(&mut self) -> Result<State> {
+        let mut migrations = Vec::<MigrationState>::new();
+        for row in self.client.query(
+            "SELECT version, dirty, applied_at, applied_by, duration_ms, planned_by, approved_by,
+            batch FROM schema_migrations ORDER BY version",
+            &[],
+        )? {
+            let applied_at: Option<DateTime<Utc>> = row.get(2);
+            migrations.push(MigrationState {
+                version: row.get(0),
+                dirty: row.get::<_, Option<bool>>(1).unwrap_or(false),
+                applied_at: applied_at.map(|v| v.to_rfc3339()),
+                applied_by: row.get(3),
+                duration_ms: row.get(4),
+                planned_by: row.get(5),
+                approved_by: row.get(6),
+                batch: row.get(7),
+            });
+        }
+        let mut tags = Vec::<TagState>::new();
+        for row in self
+            .client
+            .query("SELECT tag, version FROM schema_tags ORDER BY tag", &[])?
+        {
+            tags.push(TagState {
+                tag: row.get(0),
+                version: row.get(1),
+            });
+        }
+        Ok(State {
+            app: self.config.app.clone(),
+            dbname: self.config.dbname.clone(),
+            exported_at: Utc::now().to_rfc3339(),
+            migrations,
+            tags,
+        })
+    }
+
+    /// Imports `state` in one transaction. Existing bookkeeping is only replaced with `replace`,
+    /// otherwise the tables have to be empty.
+    pub(crate) fn import_state(&mut self, state: &State, replace: bool) -> Result<()> {
+        if state.app != self.config.app {
+            eprintln!(
+                "warning: state was exported for app {}, importing into {}",
+                state.app, self.config.app
+            );
+        }
+        for m in state.migrations.iter() {
+            if !self.versions_up.contains(&m.version) {
+                eprintln!(
+                    "warning: version {} has no migration files in {:?}",
+                    m.version, self.dir
+                );
+            }
+        }
+        let mut t = self.client.transaction()?;
+        if replace {
+            t.batch_execute("DELETE FROM schema_tags; DELETE FROM schema_migrations;")?;
+        } else {
+            let row = t.query_one(
+                "SELECT (SELECT count(*) FROM schema_migrations) + (SELECT count(*) FROM schema_tags)",
+                &[],
+            )?;
+            if row.get::<_, i64>(0) > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} has bookkeeping already. pass --replace to overwrite it",
+                    self.config.dbname
+                ));
+            }
+        }
+        for m in state.migrations.iter() {
+            let applied_at = match &m.applied_at {
+                Some(v) => Some(DateTime::parse_from_rfc3339(v)?.with_timezone(&Utc)),
+                None => None,
+            };
+            t.execute(
+                "INSERT INTO schema_migrations
+                (version, dirty, applied_at, applied_by, duration_ms, planned_by, approved_by,
+                batch)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &m.version,
+                    &m.dirty,
+                    &applied_at,
+                    &m.applied_by,
+                    &m.duration_ms,
+                    &m.planned_by,
+                    &m.approved_by,
+                    &m.batch,
+                ],
+            )?;
+        }
+        for tag in state.tags.iter() {
+            t.execute(
+                "INSERT INTO schema_tags(tag, version) VALUES ($1, $2)",
+                &[&tag.tag, &tag.version],
+            )?;
+        }
+        t.commit()?;
+        self.last_version = state.migrations.last().map(|v| v.version).unwrap_or(0);
+        Ok(())
+    }
+}
+
+pub(crate) fn read(path: &std::path::Path) -> Result<State> {
+    match serde_json::from_str(&std::fs::read_to_string(path)?) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(anyhow::anyhow!("invalid state file {:?}: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn export_and_import() {
+        let mut config = test_config().unwrap();
+        let mut m =
+            crate::Migrator::new(config.clone(), std::path::PathBuf::from("./state")).unwrap();
+        config.dbname = format!("{}_state_{}", config.dbname, std::process::id());
+        m.client
+            .batch_execute(&format!("CREATE DATABASE {}", config.dbname))
+            .unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let mut target =
+            crate::Migrator::new(config.clone(), std::path::PathBuf::from("./state")).unwrap();
+        target
+            .run_migration(version, crate::direction::Direction::Up)
+            .unwrap();
+        target.tag("release", version).unwrap();
+
+        let state = target.export_state().unwrap();
+        let refused = target.import_state(&state, false).is_err();
+        target.import_state(&state, true).unwrap();
+        let reimported = target.export_state().unwrap();
+        drop(target);
+        m.client
+            .batch_execute(&format!("DROP DATABASE {}", config.dbname))
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./state");
+
+        assert!(refused);
+        assert_eq!(state.migrations.len(), 1);
+        assert_eq!(state.migrations[0].version, version);
+        assert!(state.migrations[0].applied_at.is_some());
+        assert_eq!(state.tags[0].tag, "release");
+        assert_eq!(reimported.migrations, state.migrations);
+        assert_eq!(reimported.tags, state.tags);
+    }
+}
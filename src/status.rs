@@ -0,0 +1,164 @@
+//! The `status` command: every migration version on disk with whether and when it was applied,
+//! versions applied that aren't on disk anymore, and whether the last version is dirty. Unlike
+//! other commands it works on a dirty database, where it is most needed.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::direction::Direction;
+use crate::Migrator;
+
+#[derive(Serialize)]
+pub(crate) struct VersionStatus {
+    pub(crate) version: i64,
+    /// "applied", "pending", "skipped", or "unknown" when applied but not on disk
+    pub(crate) state: &'static str,
+    /// RFC 3339, unless not applied or applied before this was tracked
+    pub(crate) applied_at: Option<String>,
+    /// Empty when unknown, see author.rs
+    pub(crate) author: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Status {
+    pub(crate) last_version: i64,
+    /// Whether the migration of the last version failed halfway
+    pub(crate) dirty: bool,
+    pub(crate) versions: Vec<VersionStatus>,
+}
+
+impl Migrator {
+    pub(crate) fn status(&mut self) -> Result<Status> {
+        let mut applied = std::collections::BTreeMap::<i64, Option<String>>::new();
+        let mut last = (0, false);
+        for row in self.client.query(
+            "SELECT version, applied_at, dirty FROM schema_migrations ORDER BY version",
+            &[],
+        )? {
+            let applied_at: Option<chrono::DateTime<chrono::Utc>> = row.get(1);
+            applied.insert(row.get(0), applied_at.map(|v| v.to_rfc3339()));
+            last = (row.get(0), row.get(2));
+        }
+        let skipped = self.skipped_versions()?;
+        let mut versions = Vec::<VersionStatus>::new();
+        for v in self.versions_up.iter() {
+            versions.push(VersionStatus {
+                version: *v,
+                state: match (applied.contains_key(v), skipped.contains_key(v)) {
+                    (true, _) => "applied",
+                    (false, true) => "skipped",
+                    (false, false) => "pending",
+                },
+                applied_at: applied.get(v).cloned().flatten(),
+                author: crate::author::read(&self.migration_path(*v, Direction::Up))?,
+            });
+        }
+        for (v, applied_at) in applied.iter() {
+            if self.versions_up.binary_search(v).is_err() {
+                versions.push(VersionStatus {
+                    version: *v,
+                    state: "unknown",
+                    applied_at: applied_at.clone(),
+                    author: String::new(),
+                });
+            }
+        }
+        versions.sort_by_key(|v| v.version);
+        Ok(Status {
+            last_version: last.0,
+            dirty: last.1,
+            versions,
+        })
+    }
+}
+
+pub(crate) fn print(status: &Status) {
+    println!(
+        "{:<15} {:<8} {:<19}  AUTHOR",
+        "VERSION", "STATE", "APPLIED AT"
+    );
+    for v in status.versions.iter() {
+        println!(
+            "{:<15} {:<8} {:<19}  {}",
+            v.version,
+            v.state,
+            v.applied_at
+                .as_deref()
+                .and_then(|v| v.get(..19))
+                .map(|v| v.replace('T', " "))
+                .unwrap_or_else(|| "-".to_owned()),
+            if v.author.is_empty() { "-" } else { &v.author }
+        );
+    }
+    let pending = status
+        .versions
+        .iter()
+        .filter(|v| v.state == "pending")
+        .count();
+    println!();
+    if status.dirty {
+        println!(
+            "last version {} is dirty, its migration failed halfway. fix the database and clear \
+            the dirty flag in schema_migrations before migrating further",
+            status.last_version
+        );
+    } else {
+        println!("at version {}, {} pending", status.last_version, pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn status() {
+        let dir = std::path::PathBuf::from("./status");
+        let mut config = crate::tests::schema_config("__status__");
+        let mut m = crate::Migrator::new(config.clone(), dir.clone()).unwrap();
+        for v in 1..=3 {
+            std::fs::write(
+                m.dir.join(format!("{}_up.sql", v)),
+                "-- architect:author ada\nSELECT 1;",
+            )
+            .unwrap();
+            std::fs::write(m.dir.join(format!("{}_down.sql", v)), "SELECT 1;").unwrap();
+        }
+        m.available_versions().unwrap();
+        m.migrate_up_n(2, false).unwrap();
+        m.client
+            .batch_execute(
+                "INSERT INTO schema_migrations (version) VALUES (4);
+                UPDATE schema_migrations SET dirty = true WHERE version = 4;",
+            )
+            .unwrap();
+        let refused = crate::Migrator::read_only(config.clone(), dir.clone()).is_err();
+        config.dirty_ok = true;
+        let mut m = crate::Migrator::read_only(config, dir.clone()).unwrap();
+        let status = m.status().unwrap();
+        m.client
+            .batch_execute("DROP SCHEMA __status__ CASCADE")
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(refused);
+        let states: Vec<(i64, &str)> = status
+            .versions
+            .iter()
+            .map(|v| (v.version, v.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (1, "applied"),
+                (2, "applied"),
+                (3, "pending"),
+                (4, "unknown")
+            ]
+        );
+        assert!(status.versions[0].applied_at.is_some());
+        assert!(status.versions[2].applied_at.is_none());
+        assert_eq!(status.versions[2].author, "ada");
+        assert_eq!(status.versions[3].author, "");
+        assert_eq!((status.last_version, status.dirty), (4, true));
+    }
+}
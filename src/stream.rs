@@ -0,0 +1,394 @@
+//! Streaming execution of huge migration files, like multi-GB seeds or backfills. Files larger
+//! than `stream_threshold_mb` aren't read and parsed whole: their statements are split off as the
+//! file is read and executed one by one in the migration's transaction, so memory stays bounded
+//! and execution starts right away. Statements are sent as written, without being normalized.
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::email::StatementRun;
+use crate::error::ArchitectError;
+use crate::output::info;
+use crate::Migrator;
+
+/// Default of `stream_threshold_mb`.
+pub(crate) const THRESHOLD_MB: u64 = 64;
+
+/// Statements between progress reports.
+const PROGRESS_EVERY: usize = 10_000;
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Code,
+    /// In a string, E'' strings allowing backslash escapes
+    Quote {
+        escapes: bool,
+    },
+    Ident,
+    LineComment,
+    /// Nesting depth
+    BlockComment(usize),
+    /// In a dollar quoted string with this delimiter, e.g. `$body$`
+    Dollar(String),
+}
+
+/// The statements of sql read from `reader`, split at semicolons outside of strings, quoted
+/// identifiers, comments and dollar quotes.
+pub(crate) struct Statements<R> {
+    reader: R,
+    state: State,
+    statement: String,
+    /// Whether the statement has more than whitespace and comments
+    has_code: bool,
+    ready: VecDeque<String>,
+    done: bool,
+}
+
+impl<R: BufRead> Statements<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Statements {
+            reader,
+            state: State::Code,
+            statement: String::new(),
+            has_code: false,
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn end_statement(&mut self) {
+        let statement = std::mem::take(&mut self.statement);
+        if self.has_code {
+            self.ready.push_back(statement.trim().to_owned());
+        }
+        self.has_code = false;
+    }
+
+    fn split_line(&mut self, line: &str) {
+        if self.state == State::LineComment {
+            self.state = State::Code;
+        }
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if c == ';' && self.state == State::Code {
+                self.end_statement();
+                i += 1;
+                continue;
+            }
+            let in_comment = matches!(self.state, State::LineComment | State::BlockComment(_));
+            let mut skip = 1;
+            match &mut self.state {
+                State::Code => match c {
+                    '-' if next == Some('-') => {
+                        self.state = State::LineComment;
+                        skip = 2;
+                    }
+                    '/' if next == Some('*') => {
+                        self.state = State::BlockComment(1);
+                        skip = 2;
+                    }
+                    '\'' => {
+                        let escapes = i > 0
+                            && matches!(chars[i - 1], 'e' | 'E')
+                            && !(i > 1 && (chars[i - 2].is_alphanumeric() || chars[i - 2] == '_'));
+                        self.state = State::Quote { escapes };
+                    }
+                    '"' => self.state = State::Ident,
+                    '$' => {
+                        let tag: String = chars[i + 1..]
+                            .iter()
+                            .take_while(|v| v.is_alphanumeric() || **v == '_')
+                            .collect();
+                        let closed = chars.get(i + 1 + tag.chars().count()) == Some(&'$');
+                        let positional = tag.starts_with(|v: char| v.is_ascii_digit());
+                        let identifier =
+                            i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+                        if closed && !positional && !identifier {
+                            let delimiter = format!("${}$", tag);
+                            skip = delimiter.chars().count();
+                            self.state = State::Dollar(delimiter);
+                        }
+                    }
+                    _ => {}
+                },
+                State::Quote { escapes } => match c {
+                    '\\' if *escapes => skip = 2,
+                    '\'' => self.state = State::Code,
+                    _ => {}
+                },
+                State::Ident => {
+                    if c == '"' {
+                        self.state = State::Code;
+                    }
+                }
+                State::LineComment => {}
+                State::BlockComment(depth) => {
+                    if c == '/' && next == Some('*') {
+                        *depth += 1;
+                        skip = 2;
+                    } else if c == '*' && next == Some('/') {
+                        *depth -= 1;
+                        skip = 2;
+                        if *depth == 0 {
+                            self.state = State::Code;
+                        }
+                    }
+                }
+                State::Dollar(delimiter) => {
+                    let end: String = chars[i..].iter().take(delimiter.chars().count()).collect();
+                    if end == *delimiter {
+                        skip = end.chars().count();
+                        self.state = State::Code;
+                    }
+                }
+            }
+            let code = !c.is_whitespace()
+                && !in_comment
+                && !matches!(self.state, State::LineComment | State::BlockComment(_));
+            if code {
+                self.has_code = true;
+            }
+            let end = (i + skip).min(chars.len());
+            self.statement.extend(&chars[i..end]);
+            i = end;
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Statements<R> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(v) = self.ready.pop_front() {
+                return Some(Ok(v));
+            }
+            if self.done {
+                return None;
+            }
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    self.done = true;
+                    self.end_statement();
+                }
+                Ok(_) => self.split_line(&line),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+fn open(path: &std::path::Path) -> Result<Statements<std::io::BufReader<std::fs::File>>> {
+    Ok(Statements::new(std::io::BufReader::new(
+        std::fs::File::open(path)?,
+    )))
+}
+
+/// Refuses directives of the file at `path` that need the whole file, read line by line.
+fn check_directives(path: &std::path::Path) -> Result<()> {
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    for (i, line) in reader.lines().enumerate() {
+        for d in crate::directives::parse(&line?) {
+            if d.name != crate::author::DIRECTIVE {
+                return Err(anyhow::anyhow!(
+                    "{:?} line {}: architect:{} isn't supported in files larger than \
+                    stream_threshold_mb, which are streamed",
+                    path,
+                    i + 1,
+                    d.name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Migrator {
+    /// The path of a migration too large to be read whole, None for other migrations.
+    pub(crate) fn streamed(
+        &self,
+        version: i64,
+        direction: Direction,
+    ) -> Option<std::path::PathBuf> {
+        if self.script_path(version, direction).is_some() {
+            return None;
+        }
+        let path = self.migration_path(version, direction);
+        let size = std::fs::metadata(&path).map(|v| v.len()).unwrap_or(0);
+        if size > self.config.stream_threshold_mb * 1024 * 1024 {
+            return Some(path);
+        }
+        None
+    }
+
+    /// The number of statements of the streamed file at `path`.
+    pub(crate) fn count_streamed(&self, path: &std::path::Path) -> Result<usize> {
+        check_directives(path)?;
+        let mut count = 0;
+        for s in open(path)? {
+            s?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Applies the streamed migration at `path` like `apply_migration`. Returns a single run
+    /// summing up its statements.
+    pub(crate) fn apply_streamed(
+        &mut self,
+        version: i64,
+        direction: Direction,
+        path: &std::path::Path,
+    ) -> Result<Vec<StatementRun>> {
+        check_directives(path)?;
+        let before_each = self.sql_hook("before_each")?;
+        let after_each = self.sql_hook("after_each")?;
+        let faults = self.faults.clone();
+        let pooled = self.pooled();
+        let start = std::time::Instant::now();
+        let mut t = self.client.transaction()?;
+        if pooled {
+            self.config.apply_session(&mut t, true)?;
+        }
+        if !before_each.is_empty() || !after_each.is_empty() {
+            t.execute(
+                "SELECT set_config('architect.version', $1, true), \
+                set_config('architect.direction', $2, true)",
+                &[&version.to_string(), &direction.as_str()],
+            )?;
+        }
+        for query in before_each.iter() {
+            t.batch_execute(query)?;
+        }
+        let mut count = 0;
+        for query in open(path)? {
+            let query = query?;
+            if let Err(e) = crate::views::execute(&mut t, &query) {
+                return Err(ArchitectError::MigrationFailed {
+                    version,
+                    direction,
+                    statement: Some(query),
+                    source: e,
+                }
+                .into());
+            }
+            count += 1;
+            crate::faults::inject(&faults, version, crate::faults::Point::Statement(count))?;
+            if count % PROGRESS_EVERY == 0 {
+                info!(
+                    "{} {:>8}ms  {} statements",
+                    version,
+                    start.elapsed().as_millis(),
+                    count
+                );
+            }
+        }
+        let run = StatementRun {
+            statement: format!("{} statements streamed from {:?}", count, path),
+            duration_ms: start.elapsed().as_millis(),
+        };
+        info!("{} {:>8}ms  {}", version, run.duration_ms, run.short());
+        crate::faults::inject(&faults, version, crate::faults::Point::Record)?;
+        t.batch_execute(&crate::record_query(version, direction, self.batch))?;
+        for query in after_each.iter() {
+            t.batch_execute(query)?;
+        }
+        if direction == Direction::Up {
+            let duration_ms = start.elapsed().as_millis() as i64;
+            t.execute(
+                "UPDATE schema_migrations SET duration_ms = $1 WHERE version = $2",
+                &[&duration_ms, &version],
+            )?;
+        }
+        let transaction = crate::grants::transaction_id(&mut t)?;
+        t.commit()?;
+        self.run_transactions.push(transaction);
+        crate::faults::inject(&faults, version, crate::faults::Point::Commit)?;
+        Ok(vec![run])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    fn split(sql: &str) -> Vec<String> {
+        super::Statements::new(sql.as_bytes())
+            .map(|v| v.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn split_statements() {
+        assert_eq!(
+            split(
+                "-- a; comment\nCREATE TABLE a (b TEXT DEFAULT 'x;y''z');\n\
+                /* one /* nested; */ comment */ INSERT INTO \"a;\" VALUES (E'\\';');\n\
+                CREATE FUNCTION f() RETURNS INT AS $body$ SELECT 1; $body$ LANGUAGE sql;\n\
+                SELECT $1;SELECT 2\n-- trailing; comment\n"
+            ),
+            vec![
+                "-- a; comment\nCREATE TABLE a (b TEXT DEFAULT 'x;y''z')",
+                "/* one /* nested; */ comment */ INSERT INTO \"a;\" VALUES (E'\\';')",
+                "CREATE FUNCTION f() RETURNS INT AS $body$ SELECT 1; $body$ LANGUAGE sql",
+                "SELECT $1",
+                "SELECT 2\n-- trailing; comment",
+            ]
+        );
+        assert!(split("-- only a comment;\n  \n").is_empty());
+    }
+
+    #[test]
+    fn streams_large_files() {
+        let mut config = test_config().unwrap();
+        config.stream_threshold_mb = 1;
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./stream")).unwrap();
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __stream__")
+            .unwrap();
+        let (up, down) = m.new_migration().unwrap();
+        let mut sql = String::from("CREATE TABLE __stream__ (id INT, note TEXT);\n");
+        for i in 0..30_000 {
+            sql.push_str(&format!(
+                "INSERT INTO __stream__ VALUES ({}, 'row; {}');\n",
+                i, i
+            ));
+        }
+        std::fs::write(&up, sql).unwrap();
+        std::fs::write(&down, "DROP TABLE __stream__;").unwrap();
+        let version = *m.versions_up.last().unwrap();
+
+        let streamed = m.streamed(version, crate::direction::Direction::Up);
+        let migrated = m.migrate_up(false);
+        let count: i64 = m
+            .client
+            .query_one("SELECT count(*) FROM __stream__", &[])
+            .map(|v| v.get(0))
+            .unwrap_or(0);
+        m.client
+            .batch_execute("DROP TABLE IF EXISTS __stream__")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./stream");
+
+        assert!(streamed.is_some());
+        migrated.unwrap();
+        assert_eq!(count, 30_000);
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+
+use crate::Migrator;
+
+impl Migrator {
+    /// Points `name` at `version` in the `schema_tags` table. Re-tagging moves an existing tag.
+    pub(crate) fn tag(&mut self, name: &str, version: i64) -> Result<()> {
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("tag name cannot be empty"));
+        }
+        if name.parse::<i64>().is_ok() {
+            return Err(anyhow::anyhow!(
+                "tag \"{}\" would be ambiguous with a version number",
+                name
+            ));
+        }
+        if !self.versions_up.contains(&version) {
+            return Err(anyhow::anyhow!("version {} does not exist", version));
+        }
+        self.client.execute(
+            "INSERT INTO schema_tags(tag, version) VALUES ($1, $2)
+            ON CONFLICT (tag) DO UPDATE SET version = excluded.version",
+            &[&name, &version],
+        )?;
+        Ok(())
+    }
+
+    /// Resolves a version argument given on the command line. Numbers are taken as is, anything
+    /// else is looked up as a tag.
+    pub(crate) fn resolve_version(&mut self, v: &str) -> Result<i64> {
+        if let Ok(version) = v.parse::<i64>() {
+            return Ok(version);
+        }
+        match self
+            .client
+            .query("SELECT version FROM schema_tags WHERE tag = $1", &[&v])?
+            .first()
+        {
+            Some(row) => Ok(row.get(0)),
+            None => Err(anyhow::anyhow!("unknown version or tag: {}", v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn tag_and_resolve() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./tags")).unwrap();
+        m.new_migration().unwrap();
+        m.new_migration().unwrap();
+        let first = *m.versions_up.first().unwrap();
+        let last = *m.versions_up.last().unwrap();
+
+        m.tag("__test_release__", first).unwrap();
+        let tagged = m.resolve_version("__test_release__").unwrap();
+        m.tag("__test_release__", last).unwrap();
+        let moved = m.resolve_version("__test_release__").unwrap();
+        let numeric = m.resolve_version(&first.to_string()).unwrap();
+        let unknown = m.resolve_version("__no_such_tag__");
+        let ambiguous = m.tag("42", first);
+
+        let _ = std::fs::remove_dir_all("./tags");
+
+        assert_eq!(tagged, first);
+        assert_eq!(moved, last);
+        assert_eq!(numeric, first);
+        assert!(unknown.is_err());
+        assert!(ambiguous.is_err());
+    }
+}
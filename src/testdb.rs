@@ -0,0 +1,257 @@
+use anyhow::Result;
+
+use crate::output::info;
+use crate::{Config, Migrator};
+
+enum Cleanup {
+    /// Drop the database using a connection made with this config
+    Database(Box<Config>),
+    /// Remove the docker container with this id
+    Container(String),
+}
+
+/// A throw away database that is removed again when dropped.
+pub(crate) struct Ephemeral {
+    /// Config connecting to the throw away database
+    pub(crate) config: Config,
+    cleanup: Cleanup,
+}
+
+impl Ephemeral {
+    /// Creates a temporary database on the server of `config`, cloned from `template` if given.
+    pub(crate) fn database(config: &Config, template: Option<&str>) -> Result<Self> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis();
+        let name = format!("architect_test_{}_{}", std::process::id(), ts);
+        let mut admin = config.clone();
+        let sql = match template {
+            Some(v) => format!("CREATE DATABASE {name} TEMPLATE {v}"),
+            None => format!("CREATE DATABASE {name}"),
+        };
+        admin.connect()?.batch_execute(&sql)?;
+
+        // connecting filled in the password, so plugins aren't needed anymore
+        let mut ephemeral = admin.clone();
+        ephemeral.dbname = name;
+        ephemeral.hooks = Default::default();
+        ephemeral.plugins = Vec::new();
+        ephemeral.email = Default::default();
+        Ok(Ephemeral {
+            config: ephemeral,
+            cleanup: Cleanup::Database(Box::new(admin)),
+        })
+    }
+
+    /// Starts a postgres container from `image` and waits for it to accept connections.
+    pub(crate) fn docker(config: &Config, image: &str) -> Result<Self> {
+        let id = docker(&[
+            "run",
+            "-d",
+            "--rm",
+            "-e",
+            "POSTGRES_PASSWORD=architect",
+            "-p",
+            "127.0.0.1::5432",
+            image,
+        ])?;
+        let mut ephemeral = Ephemeral {
+            config: Config {
+                app: config.app.clone(),
+                host: "127.0.0.1".to_owned(),
+                dbname: "postgres".to_owned(),
+                user: "postgres".to_owned(),
+                password: "architect".to_owned(),
+                connect_timeout_seconds: 5,
+                ..Default::default()
+            },
+            cleanup: Cleanup::Container(id.clone()),
+        };
+
+        let port = docker(&["port", &id, "5432/tcp"])?;
+        ephemeral.config.port = match port.lines().next().and_then(|v| v.rsplit(':').next()) {
+            Some(v) => v.parse()?,
+            None => return Err(anyhow::anyhow!("couldn't read port of container {}", id)),
+        };
+
+        let mut attempts = 0;
+        loop {
+            match ephemeral.config.clone().connect() {
+                Ok(_) => break,
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= 60 {
+                        return Err(anyhow::anyhow!("container {} did not start: {}", id, e));
+                    }
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        }
+        Ok(ephemeral)
+    }
+}
+
+#[cfg(test)]
+impl Ephemeral {
+    /// Keeps the container until this process exits rather than until dropped, for the database
+    /// shared by the crate's tests. A detached shell waits for the process and removes the
+    /// container and `files` then. Unix only, as the shell is `sh`; tests on Windows need
+    /// `ARCHITECT_TEST_CONFIG`.
+    pub(crate) fn keep_until_exit(self, files: &[&std::path::Path]) -> Result<Config> {
+        if cfg!(windows) {
+            // dropped, removing the container
+            return Err(anyhow::anyhow!(
+                "the test container is only kept on Unix, set ARCHITECT_TEST_CONFIG"
+            ));
+        }
+        let id = match &self.cleanup {
+            Cleanup::Container(v) => v.clone(),
+            Cleanup::Database(_) => {
+                return Err(anyhow::anyhow!(
+                    "only containers are kept until the process exits"
+                ))
+            }
+        };
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "while kill -0 {} 2>/dev/null; do sleep 1; done; docker rm -f {}; rm -f {}",
+                std::process::id(),
+                id,
+                files
+                    .iter()
+                    .map(|v| format!("'{}'", v.display()))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ))
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        let this = std::mem::ManuallyDrop::new(self);
+        Ok(this.config.clone())
+    }
+}
+
+impl Drop for Ephemeral {
+    fn drop(&mut self) {
+        let result = match &self.cleanup {
+            Cleanup::Database(admin) => admin.clone().connect().and_then(|mut client| {
+                Ok(client
+                    .batch_execute(&format!("DROP DATABASE IF EXISTS {}", self.config.dbname))?)
+            }),
+            Cleanup::Container(id) => docker(&["rm", "-f", id]).map(|_| ()),
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "failed to remove test database {}: {}",
+                self.config.dbname, e
+            );
+        }
+    }
+}
+
+fn docker(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("docker").args(args).output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "docker {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Runs all migrations from zero against an ephemeral database. With `template` the database is
+/// cloned from the template database instead, so only pending migrations run.
+pub(crate) fn run(
+    config: Config,
+    dir: std::path::PathBuf,
+    docker: bool,
+    image: &str,
+    template: bool,
+) -> Result<()> {
+    let db = if docker {
+        Ephemeral::docker(&config, image)?
+    } else if template {
+        let name = Migrator::new(config.clone(), dir.clone())?.template()?;
+        Ephemeral::database(&config, Some(&name))?
+    } else {
+        Ephemeral::database(&config, None)?
+    };
+    info!(
+        "running migrations against database {} on {}:{}",
+        db.config.dbname, db.config.host, db.config.port
+    );
+    // the migrator is dropped before the database, closing its connection first
+    let mut m = Migrator::new(db.config.clone(), dir)?;
+    if m.versions_up.iter().all(|v| *v <= m.last_version) {
+        crate::output::result(
+            "no pending migrations",
+            serde_json::json!({ "migrated": 0 }),
+        );
+        return Ok(());
+    }
+    let count = m.migrate_up(false)?;
+    crate::output::result(
+        &format!("Migrated up {} versions!", count),
+        serde_json::json!({ "migrated": count, "version": m.last_version }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn ephemeral_database() {
+        let config = test_config().unwrap();
+        let db = super::Ephemeral::database(&config, None).unwrap();
+        let name = db.config.dbname.clone();
+        let connected = db.config.clone().connect().is_ok();
+        drop(db);
+
+        let rows = config
+            .clone()
+            .connect()
+            .unwrap()
+            .query("SELECT 1 FROM pg_database WHERE datname = $1", &[&name])
+            .unwrap();
+
+        assert!(connected);
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn ephemeral_from_template() {
+        let mut config = test_config().unwrap();
+        config.template_dbname = format!("architect_template_test_{}", std::process::id());
+        let mut m =
+            crate::Migrator::new(config.clone(), std::path::PathBuf::from("./template")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.sql", version)),
+            b"CREATE TABLE __template__ (id INT);",
+        )
+        .unwrap();
+        // pretend the migration is applied to the main database
+        m.last_version = version;
+
+        let template = m.template().unwrap();
+        let db = super::Ephemeral::database(&config, Some(&template)).unwrap();
+        let clone = crate::Migrator::new(db.config.clone(), std::path::PathBuf::from("./template"))
+            .unwrap()
+            .last_version;
+        drop(db);
+        m.client
+            .batch_execute(&format!("DROP DATABASE IF EXISTS {}", template))
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./template");
+
+        assert_eq!(clone, version);
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::Result;
+
+use crate::direction::Direction;
+use crate::lint::{Finding, Severity};
+
+/// Checks the migration files in `dir` without a database connection. Lint findings of `plugins`
+/// are included.
+pub(crate) fn validate(dir: &std::path::Path, plugins: &[String]) -> Result<Vec<Finding>> {
+    let mut result = Vec::<Finding>::new();
+    let reg = regex::Regex::new(crate::MIGRATION_FILE)?;
+    let project = match dir.parent() {
+        Some(v) => crate::project::Project::read(v)?,
+        None => crate::project::Project::default(),
+    };
+
+    let mut files = Vec::<String>::new();
+    for f in std::fs::read_dir(dir)? {
+        let f = f?;
+        if !f.file_type()?.is_file() {
+            continue;
+        }
+        let name = f.file_name().to_string_lossy().to_string();
+        if name.ends_with(".sql") && !reg.is_match(&name) {
+            result.push(Finding::new(
+                &name,
+                "naming",
+                Severity::Error,
+                "file name does not match <version>_(up|down).(sql|rhai|wasm) and will be ignored"
+                    .to_owned(),
+            ));
+        }
+        if reg.is_match(&name) {
+            files.push(name);
+        }
+    }
+    files.sort();
+
+    let (up, down) = crate::scan_versions(dir)?;
+    for v in up.iter() {
+        if !down.contains(v) {
+            result.push(Finding::new(
+                &format!("{}_up.sql", v),
+                "pairing",
+                Severity::Error,
+                format!("missing {}_down.sql", v),
+            ));
+        }
+    }
+    for v in down.iter() {
+        if !up.contains(v) {
+            result.push(Finding::new(
+                &format!("{}_down.sql", v),
+                "pairing",
+                Severity::Error,
+                format!("missing {}_up.sql", v),
+            ));
+        }
+    }
+
+    for name in files.iter() {
+        if let Some((stem, extension)) = name.rsplit_once('.') {
+            if extension != "sql" {
+                let others = ["sql", "rhai", "wasm"]
+                    .iter()
+                    .filter(|v| **v != extension && files.contains(&format!("{}.{}", stem, v)));
+                for other in others {
+                    result.push(Finding::new(
+                        name,
+                        "naming",
+                        Severity::Error,
+                        format!("both {}.{} and {} exist", stem, other, name),
+                    ));
+                }
+                if let Err(e) = check_script(&dir.join(name)) {
+                    result.push(Finding::new(name, "parse", Severity::Error, e.to_string()));
+                }
+                continue;
+            }
+        }
+        let sql = std::fs::read_to_string(dir.join(name))?;
+        for d in crate::directives::parse(&sql) {
+            if !crate::directives::KNOWN.contains(&d.name.as_str()) {
+                result.push(Finding::new(
+                    name,
+                    "directive",
+                    Severity::Error,
+                    format!("unknown directive \"{}\" on line {}", d.name, d.line),
+                ));
+            }
+        }
+        if let Some(args) = crate::batch_update::directive(&sql) {
+            if let Err(e) = crate::batch_update::rewrite(&sql, &args) {
+                result.push(Finding::new(
+                    name,
+                    crate::batch_update::DIRECTIVE,
+                    Severity::Error,
+                    e.to_string(),
+                ));
+            }
+        }
+        if let Some(args) = crate::foreign_key::directive(&sql) {
+            if let Err(e) = crate::foreign_key::Validate::parse(&args) {
+                result.push(Finding::new(
+                    name,
+                    crate::foreign_key::DIRECTIVE,
+                    Severity::Error,
+                    e.to_string(),
+                ));
+            }
+        }
+        if let Err(e) = crate::refresh::directives(&sql) {
+            result.push(Finding::new(
+                name,
+                crate::refresh::DIRECTIVE,
+                Severity::Error,
+                e.to_string(),
+            ));
+        }
+        if let Err(e) = crate::post::directives(&sql) {
+            result.push(Finding::new(
+                name,
+                crate::post::DIRECTIVE,
+                Severity::Error,
+                e.to_string(),
+            ));
+        }
+        if let Err(e) = crate::requires::requirements(&sql) {
+            result.push(Finding::new(
+                name,
+                crate::requires::DIRECTIVE,
+                Severity::Error,
+                e.to_string(),
+            ));
+        }
+        if let Err(e) = crate::conditions::Blocks::parse(&sql) {
+            result.push(Finding::new(
+                name,
+                crate::conditions::DIRECTIVE,
+                Severity::Error,
+                e.to_string(),
+            ));
+        }
+        for e in crate::assertions::check(&sql) {
+            result.push(Finding::new(
+                name,
+                crate::assertions::DIRECTIVE,
+                Severity::Error,
+                e,
+            ));
+        }
+        if crate::directives::has(&sql, crate::directives::VERBATIM) {
+            continue;
+        }
+        let direction = if name.ends_with("_up.sql") {
+            Direction::Up
+        } else {
+            Direction::Down
+        };
+        let statements = match crate::cache::parse(name, direction, &sql) {
+            Ok(mut parsed) => {
+                result.append(&mut parsed.lint);
+                parsed.statements
+            }
+            Err(e) => {
+                result.push(Finding::new(name, "parse", Severity::Error, e.to_string()));
+                continue;
+            }
+        };
+        if project.idempotent {
+            result.append(&mut crate::idempotent::check(name, &statements));
+        }
+        result.append(&mut project.naming.check(name, &statements)?);
+        result.append(&mut crate::plugins::lint(
+            plugins,
+            name,
+            direction.as_str(),
+            &sql,
+        )?);
+    }
+
+    result.append(&mut crate::irreversible::check(
+        dir,
+        &up,
+        project.require_down,
+    )?);
+
+    for d in crate::lock::verify(dir)? {
+        result.push(Finding::new(
+            crate::lock::LOCK_FILE,
+            "lock",
+            Severity::Error,
+            d,
+        ));
+    }
+
+    Ok(result)
+}
+
+/// Compiles a script or WASM module if architect was built with support for it.
+fn check_script(path: &std::path::Path) -> Result<()> {
+    match path.extension().and_then(|v| v.to_str()) {
+        #[cfg(feature = "rhai")]
+        Some("rhai") => {
+            if let Err(e) = rhai::Engine::new().compile(std::fs::read_to_string(path)?) {
+                return Err(anyhow::anyhow!("{}", e));
+            }
+        }
+        #[cfg(feature = "wasm")]
+        Some("wasm") => {
+            wasmi::Module::new(&wasmi::Engine::default(), &std::fs::read(path)?[..])?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Prints the findings for `dir`, failing if any of them is an error.
+pub(crate) fn run(dir: &std::path::Path, plugins: &[String]) -> Result<()> {
+    let findings = validate(dir, plugins)?;
+    for f in findings.iter() {
+        println!("{}", f);
+    }
+    for version in crate::lock::changed(dir)? {
+        for line in crate::lock::explain(dir, version)? {
+            println!("{}", line);
+        }
+    }
+    for (file, teams) in crate::owners::of_dir(dir)? {
+        println!("{}: owned by {}", file, teams.join(", "));
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow::anyhow!("validation failed with {} errors", errors));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn validate_dir() {
+        let dir = std::path::PathBuf::from("./validate");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1_up.sql"), b"CREATE TABLE a (id INT);").unwrap();
+        std::fs::write(dir.join("1_down.sql"), b"DROP TABLE a;").unwrap();
+        std::fs::write(dir.join("2_up.sql"), b"CREATE TABLE b (id INT").unwrap();
+        std::fs::write(dir.join("3_down.sql"), b"-- architect:nope\nDROP TABLE c;").unwrap();
+        std::fs::write(dir.join("4-up.sql"), b"").unwrap();
+
+        let findings = super::validate(&dir, &[]).unwrap();
+        let rules: Vec<&str> = findings.iter().map(|f| f.rule.as_str()).collect();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(rules.contains(&"naming"));
+        assert!(rules.contains(&"parse"));
+        assert!(rules.contains(&"directive"));
+        assert_eq!(rules.iter().filter(|v| **v == "pairing").count(), 2);
+        assert!(!findings.iter().any(|f| f.file.starts_with("1_")));
+    }
+}
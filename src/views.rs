@@ -0,0 +1,218 @@
+//! Views depending on altered columns. Postgres refuses to change the type of a column a view
+//! uses, so a statement altering a column's type is wrapped in dropping the views depending on
+//! it, directly or through other views, and recreating them from their original definitions in
+//! the same transaction.
+
+use anyhow::Result;
+use sqlparser::ast::{AlterColumnOperation, AlterTableOperation, Statement};
+
+use crate::output::info;
+
+/// A view as it was before it was dropped.
+#[derive(Debug)]
+pub(crate) struct View {
+    pub(crate) name: String,
+    pub(crate) materialized: bool,
+    pub(crate) definition: String,
+    pub(crate) options: Option<String>,
+    pub(crate) comment: Option<String>,
+    /// Definitions of the view's indexes, only materialized views have any
+    pub(crate) indexes: Vec<String>,
+}
+
+impl View {
+    fn kind(&self) -> &'static str {
+        if self.materialized {
+            "MATERIALIZED VIEW"
+        } else {
+            "VIEW"
+        }
+    }
+
+    fn drop(&self) -> String {
+        format!("DROP {} {}", self.kind(), self.name)
+    }
+
+    fn create(&self) -> String {
+        let options = match &self.options {
+            Some(v) => format!(" WITH ({})", v),
+            None => String::new(),
+        };
+        let mut sql = format!(
+            "CREATE {} {}{} AS {}",
+            self.kind(),
+            self.name,
+            options,
+            self.definition.trim().trim_end_matches(';')
+        );
+        if let Some(comment) = &self.comment {
+            sql.push_str(&format!(
+                ";\nCOMMENT ON {} {} IS '{}'",
+                self.kind(),
+                self.name,
+                comment.replace('\'', "''")
+            ));
+        }
+        for index in self.indexes.iter() {
+            sql.push_str(";\n");
+            sql.push_str(index);
+        }
+        sql
+    }
+}
+
+/// The tables and columns whose type `query` changes.
+pub(crate) fn altered_columns(query: &str) -> Vec<(String, String)> {
+    let statements = match crate::parse_ast(query) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::<(String, String)>::new();
+    for s in statements.iter() {
+        if let Statement::AlterTable {
+            name,
+            operation:
+                AlterTableOperation::AlterColumn {
+                    column_name,
+                    op: AlterColumnOperation::SetDataType { .. },
+                },
+        } = s
+        {
+            result.push((name.to_string(), column_name.value.clone()));
+        }
+    }
+    result
+}
+
+/// The views depending on `table.column`, directly or through other views, each after the views
+/// it depends on.
+pub(crate) fn dependent(
+    client: &mut impl postgres::GenericClient,
+    table: &str,
+    column: &str,
+) -> Result<Vec<View>> {
+    let rows = client.query(
+        "WITH RECURSIVE deps AS (
+            SELECT r.ev_class AS oid, 1 AS depth
+            FROM pg_depend d
+            JOIN pg_rewrite r ON r.oid = d.objid
+            JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid
+            WHERE d.classid = 'pg_rewrite'::regclass AND d.refobjid = $1::text::regclass
+                AND a.attname = $2 AND r.ev_class <> d.refobjid
+            UNION
+            SELECT r.ev_class, deps.depth + 1
+            FROM deps
+            JOIN pg_depend d ON d.refobjid = deps.oid AND d.classid = 'pg_rewrite'::regclass
+            JOIN pg_rewrite r ON r.oid = d.objid
+            WHERE r.ev_class <> deps.oid
+        )
+        SELECT c.oid::regclass::text, c.relkind = 'm', pg_get_viewdef(c.oid),
+            array_to_string(c.reloptions, ', '), obj_description(c.oid, 'pg_class'),
+            ARRAY(SELECT pg_get_indexdef(i.indexrelid) FROM pg_index i WHERE i.indrelid = c.oid)
+        FROM deps
+        JOIN pg_class c ON c.oid = deps.oid
+        GROUP BY c.oid
+        ORDER BY max(deps.depth), c.oid",
+        &[&table, &column],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| View {
+            name: row.get(0),
+            materialized: row.get(1),
+            definition: row.get(2),
+            options: row.get(3),
+            comment: row.get(4),
+            indexes: row.get(5),
+        })
+        .collect())
+}
+
+/// Executes `query`, dropping the views depending on the columns whose type it changes before
+/// and recreating them after it.
+pub(crate) fn execute(client: &mut impl postgres::GenericClient, query: &str) -> Result<()> {
+    let mut views = Vec::<View>::new();
+    for (table, column) in altered_columns(query) {
+        for v in dependent(client, &table, &column)? {
+            if !views.iter().any(|w| w.name == v.name) {
+                views.push(v);
+            }
+        }
+    }
+    for v in views.iter().rev() {
+        client.batch_execute(&v.drop())?;
+    }
+    client.batch_execute(query)?;
+    for v in views.iter() {
+        info!("recreating {} {}", v.kind().to_lowercase(), v.name);
+        client.batch_execute(&v.create())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_config;
+
+    #[test]
+    fn dependent_views() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./views")).unwrap();
+        m.client
+            .batch_execute(
+                "DROP TABLE IF EXISTS __views__ CASCADE;
+                CREATE TABLE __views__ (id INT PRIMARY KEY, amount INT, note TEXT);
+                INSERT INTO __views__ VALUES (1, 10, 'a');
+                CREATE VIEW __views_amounts__ AS SELECT id, amount FROM __views__;
+                COMMENT ON VIEW __views_amounts__ IS 'amounts';
+                CREATE VIEW __views_total__ AS SELECT sum(amount) AS total FROM __views_amounts__;
+                CREATE MATERIALIZED VIEW __views_notes__ AS SELECT id, note FROM __views__;
+                CREATE VIEW __views_ids__ AS SELECT id FROM __views__;",
+            )
+            .unwrap();
+        let (up, _) = m.new_migration().unwrap();
+        std::fs::write(
+            &up,
+            "ALTER TABLE __views__ ALTER COLUMN amount TYPE BIGINT;\n\
+            ALTER TABLE __views__ ALTER COLUMN note TYPE VARCHAR(20);",
+        )
+        .unwrap();
+        let version = *m.versions_up.last().unwrap();
+        let migrated = m.migrate_up(false);
+        let row = m
+            .client
+            .query_one(
+                "SELECT pg_typeof(amount)::text, obj_description('__views_amounts__'::regclass, \
+                'pg_class'), (SELECT total FROM __views_total__)::bigint, \
+                (SELECT count(*) FROM __views_notes__), (SELECT count(*) FROM __views_ids__) \
+                FROM __views_amounts__",
+                &[],
+            )
+            .map(|row| {
+                (
+                    row.get::<_, String>(0),
+                    row.get::<_, Option<String>>(1),
+                    row.get::<_, i64>(2),
+                    row.get::<_, i64>(3),
+                    row.get::<_, i64>(4),
+                )
+            });
+        m.client
+            .batch_execute("DROP TABLE __views__ CASCADE")
+            .unwrap();
+        m.client
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&version],
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./views");
+
+        assert_eq!(migrated.unwrap(), 1);
+        assert_eq!(
+            row.unwrap(),
+            ("bigint".to_owned(), Some("amounts".to_owned()), 10, 1, 1)
+        );
+    }
+}
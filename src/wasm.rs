@@ -0,0 +1,216 @@
+//! Migrations packaged as WASM modules, `<version>_(up|down).wasm`, for compiled logic shipped
+//! through the migration directory. Modules run in a sandbox without any WASI access and export
+//! `memory` and `migrate() -> i32`, returning 0 on success. The host API is imported from the
+//! `architect` module:
+//!
+//! - `execute(sql_ptr: i32, sql_len: i32) -> i64` runs one statement and returns the rows
+//!   affected. A failing statement aborts the migration.
+//! - `config(key_ptr: i32, key_len: i32, out_ptr: i32, out_cap: i32) -> i32` writes the value of
+//!   `app`, `host`, `port`, `dbname` or `user` to `out_ptr`, returning its full length, or -1 for
+//!   other keys
+//! - `log(ptr: i32, len: i32)` prints to stderr
+//!
+//! Strings are utf-8. Running modules requires building with the `wasm` feature.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::Result;
+use postgres::Client;
+
+use crate::Config;
+
+#[cfg(not(feature = "wasm"))]
+pub(crate) fn run(
+    _client: Rc<RefCell<Client>>,
+    _config: &Config,
+    path: &std::path::Path,
+) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "{:?} is a WASM migration but architect was built without the wasm feature",
+        path
+    ))
+}
+
+#[cfg(feature = "wasm")]
+struct Host {
+    client: Rc<RefCell<Client>>,
+    config: Config,
+}
+
+#[cfg(feature = "wasm")]
+fn memory(caller: &wasmi::Caller<'_, Host>) -> Result<wasmi::Memory, wasmi::Error> {
+    match caller.get_export("memory").and_then(|v| v.into_memory()) {
+        Some(v) => Ok(v),
+        None => Err(wasmi::Error::new("module does not export memory")),
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn read_string(
+    caller: &wasmi::Caller<'_, Host>,
+    ptr: i32,
+    len: i32,
+) -> Result<String, wasmi::Error> {
+    let mut buffer = vec![0u8; len as usize];
+    if let Err(e) = memory(caller)?.read(caller, ptr as usize, &mut buffer) {
+        return Err(wasmi::Error::new(e.to_string()));
+    }
+    match String::from_utf8(buffer) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(wasmi::Error::new(e.to_string())),
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn linker(engine: &wasmi::Engine) -> Result<wasmi::Linker<Host>> {
+    use wasmi::Caller;
+
+    let mut linker = wasmi::Linker::<Host>::new(engine);
+    linker.func_wrap(
+        "architect",
+        "execute",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<i64, wasmi::Error> {
+            let sql = read_string(&caller, ptr, len)?;
+            match caller.data().client.borrow_mut().execute(sql.as_str(), &[]) {
+                Ok(v) => Ok(v as i64),
+                Err(e) => Err(wasmi::Error::new(format!("{}: {}", sql, e))),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "architect",
+        "config",
+        |mut caller: Caller<'_, Host>,
+         key_ptr: i32,
+         key_len: i32,
+         out_ptr: i32,
+         out_cap: i32|
+         -> Result<i32, wasmi::Error> {
+            let key = read_string(&caller, key_ptr, key_len)?;
+            let config = &caller.data().config;
+            let value = match key.as_str() {
+                "app" => config.app.clone(),
+                "host" => config.host.clone(),
+                "port" => config.port.to_string(),
+                "dbname" => config.dbname.clone(),
+                "user" => config.user.clone(),
+                _ => return Ok(-1),
+            };
+            let n = value.len().min(out_cap.max(0) as usize);
+            let memory = memory(&caller)?;
+            if let Err(e) = memory.write(&mut caller, out_ptr as usize, &value.as_bytes()[..n]) {
+                return Err(wasmi::Error::new(e.to_string()));
+            }
+            Ok(value.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "architect",
+        "log",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+            crate::output::info!("{}", read_string(&caller, ptr, len)?);
+            Ok(())
+        },
+    )?;
+    Ok(linker)
+}
+
+/// Runs the `migrate` export of the module at `path` with `client` bound to the host API.
+#[cfg(feature = "wasm")]
+pub(crate) fn run(
+    client: Rc<RefCell<Client>>,
+    config: &Config,
+    path: &std::path::Path,
+) -> Result<()> {
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, &std::fs::read(path)?[..])?;
+    let mut store = wasmi::Store::new(
+        &engine,
+        Host {
+            client,
+            config: config.clone(),
+        },
+    );
+    let instance = linker(&engine)?
+        .instantiate(&mut store, &module)?
+        .start(&mut store)?;
+    let migrate = instance.get_typed_func::<(), i32>(&store, "migrate")?;
+    match migrate.call(&mut store, ())? {
+        0 => Ok(()),
+        v => Err(anyhow::anyhow!("{:?} returned {}", path, v)),
+    }
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use crate::tests::test_config;
+
+    const UP: &str = r#"
+        (module
+            (import "architect" "execute" (func $execute (param i32 i32) (result i64)))
+            (import "architect" "config" (func $config (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "CREATE TABLE __wasm__ (id INT)")
+            (data (i32.const 64) "app")
+            (func (export "migrate") (result i32)
+                (drop (call $execute (i32.const 0) (i32.const 30)))
+                (if (result i32) (i32.lt_s (call $config (i32.const 64) (i32.const 3) (i32.const 128) (i32.const 64)) (i32.const 1))
+                    (then (i32.const 1))
+                    (else (i32.const 0)))))
+    "#;
+
+    const DOWN: &str = r#"
+        (module
+            (import "architect" "execute" (func $execute (param i32 i32) (result i64)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "DROP TABLE __no_such_table__")
+            (func (export "migrate") (result i32)
+                (drop (call $execute (i32.const 0) (i32.const 28)))
+                (i32.const 0)))
+    "#;
+
+    #[test]
+    fn wasm_migration() {
+        let config = test_config().unwrap();
+        let mut m = crate::Migrator::new(config, std::path::PathBuf::from("./wasm")).unwrap();
+        m.new_migration().unwrap();
+        let version = *m.versions_up.last().unwrap();
+        std::fs::remove_file(m.dir.join(format!("{}_up.sql", version))).unwrap();
+        std::fs::remove_file(m.dir.join(format!("{}_down.sql", version))).unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_up.wasm", version)),
+            wat::parse_str(UP).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            m.dir.join(format!("{}_down.wasm", version)),
+            wat::parse_str(DOWN).unwrap(),
+        )
+        .unwrap();
+        m.available_versions().unwrap();
+        m.last_version = 0;
+
+        let up = m.migrate_up(false);
+        let created = m
+            .client
+            .query("SELECT 1 FROM pg_class WHERE relname = '__wasm__'", &[])
+            .map(|v| v.len());
+        // the down module fails, rolling back and keeping the version applied
+        let down = m.migrate_down(false);
+        let applied = m.applied_versions().unwrap().contains(&version);
+        m.client
+            .batch_execute(&format!(
+                "DROP TABLE __wasm__; DELETE FROM schema_migrations WHERE version = {}",
+                version
+            ))
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all("./wasm");
+
+        up.unwrap();
+        assert_eq!(created.unwrap(), 1);
+        assert!(down.is_err());
+        assert!(applied);
+    }
+}